@@ -17,10 +17,12 @@
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+use std::io::{self, Write};
+
 use clap::{Parser, Subcommand};
 
 use ui::TaskManager;
-use vault::{NodeKind, Provider, Vault};
+use vault::{DirEntry, GcOutcome, NodeStat, Provider, Vault};
 
 const APP_NAME: &str = "exomem";
 
@@ -38,7 +40,7 @@ enum Commands {
         /// The directory to list the contents of.
         path: Option<String>,
     },
-    /// Get a file.
+    /// Print the contents of a file.
     Get {
         /// The file to get.
         path: String,
@@ -48,11 +50,32 @@ enum Commands {
         /// The file to put.
         path: String,
     },
+    /// Print the kind and size of a file or directory.
+    Stat {
+        /// The path of the file or directory.
+        path: String,
+    },
     /// Create a directory.
     Mkdir {
         /// The path of the directory to create.
         path: String,
     },
+    /// Print how many blocks of each size the vault uses.
+    Blocks,
+    /// Check every saved block for corruption.
+    Fsck,
+    /// Delete the saved blocks that the vault doesn't need anymore.
+    Gc {
+        /// Only print what would be deleted.
+        #[arg(long)]
+        dry_run: bool,
+        /// The number of the most recent snapshots whose blocks are kept.
+        #[arg(long, default_value_t = 0)]
+        keep_snapshots: usize,
+        /// Delete without asking for confirmation.
+        #[arg(long)]
+        yes: bool,
+    },
     /// Initialize state.
     Init {
         /// The path of the state file.
@@ -62,30 +85,61 @@ enum Commands {
 
 fn main() {
     let cli = Cli::parse();
-    let provider = Provider::new();
+    let provider = Provider::with_base_dir("temp");
 
     if let Commands::Init { path } = &cli.command {
         TaskRunner::init(&provider, path);
         return;
     }
+    if let Commands::Fsck = &cli.command {
+        TaskRunner::fsck(&provider);
+        return;
+    }
 
-    let mut vault = Vault::open(&provider, "vault.db");
+    let mut vault = match Vault::open(&provider, "vault.db") {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("Failed to open the vault: {e}");
+            return;
+        }
+    };
     let mut task_runner = TaskRunner::new(&mut vault);
 
     match &cli.command {
         Commands::List { path } => task_runner.list(path),
         Commands::Get { path } => task_runner.get(path),
         Commands::Put { path } => task_runner.put(path),
+        Commands::Stat { path } => task_runner.stat(path),
         Commands::Mkdir { path } => task_runner.create_directory(path),
-        Commands::Init { .. } => unreachable!(),
+        Commands::Blocks => task_runner.block_size_histogram(),
+        Commands::Gc {
+            dry_run,
+            keep_snapshots,
+            yes,
+        } => task_runner.gc(*keep_snapshots, *dry_run, *yes),
+        Commands::Init { .. } | Commands::Fsck => unreachable!(),
+    }
+}
+
+/// Returns a line describing the node at `path`, including the size and block count of files.
+fn nice_node_stat(path: &str, stat: NodeStat, blocks: usize) -> String {
+    match stat.size {
+        Some(size) => format!("{:<9}    {path}    {} bytes in {blocks} blocks", stat.kind, *size),
+        None => format!("{:<9}    {path}", stat.kind),
     }
 }
 
-fn nice_node_kind(kind: NodeKind) -> &'static str {
-    match kind {
-        NodeKind::Directory => "Directory",
-        NodeKind::File => "File    .",
-        NodeKind::Vault => "Vault   .",
+/// Returns a line describing the blocks that garbage collection freed, or would free on a dry run.
+fn nice_gc_outcome(outcome: GcOutcome, dry_run: bool) -> String {
+    let verb = if dry_run { "Would free" } else { "Freed" };
+    format!("{verb} {} blocks ({} bytes).", outcome.blocks, outcome.bytes)
+}
+
+/// Returns a line describing the directory `entry`, including the size of files.
+fn nice_dir_entry(entry: &DirEntry) -> String {
+    match entry.size {
+        Some(size) => format!("{:<9}    {}    {} bytes", entry.kind, entry.name, *size),
+        None => format!("{:<9}    {}", entry.kind, entry.name),
     }
 }
 
@@ -106,40 +160,198 @@ impl<'a> TaskRunner<'a> {
     fn list(&mut self, path: &Option<String>) {
         let path = path.as_ref().map_or_else(|| "/", |path| path);
         println!("Listing {path}");
-        let entries = self.task_manager.list(path);
-        for (kind, name) in entries {
-            println!("{}    {name}", nice_node_kind(kind));
+        match self.task_manager.list(path) {
+            Ok(entries) => {
+                for entry in entries {
+                    println!("{}", nice_dir_entry(&entry));
+                }
+            }
+            Err(e) => println!("Failed to list: {e}"),
         }
     }
 
-    /// Get a specific file.
+    /// Write the contents of a specific file to standard output.
     fn get(&self, filename: &str) {
-        match self.task_manager.get(filename) {
-            Some(f) => println!("Indeed, we have: {}", f.name),
-            None => println!("But we don't have: {filename}"),
+        match self.task_manager.get_bytes(filename) {
+            Ok(data) => {
+                if let Err(e) = io::stdout().write_all(&data) {
+                    println!("Failed to write: {e}");
+                }
+            }
+            Err(e) => println!("Failed to get: {e}"),
+        }
+    }
+
+    /// Print the metadata of a specific file or directory.
+    fn stat(&self, path: &str) {
+        match self.task_manager.stat(path) {
+            Ok((stat, blocks)) => println!("{}", nice_node_stat(path, stat, blocks)),
+            Err(e) => println!("Failed to stat: {e}"),
         }
     }
 
     /// Put a specific file.
     fn put(&mut self, filename: &str) {
         match self.task_manager.put(filename) {
-            Ok(f) => println!("Added: {}", f.name),
+            Ok(outcome) => println!(
+                "Added: {} ({} blocks written, {} blocks deduplicated)",
+                outcome.file.to_str().unwrap_or_default(),
+                outcome.blocks_written,
+                outcome.blocks_deduped
+            ),
             Err(e) => println!("Failed to add: {e}"),
         }
     }
 
     fn init(provider: &Provider, path: &str) {
-        TaskManager::init(provider, path);
+        match TaskManager::init(provider, path) {
+            Ok(()) => println!("Initialized a new vault in {path}"),
+            Err(e) => println!("Failed to initialize: {e}"),
+        }
+    }
+
+    /// Print the saved blocks that don't match their id.
+    fn fsck(provider: &Provider) {
+        match TaskManager::fsck(provider) {
+            Ok(damaged) if damaged.is_empty() => println!("No damaged blocks found."),
+            Ok(damaged) => {
+                for (id, e) in &damaged {
+                    println!("{}    {e}", id.base64());
+                }
+                println!("Found {} damaged blocks.", damaged.len());
+            }
+            Err(e) => println!("Failed to check blocks: {e}"),
+        }
     }
 
     /// Create a directory.
     fn create_directory(&mut self, path: &str) {
-        self.task_manager.create_directory(path);
-        /*
-        match self.task_manager.create_directory(name) {
-            Ok(f) => println!("Created: {}", f.name),
-            Err(e) => println!("Failed to add: {e}"),
+        match self.task_manager.create_directory(path) {
+            Ok(()) => println!("Created directory {path}"),
+            Err(e) => println!("Failed to create: {e}"),
+        }
+    }
+
+    /// Delete the blocks that aren't needed anymore, after asking for confirmation unless `yes` is set.
+    fn gc(&self, keep_snapshots: usize, dry_run: bool, yes: bool) {
+        let outcome = match self.task_manager.gc(keep_snapshots, true) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                println!("Failed to collect garbage: {e}");
+                return;
+            }
+        };
+        println!("{}", nice_gc_outcome(outcome, true));
+        if dry_run || outcome.blocks == 0 {
+            return;
         }
-        */
+        if !yes {
+            print!("Delete them? [y/N] ");
+            let mut answer = String::new();
+            if io::stdout().flush().is_err() || io::stdin().read_line(&mut answer).is_err() {
+                return;
+            }
+            if !answer.trim().eq_ignore_ascii_case("y") {
+                println!("Nothing deleted.");
+                return;
+            }
+        }
+        match self.task_manager.gc(keep_snapshots, false) {
+            Ok(outcome) => println!("{}", nice_gc_outcome(outcome, false)),
+            Err(e) => println!("Failed to collect garbage: {e}"),
+        }
+    }
+
+    /// Print the number of reachable blocks per block size.
+    fn block_size_histogram(&self) {
+        match self.task_manager.block_size_histogram() {
+            Ok(histogram) => {
+                for (size, count) in histogram {
+                    println!("{:>9} bytes    {count}", *size);
+                }
+            }
+            Err(e) => println!("Failed to count blocks: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use vault::{FileSize, VaultError};
+
+    use super::*;
+
+    /// Directory in the OS temporary directory, deleted along with its contents on drop.
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Make sure that the stat line of a file includes its size and block count.
+    #[test]
+    fn stat() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let data = vec![7; 5000];
+        vault
+            .put_reader("data.bin", data.as_slice(), FileSize::new(data.len() as u64))
+            .unwrap();
+        let task_manager = TaskManager::new(&mut vault);
+
+        let (stat, blocks) = task_manager.stat("/data.bin").unwrap();
+        assert_eq!(
+            nice_node_stat("/data.bin", stat, blocks),
+            "File         /data.bin    5000 bytes in 2 blocks"
+        );
+        let (stat, blocks) = task_manager.stat("/welcome").unwrap();
+        assert_eq!(nice_node_stat("/welcome", stat, blocks), "Directory    /welcome");
+        assert!(matches!(task_manager.stat("/missing"), Err(VaultError::NotFound(_))));
+    }
+
+    /// Make sure that a dry run reports the blocks of replaced snapshots without deleting them, and a real run does.
+    #[test]
+    fn gc() {
+        let dir = TempDir(std::env::temp_dir().join(format!("exomem-cli-gc-{}", std::process::id())));
+        std::fs::create_dir_all(&dir.0).unwrap();
+        let provider = Provider::with_base_dir(dir.0.join("blocks"));
+        let mut vault = Vault::initialize(&provider, dir.0.join("vault.db")).unwrap();
+        vault
+            .put_reader("data.bin", &[7; 5000][..], FileSize::new(5000))
+            .unwrap();
+        let saved = provider.stats().unwrap().blocks;
+        let task_manager = TaskManager::new(&mut vault);
+
+        let dry_run = task_manager.gc(0, true).unwrap();
+        assert!(dry_run.blocks > 0);
+        assert_eq!(provider.stats().unwrap().blocks, saved);
+        assert_eq!(
+            nice_gc_outcome(dry_run, true),
+            format!("Would free {} blocks ({} bytes).", dry_run.blocks, dry_run.bytes)
+        );
+
+        let outcome = task_manager.gc(0, false).unwrap();
+        assert_eq!(outcome, dry_run);
+        assert_eq!(provider.stats().unwrap().blocks, saved - outcome.blocks);
+        assert_eq!(task_manager.get_bytes("/data.bin").unwrap(), [7; 5000]);
+        assert_eq!(task_manager.gc(0, true).unwrap(), GcOutcome::default());
+    }
+
+    /// Make sure that listings show the size of files next to their name.
+    #[test]
+    fn list() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault
+            .put_reader("data.bin", &[7; 5000][..], FileSize::new(5000))
+            .unwrap();
+        let task_manager = TaskManager::new(&mut vault);
+
+        let lines: Vec<String> = task_manager.list("/").unwrap().iter().map(nice_dir_entry).collect();
+        assert_eq!(lines, ["Directory    welcome", "File         data.bin    5000 bytes"]);
     }
 }
@@ -17,9 +17,13 @@
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use std::{io, path::PathBuf};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 
-use vault::{File, NodeKind, Provider, Vault, VaultPath};
+use vault::{
+    BlockId, BlockSize, DirEntry, GcOutcome, IntegrityError, NodeKind, NodeStat, Provider, PutOutcome, Vault,
+    VaultError, VaultPath,
+};
 
 pub struct TaskManager<'a> {
     vault: &'a mut Vault<'a>,
@@ -30,25 +34,94 @@ impl<'a> TaskManager<'a> {
         TaskManager { vault }
     }
 
-    pub fn put(&mut self, s: &str) -> Result<&File, io::Error> {
+    pub fn put(&mut self, s: &str) -> Result<PutOutcome, VaultError> {
         self.vault.put(s)
     }
 
-    pub fn get(&self, s: &str) -> Option<&File> {
-        self.vault.get(s)
+    pub fn get_bytes(&self, path: impl Into<PathBuf>) -> Result<Vec<u8>, VaultError> {
+        let path = self.vault_path(path)?;
+        self.vault.get(path)
     }
 
-    pub fn create_directory(&mut self, path: impl Into<PathBuf>) {
-        let path = VaultPath::new(path);
-        self.vault.create_directory(path);
+    pub fn create_directory(&mut self, path: impl Into<PathBuf>) -> Result<(), VaultError> {
+        let path = self.vault_path(path)?;
+        self.vault.create_directory(path)
     }
 
-    pub fn init(provider: &Provider, path: &str) {
-        Vault::initialize(provider, path);
+    pub fn init(provider: &Provider, path: &str) -> Result<(), VaultError> {
+        Vault::initialize(provider, path)?;
+        Ok(())
     }
 
-    pub fn list(&mut self, path: impl Into<PathBuf>) -> Vec<(NodeKind, String)> {
-        let path = VaultPath::new(path);
-        self.vault.list(path)
+    pub fn fsck(provider: &Provider) -> Result<Vec<(BlockId, IntegrityError)>, VaultError> {
+        Ok(provider.verify_all()?)
+    }
+
+    /// Returns the entries of the directory at `path`, including the size of files.
+    pub fn list(&self, path: impl Into<PathBuf>) -> Result<Vec<DirEntry>, VaultError> {
+        let path = self.vault_path(path)?;
+        self.vault.ls(path)
+    }
+
+    /// Returns the [`NodeStat`] of the node at `path`, along with the number of data blocks if it's a file.
+    pub fn stat(&self, path: impl Into<PathBuf>) -> Result<(NodeStat, usize), VaultError> {
+        let path = self.vault_path(path)?;
+        let stat = self.vault.stat(path.clone())?;
+        let blocks = match stat.kind {
+            NodeKind::File => self.vault.file_block_ids(path)?.len(),
+            _ => 0,
+        };
+        Ok((stat, blocks))
+    }
+
+    pub fn block_size_histogram(&self) -> Result<BTreeMap<BlockSize, usize>, VaultError> {
+        self.vault.block_size_histogram()
+    }
+
+    /// Deletes the saved blocks that neither the vault nor its last `keep_snapshots` snapshots need,
+    /// or only counts them if `dry_run` is set.
+    pub fn gc(&self, keep_snapshots: usize, dry_run: bool) -> Result<GcOutcome, VaultError> {
+        self.vault.gc(keep_snapshots, dry_run)
+    }
+
+    /// Returns the vault path of `path`, resolving relative paths against the current directory of the vault.
+    fn vault_path(&self, path: impl Into<PathBuf>) -> Result<VaultPath, VaultError> {
+        let path = path.into();
+        match VaultPath::relative(path.clone()) {
+            Some(relative) => self.vault.resolve_relative(&relative),
+            None => Ok(VaultPath::try_new(path)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use vault::FileSize;
+
+    use super::*;
+
+    /// Make sure that file contents can be read back through the task manager.
+    #[test]
+    fn get_bytes() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        vault
+            .put_reader("data.bin", Cursor::new(&data), FileSize::new(data.len() as u64))
+            .unwrap();
+
+        let task_manager = TaskManager::new(&mut vault);
+        assert_eq!(task_manager.get_bytes("/data.bin").unwrap(), data);
+        assert_eq!(task_manager.get_bytes("data.bin").unwrap(), data);
+        assert!(matches!(
+            task_manager.get_bytes("/missing.bin"),
+            Err(VaultError::NotFound(_))
+        ));
+        assert!(matches!(
+            task_manager.get_bytes("/welcome"),
+            Err(VaultError::NotAFile(_))
+        ));
     }
 }
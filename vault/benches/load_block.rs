@@ -0,0 +1,78 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Compares the read throughput of loading blocks from disk with and without memory mapping.
+//!
+//! Run with `cargo bench -p exomem-vault --features mmap`.
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use exomem_vault::{Block, BlockId, BlockKind, EncryptedBlock, Provider};
+use rand::{thread_rng, Rng};
+
+/// The number of blocks that get loaded per round.
+const BLOCKS: usize = 256;
+/// The size of every block, 1 MiB.
+const BLOCK_SIZE: usize = 1024 * 1024;
+/// The number of rounds per loader, of which the fastest one is reported.
+const ROUNDS: usize = 5;
+
+fn main() {
+    let base_dir = std::env::temp_dir().join(format!("exomem-bench-{}", thread_rng().gen::<u64>()));
+    let provider = Provider::with_base_dir(&base_dir);
+    let mut ids = Vec::with_capacity(BLOCKS);
+    for _ in 0..BLOCKS {
+        let mut data = vec![0; BLOCK_SIZE];
+        thread_rng().fill(&mut data[..]);
+        let block = Block::from_data(data.into());
+        let encrypted_block = EncryptedBlock::encrypt(&block, 0);
+        let id = encrypted_block.id(BlockKind::Data).unwrap();
+        provider.add_block(id, encrypted_block, block).unwrap();
+        ids.push(id);
+    }
+
+    for mmap in [false, true] {
+        let fastest = (0..ROUNDS).map(|_| load_all(&base_dir, &ids, mmap)).min().unwrap();
+        let throughput = (BLOCKS * BLOCK_SIZE) as f64 / fastest.as_secs_f64() / (1024.0 * 1024.0);
+        let loader = if mmap { "mmap" } else { "read" };
+        println!("{loader}: {BLOCKS} blocks in {fastest:?}, {throughput:.0} MiB/s");
+    }
+
+    fs::remove_dir_all(&base_dir).unwrap();
+}
+
+/// Returns how long it takes a fresh provider to load all the blocks with `ids` and read every byte of them.
+fn load_all(base_dir: &Path, ids: &[BlockId], mmap: bool) -> Duration {
+    let mut provider = Provider::with_base_dir(base_dir);
+    // Hashing would dominate the time spent reading
+    provider.set_verify_on_load(false);
+    provider.set_mmap(mmap);
+    let start = Instant::now();
+    let mut checksum = 0u8;
+    for &id in ids {
+        let block = provider.load_block_from_file(id, 0).unwrap();
+        checksum = block.data().iter().fold(checksum, |acc, byte| acc ^ byte);
+    }
+    let elapsed = start.elapsed();
+    // Keep the reads from being optimized away
+    std::hint::black_box(checksum);
+    elapsed
+}
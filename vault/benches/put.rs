@@ -0,0 +1,87 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+//! Measures the throughput of chunking, hashing, encrypting and storing a large file,
+//! with the blocks hashed on a single thread and on all of them.
+//!
+//! Run with `cargo bench -p exomem-vault --bench put`.
+
+use std::io::{self, Read};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use exomem_vault::{FileSize, Provider, Vault};
+use rand::{thread_rng, Rng};
+use rayon::ThreadPoolBuilder;
+
+/// The size of the file that gets put, 1 GiB.
+const FILE_SIZE: u64 = 1024 * 1024 * 1024;
+/// The length of the pattern that the file repeats, which is off the power of two block sizes
+/// so that no two blocks of the file end up with the same contents.
+const PATTERN_LEN: usize = 1024 * 1024 + 7;
+
+/// Endless source of data that repeats a random pattern, as cheap to read as it gets.
+struct Synthetic {
+    pattern: Vec<u8>,
+    position: usize,
+}
+
+impl Read for Synthetic {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.pattern.len() - self.position);
+        buf[..len].copy_from_slice(&self.pattern[self.position..self.position + len]);
+        self.position = (self.position + len) % self.pattern.len();
+        Ok(len)
+    }
+}
+
+fn put(c: &mut Criterion) {
+    let mut pattern = vec![0; PATTERN_LEN];
+    thread_rng().fill(&mut pattern[..]);
+
+    let mut group = c.benchmark_group("put");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(FILE_SIZE));
+    for (name, threads) in [("serial", 1), ("parallel", 0)] {
+        // Zero threads lets rayon pick as many as there are cores
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                Provider::in_memory,
+                |provider| {
+                    let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+                    let reader = Synthetic {
+                        pattern: pattern.clone(),
+                        position: 0,
+                    }
+                    .take(FILE_SIZE);
+                    pool.install(|| vault.put_reader("data.bin", reader, FileSize::new(FILE_SIZE)))
+                        .unwrap();
+                    drop(vault);
+                    // Dropping the stored blocks isn't part of the measurement
+                    provider
+                },
+                BatchSize::PerIteration,
+            );
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, put);
+criterion_main!(benches);
@@ -17,15 +17,31 @@
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
+use std::iter;
+use std::mem;
+use std::ptr;
+use std::str::FromStr;
 
 use bytes::Bytes;
 use capnp::{
-    message::{self, ReaderOptions, ReaderSegments, TypedBuilder},
+    message::{self, HeapAllocator, ReaderOptions, ReaderSegments, TypedBuilder},
     raw::get_struct_data_section,
 };
 
 use crate::vault_capnp::{block, block_id, index, node, union_id, NodeKind};
+use crate::Blake3;
+use crate::Hasher;
+use crate::InvalidBlockIdHex;
+use crate::InvalidBlockIdLength;
+use crate::InvalidBlockSize;
+use crate::NodeStat;
+use crate::Timestamp;
+use crate::TooManyLocalNodes;
+use crate::UnsupportedBlock;
+use crate::UnsupportedBlockVersion;
+use crate::VaultError;
 
 // TODO: Create UnionId? LocalId tracking is getting out of hand
 
@@ -43,10 +59,13 @@ pub struct BlockId {
 
 impl BlockId {
     /// Create a new `BlockId` from the provided `hash` and options.
-    pub fn new(hash: blake3::Hash, size: usize, has_header: bool) -> BlockId {
-        let mut id = BlockId { data: *hash.as_bytes() };
-        id.set_header(size, has_header);
-        id
+    ///
+    /// The `size` is rounded up to the smallest [`BlockSize`] that fits it, as the last data block of a file
+    /// and info blocks are usually smaller. Fails if even the largest `BlockSize` doesn't fit it.
+    pub fn new(hash: impl Into<[u8; 32]>, size: usize, has_header: bool) -> Result<BlockId, InvalidBlockSize> {
+        let mut id = BlockId { data: hash.into() };
+        id.set_header(size, has_header)?;
+        Ok(id)
     }
 
     /// Create a new `BlockId` from raw `data`.
@@ -54,12 +73,15 @@ impl BlockId {
         BlockId { data }
     }
 
+    /// Create a new `BlockId` from raw `data`, which must be exactly 32 bytes long.
+    pub fn from_slice(data: &[u8]) -> Result<BlockId, InvalidBlockIdLength> {
+        let data = data.try_into().map_err(|_| InvalidBlockIdLength(data.len()))?;
+        Ok(BlockId { data })
+    }
+
     /// Create a new `BlockId` from a capnp reader.
-    pub fn from_reader(block_id_r: block_id::Reader) -> BlockId {
-        let mut data = [0; 32];
-        // TODO: Check length before hand to not panic?
-        data.copy_from_slice(get_struct_data_section(block_id_r));
-        BlockId { data }
+    pub fn from_reader(block_id_r: block_id::Reader) -> Result<BlockId, InvalidBlockIdLength> {
+        BlockId::from_slice(get_struct_data_section(block_id_r))
     }
 
     /// Copy raw `BlockId` data to the specified capnp builder.
@@ -70,18 +92,23 @@ impl BlockId {
         block_id_b.set_d4(u64::from_le_bytes(self.data[24..32].try_into().unwrap()));
     }
 
-    // TODO: Write tests for this at every size
-    fn set_header(&mut self, size: usize, has_header: bool) {
-        let size_marker = 12 - size.ilog2() as u8;
-        if size_marker > MAX_SIZE_MARKER {
-            panic!("Unexpected size marker");
+    /// Encodes the smallest [`BlockSize`] that fits `size` bytes and whether the block has a header
+    /// into the header byte, failing if even the largest `BlockSize` doesn't fit it.
+    ///
+    /// Sizes below 4 KiB, like the last data block of a file, get the marker of the 4 KiB block size.
+    fn set_header(&mut self, size: usize, has_header: bool) -> Result<(), InvalidBlockSize> {
+        if size > MAX_BLOCK_SIZE as usize {
+            return Err(InvalidBlockSize(size));
         }
+        // The smallest block size that fits the data, starting from 4 KiB.
+        let size_marker = size.next_power_of_two().ilog2().saturating_sub(12) as u8;
         let mut header = 0;
         if has_header {
             header |= 0b0000_0010u8;
         }
         header |= size_marker << 2;
-        self.data[0] = header
+        self.data[0] = header;
+        Ok(())
     }
 
     /// Returns the raw bytes that make up this `BlockId`.
@@ -114,6 +141,15 @@ impl BlockId {
         (self.data[0] & 0b0000_0010u8) >> 1 != 0
     }
 
+    /// Returns the [`BlockKind`] of the block, as determined by whether it has a header.
+    pub fn block_kind(&self) -> BlockKind {
+        if self.block_has_header() {
+            BlockKind::Info
+        } else {
+            BlockKind::Data
+        }
+    }
+
     /// Returns the block size in number of bytes, in powers of two in the range of 4 KiB - 128 MiB.
     ///
     /// Check out [`BlockSize::from_marker`] for more information.
@@ -124,22 +160,68 @@ impl BlockId {
         BlockSize::from_marker(size_marker)
     }
 
+    /// Returns the id of a hole, a data block of `size` zero bytes that is never stored.
+    ///
+    /// Holes keep the long runs of zeros in sparse files from taking up any space. The hash bytes of their id
+    /// are all zero, which no actual hash is going to be.
+    pub fn hole(size: BlockSize) -> BlockId {
+        BlockId::new([0; 32], *size as usize, false).expect("block sizes fit")
+    }
+
+    /// Returns `true` if the `BlockId` is the id of a hole, as created by [`BlockId::hole`].
+    pub fn is_hole(&self) -> bool {
+        !self.block_has_header() && self.data[1..].iter().all(|byte| *byte == 0)
+    }
+
     /// Returns the Base64 representation of the `BlockId`.
     pub fn base64(&self) -> String {
         use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
         URL_SAFE_NO_PAD.encode(self.data)
     }
+
+    /// Create a new `BlockId` from its Base64 representation, as returned by [`BlockId::base64`].
+    ///
+    /// Returns `None` if `base64` isn't valid Base64 of exactly 32 bytes.
+    pub fn from_base64(base64: &str) -> Option<BlockId> {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+        let data = URL_SAFE_NO_PAD.decode(base64).ok()?;
+        BlockId::from_slice(&data).ok()
+    }
+
+    /// Returns the first `n` characters of the Base64 representation, for use in logs.
+    ///
+    /// Use [`Provider::resolve_short_id`](crate::Provider::resolve_short_id) to turn it back into a `BlockId`.
+    pub fn short(&self, n: usize) -> String {
+        let mut short = self.base64();
+        short.truncate(n);
+        short
+    }
 }
 
+/// Displays the `BlockId` as lowercase hex of its bytes in order, which [`BlockId::from_str`] parses back.
 impl fmt::Display for BlockId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: Figure out if there is a more efficient printing code
-        write!(
-            f,
-            "{:032x}{:032x}",
-            u128::from_le_bytes(self.data.as_slice()[0..16].try_into().unwrap()),
-            u128::from_le_bytes(self.data.as_slice()[16..32].try_into().unwrap()),
-        )
+        for byte in &self.data {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for BlockId {
+    type Err = InvalidBlockIdHex;
+
+    /// Parses the 64 hex digits written by the [`Display`](fmt::Display) implementation, in either case.
+    fn from_str(s: &str) -> Result<BlockId, InvalidBlockIdHex> {
+        let invalid = || InvalidBlockIdHex(String::from(s));
+        if s.len() != 64 || !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let mut data = [0; 32];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(BlockId { data })
     }
 }
 
@@ -154,7 +236,7 @@ impl fmt::Debug for BlockId {
 /// There are two kinds:
 /// * Data blocks which are 100% data without any metadata.
 /// * Info blocks which start with a header describing the block.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum BlockKind {
     /// 100% of the block is data, there is no metadata.
     Data,
@@ -173,6 +255,9 @@ impl BlockKind {
 }
 
 /// Immutable encrypted block.
+///
+/// The [`BlockId`] is the hash of the encrypted contents, so corruption is detected before decrypting
+/// by hashing the contents again, as done by [`Provider::set_verify_on_load`](crate::Provider::set_verify_on_load).
 #[derive(Clone)]
 pub struct EncryptedBlock {
     /// The raw bytes of this encrypted block.
@@ -207,14 +292,37 @@ impl EncryptedBlock {
         self.data.clone()
     }
 
-    /// Returns the [`BlockId`] of this [`EncryptedBlock`].
-    pub fn id(&self, kind: BlockKind) -> BlockId {
-        let hash = blake3::hash(self.data.as_ref());
+    /// Returns the [`BlockId`] of this [`EncryptedBlock`], as hashed by the default [`Blake3`] hasher.
+    ///
+    /// Fails if the block is larger than the largest [`BlockSize`].
+    pub fn id(&self, kind: BlockKind) -> Result<BlockId, InvalidBlockSize> {
+        self.id_with(kind, &Blake3)
+    }
+
+    /// Returns the [`BlockId`] of this [`EncryptedBlock`], as hashed by `hasher`.
+    ///
+    /// Fails if the block is larger than the largest [`BlockSize`].
+    pub fn id_with(&self, kind: BlockKind, hasher: &dyn Hasher) -> Result<BlockId, InvalidBlockSize> {
+        let hash = hasher.hash(self.data.as_ref());
         BlockId::new(hash, self.data.len(), kind.has_header())
     }
+
+    /// Returns the [`BlockId`] of this [`EncryptedBlock`] as a data block that has to fill all of `size`,
+    /// as hashed by `hasher`.
+    ///
+    /// Only the last block of a file can be smaller than its size in the layout or a fixed size, so this fails
+    /// for any other block that isn't exactly `size` bytes.
+    pub fn full_data_id_with(&self, size: BlockSize, hasher: &dyn Hasher) -> Result<BlockId, InvalidBlockSize> {
+        if self.data.len() != *size as usize {
+            return Err(InvalidBlockSize(self.data.len()));
+        }
+        self.id_with(BlockKind::Data, hasher)
+    }
 }
 
 /// Immutable unencrypted block.
+///
+/// Blocks are `Send` and `Sync`, so that providers can hand them out to any number of threads.
 #[derive(Clone)]
 pub struct Block {
     /// The raw bytes of this unencrypted block.
@@ -242,9 +350,70 @@ impl Block {
         self.data.len()
     }
 
+    /// Returns the [`BlockKind`] of the block identified by `id`.
+    ///
+    /// The kind is encoded in the header bit of the [`BlockId`], so it can be inferred
+    /// without inspecting the block contents.
+    pub fn kind_from_id(id: BlockId) -> BlockKind {
+        id.block_kind()
+    }
+
     /// Returns an [`InfoBlock`] if you know this is an info block.
-    pub fn info(&self) -> InfoBlock {
-        InfoBlock::from(self.clone())
+    ///
+    /// Fails if the block was written with a schema version that this build doesn't know.
+    pub fn info(&self) -> Result<InfoBlock, UnsupportedBlockVersion> {
+        InfoBlock::try_from(self.clone())
+    }
+}
+
+/// Unencrypted block whose contents are overwritten with zeros when it's dropped,
+/// so that the plaintext of blocks decrypted from an encrypted vault doesn't linger in memory.
+///
+/// The data of a [`Block`] may be shared, in which case it's left as it is.
+/// Only data that no clone of the block refers to anymore gets cleared.
+pub struct SensitiveBlock {
+    block: Block,
+}
+
+impl SensitiveBlock {
+    /// Create a new `SensitiveBlock` that clears the contents of `block` when dropped.
+    pub fn new(block: Block) -> SensitiveBlock {
+        SensitiveBlock { block }
+    }
+
+    /// Returns the wrapped block.
+    ///
+    /// Clones of the block or its data that are still around keep the contents from being cleared.
+    pub fn block(&self) -> &Block {
+        &self.block
+    }
+
+    /// Overwrites the contents with zeros right away, returning `false` if they're shared and were left as they are.
+    pub fn zeroize(&mut self) -> bool {
+        if self.block.data.is_empty() {
+            return true;
+        }
+        match mem::take(&mut self.block.data).try_into_mut() {
+            Ok(mut data) => {
+                for byte in data.iter_mut() {
+                    // Volatile writes can't be optimized away, even though the buffer is about to be freed
+                    // SAFETY: The pointer comes from a mutable reference, so it's valid and aligned
+                    unsafe { ptr::write_volatile(byte, 0) };
+                }
+                self.block.data = data.freeze();
+                true
+            }
+            Err(data) => {
+                self.block.data = data;
+                false
+            }
+        }
+    }
+}
+
+impl Drop for SensitiveBlock {
+    fn drop(&mut self) {
+        self.zeroize();
     }
 }
 
@@ -315,7 +484,7 @@ impl From<u32> for BlockIdIndex {
 }
 
 const MAX_SIZE_MARKER: u8 = 0b1111; // 4 bits
-const MAX_BLOCK_SIZE: u32 = 2u32.pow(27); // 128 MiB
+pub(crate) const MAX_BLOCK_SIZE: u32 = 2u32.pow(27); // 128 MiB
 
 // TODO: `BlockSize` and `FileSize` structs should guarantee sanity.
 //       That is, when they are mutated, validity is checked.
@@ -347,6 +516,11 @@ impl BlockSize {
         BlockSize(2u32.pow(12 + size_marker as u32))
     }
 
+    /// Returns the size marker of the block size, the inverse of [`BlockSize::from_marker`].
+    pub const fn marker(&self) -> u8 {
+        (self.0.trailing_zeros() - 12) as u8
+    }
+
     pub const fn new(size: u32) -> BlockSize {
         assert!(BlockSize::valid(size));
         BlockSize(size)
@@ -356,6 +530,45 @@ impl BlockSize {
         size.count_ones() == 1 && size << 4 > 0 && size >> 12 > 0
     }
 
+    /// Returns all the 16 valid block sizes in ascending order.
+    pub fn all() -> impl Iterator<Item = BlockSize> {
+        (0..=MAX_SIZE_MARKER).map(BlockSize::from_marker)
+    }
+
+    /// Returns the next larger block size, or `None` if this is already the maximum.
+    pub fn next_larger(self) -> Option<BlockSize> {
+        BlockSize::all().find(|size| *size > self)
+    }
+
+    /// Returns the size of the block at `block_index` of a file, when `remaining` bytes of the file start there.
+    ///
+    /// Every block of a file follows [`BlockSize::layout`], except the very last one,
+    /// which only has to fit the remaining data and never exceeds its size in the layout.
+    /// So this is the size in the layout, or the smallest block size that fits `remaining` bytes if that's smaller.
+    pub fn best_fit(block_index: BlockIdIndex, remaining: FileSize) -> BlockSize {
+        let layout_size = BlockSize::layout()
+            .nth(*block_index as usize)
+            .expect("the layout is endless");
+        BlockSize::all()
+            .find(|size| FileSize::from(*size) >= remaining)
+            .map_or(layout_size, |size| size.min(layout_size))
+    }
+
+    /// Returns the endless sequence of block sizes that a file is split into.
+    ///
+    /// Every file starts with a deterministic sequence of variable sized blocks,
+    /// followed by maximum sized blocks. Check out `InfoBlock::translate_file_offset` for more information.
+    pub fn layout() -> impl Iterator<Item = BlockSize> {
+        (0..=MAX_SIZE_MARKER)
+            .flat_map(|size_marker| {
+                // Every block size gets at least 16 repetitions.
+                // Blocks starting from 64 KiB require even more repetitions to keep alignment.
+                let repetitions = 16 + size_marker.saturating_sub(3);
+                (0..repetitions).map(move |_| BlockSize::from_marker(size_marker))
+            })
+            .chain(iter::repeat(BlockSize::from_marker(MAX_SIZE_MARKER)))
+    }
+
     /// Can panic!
     pub const fn as_offset(&self) -> BlockOffset {
         BlockOffset::new(self.0)
@@ -387,6 +600,9 @@ impl std::ops::Sub for BlockSize {
 /// `BlockOffset` is a `u32` that refers to an offset inside a block.
 ///
 /// This means a limit of 4 GiB, which is great because max block size is 128 MiB.
+///
+/// Like with plain integers, the arithmetic operators panic on overflow in debug builds and wrap in release builds.
+/// Use [`BlockOffset::checked_add`] and [`BlockOffset::checked_sub`] where that matters.
 #[derive(Eq, PartialEq, PartialOrd, Ord, Clone, Copy, Debug)]
 pub struct BlockOffset(u32);
 
@@ -395,6 +611,22 @@ impl BlockOffset {
         assert!(value < MAX_BLOCK_SIZE);
         BlockOffset(value)
     }
+
+    /// Returns `self + other`, or `None` if the result wouldn't be an offset inside a block.
+    pub const fn checked_add(self, other: BlockOffset) -> Option<BlockOffset> {
+        match self.0.checked_add(other.0) {
+            Some(value) if value < MAX_BLOCK_SIZE => Some(BlockOffset(value)),
+            _ => None,
+        }
+    }
+
+    /// Returns `self - other`, or `None` if `other` is larger.
+    pub const fn checked_sub(self, other: BlockOffset) -> Option<BlockOffset> {
+        match self.0.checked_sub(other.0) {
+            Some(value) => Some(BlockOffset(value)),
+            None => None,
+        }
+    }
 }
 
 impl From<u32> for BlockOffset {
@@ -406,7 +638,6 @@ impl From<u32> for BlockOffset {
 impl std::ops::Add for BlockOffset {
     type Output = Self;
 
-    // TODO: Check for validity?
     fn add(self, other: Self) -> Self::Output {
         Self(self.0.add(other.0))
     }
@@ -415,7 +646,6 @@ impl std::ops::Add for BlockOffset {
 impl std::ops::Sub for BlockOffset {
     type Output = Self;
 
-    // TODO: Check for sanity?
     fn sub(self, other: Self) -> Self::Output {
         Self(self.0.sub(other.0))
     }
@@ -432,10 +662,21 @@ impl FileSize {
         FileSize(value)
     }
 
+    /// Returns a new `FileSize`, or `None` if `value` exceeds [`MAX_FILE_SIZE`].
+    pub const fn checked_new(value: u64) -> Option<FileSize> {
+        if value <= MAX_FILE_SIZE {
+            Some(FileSize(value))
+        } else {
+            None
+        }
+    }
+
     /// Converts the `FileSize` into a `BlockOffset`.
     ///
     /// This conversion is only safe if the `FileSize` value fits into `BlockOffset`.
+    /// Debug builds assert this instead of silently truncating.
     pub fn as_block_offset(&self) -> BlockOffset {
+        debug_assert!(self.0 < MAX_BLOCK_SIZE as u64, "FileSize doesn't fit into BlockOffset");
         (self.0 as u32).into()
     }
 
@@ -494,6 +735,10 @@ impl std::ops::Sub for FileSize {
 ///
 /// This means a limit of 16384 PiB, which is great becauxe max supported file size is ~512 PiB.
 /// With the exact maximum file size being 2^59 - 280 * 2^27.
+///
+/// Like with plain integers, the arithmetic operators panic on overflow in debug builds and wrap in release builds,
+/// and they don't check the maximum file size either.
+/// Use [`FileOffset::checked_add`] and [`FileOffset::checked_sub`] where that matters.
 #[derive(Eq, PartialEq, PartialOrd, Ord, Clone, Copy, Debug)]
 pub struct FileOffset(u64);
 
@@ -507,10 +752,31 @@ impl FileOffset {
         FileSize(self.0)
     }
 
+    /// Returns `self + other`, or `None` if the result wouldn't be an offset inside a file.
+    pub const fn checked_add(self, other: FileOffset) -> Option<FileOffset> {
+        match self.0.checked_add(other.0) {
+            Some(value) if value < MAX_FILE_SIZE => Some(FileOffset(value)),
+            _ => None,
+        }
+    }
+
+    /// Returns `self - other`, or `None` if `other` is larger.
+    pub const fn checked_sub(self, other: FileOffset) -> Option<FileOffset> {
+        match self.0.checked_sub(other.0) {
+            Some(value) => Some(FileOffset(value)),
+            None => None,
+        }
+    }
+
     /// Converts the `FileOffset` into a `BlockOffset`.
     ///
     /// This conversion is only safe if the `FileOffset` value fits into `BlockOffset`.
+    /// Debug builds assert this instead of silently truncating.
     pub fn as_block_offset(&self) -> BlockOffset {
+        debug_assert!(
+            self.0 < MAX_BLOCK_SIZE as u64,
+            "FileOffset doesn't fit into BlockOffset"
+        );
         (self.0 as u32).into()
     }
 }
@@ -578,12 +844,24 @@ impl std::ops::Mul for FileOffset {
 impl std::ops::Div for FileOffset {
     type Output = Self;
 
+    /// Panics if `rhs` is zero.
     fn div(self, rhs: Self) -> Self::Output {
         Self(self.0.div(rhs.0))
     }
 }
 
+/// The name of the directory entry that continues the directory in the block it refers to.
+///
+/// A directory that outgrows its block moves its oldest entries out to a new block and refers to it
+/// with a continuation entry, which always comes first. The new block can in turn continue in another one.
+/// No path component is empty, so the name never clashes with an actual entry.
+pub const DIRECTORY_CONTINUATION: &str = "";
+
 /// Immutable unencrypted info block.
+///
+/// An `InfoBlock` can be moved to another thread, but not shared between threads,
+/// because the capnp message reader counts the words it has read in a `Cell`.
+/// Share the underlying [`Block`] instead, which is cheap to clone.
 pub struct InfoBlock {
     /// The underlying unencrypted [`Block`].
     block: Block,
@@ -591,14 +869,31 @@ pub struct InfoBlock {
     message_reader: message::Reader<Block>,
 }
 
-impl From<Block> for InfoBlock {
-    fn from(block: Block) -> Self {
-        InfoBlock {
-            block: block.clone(),
+/// The schema version of info blocks, stored in the `version` field of the `Block` and `Index` messages.
+///
+/// Bump it whenever `vault_capnp` changes in a way that older builds would misread.
+const SCHEMA_VERSION: u16 = 1;
+
+impl TryFrom<Block> for InfoBlock {
+    type Error = UnsupportedBlockVersion;
+
+    /// Checks the schema version of the block, blocks that can't be read at all are reported as version 0.
+    fn try_from(block: Block) -> Result<Self, Self::Error> {
+        let info_block = InfoBlock {
             // We construct a capnp message reader directly without doing any segment analysis.
             // Our messages are always expected to be a single segment.
-            message_reader: message::Reader::new(block, ReaderOptions::new()),
+            message_reader: message::Reader::new(block.clone(), ReaderOptions::new()),
+            block,
+        };
+        // An index message keeps its version at the same offset, so it reads the same as a block message
+        let version = info_block
+            .message_reader
+            .get_root::<block::Reader>()
+            .map_or(0, |block_r| block_r.get_version());
+        if version != SCHEMA_VERSION {
+            return Err(UnsupportedBlockVersion(version.into()));
         }
+        Ok(info_block)
     }
 }
 
@@ -614,7 +909,14 @@ impl InfoBlock {
     /// This sequence is [`REPEATING_BLOCKS_START_OFFSET`] bytes long (6.75 GiB).
     /// After the initial sequence every block is maximum sized at 128 MiB.
     /// With the exception of the very last block which can be of any size that fits the data.
-    fn translate_file_offset(offset: FileOffset) -> (BlockIdIndex, BlockOffset) {
+    /// Its id records the smallest [`BlockSize`] that fits it, while its exact length is simply the length
+    /// of the block data, which adds up to the file size recorded in the file node.
+    ///
+    /// Returns `None` if the offset is beyond [`MAX_FILE_SIZE`], which the [`FileOffset`] operators don't prevent.
+    pub(crate) fn translate_file_offset(offset: FileOffset) -> Option<(BlockIdIndex, BlockOffset)> {
+        if *offset >= MAX_FILE_SIZE {
+            return None;
+        }
         if offset < REPEATING_BLOCKS_START_OFFSET {
             // OPTIMIZE: More can be pre-calculated, fewer loops and branches.
             let mut block_start_offset = FileOffset::new(0);
@@ -628,7 +930,7 @@ impl InfoBlock {
                         // Every subsequently larger block size adds an extra repetition.
                         for n in 0..(size_marker - 3) {
                             let old_block_offset = block_start_offset;
-                            block_start_offset += block_size.into();
+                            block_start_offset = block_start_offset.checked_add(block_size.into())?;
                             let mut block_index = size_marker * 16 + j;
                             if size_marker > 3 {
                                 for ii in 4..size_marker {
@@ -637,12 +939,15 @@ impl InfoBlock {
                                 block_index += n;
                             }
                             if offset < block_start_offset {
-                                return (block_index.into(), (offset - old_block_offset).as_block_offset());
+                                return Some((
+                                    block_index.into(),
+                                    offset.checked_sub(old_block_offset)?.as_block_offset(),
+                                ));
                             }
                         }
                     }
                     let old_block_offset = block_start_offset;
-                    block_start_offset += block_size.into();
+                    block_start_offset = block_start_offset.checked_add(block_size.into())?;
                     let mut block_index = size_marker * 16 + j;
                     if size_marker > 3 {
                         for ii in 4..size_marker {
@@ -653,7 +958,10 @@ impl InfoBlock {
                         }
                     }
                     if offset < block_start_offset {
-                        return (block_index.into(), (offset - old_block_offset).as_block_offset());
+                        return Some((
+                            block_index.into(),
+                            offset.checked_sub(old_block_offset)?.as_block_offset(),
+                        ));
                     }
                 }
             }
@@ -661,13 +969,16 @@ impl InfoBlock {
         }
 
         let repeating_block_size = *BlockSize::from_marker(MAX_SIZE_MARKER) as u64;
-        let remaining_bytes = offset - REPEATING_BLOCKS_START_OFFSET;
+        let remaining_bytes = offset.checked_sub(REPEATING_BLOCKS_START_OFFSET)?;
         let remaining_blocks = *remaining_bytes / repeating_block_size;
         let remaining_blocks_size = FileSize::from(remaining_blocks * repeating_block_size);
-        let last_block_start_offset = REPEATING_BLOCKS_START_OFFSET + remaining_blocks_size.as_offset();
-        let block_index = (334 + remaining_blocks as u32).into();
+        let last_block_start_offset = REPEATING_BLOCKS_START_OFFSET.checked_add(remaining_blocks_size.as_offset())?;
+        let block_index = 334u32.checked_add(u32::try_from(remaining_blocks).ok()?)?.into();
 
-        (block_index, (offset - last_block_start_offset).as_block_offset())
+        Some((
+            block_index,
+            offset.checked_sub(last_block_start_offset)?.as_block_offset(),
+        ))
     }
 
     pub fn new_vault(root_id: BlockId, index_id: BlockId) -> Block {
@@ -681,28 +992,20 @@ impl InfoBlock {
         let index_b = vault_b.init_index();
         index_id.to_builder(index_b.init_block_id());
 
-        let segment = match message_b.borrow_inner().get_segments_for_output() {
-            capnp::OutputSegments::SingleSegment(ss) => Bytes::copy_from_slice(ss[0]),
-            capnp::OutputSegments::MultiSegment(_) => {
-                panic!("got multiple output segments, but our reader doesn't want that")
-            }
-        };
-
-        Block::from_data(segment)
+        block_from_message(&message_b)
     }
 
     pub fn new_index() -> Block {
         let mut message_b = TypedBuilder::<index::Owned>::new_default(); // TODO: Look into allocation strategies
-        let _block_b = message_b.init_root();
+        let mut index_b = message_b.init_root();
+        index_b.set_version(SCHEMA_VERSION);
 
-        let segment = match message_b.borrow_inner().get_segments_for_output() {
-            capnp::OutputSegments::SingleSegment(ss) => Bytes::copy_from_slice(ss[0]),
+        match message_b.borrow_inner().get_segments_for_output() {
+            capnp::OutputSegments::SingleSegment(ss) => block_from_segment(ss[0]),
             capnp::OutputSegments::MultiSegment(_) => {
                 panic!("got multiple output segments, but our reader doesn't want that")
             }
-        };
-
-        Block::from_data(segment)
+        }
     }
 
     pub fn new_directory() -> Block {
@@ -713,14 +1016,7 @@ impl InfoBlock {
         let directory_b = node_b.init_directory();
         directory_b.init_entries(0);
 
-        let segment = match message_b.borrow_inner().get_segments_for_output() {
-            capnp::OutputSegments::SingleSegment(ss) => Bytes::copy_from_slice(ss[0]),
-            capnp::OutputSegments::MultiSegment(_) => {
-                panic!("got multiple output segments, but our reader doesn't want that")
-            }
-        };
-
-        Block::from_data(segment)
+        block_from_message(&message_b)
     }
 
     /// Returns the underlying `Block`.
@@ -752,17 +1048,17 @@ impl InfoBlock {
             union_id::Which::LocalId(_) => todo!(),
             union_id::Which::BlockId(block_id_r) => {
                 let block_id_r = block_id_r.unwrap();
-                BlockId::from_reader(block_id_r)
+                BlockId::from_reader(block_id_r).expect("failed to read block id")
             }
             union_id::Which::ShardId(_) => todo!(),
         };
 
-        let index_r = vault_r.get_root().unwrap();
+        let index_r = vault_r.get_index().unwrap();
         let index_id = match index_r.which().unwrap() {
             union_id::Which::LocalId(_) => todo!(),
             union_id::Which::BlockId(block_id_r) => {
                 let block_id_r = block_id_r.unwrap();
-                BlockId::from_reader(block_id_r)
+                BlockId::from_reader(block_id_r).expect("failed to read block id")
             }
             union_id::Which::ShardId(_) => todo!(),
         };
@@ -786,20 +1082,48 @@ impl InfoBlock {
         let root_b = vault_b.init_root();
         block_id.to_builder(root_b.init_block_id());
 
-        let segment = match message_b.borrow_inner().get_segments_for_output() {
-            capnp::OutputSegments::SingleSegment(ss) => Bytes::copy_from_slice(ss[0]),
-            capnp::OutputSegments::MultiSegment(_) => {
-                panic!("got multiple output segments, but our reader doesn't want that")
-            }
-        };
-
-        Block::from_data(segment)
+        block_from_message(&message_b)
     }
 
     /// Creates a new node of `kind` with `name`.
     ///
+    /// The new node is stamped as created at `time` and so is the directory as modified, unless `time` is `None`.
+    /// Returns the new [`Block`] that contains the newly created inlined node, as well as the local id of that node.
+    pub fn directory_create_local_node(
+        &self,
+        directory_node_idx: u32,
+        name: &str,
+        kind: NodeKind,
+        time: Option<Timestamp>,
+    ) -> (Block, u32) {
+        self.directory_create_local_node_with(directory_node_idx, name, time, |node_b| init_local_node(node_b, kind))
+    }
+
+    /// Creates a new file node with `name`, whose contents are `size` bytes stored in the data blocks `block_ids`.
+    ///
+    /// The new node is stamped as created at `time` and so is the directory as modified, unless `time` is `None`.
     /// Returns the new [`Block`] that contains the newly created inlined node, as well as the local id of that node.
-    pub fn directory_create_local_node(&self, directory_node_idx: u32, name: &str, kind: NodeKind) -> (Block, u32) {
+    pub fn directory_create_local_file(
+        &self,
+        directory_node_idx: u32,
+        name: &str,
+        size: FileSize,
+        block_ids: &[BlockId],
+        time: Option<Timestamp>,
+    ) -> (Block, u32) {
+        self.directory_create_local_node_with(directory_node_idx, name, time, |node_b| {
+            init_local_file(node_b, size, block_ids)
+        })
+    }
+
+    /// Creates a new node with `name`, which is initialized by `init` and stamped with `time`.
+    fn directory_create_local_node_with(
+        &self,
+        directory_node_idx: u32,
+        name: &str,
+        time: Option<Timestamp>,
+        init: impl FnOnce(node::Builder),
+    ) -> (Block, u32) {
         let block_r = self.block_reader();
         let nodes_r = block_r.get_nodes().unwrap();
         let old_nodes_len = nodes_r.len();
@@ -824,12 +1148,14 @@ impl InfoBlock {
             nodes_b.set_with_caveats(i, old_node).unwrap();
         }
 
+        stamp_modified(nodes_b.reborrow().get(directory_node_idx), time);
         let node_b = nodes_b.reborrow().get(directory_node_idx);
 
         let directory_b = match node_b.which().unwrap() {
             node::Directory(directory_b) => directory_b,
             node::Vault(_) => panic!("Unexpected vault node in the builder."),
             node::File(_) => panic!("Unexpected file node in the builder."),
+            node::Symlink(_) => panic!("Unexpected symlink node in the builder."),
         };
         let directory_b = directory_b.unwrap();
 
@@ -847,30 +1173,10 @@ impl InfoBlock {
         let next_local_id = old_nodes_len;
         id_b.set_local_id(next_local_id as u16); // TODO: Make sure we're not truncating
 
-        let inline_node_b = nodes_b.get(next_local_id);
-        match kind {
-            NodeKind::Directory => {
-                let directory_b = inline_node_b.init_directory();
-                directory_b.init_entries(0);
-            }
-            NodeKind::File => {
-                let mut file_b = inline_node_b.init_file();
-                file_b.set_size(1234);
-                // TODO: Set id
-            }
-            NodeKind::Vault => {
-                // TODO
-            }
-        }
-
-        let segment = match message_b.borrow_inner().get_segments_for_output() {
-            capnp::OutputSegments::SingleSegment(ss) => Bytes::copy_from_slice(ss[0]),
-            capnp::OutputSegments::MultiSegment(_) => {
-                panic!("got multiple output segments, but our reader doesn't want that")
-            }
-        };
+        init(nodes_b.reborrow().get(next_local_id));
+        stamp_created(nodes_b.get(next_local_id), time);
 
-        (Block::from_data(segment), next_local_id)
+        (block_from_message(&message_b), next_local_id)
     }
 
     pub fn directory_get_entry_block_id_and_node_index(
@@ -899,7 +1205,7 @@ impl InfoBlock {
                     }
                     union_id::Which::BlockId(block_id_r) => {
                         let block_id_r = block_id_r.unwrap();
-                        let block_id = BlockId::from_reader(block_id_r);
+                        let block_id = BlockId::from_reader(block_id_r).expect("failed to read block id");
                         return Some((Some(block_id), 0));
                     }
                     union_id::Which::ShardId(_) => unimplemented!(),
@@ -935,7 +1241,7 @@ impl InfoBlock {
                     union_id::Which::LocalId(local_id) => block_id.is_none() && local_id == node_index,
                     union_id::Which::BlockId(block_id_r) => {
                         let block_id_r = block_id_r.unwrap();
-                        let current_block_id = BlockId::from_reader(block_id_r);
+                        let current_block_id = BlockId::from_reader(block_id_r).expect("failed to read block id");
                         block_id.is_some() && *block_id.unwrap() == current_block_id
                     }
                     union_id::Which::ShardId(_) => unimplemented!(),
@@ -955,29 +1261,167 @@ impl InfoBlock {
 
                     let entries_b = directory_b.get_entries().unwrap();
                     let entry_b = entries_b.get(entry_idx as u32);
-                    let mut id_b = entry_b.init_id();
+                    set_union_id(entry_b.init_id(), block_id, node_index);
 
-                    if let Some(block_id) = block_id {
-                        block_id.to_builder(id_b.init_block_id());
-                    } else {
-                        id_b.set_local_id(node_index);
-                    }
+                    return Some(block_from_message(&message_b));
+                }
+            }
+        }
+        None
+    }
 
-                    let segment = match message_b.borrow_inner().get_segments_for_output() {
-                        capnp::OutputSegments::SingleSegment(ss) => Bytes::copy_from_slice(ss[0]),
-                        capnp::OutputSegments::MultiSegment(_) => {
-                            panic!("got multiple output segments, but our reader doesn't want that")
-                        }
-                    };
+    /// Returns the [`NodeStat`] of the node at `node_idx`.
+    pub fn node_stat(&self, node_idx: u32) -> NodeStat {
+        let block_r = self.block_reader();
+        let nodes_r = block_r.get_nodes().unwrap();
+        let node_r = nodes_r.get(node_idx);
+
+        let (kind, size) = match node_r.which().expect("not a readable node") {
+            node::Which::Directory(_) => (NodeKind::Directory, None),
+            node::Which::File(file_r) => (NodeKind::File, Some(file_r.unwrap().get_size().into())),
+            node::Which::Vault(_) => (NodeKind::Vault, None),
+            node::Which::Symlink(_) => (NodeKind::Symlink, None),
+        };
+        // Nodes that weren't stamped read as 0
+        let timestamp = |millis| Some(millis).filter(|millis| *millis != 0).map(Timestamp::from_millis);
+        NodeStat {
+            kind,
+            size,
+            created: timestamp(node_r.get_created()),
+            modified: timestamp(node_r.get_modified()),
+        }
+    }
+
+    /// Returns the target path of the symlink node at `node_idx`, or `None` if the node isn't a symlink.
+    pub fn symlink_target(&self, node_idx: u32) -> Option<&str> {
+        let block_r = self.block_reader();
+        let nodes_r = block_r.get_nodes().unwrap();
+        let node_r = nodes_r.get(node_idx);
+
+        let node::Symlink(target_r) = node_r.which().expect("not a readable node") else {
+            return None;
+        };
+        Some(target_r.unwrap().to_str().expect("symlink target is not valid UTF-8"))
+    }
+
+    /// Returns the block of the target of the symlink node at `node_idx`,
+    /// or `None` if the node isn't a symlink or only points to a path.
+    pub fn symlink_block(&self, node_idx: u32) -> Option<BlockId> {
+        let block_r = self.block_reader();
+        let nodes_r = block_r.get_nodes().unwrap();
+        let node_r = nodes_r.get(node_idx);
+
+        if !matches!(node_r.which(), Ok(node::Symlink(_))) || !node_r.has_symlink_block() {
+            return None;
+        }
+        Some(BlockId::from_reader(node_r.get_symlink_block().unwrap()).expect("failed to read block id"))
+    }
+
+    /// Returns the ids of the data blocks of the file node at `node_idx`.
+    ///
+    /// Fails if the file refers to its data by anything but block ids, which isn't supported yet.
+    pub fn file_block_ids(&self, node_idx: u32) -> Result<Vec<BlockId>, UnsupportedBlock> {
+        let block_r = self.block_reader();
+        let nodes_r = block_r.get_nodes().unwrap();
+        let node_r = nodes_r.get(node_idx);
+
+        let node::File(file_r) = node_r.which().unwrap() else {
+            panic!("Unexpected node");
+        };
+        let ids_r = file_r.unwrap().get_id().unwrap();
+
+        ids_r
+            .iter()
+            .map(|id_r| match id_r.which().expect("failed to get readable id") {
+                union_id::Which::BlockId(block_id_r) => {
+                    Ok(BlockId::from_reader(block_id_r.unwrap()).expect("failed to read block id"))
+                }
+                union_id::Which::LocalId(_) | union_id::Which::ShardId(_) => Err(UnsupportedBlock::FileDataId),
+            })
+            .collect()
+    }
+
+    /// Returns the ids of all the blocks that the directory and file nodes of this block refer to.
+    ///
+    /// This includes both the info blocks of directories stored in other blocks and the data blocks of files.
+    pub fn referenced_block_ids(&self) -> Vec<BlockId> {
+        let block_r = self.block_reader();
+        let nodes_r = block_r.get_nodes().unwrap();
 
-                    return Some(Block::from_data(segment));
+        let mut result = Vec::new();
+        for node_r in nodes_r.iter() {
+            let ids_r = match node_r.which().expect("not a readable node") {
+                node::Which::Directory(directory_r) => {
+                    let entries_r = directory_r.unwrap().get_entries().unwrap();
+                    entries_r.iter().map(|entry_r| entry_r.get_id().unwrap()).collect()
+                }
+                node::Which::File(file_r) => file_r.unwrap().get_id().unwrap().iter().collect(),
+                // Vault nodes only live in vault blocks, which aren't part of the tree
+                node::Which::Vault(_) => Vec::new(),
+                // Symlinks refer to paths, which are resolved anew every time they're followed
+                node::Which::Symlink(_) => Vec::new(),
+            };
+            for id_r in ids_r {
+                if let union_id::Which::BlockId(block_id_r) = id_r.which().expect("failed to get readable id") {
+                    result.push(BlockId::from_reader(block_id_r.unwrap()).expect("failed to read block id"));
                 }
             }
         }
-        None
+
+        result
+    }
+
+    /// Returns a new [`Block`] in which every block id that the directory and file nodes refer to
+    /// is replaced by the one returned by `new_id`.
+    ///
+    /// Local entries are left as they are, so only the references to other blocks change.
+    pub(crate) fn replace_block_ids(&self, new_id: impl Fn(BlockId) -> BlockId) -> Block {
+        let block_r = self.block_reader();
+        let nodes_r = block_r.get_nodes().unwrap();
+
+        let mut message_b = TypedBuilder::<block::Owned>::new_default();
+        message_b.set_root(block_r).unwrap();
+        let block_b = message_b.get_root().unwrap();
+        let mut nodes_b = block_b.get_nodes().unwrap();
+        for (i, node_r) in nodes_r.iter().enumerate() {
+            let node_b = nodes_b.reborrow().get(i as u32);
+            match (node_r.which().expect("not a readable node"), node_b.which().unwrap()) {
+                (node::Which::Directory(directory_r), node::Which::Directory(directory_b)) => {
+                    let entries_r = directory_r.unwrap().get_entries().unwrap();
+                    let mut entries_b = directory_b.unwrap().get_entries().unwrap();
+                    for (j, entry_r) in entries_r.iter().enumerate() {
+                        let id_r = entry_r.get_id().expect("failed to get id");
+                        if let union_id::Which::BlockId(block_id_r) = id_r.which().expect("failed to get readable id") {
+                            let block_id = BlockId::from_reader(block_id_r.unwrap()).expect("failed to read block id");
+                            let id_b = entries_b.reborrow().get(j as u32).get_id().unwrap();
+                            new_id(block_id).to_builder(id_b.init_block_id());
+                        }
+                    }
+                }
+                (node::Which::File(file_r), node::Which::File(file_b)) => {
+                    let ids_r = file_r.unwrap().get_id().unwrap();
+                    let mut ids_b = file_b.unwrap().get_id().unwrap();
+                    for (j, id_r) in ids_r.iter().enumerate() {
+                        if let union_id::Which::BlockId(block_id_r) = id_r.which().expect("failed to get readable id") {
+                            let block_id = BlockId::from_reader(block_id_r.unwrap()).expect("failed to read block id");
+                            new_id(block_id).to_builder(ids_b.reborrow().get(j as u32).init_block_id());
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        block_from_message(&message_b)
     }
 
-    pub fn directory_list(&self, node_idx: u32) -> Vec<(NodeKind, &str)> {
+    /// Returns the kinds and names of the entries of the directory at `node_idx` that are stored in this block.
+    ///
+    /// The entries in continuation blocks aren't included, check out [`VaultReader::list`](crate::VaultReader::list)
+    /// for listing the entries of the whole directory.
+    /// Fails with [`UnsupportedBlock::BlockIdEntry`] if an entry refers to a node in another block,
+    /// as its kind is only known to that block, which `VaultReader::list` loads.
+    pub fn directory_list(&self, node_idx: u32) -> Result<Vec<(NodeKind, &str)>, UnsupportedBlock> {
         let block_r = self.block_reader();
         let nodes_r = block_r.get_nodes().unwrap();
         let node_r = nodes_r.get(node_idx);
@@ -991,6 +1435,9 @@ impl InfoBlock {
 
         let mut result = Vec::<(NodeKind, &str)>::with_capacity(entries_r.len() as usize);
         for entry_r in entries_r.iter() {
+            if entry_r.get_name().unwrap() == DIRECTORY_CONTINUATION {
+                continue;
+            }
             assert!(entry_r.has_id());
             let id_r = entry_r.get_id().expect("failed to get id");
             let kind = match id_r.which().expect("failed to get readable id") {
@@ -1000,54 +1447,764 @@ impl InfoBlock {
                         node::Which::Directory(_) => NodeKind::Directory,
                         node::Which::File(_) => NodeKind::File,
                         node::Which::Vault(_) => NodeKind::Vault,
+                        node::Which::Symlink(_) => NodeKind::Symlink,
                     }
                 }
-                union_id::Which::BlockId(_) => unimplemented!(),
-                union_id::Which::ShardId(_) => unimplemented!(),
+                union_id::Which::BlockId(_) => return Err(UnsupportedBlock::BlockIdEntry),
+                union_id::Which::ShardId(_) => return Err(UnsupportedBlock::ShardedEntry),
             };
 
             let name = entry_r.get_name().unwrap().to_str().unwrap();
             result.push((kind, name));
         }
 
-        result
+        Ok(result)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use rand::{thread_rng, Rng};
+    /// Returns up to `limit` entries of the directory at `node_idx` starting from `offset`,
+    /// along with whether more entries follow.
+    pub fn directory_entries(
+        &self,
+        node_idx: u32,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<DirectoryEntry<'_>>, bool), UnsupportedBlock> {
+        let block_r = self.block_reader();
+        let nodes_r = block_r.get_nodes().unwrap();
+        let node_r = nodes_r.get(node_idx);
 
-    use super::*;
+        let node::Directory(directory_r) = node_r.which().unwrap() else {
+            panic!("Unexpected node");
+        };
+        let directory_r = directory_r.unwrap();
 
-    #[test]
-    fn block_size() {
-        for size_marker in 0..MAX_SIZE_MARKER {
-            let size = *BlockSize::from_marker(size_marker);
-            assert_eq!(size, 2u32.pow(12 + size_marker as u32));
-            assert!(BlockSize::valid(size));
-            assert!(!BlockSize::valid(size - 1));
-            assert!(!BlockSize::valid(size + 1));
+        let entries_r = directory_r.get_entries().unwrap();
+        let mut result = Vec::with_capacity(limit.min(entries_r.len() as usize));
+        for entry_r in entries_r.iter().skip(offset).take(limit) {
+            assert!(entry_r.has_id());
+            let id_r = entry_r.get_id().expect("failed to get id");
+            let (block_id, node_index) = match id_r.which().expect("failed to get readable id") {
+                union_id::Which::LocalId(local_id) => (None, local_id as u32),
+                union_id::Which::BlockId(block_id_r) => {
+                    let block_id_r = block_id_r.unwrap();
+                    let block_id = BlockId::from_reader(block_id_r).expect("failed to read block id");
+                    (Some(block_id), 0)
+                }
+                union_id::Which::ShardId(_) => return Err(UnsupportedBlock::ShardedEntry),
+            };
+
+            let name = entry_r.get_name().unwrap().to_str().unwrap();
+            result.push((block_id, node_index, name));
         }
-        assert!(BlockSize::valid(MAX_BLOCK_SIZE));
-        assert!(!BlockSize::valid(MAX_BLOCK_SIZE - 1));
-        assert!(!BlockSize::valid(MAX_BLOCK_SIZE + 1));
-        assert!(!BlockSize::valid(2u32.pow(28)));
-        assert!(!BlockSize::valid(2u32.pow(29) + 45));
-        assert!(!BlockSize::valid(2u32.pow(30) + 123456));
-    }
 
-    /// Make sure that all `BlockId` variants are properly detected.
-    #[test]
-    fn block_id_header() {
-        let mut id_bytes = [0; 32];
-        thread_rng().fill(&mut id_bytes[..]);
+        let more = (entries_r.len() as usize).saturating_sub(offset) > limit;
+        Ok((result, more))
+    }
 
-        id_bytes[0] = 0b0000_0000;
-        let block_id = BlockId::from_data(id_bytes);
-        assert!(block_id.supported_version());
-        assert!(block_id.valid());
-        assert!(!block_id.block_has_header());
+    /// Returns the local ids of the node at `node_idx` and of all the local nodes reachable from it,
+    /// starting with `node_idx` itself.
+    ///
+    /// Nodes reachable through several entries are only listed once.
+    pub(crate) fn local_subtree(&self, node_idx: u32) -> Result<Vec<u32>, UnsupportedBlock> {
+        let mut subtree = vec![node_idx];
+        let mut seen = BTreeSet::from([node_idx]);
+        let mut i = 0;
+        while i < subtree.len() {
+            let current_idx = subtree[i];
+            if self.node_stat(current_idx).kind == NodeKind::Directory {
+                let (entries, _) = self.directory_entries(current_idx, 0, usize::MAX)?;
+                for (block_id, entry_idx, _) in entries {
+                    if block_id.is_none() && seen.insert(entry_idx) {
+                        subtree.push(entry_idx);
+                    }
+                }
+            }
+            i += 1;
+        }
+        Ok(subtree)
+    }
+
+    /// Returns `true` if any node of the subtree at `node_idx` is referenced from outside of the subtree,
+    /// apart from a single entry referring to `node_idx` itself.
+    pub(crate) fn local_subtree_is_shared(&self, node_idx: u32) -> Result<bool, UnsupportedBlock> {
+        let subtree = self.local_subtree(node_idx)?;
+        let nodes_len = self.block_reader().get_nodes().unwrap().len();
+        let mut references = 0;
+        for directory_idx in (0..nodes_len).filter(|idx| !subtree.contains(idx)) {
+            if self.node_stat(directory_idx).kind != NodeKind::Directory {
+                continue;
+            }
+            let (entries, _) = self.directory_entries(directory_idx, 0, usize::MAX)?;
+            references += entries
+                .into_iter()
+                .filter(|(block_id, entry_idx, _)| block_id.is_none() && subtree.contains(entry_idx))
+                .count();
+        }
+        Ok(references > 1)
+    }
+
+    /// Returns a new [`Block`] that consists of the subtree of local nodes at `node_idx`,
+    /// with `node_idx` as its first node, so that it can be referred to by the id of the new block.
+    ///
+    /// The nodes keep referring to the nodes of other blocks by block id.
+    pub(crate) fn extract_local_subtree(&self, node_idx: u32) -> Result<Block, VaultError> {
+        self.remap_local_nodes(&self.local_subtree(node_idx)?, false)
+    }
+
+    /// Returns the largest number of leading entries of the directory at the first node
+    /// that fit into a block of at most `max_size` bytes, as built by [`InfoBlock::directory_take_entries`].
+    ///
+    /// Entries that share local nodes through links are kept together, so some counts are never returned.
+    pub fn directory_fitting_entries(&self, max_size: usize) -> Result<usize, VaultError> {
+        let (entries, _) = self.directory_entries(0, 0, usize::MAX)?;
+        // The first and the last entry that every local node is reachable from
+        let mut uses: BTreeMap<u32, (usize, usize)> = BTreeMap::new();
+        for (i, (block_id, node_idx, _)) in entries.iter().enumerate() {
+            if block_id.is_none() {
+                for subtree_idx in self.local_subtree(*node_idx)? {
+                    uses.entry(subtree_idx).or_insert((i, i)).1 = i;
+                }
+            }
+        }
+        // The number of nodes shared across the boundary in front of every entry
+        let mut crossings = vec![0i64; entries.len() + 2];
+        for (first, last) in uses.into_values().filter(|(first, last)| first < last) {
+            crossings[first + 1] += 1;
+            crossings[last + 1] -= 1;
+        }
+        let mut shared = 0;
+        let mut counts = vec![0];
+        for (count, crossing) in crossings.iter().enumerate().take(entries.len() + 1).skip(1) {
+            shared += crossing;
+            if shared == 0 {
+                counts.push(count);
+            }
+        }
+
+        // Blocks only grow with more entries, so look for the largest fitting count by bisection
+        let (mut fits, mut too_large) = (0, counts.len());
+        while too_large - fits > 1 {
+            let middle = (fits + too_large) / 2;
+            if self.directory_take_entries(counts[middle])?.size() <= max_size {
+                fits = middle;
+            } else {
+                too_large = middle;
+            }
+        }
+        Ok(counts[fits])
+    }
+
+    /// Returns a new [`Block`] with only the first `count` entries of the directory at the first node,
+    /// along with the local nodes they refer to.
+    pub fn directory_take_entries(&self, count: usize) -> Result<Block, VaultError> {
+        let taken = self.filter_directory_entries(|i| i < count, None).info()?;
+        taken.remap_local_nodes(&taken.local_subtree(0)?, false)
+    }
+
+    /// Returns a new [`Block`] without the first `count` entries of the directory at the first node,
+    /// which continues in the block `next_id` instead, as if those entries were moved there.
+    ///
+    /// The local nodes that are no longer referred to are left out.
+    pub fn directory_continue(&self, count: usize, next_id: &BlockId) -> Result<Block, VaultError> {
+        let rest = self.filter_directory_entries(|i| i >= count, Some(next_id)).info()?;
+        rest.remap_local_nodes(&rest.local_subtree(0)?, true)
+    }
+
+    /// Returns a new [`Block`] with only the entries of the directory at the first node whose index is kept,
+    /// preceded by a continuation entry referring to `next_id` if present.
+    ///
+    /// The nodes are left as they are, even if no longer referred to.
+    fn filter_directory_entries(&self, keep: impl Fn(usize) -> bool, next_id: Option<&BlockId>) -> Block {
+        let block_r = self.block_reader();
+        let nodes_r = block_r.get_nodes().unwrap();
+        let node::Directory(directory_r) = nodes_r.get(0).which().unwrap() else {
+            panic!("Unexpected node");
+        };
+        let entries_r = directory_r.unwrap().get_entries().unwrap();
+        let kept: Vec<u32> = (0..entries_r.len()).filter(|i| keep(*i as usize)).collect();
+
+        let mut message_b = TypedBuilder::<block::Owned>::new_default();
+        message_b.set_root(block_r).unwrap();
+        let block_b = message_b.get_root().unwrap();
+        let nodes_b = block_b.get_nodes().unwrap();
+        let node::Directory(directory_b) = nodes_b.get(0).which().unwrap() else {
+            panic!("Unexpected node");
+        };
+
+        let offset = u32::from(next_id.is_some());
+        let mut entries_b = directory_b.unwrap().init_entries(offset + kept.len() as u32);
+        if let Some(next_id) = next_id {
+            let mut entry_b = entries_b.reborrow().get(0);
+            entry_b.set_name(DIRECTORY_CONTINUATION);
+            set_union_id(entry_b.init_id(), Some(next_id), 0);
+        }
+        for (i, old_idx) in kept.into_iter().enumerate() {
+            entries_b
+                .set_with_caveats(offset + i as u32, entries_r.get(old_idx))
+                .unwrap();
+        }
+
+        block_from_message(&message_b)
+    }
+
+    /// Returns a new [`Block`] whose nodes are the nodes at the local ids listed in `order`,
+    /// with the local entries renumbered to match.
+    ///
+    /// Everything besides the nodes is only kept if `keep_rest` is set.
+    ///
+    /// Fails with [`VaultError::DanglingLocalNode`] if an entry of a listed node refers to a node that isn't listed.
+    fn remap_local_nodes(&self, order: &[u32], keep_rest: bool) -> Result<Block, VaultError> {
+        let block_r = self.block_reader();
+        let nodes_r = block_r.get_nodes().unwrap();
+
+        let mut message_b = TypedBuilder::<block::Owned>::new_default();
+        let block_b = if keep_rest {
+            message_b.set_root(block_r).unwrap();
+            message_b.get_root().unwrap()
+        } else {
+            message_b.init_root()
+        };
+
+        let mut nodes_b = block_b.init_nodes(order.len() as u32);
+        for (i, old_idx) in order.iter().enumerate() {
+            nodes_b.set_with_caveats(i as u32, nodes_r.get(*old_idx)).unwrap();
+
+            let node::Directory(directory_b) = nodes_b.reborrow().get(i as u32).which().unwrap() else {
+                continue;
+            };
+            let mut entries_b = directory_b.unwrap().get_entries().unwrap();
+            for j in 0..entries_b.len() {
+                let mut id_b = entries_b.reborrow().get(j).get_id().unwrap();
+                if let union_id::Which::LocalId(local_id) = id_b.reborrow().which().unwrap() {
+                    let new_idx = order
+                        .iter()
+                        .position(|idx| *idx == local_id as u32)
+                        .ok_or(VaultError::DanglingLocalNode(local_id as u32))?;
+                    let new_idx = new_idx as u32;
+                    id_b.set_local_id(u16::try_from(new_idx).map_err(|_| TooManyLocalNodes(new_idx))?);
+                }
+            }
+        }
+
+        Ok(block_from_message(&message_b))
+    }
+}
+
+/// The block id and node index of the node that a directory entry refers to, followed by the entry name.
+///
+/// As in [`InfoBlock::directory_get_entry_block_id_and_node_index`], a block id of `None` means the same block.
+pub type DirectoryEntry<'a> = (Option<BlockId>, u32, &'a str);
+
+/// Initializes the freshly allocated inline node `node_b` as an empty node of `kind`.
+fn init_local_node(mut node_b: node::Builder, kind: NodeKind) {
+    match kind {
+        NodeKind::Directory => {
+            let directory_b = node_b.init_directory();
+            directory_b.init_entries(0);
+        }
+        NodeKind::File => {
+            // An empty file without any data blocks
+            node_b.init_file();
+        }
+        NodeKind::Vault => {
+            // TODO
+        }
+        NodeKind::Symlink => {
+            // A symlink that points nowhere yet
+            node_b.set_symlink("");
+        }
+    }
+}
+
+/// Initializes the freshly allocated inline node `node_b` as a file of `size` bytes stored in `block_ids`.
+fn init_local_file(node_b: node::Builder, size: FileSize, block_ids: &[BlockId]) {
+    let mut file_b = node_b.init_file();
+    file_b.set_size(*size);
+    let mut ids_b = file_b.init_id(block_ids.len() as u32);
+    for (i, block_id) in block_ids.iter().enumerate() {
+        block_id.to_builder(ids_b.reborrow().get(i as u32).init_block_id());
+    }
+}
+
+/// Initializes the freshly allocated inline node `node_b` with the contents of the pending `node`.
+fn init_pending_node(mut node_b: node::Builder, node: &PendingNode) {
+    match node {
+        PendingNode::Empty(kind) => init_local_node(node_b, *kind),
+        PendingNode::File(size, block_ids) => init_local_file(node_b, *size, block_ids),
+        PendingNode::Symlink(target, block_id) => {
+            node_b.set_symlink(target.as_str());
+            if let Some(block_id) = block_id {
+                block_id.to_builder(node_b.init_symlink_block());
+            }
+        }
+    }
+}
+
+/// Stamps the freshly initialized node `node_b` as created and modified at `time`, if any.
+fn stamp_created(mut node_b: node::Builder, time: Option<Timestamp>) {
+    if let Some(time) = time {
+        node_b.set_created(time.as_millis());
+        node_b.set_modified(time.as_millis());
+    }
+}
+
+/// Stamps the node `node_b` as modified at `time`, if any.
+///
+/// Clocks can go backwards, so the modification time is kept from going back before the previous one.
+fn stamp_modified(mut node_b: node::Builder, time: Option<Timestamp>) {
+    if let Some(time) = time {
+        let previous = node_b.reborrow().get_modified().max(node_b.reborrow().get_created());
+        node_b.set_modified(time.as_millis().max(previous));
+    }
+}
+
+/// Points `id_b` to the block `block_id` if present, otherwise to the local node `node_index`.
+fn set_union_id(mut id_b: union_id::Builder, block_id: Option<&BlockId>, node_index: u16) {
+    if let Some(block_id) = block_id {
+        block_id.to_builder(id_b.init_block_id());
+    } else {
+        id_b.set_local_id(node_index);
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The number of blocks serialized by [`block_from_message`] on the current thread.
+    static SERIALIZATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Serializes `message_b` into a [`Block`] that consists of a single segment.
+///
+/// The message is first copied into a fresh builder whose first segment fits the whole message,
+/// because [`InfoBlock`] can only read single segment messages, no matter how many segments `message_b` spans.
+/// The copy also leaves behind any space orphaned by earlier edits.
+/// This way the same content always results in the same bytes, no matter how many steps it took to build.
+/// It also means that a block copied from another one, like a subtree moved to a block of its own,
+/// doesn't keep the bytes of the original, so its id can differ even when the nodes are the same.
+/// Every block is stamped with the current [`SCHEMA_VERSION`] along the way.
+fn block_from_message(message_b: &TypedBuilder<block::Owned>) -> Block {
+    #[cfg(test)]
+    SERIALIZATIONS.with(|count| count.set(count.get() + 1));
+
+    let block_r = message_b.get_root_as_reader().expect("failed to read the message");
+    // One more word for the root pointer
+    let words = block_r.total_size().expect("failed to measure the message").word_count + 1;
+    let allocator = HeapAllocator::new().first_segment_words(u32::try_from(words).expect("message too large"));
+    let mut compact_b = TypedBuilder::<block::Owned>::new(message::Builder::new(allocator));
+    compact_b.set_root(block_r).unwrap();
+    compact_b.get_root().unwrap().set_version(SCHEMA_VERSION);
+
+    match compact_b.borrow_inner().get_segments_for_output() {
+        capnp::OutputSegments::SingleSegment(ss) => block_from_segment(ss[0]),
+        capnp::OutputSegments::MultiSegment(_) => unreachable!("the first segment fits the whole message"),
+    }
+}
+
+/// Returns a new [`Block`] with a copy of the message `segment`.
+fn block_from_segment(segment: &[u8]) -> Block {
+    Block::from_data(Bytes::copy_from_slice(segment))
+}
+
+/// A local node that hasn't been written to a block yet.
+enum PendingNode {
+    /// An empty node of the kind.
+    Empty(NodeKind),
+    /// A file node of the size, stored in the data blocks.
+    File(FileSize, Vec<BlockId>),
+    /// A symlink node that points to the path, and to the block of the target if present.
+    Symlink(String, Option<BlockId>),
+}
+
+/// A directory entry that hasn't been written to a block yet.
+struct PendingEntry {
+    /// The local id of the directory node that contains the entry.
+    directory_node_idx: u32,
+    /// The name of the entry.
+    name: String,
+    /// The block that contains the entry's node, `None` if it's a local node.
+    block_id: Option<BlockId>,
+    /// The local id of the entry's node.
+    node_index: u16,
+}
+
+/// Accumulates changes to the directory nodes of an [`InfoBlock`] and serializes the block only once.
+///
+/// The resulting block is identical to the one produced by applying the same changes one by one
+/// via [`InfoBlock::directory_create_local_node`] and [`InfoBlock::directory_set_entry_block_id_and_node_index`].
+pub struct DirectoryBuilder {
+    /// The block that the changes are applied to.
+    base: InfoBlock,
+    /// The newly created local nodes, in local id order.
+    new_nodes: Vec<PendingNode>,
+    /// The newly created entries, in creation order.
+    new_entries: Vec<PendingEntry>,
+    /// The updated ids of entries that already exist in the base block.
+    updated_entries: Vec<PendingEntry>,
+    /// The replacements of nodes that already exist in the base block, by local id.
+    replaced_nodes: Vec<(u32, PendingNode)>,
+    /// The local ids of the nodes to leave out of the block.
+    removed_nodes: Vec<u32>,
+    /// The local ids of the directory nodes and the names of the entries to leave out of them.
+    removed_entries: Vec<(u32, String)>,
+    /// The local ids of the directory nodes, the names of the entries to rename and their new names.
+    renamed_entries: Vec<(u32, String, String)>,
+    /// The time that new and changed nodes are stamped with, if any.
+    time: Option<Timestamp>,
+}
+
+impl DirectoryBuilder {
+    /// Create a new `DirectoryBuilder` that applies its changes on top of `base`.
+    pub fn new(base: InfoBlock) -> DirectoryBuilder {
+        DirectoryBuilder {
+            base,
+            new_nodes: Vec::new(),
+            new_entries: Vec::new(),
+            updated_entries: Vec::new(),
+            replaced_nodes: Vec::new(),
+            removed_nodes: Vec::new(),
+            removed_entries: Vec::new(),
+            renamed_entries: Vec::new(),
+            time: None,
+        }
+    }
+
+    /// Sets the time that new nodes are stamped as created at, and changed ones as modified at.
+    ///
+    /// Directories count as changed when entries are added, removed or renamed, but not when an entry
+    /// is only pointed to a new location. Nothing is stamped by default.
+    pub fn set_time(&mut self, time: Option<Timestamp>) {
+        self.time = time;
+    }
+
+    /// Returns the block that the changes are applied to, without any of the changes.
+    pub fn base(&self) -> &InfoBlock {
+        &self.base
+    }
+
+    /// Returns the number of nodes in the base block.
+    fn base_nodes_len(&self) -> u32 {
+        self.base.block_reader().get_nodes().unwrap().len()
+    }
+
+    /// Creates a new local node of `kind` with `name` in the directory node at `directory_node_idx`.
+    ///
+    /// Returns the local id of the new node, or fails if the block already has as many nodes as local ids can refer to.
+    pub fn create_local_node(
+        &mut self,
+        directory_node_idx: u32,
+        name: &str,
+        kind: NodeKind,
+    ) -> Result<u32, TooManyLocalNodes> {
+        self.push_local_node(directory_node_idx, name, PendingNode::Empty(kind))
+    }
+
+    /// Creates a new local file node with `name` in the directory node at `directory_node_idx`,
+    /// whose contents are `size` bytes stored in the data blocks `block_ids`.
+    ///
+    /// Returns the local id of the new node, failing like [`DirectoryBuilder::create_local_node`].
+    pub fn create_local_file(
+        &mut self,
+        directory_node_idx: u32,
+        name: &str,
+        size: FileSize,
+        block_ids: &[BlockId],
+    ) -> Result<u32, TooManyLocalNodes> {
+        self.push_local_node(directory_node_idx, name, PendingNode::File(size, block_ids.to_vec()))
+    }
+
+    /// Creates a new local symlink node with `name` in the directory node at `directory_node_idx`,
+    /// which points to the path `target`, and to the block `target_block` of the target if present.
+    ///
+    /// Returns the local id of the new node, failing like [`DirectoryBuilder::create_local_node`].
+    pub fn create_local_symlink(
+        &mut self,
+        directory_node_idx: u32,
+        name: &str,
+        target: &str,
+        target_block: Option<&BlockId>,
+    ) -> Result<u32, TooManyLocalNodes> {
+        let node = PendingNode::Symlink(String::from(target), target_block.copied());
+        self.push_local_node(directory_node_idx, name, node)
+    }
+
+    /// Replaces the local file node at `node_idx` with one of `size` bytes stored in the data blocks `block_ids`.
+    ///
+    /// All the entries pointing to the node see the new contents.
+    pub fn set_local_file(&mut self, node_idx: u32, size: FileSize, block_ids: &[BlockId]) {
+        let node = PendingNode::File(size, block_ids.to_vec());
+        let base_nodes_len = self.base_nodes_len();
+        if node_idx >= base_nodes_len {
+            self.new_nodes[(node_idx - base_nodes_len) as usize] = node;
+        } else if let Some((_, replaced)) = self.replaced_nodes.iter_mut().find(|(idx, _)| *idx == node_idx) {
+            *replaced = node;
+        } else {
+            self.replaced_nodes.push((node_idx, node));
+        }
+    }
+
+    /// Creates a new entry with `name` in the directory node at `directory_node_idx`,
+    /// pointing to the already existing local node at `node_idx`.
+    ///
+    /// Fails if `node_idx` is beyond what a local id can refer to.
+    pub fn link_local_node(
+        &mut self,
+        directory_node_idx: u32,
+        name: &str,
+        node_idx: u32,
+    ) -> Result<(), TooManyLocalNodes> {
+        let node_index = u16::try_from(node_idx).map_err(|_| TooManyLocalNodes(node_idx))?;
+        self.new_entries.push(PendingEntry {
+            directory_node_idx,
+            name: String::from(name),
+            block_id: None,
+            node_index,
+        });
+        Ok(())
+    }
+
+    /// Removes the local nodes at `node_idxs`, which mustn't be referred to by any entry of the block anymore.
+    ///
+    /// The remaining nodes are renumbered when the block is built, keeping their order,
+    /// so the local ids passed to other calls still refer to the nodes before the removal.
+    pub fn remove_local_nodes(&mut self, node_idxs: &[u32]) {
+        self.removed_nodes.extend_from_slice(node_idxs);
+    }
+
+    /// Removes the entry `entry_name` of the directory node at `directory_node_idx`.
+    ///
+    /// The node that the entry points to is left in place, check out [`DirectoryBuilder::remove_local_nodes`]
+    /// for removing it as well.
+    pub fn remove_entry(&mut self, directory_node_idx: u32, entry_name: &str) {
+        self.removed_entries
+            .push((directory_node_idx, String::from(entry_name)));
+    }
+
+    /// Renames the entry `entry_name` of the directory node at `directory_node_idx` to `new_name`,
+    /// keeping it in place and pointing to the same node.
+    pub fn rename_entry(&mut self, directory_node_idx: u32, entry_name: &str, new_name: &str) {
+        self.renamed_entries
+            .push((directory_node_idx, String::from(entry_name), String::from(new_name)));
+    }
+
+    /// Appends the local `node` and an entry with `name` pointing to it.
+    ///
+    /// Fails if the new node would be beyond what a local id can refer to, leaving the builder unchanged.
+    fn push_local_node(
+        &mut self,
+        directory_node_idx: u32,
+        name: &str,
+        node: PendingNode,
+    ) -> Result<u32, TooManyLocalNodes> {
+        let next_local_id = self.base_nodes_len() + self.new_nodes.len() as u32;
+        let node_index = u16::try_from(next_local_id).map_err(|_| TooManyLocalNodes(next_local_id))?;
+        self.new_nodes.push(node);
+        self.new_entries.push(PendingEntry {
+            directory_node_idx,
+            name: String::from(name),
+            block_id: None,
+            node_index,
+        });
+        Ok(next_local_id)
+    }
+
+    /// Points the entry `entry_name` of the directory node at `directory_node_idx` to a new location.
+    pub fn set_entry_block_id_and_node_index(
+        &mut self,
+        directory_node_idx: u32,
+        entry_name: &str,
+        block_id: Option<&BlockId>,
+        node_index: u16,
+    ) {
+        let pending = self
+            .new_entries
+            .iter_mut()
+            .chain(self.updated_entries.iter_mut())
+            .find(|entry| entry.directory_node_idx == directory_node_idx && entry.name == entry_name);
+        if let Some(entry) = pending {
+            entry.block_id = block_id.copied();
+            entry.node_index = node_index;
+        } else {
+            self.updated_entries.push(PendingEntry {
+                directory_node_idx,
+                name: String::from(entry_name),
+                block_id: block_id.copied(),
+                node_index,
+            });
+        }
+    }
+
+    /// Returns the new [`Block`] with all the changes applied.
+    ///
+    /// Fails with [`VaultError::DanglingLocalNode`] if a removed local node is still referred to by an entry.
+    pub fn build(self) -> Result<Block, VaultError> {
+        let block_r = self.base.block_reader();
+        let nodes_r = block_r.get_nodes().unwrap();
+        let old_nodes_len = nodes_r.len();
+
+        let mut message_b = TypedBuilder::<block::Owned>::new_default();
+        message_b.set_root(block_r).unwrap();
+        let block_b = message_b.get_root().unwrap();
+
+        let mut nodes_b = block_b.init_nodes(old_nodes_len + self.new_nodes.len() as u32);
+        for i in 0..old_nodes_len {
+            match self.replaced_nodes.iter().find(|(idx, _)| *idx == i) {
+                Some((_, node)) => {
+                    let old_node_r = nodes_r.reborrow().get(i);
+                    let mut node_b = nodes_b.reborrow().get(i);
+                    init_pending_node(node_b.reborrow(), node);
+                    // The replacement is the same node with new contents
+                    node_b.set_created(old_node_r.get_created());
+                    node_b.set_modified(old_node_r.get_modified());
+                    stamp_modified(node_b, self.time);
+                }
+                None => nodes_b.set_with_caveats(i, nodes_r.reborrow().get(i)).unwrap(),
+            }
+        }
+        for (i, node) in self.new_nodes.iter().enumerate() {
+            let idx = old_nodes_len + i as u32;
+            init_pending_node(nodes_b.reborrow().get(idx), node);
+            stamp_created(nodes_b.reborrow().get(idx), self.time);
+        }
+
+        for directory_node_idx in 0..old_nodes_len + self.new_nodes.len() as u32 {
+            let new_entries: Vec<&PendingEntry> = self
+                .new_entries
+                .iter()
+                .filter(|entry| entry.directory_node_idx == directory_node_idx)
+                .collect();
+            let has_updates = self
+                .updated_entries
+                .iter()
+                .any(|entry| entry.directory_node_idx == directory_node_idx);
+            let has_removals = self.removed_entries.iter().any(|(idx, _)| *idx == directory_node_idx);
+            let has_renames = self
+                .renamed_entries
+                .iter()
+                .any(|(idx, _, _)| *idx == directory_node_idx);
+            if new_entries.is_empty() && !has_updates && !has_removals && !has_renames {
+                continue;
+            }
+            if !new_entries.is_empty() || has_removals || has_renames {
+                stamp_modified(nodes_b.reborrow().get(directory_node_idx), self.time);
+            }
+
+            let node_b = nodes_b.reborrow().get(directory_node_idx);
+            let node::Directory(directory_b) = node_b.which().unwrap() else {
+                panic!("Unexpected node");
+            };
+            let directory_b = directory_b.unwrap();
+
+            let old_entries_r = if directory_node_idx < old_nodes_len {
+                let node::Directory(directory_r) = nodes_r.get(directory_node_idx).which().unwrap() else {
+                    panic!("Unexpected node");
+                };
+                Some(directory_r.unwrap().get_entries().unwrap())
+            } else {
+                None
+            };
+            let kept_entries_r: Vec<_> = old_entries_r
+                .iter()
+                .flat_map(|entries_r| entries_r.iter())
+                .filter(|entry_r| {
+                    let name = entry_r.get_name().unwrap();
+                    !self
+                        .removed_entries
+                        .iter()
+                        .any(|(idx, removed)| *idx == directory_node_idx && name == removed.as_str())
+                })
+                .collect();
+            let old_entries_len = kept_entries_r.len() as u32;
+
+            let mut entries_b = directory_b.init_entries(old_entries_len + new_entries.len() as u32);
+            for (i, old_entry_r) in kept_entries_r.into_iter().enumerate() {
+                entries_b.set_with_caveats(i as u32, old_entry_r).unwrap();
+
+                let name = old_entry_r.get_name().unwrap();
+                let updated = self
+                    .updated_entries
+                    .iter()
+                    .find(|entry| entry.directory_node_idx == directory_node_idx && name == entry.name.as_str());
+                if let Some(entry) = updated {
+                    let entry_b = entries_b.reborrow().get(i as u32);
+                    set_union_id(entry_b.init_id(), entry.block_id.as_ref(), entry.node_index);
+                }
+                let renamed = self
+                    .renamed_entries
+                    .iter()
+                    .find(|(idx, renamed, _)| *idx == directory_node_idx && name == renamed.as_str());
+                if let Some((_, _, new_name)) = renamed {
+                    entries_b.reborrow().get(i as u32).set_name(new_name.as_str());
+                }
+            }
+            for (i, entry) in new_entries.into_iter().enumerate() {
+                let mut entry_b = entries_b.reborrow().get(old_entries_len + i as u32);
+                entry_b.set_name(entry.name.as_str());
+                set_union_id(entry_b.init_id(), entry.block_id.as_ref(), entry.node_index);
+            }
+        }
+
+        if self.removed_nodes.is_empty() {
+            return Ok(block_from_message(&message_b));
+        }
+        let order: Vec<u32> = (0..old_nodes_len + self.new_nodes.len() as u32)
+            .filter(|idx| !self.removed_nodes.contains(idx))
+            .collect();
+        block_from_message(&message_b).info()?.remap_local_nodes(&order, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+
+    /// Make sure that the contents of a sensitive block are zeroed if they're uniquely owned,
+    /// and left alone if they're shared.
+    #[test]
+    fn sensitive_block() {
+        let mut sensitive = SensitiveBlock::new(Block::from_data(vec![7; 4096].into()));
+        assert!(sensitive.zeroize());
+        assert_eq!(sensitive.block().size(), 4096);
+        assert!(sensitive.block().data().iter().all(|byte| *byte == 0));
+
+        let block = Block::from_data(vec![7; 4096].into());
+        let mut sensitive = SensitiveBlock::new(block.clone());
+        assert!(!sensitive.zeroize());
+        drop(sensitive);
+        assert!(block.data().iter().all(|byte| *byte == 7));
+
+        assert!(SensitiveBlock::new(Block::empty()).zeroize());
+    }
+
+    #[test]
+    fn block_size() {
+        for size_marker in 0..MAX_SIZE_MARKER {
+            let size = *BlockSize::from_marker(size_marker);
+            assert_eq!(size, 2u32.pow(12 + size_marker as u32));
+            assert!(BlockSize::valid(size));
+            assert!(!BlockSize::valid(size - 1));
+            assert!(!BlockSize::valid(size + 1));
+        }
+        assert!(BlockSize::valid(MAX_BLOCK_SIZE));
+        assert!(!BlockSize::valid(MAX_BLOCK_SIZE - 1));
+        assert!(!BlockSize::valid(MAX_BLOCK_SIZE + 1));
+        assert!(!BlockSize::valid(2u32.pow(28)));
+        assert!(!BlockSize::valid(2u32.pow(29) + 45));
+        assert!(!BlockSize::valid(2u32.pow(30) + 123456));
+    }
+
+    /// Make sure that all `BlockId` variants are properly detected.
+    #[test]
+    fn block_id_header() {
+        let mut id_bytes = [0; 32];
+        thread_rng().fill(&mut id_bytes[..]);
+
+        id_bytes[0] = 0b0000_0000;
+        let block_id = BlockId::from_data(id_bytes);
+        assert!(block_id.supported_version());
+        assert!(block_id.valid());
+        assert!(!block_id.block_has_header());
         assert_eq!(block_id.block_size(), 4096.into());
 
         id_bytes[0] = 0b0000_0001;
@@ -1087,6 +2244,209 @@ mod tests {
         }
     }
 
+    /// Make sure that `best_fit` chooses sizes that the offset translation agrees with.
+    #[test]
+    fn block_size_best_fit() {
+        let sizes: Vec<BlockSize> = BlockSize::all().collect();
+        assert_eq!(sizes.len(), 16);
+        for pair in sizes.windows(2) {
+            assert_eq!(pair[0].next_larger(), Some(pair[1]));
+        }
+        assert_eq!(BlockSize::from_marker(MAX_SIZE_MARKER).next_larger(), None);
+        for (marker, size) in sizes.iter().enumerate() {
+            assert_eq!(size.marker() as usize, marker);
+        }
+
+        let first = BlockIdIndex::from(0);
+        assert_eq!(*BlockSize::best_fit(first, FileSize::new(0)), 4096);
+        assert_eq!(*BlockSize::best_fit(first, FileSize::new(100 * 1024 * 1024)), 4096);
+        assert_eq!(*BlockSize::best_fit(BlockIdIndex::from(16), FileSize::new(4097)), 8192);
+        assert_eq!(*BlockSize::best_fit(BlockIdIndex::from(16), FileSize::new(4096)), 4096);
+        let last = BlockIdIndex::from(u32::MAX);
+        assert_eq!(*BlockSize::best_fit(last, FileSize::new(MAX_FILE_SIZE)), MAX_BLOCK_SIZE);
+
+        // A file that ends anywhere inside a layout block has a last block that fits
+        let mut block_start_offset = FileOffset::new(0);
+        for (index, size) in BlockSize::layout().take(340).enumerate() {
+            for remaining in [1, *size / 2 + 1, *size, *size + 1] {
+                let best_fit = BlockSize::best_fit(BlockIdIndex::from(index as u32), FileSize::new(remaining as u64));
+                assert!(best_fit <= size);
+                assert!(*best_fit >= remaining.min(*size));
+                let remaining = remaining.min(*size);
+
+                let last_offset = block_start_offset + FileOffset::new(remaining as u64 - 1);
+                let (block_index, offset) = InfoBlock::translate_file_offset(last_offset).unwrap();
+                assert_eq!(*block_index, index as u32);
+                assert!(FileOffset::from(offset) < FileOffset::from(best_fit));
+            }
+            block_start_offset += size.into();
+        }
+    }
+
+    /// Make sure that a new `BlockId` records the smallest block size that fits the data.
+    #[test]
+    fn block_id_size_marker() {
+        let hash = blake3::hash(b"exomem");
+        for (size, expected) in [
+            (0, 4096),
+            (100, 4096),
+            (4096, 4096),
+            (4097, 8192),
+            (8192, 8192),
+            (5 * 4096, 32768),
+            (MAX_BLOCK_SIZE as usize, MAX_BLOCK_SIZE),
+        ] {
+            let block_id = BlockId::new(hash, size, false).unwrap();
+            assert_eq!(*block_id.block_size(), expected, "size {size}");
+            assert!(block_id.valid());
+        }
+    }
+
+    /// Make sure that blocks larger than the largest block size are refused,
+    /// and so are data blocks that don't fill their size unless they're the last one of a file.
+    #[test]
+    fn encrypted_block_id_size() {
+        for (size, expected) in [(4096, 4096), (MAX_BLOCK_SIZE as usize, MAX_BLOCK_SIZE)] {
+            let encrypted_block = EncryptedBlock::from_data(vec![1; size].into());
+            for kind in [BlockKind::Data, BlockKind::Info] {
+                let id = encrypted_block.id(kind).unwrap();
+                assert_eq!(*id.block_size(), expected, "size {size}");
+                assert_eq!(id.block_kind(), kind);
+            }
+        }
+
+        let full = EncryptedBlock::from_data(vec![1; 8192].into());
+        let size = BlockSize::new(8192);
+        assert_eq!(full.full_data_id_with(size, &Blake3), full.id(BlockKind::Data));
+        // A size between two block sizes is only allowed at the end of a file
+        let partial = EncryptedBlock::from_data(vec![1; 5000].into());
+        assert_eq!(partial.full_data_id_with(size, &Blake3), Err(InvalidBlockSize(5000)));
+        assert_eq!(*partial.id(BlockKind::Data).unwrap().block_size(), 8192);
+
+        let oversized = MAX_BLOCK_SIZE as usize + 1;
+        let encrypted_block = EncryptedBlock::from_data(vec![1; oversized].into());
+        assert_eq!(encrypted_block.id(BlockKind::Data), Err(InvalidBlockSize(oversized)));
+        assert_eq!(
+            BlockId::new(blake3::hash(b"exomem"), oversized, true),
+            Err(InvalidBlockSize(oversized))
+        );
+    }
+
+    /// Make sure that a `BlockId` is displayed as plain hex of its bytes and parsed back.
+    #[test]
+    fn block_id_hex() {
+        let mut data = [0; 32];
+        thread_rng().fill(&mut data[..]);
+        data[0] = 0x0a;
+        data[31] = 0xf0;
+        let block_id = BlockId::from_data(data);
+
+        let hex = block_id.to_string();
+        let expected: String = block_id.data().iter().map(|byte| format!("{byte:02x}")).collect();
+        assert_eq!(hex, expected);
+        assert!(hex.starts_with("0a"));
+        assert!(hex.ends_with("f0"));
+
+        assert_eq!(hex.parse::<BlockId>().unwrap(), block_id);
+        assert_eq!(hex.to_uppercase().parse::<BlockId>().unwrap(), block_id);
+        assert!(hex[1..].parse::<BlockId>().is_err());
+        assert!(format!("g{}", &hex[1..]).parse::<BlockId>().is_err());
+        assert!(format!("+{}", &hex[1..]).parse::<BlockId>().is_err());
+    }
+
+    /// Make sure that the `BlockKind` survives the round trip through the header of a `BlockId`.
+    #[test]
+    fn block_id_block_kind() {
+        let hash = blake3::hash(b"exomem");
+        for kind in [BlockKind::Info, BlockKind::Data] {
+            let block_id = BlockId::new(hash, 4096, kind.has_header()).unwrap();
+            assert_eq!(block_id.block_kind(), kind);
+            assert!(block_id.valid());
+        }
+    }
+
+    /// Make sure that the `BlockKind` is correctly inferred from the `BlockId`.
+    #[test]
+    fn block_kind_from_id() {
+        let mut data = vec![0; 4096];
+        thread_rng().fill(&mut data[..]);
+        let encrypted_block = EncryptedBlock::from_data(data.into());
+
+        let data_id = encrypted_block.id(BlockKind::Data).unwrap();
+        assert_eq!(Block::kind_from_id(data_id), BlockKind::Data);
+
+        let info_id = encrypted_block.id(BlockKind::Info).unwrap();
+        assert_eq!(Block::kind_from_id(info_id), BlockKind::Info);
+    }
+
+    /// Make sure that the block size layout matches the file offset translation.
+    #[test]
+    fn block_size_layout() {
+        let mut block_start_offset = FileOffset::new(0);
+        for (block_index, block_size) in BlockSize::layout().take(340).enumerate() {
+            let (index, offset) = InfoBlock::translate_file_offset(block_start_offset).unwrap();
+            assert_eq!(index, (block_index as u32).into());
+            assert_eq!(offset, 0.into());
+            block_start_offset += block_size.into();
+            if block_index == 333 {
+                assert_eq!(block_start_offset, REPEATING_BLOCKS_START_OFFSET);
+            }
+        }
+    }
+
+    /// Make sure that the Base64 representation parses back into the same `BlockId`.
+    #[test]
+    fn block_id_base64() {
+        let mut id_bytes = [0; 32];
+        thread_rng().fill(&mut id_bytes[..]);
+        let block_id = BlockId::from_data(id_bytes);
+
+        let base64 = block_id.base64();
+        assert_eq!(base64.len(), 43);
+        assert_eq!(BlockId::from_base64(&base64), Some(block_id));
+        assert_eq!(BlockId::from_base64(&base64[..42]), None);
+        assert_eq!(BlockId::from_base64(&format!("{base64}AA")), None);
+        assert_eq!(BlockId::from_base64("not base64!"), None);
+
+        let hex = block_id.to_string();
+        assert_eq!(hex.len(), 64);
+        assert!(hex.chars().all(|c| c.is_ascii_hexdigit()));
+
+        assert_eq!(block_id.short(8), base64[..8]);
+        assert_eq!(block_id.short(0), "");
+        assert_eq!(block_id.short(100), base64);
+    }
+
+    /// Make sure that `BlockId` is only created from data of the exact length.
+    #[test]
+    fn block_id_length() {
+        let mut id_bytes = [0; 33];
+        thread_rng().fill(&mut id_bytes[..]);
+
+        let block_id = BlockId::from_slice(&id_bytes[..32]).unwrap();
+        assert_eq!(block_id.data(), &id_bytes[..32]);
+        assert_eq!(BlockId::from_slice(&id_bytes[..31]), Err(InvalidBlockIdLength(31)));
+        assert_eq!(BlockId::from_slice(&id_bytes), Err(InvalidBlockIdLength(33)));
+        assert_eq!(BlockId::from_slice(&[]), Err(InvalidBlockIdLength(0)));
+
+        // A valid capnp message with a struct that is too short to be a block id
+        let mut message_b = TypedBuilder::<union_id::Owned>::new_default();
+        message_b.init_root().set_shard_id(1234);
+        let block_id_r = message_b
+            .borrow_inner()
+            .get_root_as_reader::<block_id::Reader>()
+            .unwrap();
+        assert_eq!(BlockId::from_reader(block_id_r), Err(InvalidBlockIdLength(16)));
+
+        let mut message_b = TypedBuilder::<block_id::Owned>::new_default();
+        block_id.to_builder(message_b.init_root());
+        let block_id_r = message_b
+            .borrow_inner()
+            .get_root_as_reader::<block_id::Reader>()
+            .unwrap();
+        assert_eq!(BlockId::from_reader(block_id_r), Ok(block_id));
+    }
+
     /// Make sure that `BlockId` is sorted by size.
     #[test]
     fn block_id_sorting() {
@@ -1134,15 +2494,20 @@ mod tests {
     #[test]
     fn file_offset_translation() {
         // A very simple single block case
-        let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset(4000.into());
+        let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset(4000.into()).unwrap();
         assert_eq!(block_id_idx, 0.into());
         assert_eq!(offset_in_block, 4000.into());
 
         // Simple two block case
-        let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset(7000.into());
+        let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset(7000.into()).unwrap();
         assert_eq!(block_id_idx, 1.into());
         assert_eq!(offset_in_block, 2904.into());
 
+        // Offsets past the largest file size, which the operators can produce, have no block
+        assert!(InfoBlock::translate_file_offset(FileOffset(MAX_FILE_SIZE - 1)).is_some());
+        assert_eq!(InfoBlock::translate_file_offset(FileOffset(MAX_FILE_SIZE)), None);
+        assert_eq!(InfoBlock::translate_file_offset(FileOffset(u64::MAX)), None);
+
         // Test every prefix of the size strategy
         let mut total = FileSize::new(0);
         let mut idx = BlockIdIndex::from(0);
@@ -1153,28 +2518,31 @@ mod tests {
                     for _ in 0..(size_marker - 3) {
                         total += size.into();
                         *idx += 1;
-                        let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset(total.as_offset());
+                        let (block_id_idx, offset_in_block) =
+                            InfoBlock::translate_file_offset(total.as_offset()).unwrap();
                         assert_eq!(block_id_idx, idx);
                         assert_eq!(offset_in_block, 0.into());
                         let (block_id_idx, offset_in_block) =
-                            InfoBlock::translate_file_offset((total - 1.into()).as_offset());
+                            InfoBlock::translate_file_offset((total - 1.into()).as_offset()).unwrap();
                         assert_eq!(block_id_idx, idx - 1.into());
                         assert_eq!(offset_in_block, (*size - 1).into());
                         let (block_id_idx, offset_in_block) =
-                            InfoBlock::translate_file_offset((total + 1.into()).as_offset());
+                            InfoBlock::translate_file_offset((total + 1.into()).as_offset()).unwrap();
                         assert_eq!(block_id_idx, idx);
                         assert_eq!(offset_in_block, 1.into());
                     }
                 }
                 total += size.into();
                 *idx += 1;
-                let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset(total.as_offset());
+                let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset(total.as_offset()).unwrap();
                 assert_eq!(block_id_idx, idx);
                 assert_eq!(offset_in_block, 0.into());
-                let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset((total - 1.into()).as_offset());
+                let (block_id_idx, offset_in_block) =
+                    InfoBlock::translate_file_offset((total - 1.into()).as_offset()).unwrap();
                 assert_eq!(block_id_idx, idx - 1.into());
                 assert_eq!(offset_in_block, (*size - 1).into());
-                let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset((total + 1.into()).as_offset());
+                let (block_id_idx, offset_in_block) =
+                    InfoBlock::translate_file_offset((total + 1.into()).as_offset()).unwrap();
                 assert_eq!(block_id_idx, idx);
                 assert_eq!(offset_in_block, 1.into());
             }
@@ -1185,32 +2553,34 @@ mod tests {
         for _ in 0..8138 {
             total += size.into();
             *idx += 1;
-            let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset(total.as_offset());
+            let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset(total.as_offset()).unwrap();
             assert_eq!(block_id_idx, idx);
             assert_eq!(offset_in_block, 0.into());
-            let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset((total - 1.into()).as_offset());
+            let (block_id_idx, offset_in_block) =
+                InfoBlock::translate_file_offset((total - 1.into()).as_offset()).unwrap();
             assert_eq!(block_id_idx, idx - 1.into());
             assert_eq!(offset_in_block, (*size - 1).into());
-            let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset((total + 1.into()).as_offset());
+            let (block_id_idx, offset_in_block) =
+                InfoBlock::translate_file_offset((total + 1.into()).as_offset()).unwrap();
             assert_eq!(block_id_idx, idx);
             assert_eq!(offset_in_block, 1.into());
         }
 
         // 32 TiB with some ~118 MiB of change
         let (block_id_idx, offset_in_block) =
-            InfoBlock::translate_file_offset(FileOffset::from(2u64.pow(45) + 123456789));
+            InfoBlock::translate_file_offset(FileOffset::from(2u64.pow(45) + 123456789)).unwrap();
         assert_eq!(block_id_idx, BlockIdIndex::from(262424));
         assert_eq!(offset_in_block, 123456789.into());
 
-        let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset(FileOffset::from(2u64.pow(50))); // 1 PiB
+        let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset(FileOffset::from(2u64.pow(50))).unwrap(); // 1 PiB
         assert_eq!(block_id_idx, BlockIdIndex::from(8388888));
         assert_eq!(offset_in_block, 0.into());
 
-        let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset(FileOffset::from(2u64.pow(58))); // 256 PiB
+        let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset(FileOffset::from(2u64.pow(58))).unwrap(); // 256 PiB
         assert_eq!(block_id_idx, BlockIdIndex::from(2u32.pow(31) + 280));
         assert_eq!(offset_in_block, 0.into());
 
-        let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset((MAX_FILE_SIZE - 1).into());
+        let (block_id_idx, offset_in_block) = InfoBlock::translate_file_offset((MAX_FILE_SIZE - 1).into()).unwrap();
         assert_eq!(block_id_idx, BlockIdIndex::from(u32::MAX));
         assert_eq!(offset_in_block, (2u32.pow(27) - 1).into());
     }
@@ -1218,6 +2588,249 @@ mod tests {
     #[test]
     #[should_panic = "assertion failed: value < MAX_FILE_SIZE"]
     fn file_offset_translation_too_large_offset() {
-        InfoBlock::translate_file_offset(MAX_FILE_SIZE.into());
+        InfoBlock::translate_file_offset(MAX_FILE_SIZE.into()).unwrap();
+    }
+
+    /// Make sure that the checked offset arithmetic refuses to overflow or leave the valid range.
+    #[test]
+    fn checked_offsets() {
+        let max_file_offset = FileOffset::new(MAX_FILE_SIZE - 1);
+        assert_eq!(max_file_offset.checked_add(FileOffset::new(0)), Some(max_file_offset));
+        assert_eq!(max_file_offset.checked_add(FileOffset::new(1)), None);
+        assert_eq!(FileOffset(u64::MAX).checked_add(FileOffset::new(1)), None);
+        assert_eq!(FileOffset::new(1).checked_sub(FileOffset::new(2)), None);
+        assert_eq!(
+            FileOffset::new(5).checked_sub(FileOffset::new(2)),
+            Some(FileOffset::new(3))
+        );
+
+        let max_block_offset = BlockOffset::new(MAX_BLOCK_SIZE - 1);
+        assert_eq!(
+            max_block_offset.checked_add(BlockOffset::new(0)),
+            Some(max_block_offset)
+        );
+        assert_eq!(max_block_offset.checked_add(BlockOffset::new(1)), None);
+        assert_eq!(BlockOffset(u32::MAX).checked_add(BlockOffset::new(1)), None);
+        assert_eq!(BlockOffset::new(1).checked_sub(BlockOffset::new(2)), None);
+        assert_eq!(
+            BlockOffset::new(5).checked_sub(BlockOffset::new(2)),
+            Some(BlockOffset::new(3))
+        );
+    }
+
+    /// Make sure that an offset difference too large for a block would trip the debug assertion,
+    /// instead of being truncated into a seemingly valid `BlockOffset`.
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "FileOffset doesn't fit into BlockOffset")]
+    fn file_offset_as_block_offset_truncation() {
+        // A broken sequence where a block would start 4 GiB before the offset
+        let offset = FileOffset::new(2u64.pow(32) + 4000);
+        let block_start_offset = FileOffset::new(0);
+        (offset - block_start_offset).as_block_offset();
+    }
+
+    /// Make sure that blocks and their ids can be shared between threads, and info blocks moved between them.
+    #[test]
+    fn thread_safety() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Block>();
+        assert_send_sync::<BlockId>();
+        assert_send_sync::<EncryptedBlock>();
+
+        fn assert_send<T: Send>() {}
+        assert_send::<InfoBlock>();
+    }
+
+    /// Make sure that info blocks of an unknown schema version are refused instead of misread.
+    #[test]
+    fn schema_version() {
+        assert!(InfoBlock::new_directory().info().is_ok());
+        assert!(InfoBlock::new_index().info().is_ok());
+
+        let mut message_b = TypedBuilder::<block::Owned>::new_default();
+        let mut block_b = message_b.init_root();
+        block_b.set_version(SCHEMA_VERSION + 1);
+        block_b.init_nodes(1).get(0).init_directory().init_entries(0);
+        let capnp::OutputSegments::SingleSegment(ss) = message_b.borrow_inner().get_segments_for_output() else {
+            panic!("expected a single segment");
+        };
+        let bumped = block_from_segment(ss[0]);
+        let expected = UnsupportedBlockVersion(u64::from(SCHEMA_VERSION) + 1);
+        assert_eq!(bumped.info().err(), Some(expected));
+
+        let truncated = Block::from_data(Bytes::from_static(&[1, 0, 0]));
+        assert_eq!(truncated.info().err(), Some(UnsupportedBlockVersion(0)));
+    }
+
+    /// Make sure that a file referring to its data by anything but block ids is rejected instead of panicking.
+    #[test]
+    fn file_block_ids_unsupported() {
+        let mut message_b = TypedBuilder::<block::Owned>::new_default();
+        let block_b = message_b.init_root();
+        let nodes_b = block_b.init_nodes(1);
+        let mut file_b = nodes_b.get(0).init_file();
+        file_b.set_size(4096);
+        let mut ids_b = file_b.init_id(2);
+        ids_b.reborrow().get(0).set_local_id(0);
+        ids_b.get(1).set_shard_id(0);
+        let block = block_from_message(&message_b).info().unwrap();
+
+        assert_eq!(block.file_block_ids(0), Err(UnsupportedBlock::FileDataId));
+    }
+
+    /// Make sure that a directory entry referring to its node by shard id is rejected instead of panicking.
+    #[test]
+    fn directory_entries_unsupported() {
+        let mut message_b = TypedBuilder::<block::Owned>::new_default();
+        let block_b = message_b.init_root();
+        let nodes_b = block_b.init_nodes(1);
+        let mut entry_b = nodes_b.get(0).init_directory().init_entries(1).get(0);
+        entry_b.set_name("sharded");
+        entry_b.init_id().set_shard_id(0);
+        let block = block_from_message(&message_b).info().unwrap();
+
+        assert_eq!(
+            block.directory_entries(0, 0, usize::MAX),
+            Err(UnsupportedBlock::ShardedEntry)
+        );
+        assert_eq!(block.local_subtree(0), Err(UnsupportedBlock::ShardedEntry));
+        assert_eq!(block.directory_list(0), Err(UnsupportedBlock::ShardedEntry));
+    }
+
+    /// Make sure that a directory too large for the first segment of a builder still serializes into one segment.
+    #[test]
+    fn large_directory() {
+        let mut builder = DirectoryBuilder::new(InfoBlock::new_directory().info().unwrap());
+        let names: Vec<String> = (0..4000).map(|i| format!("directory-with-a-long-name-{i}")).collect();
+        for name in &names {
+            builder.create_local_node(0, name, NodeKind::Directory).unwrap();
+        }
+        let block = builder.build().unwrap();
+        // Far beyond the default first segment of 8 KiB
+        assert!(block.size() > 64 * 1024);
+
+        let directory = block.info().unwrap();
+        let listed: Vec<&str> = directory
+            .directory_list(0)
+            .unwrap()
+            .into_iter()
+            .map(|(kind, name)| {
+                assert_eq!(kind, NodeKind::Directory);
+                name
+            })
+            .collect();
+        assert_eq!(listed, names);
+    }
+
+    /// Make sure that the `DirectoryBuilder` matches the incremental changes with a single serialization.
+    #[test]
+    fn directory_builder() {
+        let serializations = || SERIALIZATIONS.with(|count| count.get());
+        let time = Some(Timestamp::from_millis(1_700_000_000_000));
+        let base = InfoBlock::new_directory();
+        let mut id_bytes = [0; 32];
+        thread_rng().fill(&mut id_bytes[1..]);
+        let block_id = BlockId::from_data(id_bytes);
+
+        let start = serializations();
+        let mut incremental = base.clone();
+        for i in 0..32 {
+            let kind = if i % 2 == 0 {
+                NodeKind::Directory
+            } else {
+                NodeKind::File
+            };
+            (incremental, _) =
+                incremental
+                    .info()
+                    .unwrap()
+                    .directory_create_local_node(0, &format!("entry-{i}"), kind, time);
+        }
+        let (incremental, nested_idx) =
+            incremental
+                .info()
+                .unwrap()
+                .directory_create_local_node(1, "nested", NodeKind::Directory, time);
+        let incremental = incremental
+            .info()
+            .unwrap()
+            .directory_set_entry_block_id_and_node_index(0, "entry-2", Some(&block_id), 0)
+            .unwrap();
+        assert_eq!(serializations() - start, 34);
+
+        let start = serializations();
+        let mut builder = DirectoryBuilder::new(base.info().unwrap());
+        builder.set_time(time);
+        for i in 0..32 {
+            let kind = if i % 2 == 0 {
+                NodeKind::Directory
+            } else {
+                NodeKind::File
+            };
+            builder.create_local_node(0, &format!("entry-{i}"), kind).unwrap();
+        }
+        assert_eq!(
+            builder.create_local_node(1, "nested", NodeKind::Directory),
+            Ok(nested_idx)
+        );
+        builder.set_entry_block_id_and_node_index(0, "entry-2", Some(&block_id), 0);
+        let built = builder.build().unwrap();
+        assert_eq!(serializations() - start, 1);
+
+        assert_eq!(built.data(), incremental.data());
+
+        // Updating an entry of the base block
+        let incremental = incremental
+            .info()
+            .unwrap()
+            .directory_set_entry_block_id_and_node_index(0, "entry-4", Some(&block_id), 0)
+            .unwrap();
+        let mut builder = DirectoryBuilder::new(built.info().unwrap());
+        builder.set_entry_block_id_and_node_index(0, "entry-4", Some(&block_id), 0);
+        assert_eq!(builder.build().unwrap().data(), incremental.data());
+        assert_eq!(
+            incremental.info().unwrap().directory_list(0),
+            Err(UnsupportedBlock::BlockIdEntry)
+        );
+    }
+
+    /// Make sure that links to local nodes beyond the reach of a local id are refused instead of truncated.
+    #[test]
+    fn link_local_node_out_of_reach() {
+        let mut builder = DirectoryBuilder::new(InfoBlock::new_directory().info().unwrap());
+        let last = u32::from(u16::MAX);
+        assert_eq!(builder.link_local_node(0, "last", last), Ok(()));
+        assert_eq!(
+            builder.link_local_node(0, "beyond", last + 1),
+            Err(TooManyLocalNodes(last + 1))
+        );
+    }
+
+    /// Make sure that new local nodes beyond the reach of a local id are refused instead of truncated.
+    #[test]
+    fn create_local_node_out_of_reach() {
+        let mut builder = DirectoryBuilder::new(InfoBlock::new_directory().info().unwrap());
+        let last = u32::from(u16::MAX);
+        for i in 1..=last {
+            assert_eq!(builder.create_local_node(0, &format!("{i}"), NodeKind::File), Ok(i));
+        }
+        assert_eq!(
+            builder.create_local_node(0, "beyond", NodeKind::File),
+            Err(TooManyLocalNodes(last + 1))
+        );
+        assert_eq!(
+            builder.create_local_symlink(0, "beyond", "/", None),
+            Err(TooManyLocalNodes(last + 1))
+        );
+    }
+
+    /// Make sure that removing a local node that an entry still refers to is an error instead of a panic.
+    #[test]
+    fn remove_referenced_local_node() {
+        let mut builder = DirectoryBuilder::new(InfoBlock::new_directory().info().unwrap());
+        let node_idx = builder.create_local_node(0, "kept", NodeKind::File).unwrap();
+        builder.remove_local_nodes(&[node_idx]);
+        assert!(matches!(builder.build(), Err(VaultError::DanglingLocalNode(idx)) if idx == node_idx));
     }
 }
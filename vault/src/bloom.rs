@@ -0,0 +1,209 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{BlockId, ProviderError};
+
+/// The bytes that every bloom filter file starts with.
+const BLOOM_MAGIC: &[u8] = b"EXOBLOOM";
+/// The version of the bloom filter file format, stored right after [`BLOOM_MAGIC`].
+const BLOOM_VERSION: u8 = 1;
+/// The length of the file header, which is followed by the bits as little endian words.
+///
+/// The header consists of [`BLOOM_MAGIC`], [`BLOOM_VERSION`] and the number of ids as a little endian `u64`.
+const HEADER_LEN: usize = BLOOM_MAGIC.len() + 1 + 8;
+/// The number of bits per id at full capacity, which together with [`HASHES`] makes for about 1% false positives.
+const BITS_PER_ID: u64 = 10;
+/// The number of bits set per id.
+const HASHES: u64 = 7;
+/// The smallest number of ids that a filter has room for.
+const MIN_CAPACITY: u64 = 1024;
+
+/// Probabilistic set of block ids that is persisted to a file.
+///
+/// An id that has been inserted is always reported as possibly contained, while an id that hasn't been
+/// is usually reported as absent. Ids can't be removed, so the filter has to be rebuilt to forget them.
+pub(crate) struct BloomFilter {
+    /// The file that the filter is persisted to.
+    path: PathBuf,
+    /// The bits of the filter.
+    words: Vec<u64>,
+    /// The number of ids inserted into the filter.
+    len: u64,
+}
+
+impl BloomFilter {
+    /// Returns the filter persisted at `path`, or `None` if there is none or it's damaged.
+    pub(crate) fn load(path: PathBuf) -> Result<Option<BloomFilter>, ProviderError> {
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(ProviderError::Io(path, e)),
+        };
+        let valid = data.len() > HEADER_LEN
+            && data[HEADER_LEN..].chunks_exact(8).remainder().is_empty()
+            && data.starts_with(BLOOM_MAGIC)
+            && data[BLOOM_MAGIC.len()] == BLOOM_VERSION;
+        if !valid {
+            return Ok(None);
+        }
+        let len = u64::from_le_bytes(data[BLOOM_MAGIC.len() + 1..HEADER_LEN].try_into().unwrap());
+        let words = data[HEADER_LEN..]
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        Ok(Some(BloomFilter { path, words, len }))
+    }
+
+    /// Returns a new filter with room for at least `capacity` ids, containing `ids` and persisted at `path`.
+    ///
+    /// The filter is written to a temporary file first, so that a crash can't leave a partial filter at `path`.
+    pub(crate) fn build(path: PathBuf, ids: &[BlockId], capacity: u64) -> Result<BloomFilter, ProviderError> {
+        let bits = capacity.max(ids.len() as u64).max(MIN_CAPACITY) * BITS_PER_ID;
+        let mut filter = BloomFilter {
+            path,
+            words: vec![0; bits.div_ceil(64) as usize],
+            len: 0,
+        };
+        for id in ids {
+            filter.set_bits(*id);
+        }
+        let mut data = Vec::with_capacity(HEADER_LEN + 8 * filter.words.len());
+        data.extend_from_slice(BLOOM_MAGIC);
+        data.push(BLOOM_VERSION);
+        data.extend_from_slice(&filter.len.to_le_bytes());
+        for word in &filter.words {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        let temp_path = filter.path.with_extension("tmp");
+        fs::write(&temp_path, data).map_err(|e| ProviderError::Io(temp_path.clone(), e))?;
+        fs::rename(&temp_path, &filter.path).map_err(|e| ProviderError::Io(filter.path.clone(), e))?;
+        Ok(filter)
+    }
+
+    /// Returns `false` if the id was never inserted, `true` if it probably was.
+    pub(crate) fn might_contain(&self, id: BlockId) -> bool {
+        self.bit_indexes(id)
+            .all(|bit| self.words[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Returns the path of the file that the filter is persisted to.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the number of ids inserted into the filter.
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if inserting more ids would raise the rate of false positives above the intended one.
+    pub(crate) fn is_full(&self) -> bool {
+        self.len >= self.words.len() as u64 * 64 / BITS_PER_ID
+    }
+
+    /// Inserts the id and persists only the changed words, so that inserting stays cheap for large filters.
+    pub(crate) fn insert(&mut self, id: BlockId) -> Result<(), ProviderError> {
+        let changed = self.set_bits(id);
+        let io_err = |e| ProviderError::Io(self.path.clone(), e);
+        let mut file = OpenOptions::new().write(true).open(&self.path).map_err(io_err)?;
+        let mut write_at = |offset: usize, bytes: [u8; 8]| {
+            file.seek(SeekFrom::Start(offset as u64))
+                .and_then(|_| file.write_all(&bytes))
+        };
+        for word_index in changed {
+            write_at(HEADER_LEN + 8 * word_index, self.words[word_index].to_le_bytes()).map_err(io_err)?;
+        }
+        write_at(BLOOM_MAGIC.len() + 1, self.len.to_le_bytes()).map_err(io_err)?;
+        Ok(())
+    }
+
+    /// Sets the bits of the id in memory and returns the indexes of the words that changed.
+    fn set_bits(&mut self, id: BlockId) -> Vec<usize> {
+        let mut changed = Vec::new();
+        let bit_indexes: Vec<usize> = self.bit_indexes(id).collect();
+        for bit in bit_indexes {
+            let word = &mut self.words[bit / 64];
+            if *word & (1 << (bit % 64)) == 0 {
+                *word |= 1 << (bit % 64);
+                changed.push(bit / 64);
+            }
+        }
+        self.len += 1;
+        changed
+    }
+
+    /// Returns the indexes of the bits that belong to the id.
+    ///
+    /// Block ids already consist of a hash, so two words of it are combined by double hashing
+    /// instead of hashing the id again.
+    fn bit_indexes(&self, id: BlockId) -> impl Iterator<Item = usize> {
+        let data = id.data();
+        // The first byte is the header, which is shared by many ids
+        let h1 = u64::from_le_bytes(data[1..9].try_into().unwrap());
+        let h2 = u64::from_le_bytes(data[9..17].try_into().unwrap()) | 1;
+        let bits = self.words.len() as u64 * 64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+
+    fn random_id() -> BlockId {
+        let mut data = [0; 32];
+        thread_rng().fill(&mut data[..]);
+        BlockId::from_data(data)
+    }
+
+    /// Make sure that inserted ids are never reported as absent, also after loading the filter again,
+    /// and that false positives stay rare.
+    #[test]
+    fn no_false_negatives() {
+        let path = std::env::temp_dir().join(format!("exomem-bloom-{}", thread_rng().gen::<u64>()));
+        let built: Vec<BlockId> = (0..524).map(|_| random_id()).collect();
+        let mut filter = BloomFilter::build(path.clone(), &built, 0).unwrap();
+        let inserted: Vec<BlockId> = (0..500).map(|_| random_id()).collect();
+        for id in &inserted {
+            filter.insert(*id).unwrap();
+        }
+        assert_eq!(filter.len(), MIN_CAPACITY);
+        assert!(filter.is_full());
+
+        let loaded = BloomFilter::load(path.clone()).unwrap().unwrap();
+        assert_eq!(loaded.len(), MIN_CAPACITY);
+        for id in built.iter().chain(&inserted) {
+            assert!(filter.might_contain(*id));
+            assert!(loaded.might_contain(*id));
+        }
+        let false_positives = (0..10_000).filter(|_| loaded.might_contain(random_id())).count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+
+        fs::write(&path, &BLOOM_MAGIC[..4]).unwrap();
+        assert!(BloomFilter::load(path.clone()).unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+        assert!(BloomFilter::load(path).unwrap().is_none());
+    }
+}
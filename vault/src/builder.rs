@@ -0,0 +1,135 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+#[cfg(feature = "std-fs")]
+use std::path::PathBuf;
+
+use crate::{ChunkStrategy, DurabilityMode, Provider, Vault, VaultError};
+
+/// Collects the options of a [`Vault`] and then opens or initializes it.
+///
+/// Most options are carried out by the [`Provider`], so the builder borrows it mutably
+/// and hands it on to the vault once it's created. Options that aren't set keep the provider's settings.
+pub struct VaultBuilder<'a> {
+    provider: &'a mut Provider,
+    key: u128,
+}
+
+impl<'a> VaultBuilder<'a> {
+    /// Create a new `VaultBuilder` for a vault that stores its blocks with `provider`.
+    pub fn new(provider: &'a mut Provider) -> VaultBuilder<'a> {
+        VaultBuilder { provider, key: 0 }
+    }
+
+    /// Sets the key that the blocks of the vault are encrypted with.
+    ///
+    /// Blocks aren't actually encrypted yet, so any key other than 0 makes opening the vault fail
+    /// with [`VaultError::Unsupported`].
+    pub fn key(mut self, key: u128) -> VaultBuilder<'a> {
+        self.key = key;
+        self
+    }
+
+    /// Sets the maximum number of blocks cached in memory, check out [`Provider::set_cache_capacity`].
+    pub fn cache_capacity(self, capacity: Option<usize>) -> VaultBuilder<'a> {
+        self.provider.set_cache_capacity(capacity);
+        self
+    }
+
+    /// Sets how hard the written files are made to survive a crash, check out [`DurabilityMode`].
+    pub fn durability(self, durability: DurabilityMode) -> VaultBuilder<'a> {
+        self.provider.set_durability(durability);
+        self
+    }
+
+    /// Sets how files are split into data blocks, check out [`Provider::set_chunk_strategy`].
+    pub fn chunk_strategy(self, chunk_strategy: impl ChunkStrategy + 'static) -> VaultBuilder<'a> {
+        self.provider.set_chunk_strategy(chunk_strategy);
+        self
+    }
+
+    /// Opens the vault with the state file at `path`, as done by [`Vault::open`].
+    #[cfg(feature = "std-fs")]
+    pub fn open(self, path: impl Into<PathBuf>) -> Result<Vault<'a>, VaultError> {
+        Vault::open(self.into_provider()?, path)
+    }
+
+    /// Creates a new vault with its state file at `path`, as done by [`Vault::initialize`].
+    #[cfg(feature = "std-fs")]
+    pub fn initialize(self, path: impl Into<PathBuf>) -> Result<Vault<'a>, VaultError> {
+        Vault::initialize(self.into_provider()?, path)
+    }
+
+    /// Creates a new vault without a state file, as done by [`Vault::initialize_in_memory`].
+    pub fn initialize_in_memory(self) -> Result<Vault<'a>, VaultError> {
+        Vault::initialize_in_memory(self.into_provider()?)
+    }
+
+    /// Returns the provider for the vault to borrow, after checking the options that only the builder knows of.
+    fn into_provider(self) -> Result<&'a Provider, VaultError> {
+        if self.key != 0 {
+            return Err(VaultError::Unsupported("encryption keys"));
+        }
+        Ok(self.provider)
+    }
+}
+
+#[cfg(all(test, feature = "std-fs"))]
+mod tests {
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use crate::{BlockSize, FileSize, FixedSize, VaultPath};
+
+    /// Make sure that the options are applied to the provider, and that the cache capacity holds
+    /// without losing access to evicted blocks.
+    #[test]
+    fn cache_capacity() {
+        // The state file, the history and the journal all end up next to the blocks
+        let dir = TempDir::new("builder");
+        let path = dir.join("vault.db");
+        let mut provider = Provider::with_base_dir(dir.join("blocks"));
+
+        let mut vault = VaultBuilder::new(&mut provider)
+            .cache_capacity(Some(4))
+            .durability(DurabilityMode::Fsync)
+            .chunk_strategy(FixedSize(BlockSize::from_marker(0)))
+            .initialize(&path)
+            .unwrap();
+        let mut data = vec![0; 10 * 4096];
+        thread_rng().fill(&mut data[..]);
+        vault
+            .put_reader("data.bin", &data[..], FileSize::new(data.len() as u64))
+            .unwrap();
+        assert_eq!(vault.get(VaultPath::new("/data.bin")).unwrap(), data);
+        drop(vault);
+
+        assert_eq!(provider.cache_capacity(), Some(4));
+        assert!(provider.stats().unwrap().cached_blocks <= 4);
+        let vault = VaultBuilder::new(&mut provider).open(&path).unwrap();
+        assert_eq!(vault.get(VaultPath::new("/data.bin")).unwrap(), data);
+        drop(vault);
+
+        assert!(matches!(
+            VaultBuilder::new(&mut provider).key(7).open(&path),
+            Err(VaultError::Unsupported(_))
+        ));
+    }
+}
@@ -0,0 +1,263 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::iter;
+
+use crate::BlockSize;
+use crate::InvalidChunkSizes;
+
+/// Determines the sizes of the data blocks that files are split into.
+///
+/// By default the sizes depend only on the position of a block in a file, so that file offsets can be translated
+/// into blocks without reading any data. Files split one way must not be modified as if split another way,
+/// so the [`id`](ChunkStrategy::id) of the [`Provider`](crate::Provider)'s strategy is recorded
+/// in the vault state file and checked on load.
+pub trait ChunkStrategy: Send + Sync {
+    /// Returns the number that identifies the strategy, including its parameters.
+    ///
+    /// Every strategy that splits files differently needs a different id,
+    /// as a file split one way can't be modified as if split another way.
+    fn id(&self) -> u16;
+
+    /// Returns the endless sequence of the largest block sizes that a file is split into.
+    ///
+    /// The last block of a file only has to fit the remaining data.
+    fn sizes(&self) -> Box<dyn Iterator<Item = BlockSize> + '_>;
+
+    /// Returns the length of the block that starts with `data`.
+    ///
+    /// The `data` holds as many bytes as the next size in [`sizes`](ChunkStrategy::sizes),
+    /// fewer only at the end of the file. Defaults to the whole `data`.
+    fn block_len(&self, data: &[u8]) -> usize {
+        data.len()
+    }
+
+    /// Returns `true` if [`block_len`](ChunkStrategy::block_len) depends on the contents of the block,
+    /// in which case file offsets can only be translated into blocks by reading the blocks.
+    fn is_content_defined(&self) -> bool {
+        false
+    }
+}
+
+/// The default [`ChunkStrategy`], which follows [`BlockSize::layout`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Layout;
+
+impl ChunkStrategy for Layout {
+    fn id(&self) -> u16 {
+        0
+    }
+
+    fn sizes(&self) -> Box<dyn Iterator<Item = BlockSize> + '_> {
+        Box::new(BlockSize::layout())
+    }
+}
+
+/// [`ChunkStrategy`] that splits every file into blocks of the same size, e.g. for alignment sensitive data.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedSize(pub BlockSize);
+
+impl ChunkStrategy for FixedSize {
+    fn id(&self) -> u16 {
+        1 + u16::from(self.0.marker())
+    }
+
+    fn sizes(&self) -> Box<dyn Iterator<Item = BlockSize> + '_> {
+        Box::new(iter::repeat(self.0))
+    }
+}
+
+/// [`ChunkStrategy`] that picks block boundaries with a rolling hash of the contents.
+///
+/// Inserting or removing bytes only changes the blocks around the change, as the boundaries
+/// after it are found again at the same content. This keeps deduplication high for edited files.
+/// The boundaries are found with the Gear rolling hash, as used by FastCDC.
+#[derive(Clone, Copy, Debug)]
+pub struct ContentDefinedChunker {
+    /// The smallest block length, except for the last block of a file.
+    min: BlockSize,
+    /// The average distance between boundaries past the minimum length.
+    average: BlockSize,
+    /// The largest block length.
+    max: BlockSize,
+}
+
+impl ContentDefinedChunker {
+    /// Create a new `ContentDefinedChunker` with blocks between `min` and `max` bytes long.
+    ///
+    /// Past `min` bytes a boundary is found every `average` bytes on average.
+    /// Fails if `min` or `average` exceed `max`, as blocks are always cut at `max` bytes.
+    pub fn new(min: BlockSize, average: BlockSize, max: BlockSize) -> Result<ContentDefinedChunker, InvalidChunkSizes> {
+        if min > max || average > max {
+            return Err(InvalidChunkSizes { min, average, max });
+        }
+        Ok(ContentDefinedChunker { min, average, max })
+    }
+}
+
+impl Default for ContentDefinedChunker {
+    /// Blocks of 4 KiB - 128 KiB, with boundaries every 16 KiB past the minimum on average.
+    fn default() -> Self {
+        ContentDefinedChunker {
+            min: BlockSize::from_marker(0),
+            average: BlockSize::from_marker(2),
+            max: BlockSize::from_marker(5),
+        }
+    }
+}
+
+impl ChunkStrategy for ContentDefinedChunker {
+    fn id(&self) -> u16 {
+        // 0x1000 followed by the size markers, which keeps clear of the ids of the fixed size strategies
+        let markers = [self.min, self.average, self.max].map(|size| u16::from(size.marker()));
+        0x1000 | markers[0] << 8 | markers[1] << 4 | markers[2]
+    }
+
+    fn sizes(&self) -> Box<dyn Iterator<Item = BlockSize> + '_> {
+        Box::new(iter::repeat(self.max))
+    }
+
+    fn block_len(&self, data: &[u8]) -> usize {
+        // The top bits of the hash depend on the most bytes, so they decide the boundaries
+        let mask = !(u64::MAX >> self.average.trailing_zeros());
+        let mut hash: u64 = 0;
+        for (i, byte) in data.iter().enumerate().skip(*self.min as usize) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        data.len()
+    }
+
+    fn is_content_defined(&self) -> bool {
+        true
+    }
+}
+
+/// The random values that bytes contribute to the Gear rolling hash.
+///
+/// Generated with SplitMix64, so that the boundaries never change between builds.
+const GEAR: [u64; 256] = gear_table();
+
+/// Returns the values of [`GEAR`].
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+    use crate::{BlockKind, EncryptedBlock, File};
+
+    /// Make sure that inserting bytes at the front only changes the first few block ids.
+    #[test]
+    fn content_defined_insert() {
+        let chunker = ContentDefinedChunker::default();
+        let mut data = vec![0; 2 * 1024 * 1024];
+        thread_rng().fill(&mut data[..]);
+        let mut inserted = vec![1, 2, 3, 4, 5];
+        inserted.extend_from_slice(&data);
+
+        let block_ids = |data: &[u8]| -> Vec<_> {
+            File::reader_chunk_stream(data, &chunker, 0)
+                .map(|block| {
+                    let block = block.unwrap();
+                    assert!(block.size() <= *chunker.max as usize);
+                    EncryptedBlock::encrypt(&block, 0).id(BlockKind::Data).unwrap()
+                })
+                .collect()
+        };
+        let original = block_ids(&data);
+        let changed = block_ids(&inserted);
+        // Around 16 KiB + 4 KiB per block on average
+        assert!(original.len() > 50);
+
+        let original: HashSet<_> = original.into_iter().collect();
+        let unchanged = changed.iter().filter(|id| original.contains(id)).count();
+        assert!(
+            unchanged + 3 >= changed.len(),
+            "{unchanged} of {} blocks unchanged",
+            changed.len()
+        );
+    }
+
+    /// Make sure that the blocks are never shorter than the minimum, except at the end.
+    #[test]
+    fn content_defined_bounds() {
+        let chunker = ContentDefinedChunker::new(
+            BlockSize::from_marker(0),
+            BlockSize::from_marker(0),
+            BlockSize::from_marker(1),
+        )
+        .unwrap();
+        let data = vec![0; 100_000];
+        let blocks: Vec<_> = File::reader_chunk_stream(data.as_slice(), &chunker, 0)
+            .map(|block| block.unwrap())
+            .collect();
+        let (last, blocks) = blocks.split_last().unwrap();
+        assert!(blocks.iter().all(|block| (4096..=8192).contains(&block.size())));
+        assert!(last.size() <= 8192);
+        let total: usize = blocks.iter().map(|block| block.size()).sum::<usize>() + last.size();
+        assert_eq!(total, data.len());
+    }
+
+    /// Make sure that sizes that don't fit together are refused, and that every set of sizes gets its own id.
+    #[test]
+    fn content_defined_parameters() {
+        let [small, medium, large] = [0, 2, 5].map(BlockSize::from_marker);
+        assert_eq!(
+            ContentDefinedChunker::new(large, medium, small).unwrap_err(),
+            InvalidChunkSizes {
+                min: large,
+                average: medium,
+                max: small
+            }
+        );
+        assert!(ContentDefinedChunker::new(small, large, medium).is_err());
+
+        let default = ContentDefinedChunker::default();
+        assert_eq!(
+            ContentDefinedChunker::new(small, medium, large).unwrap().id(),
+            default.id()
+        );
+        let ids: HashSet<u16> = [(small, small, large), (small, large, large), (medium, medium, large)]
+            .into_iter()
+            .map(|(min, average, max)| ContentDefinedChunker::new(min, average, max).unwrap().id())
+            .chain([default.id(), Layout.id()])
+            .chain(BlockSize::all().map(|size| FixedSize(size).id()))
+            .collect();
+        assert_eq!(ids.len(), 5 + 16);
+    }
+}
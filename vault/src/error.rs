@@ -0,0 +1,331 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::{BlockId, BlockSize, RelativeVaultPath, VaultPath};
+
+/// Errors returned by [`Vault`](crate::Vault) operations.
+#[derive(Debug, Error)]
+pub enum VaultError {
+    /// The file is larger than the maximum supported [`FileSize`](crate::FileSize).
+    #[error("File is too large: {size} bytes exceeds the maximum of {max} bytes.")]
+    FileTooLarge {
+        /// The size of the file in bytes.
+        size: u64,
+        /// The maximum supported size in bytes.
+        max: u64,
+    },
+    /// The amount of data read doesn't match the declared size.
+    #[error("Size mismatch: expected {expected} bytes, read {actual} bytes.")]
+    SizeMismatch {
+        /// The declared size in bytes.
+        expected: u64,
+        /// The number of bytes read, which stops one byte past `expected` if there was more data.
+        actual: u64,
+    },
+    /// The offset lies past the end of the file.
+    #[error("Offset past end: {offset} is beyond the file size of {size} bytes.")]
+    OffsetPastEnd {
+        /// The offset in bytes.
+        offset: u64,
+        /// The size of the file in bytes.
+        size: u64,
+    },
+    /// A node already exists at the path.
+    #[error("Already exists: {}", .0.to_str().unwrap_or_default())]
+    AlreadyExists(VaultPath),
+    /// No node exists at the path.
+    #[error("Not found: {}", .0.to_str().unwrap_or_default())]
+    NotFound(VaultPath),
+    /// The node at the path is not a directory.
+    #[error("Not a directory: {}", .0.to_str().unwrap_or_default())]
+    NotADirectory(VaultPath),
+    /// The node at the path is not a file.
+    #[error("Not a file: {}", .0.to_str().unwrap_or_default())]
+    NotAFile(VaultPath),
+    /// The node can't be linked to the path, because the path's directory is stored in a different block.
+    #[error("Can't link across blocks: {}", .0.to_str().unwrap_or_default())]
+    LinkAcrossBlocks(VaultPath),
+    /// The directory can't be linked to the path, because the path is inside of the directory itself.
+    #[error("Can't link into itself: {}", .0.to_str().unwrap_or_default())]
+    LinkCycle(VaultPath),
+    /// Too many symlinks were followed while resolving the path, most likely because they form a loop.
+    #[error("Too many levels of symlinks: {}", .0.to_str().unwrap_or_default())]
+    SymlinkLoop(VaultPath),
+    /// The path can't refer to a node, e.g. because it contains `..` or isn't valid Unicode.
+    #[error("Invalid path: {}", .0.to_str().unwrap_or_default())]
+    InvalidPath(VaultPath),
+    /// The operation isn't supported yet.
+    #[error("Not supported yet: {0}")]
+    Unsupported(&'static str),
+    /// The relative path leads past the root directory.
+    #[error("Leads past the root: {}", .0.to_str().unwrap_or_default())]
+    EscapesRoot(RelativeVaultPath),
+    /// A block required by the operation could not be found.
+    #[error("Missing block {}", .0.base64())]
+    MissingBlock(BlockId),
+    /// The vault state file at the path doesn't exist.
+    #[error("No vault state file at {0:?}")]
+    MissingState(PathBuf),
+    /// The vault block that the state file points to could not be loaded.
+    #[error("Failed to load vault block {}: {1}", .0.base64())]
+    UnreadableVaultBlock(BlockId, #[source] ProviderError),
+    /// The root block that the vault block points to could not be loaded.
+    #[error("Failed to load root block {}: {1}", .0.base64())]
+    DanglingRoot(BlockId, #[source] ProviderError),
+    /// The index block that the vault block points to could not be loaded.
+    #[error("Failed to load index block {}: {1}", .0.base64())]
+    DanglingIndex(BlockId, #[source] ProviderError),
+    /// A block is larger than the largest [`BlockSize`](crate::BlockSize).
+    #[error("{0}")]
+    InvalidBlockSize(#[from] InvalidBlockSize),
+    /// A path can't be a [`VaultPath`](crate::VaultPath), e.g. because it's too deep.
+    #[error("{0}")]
+    InvalidVaultPath(#[from] InvalidVaultPath),
+    /// An info block was written with a schema version that isn't supported.
+    #[error("{0}")]
+    UnsupportedBlockVersion(#[from] UnsupportedBlockVersion),
+    /// An info block stores something in a way that isn't supported yet.
+    #[error("{0}")]
+    UnsupportedBlock(#[from] UnsupportedBlock),
+    /// A block would hold more local nodes than entries can refer to.
+    #[error("{0}")]
+    TooManyLocalNodes(#[from] TooManyLocalNodes),
+    /// A directory entry refers to the local node with the index, which the block doesn't keep.
+    #[error("A directory entry refers to local node {0}, which the block doesn't keep.")]
+    DanglingLocalNode(u32),
+    /// The [`Provider`](crate::Provider) failed.
+    #[error("{0}")]
+    Provider(#[from] ProviderError),
+    /// An I/O error occurred.
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+/// Errors returned by [`Provider`](crate::Provider) operations.
+#[derive(Debug, Error)]
+pub enum ProviderError {
+    /// Reading or writing the file at the path failed.
+    #[error("Failed to access file {0:?}: {1}")]
+    Io(PathBuf, #[source] io::Error),
+    /// The file at the path doesn't contain a valid block id.
+    #[error("Invalid block id in file {0:?}: {1}")]
+    InvalidBlockId(PathBuf, #[source] InvalidBlockIdLength),
+    /// The file at the path doesn't start with the magic of a vault state file.
+    #[error("Not a vault state file: {0:?}")]
+    NotAStateFile(PathBuf),
+    /// The state file at the path has a format version that isn't supported.
+    #[error("Unsupported version {1} of the vault state file {0:?}")]
+    UnsupportedStateVersion(PathBuf, u8),
+    /// The block id in the state file at the path was computed by a different [`Hasher`](crate::Hasher).
+    #[error("Vault state file {path:?} was written with hash algorithm {actual}, expected {expected}")]
+    HasherMismatch {
+        /// The path of the state file.
+        path: PathBuf,
+        /// The algorithm of the provider's hasher.
+        expected: u8,
+        /// The algorithm recorded in the state file.
+        actual: u8,
+    },
+    /// The vault state file at the path was written with a different [`ChunkStrategy`](crate::ChunkStrategy).
+    #[error("Vault state file {path:?} was written with chunk strategy {actual}, expected {expected}")]
+    ChunkStrategyMismatch {
+        /// The path of the state file.
+        path: PathBuf,
+        /// The id of the provider's chunk strategy.
+        expected: u16,
+        /// The id recorded in the state file.
+        actual: u16,
+    },
+    /// Saving the block would exceed the disk budget.
+    #[error("Disk budget exceeded: {required} bytes required of {budget} bytes.")]
+    QuotaExceeded {
+        /// The disk budget in bytes.
+        budget: u64,
+        /// The number of bytes required to save the block.
+        required: u64,
+    },
+    /// The block isn't saved anywhere, because the provider keeps blocks only in memory.
+    #[error("Block {} is not saved, only kept in memory", .0.base64())]
+    NotSaved(BlockId),
+    /// No known block id starts with the short id.
+    #[error("No block id starts with {0}")]
+    UnknownShortId(String),
+    /// More than one known block id starts with the short id.
+    #[error("More than one block id starts with {0}")]
+    AmbiguousShortId(String),
+    /// The block with the id is larger than the largest [`BlockSize`](crate::BlockSize).
+    #[error("Invalid block {}: {1}", .0.base64())]
+    InvalidBlockSize(BlockId, #[source] InvalidBlockSize),
+    /// Talking to the remote block store failed.
+    #[error("Failed to talk to the remote block store: {0}")]
+    Remote(#[source] io::Error),
+    /// The remote block store doesn't have the block.
+    #[error("Block {} not found on the remote block store", .0.base64())]
+    RemoteNotFound(BlockId),
+    /// Talking to the peers on the local network failed.
+    #[error("Failed to talk to the local network: {0}")]
+    Lan(#[source] io::Error),
+    /// No peer on the local network sent the block in time.
+    #[error("Block {} not found on the local network", .0.base64())]
+    LanNotFound(BlockId),
+    /// The block store doesn't accept new blocks, so the block with the id couldn't be stored.
+    #[error("Block {} refused by a read-only store", .0.base64())]
+    ReadOnlyStore(BlockId),
+    /// The contents of a block don't match the block id.
+    #[error("Corrupted block: expected block {}, got {}", .expected.base64(), .actual.base64())]
+    BlockMismatch {
+        /// The id of the requested block.
+        expected: BlockId,
+        /// The id that the contents of the block hash to.
+        actual: BlockId,
+    },
+    /// The contents of the block file at the path don't match the block id.
+    #[error("Corrupted block file {path:?}: expected block {}, got {}", .expected.base64(), .actual.base64())]
+    IntegrityMismatch {
+        /// The path of the block file.
+        path: PathBuf,
+        /// The id of the requested block.
+        expected: BlockId,
+        /// The id that the contents of the file hash to.
+        actual: BlockId,
+    },
+}
+
+impl ProviderError {
+    /// Returns `true` if the error only tells that the block isn't there, rather than that looking for it failed.
+    pub(crate) fn is_not_found(&self) -> bool {
+        match self {
+            ProviderError::Io(_, e) => e.kind() == io::ErrorKind::NotFound,
+            ProviderError::NotSaved(_) | ProviderError::RemoteNotFound(_) | ProviderError::LanNotFound(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Problems found with a saved block by [`Provider::verify_all`](crate::Provider::verify_all).
+#[derive(Debug, Error)]
+pub enum IntegrityError {
+    /// The block id itself is invalid, check out [`BlockId::valid`].
+    #[error("Invalid block id")]
+    InvalidId,
+    /// The contents of the block hash to the contained id instead.
+    #[error("Contents hash to {}", .0.base64())]
+    Mismatch(BlockId),
+    /// The block is larger than the largest [`BlockSize`](crate::BlockSize).
+    #[error("{0}")]
+    InvalidSize(#[source] InvalidBlockSize),
+    /// The block file could not be read.
+    #[error("Unreadable: {0}")]
+    Unreadable(#[source] io::Error),
+}
+
+/// Error returned when raw data doesn't have the length of a [`BlockId`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("Expected a block id of 32 bytes, got {0} bytes.")]
+pub struct InvalidBlockIdLength(pub usize);
+
+/// Error returned when a block of the size in bytes is larger than the largest [`BlockSize`](crate::BlockSize),
+/// or doesn't fill the `BlockSize` that it has to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("Invalid block size of {0} bytes.")]
+pub struct InvalidBlockSize(pub usize);
+
+/// Error returned when a path can't be a [`VaultPath`](crate::VaultPath).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum InvalidVaultPath {
+    /// The path doesn't start at the root.
+    #[error("Expected a path starting at the root.")]
+    NotAbsolute,
+    /// The path has more components below the root than allowed.
+    #[error("Expected a path of at most {max} components, got {depth} components.")]
+    TooDeep {
+        /// The number of components below the root.
+        depth: usize,
+        /// The maximum number of components.
+        max: usize,
+    },
+    /// A component of the path is longer than allowed.
+    #[error("Expected names of at most {max} bytes, got {length} bytes.")]
+    NameTooLong {
+        /// The length of the component in bytes.
+        length: usize,
+        /// The maximum length in bytes.
+        max: usize,
+    },
+}
+
+/// Error returned when an info block was written with a schema version that isn't supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("Unsupported info block schema version {0}.")]
+pub struct UnsupportedBlockVersion(pub u64);
+
+/// Error returned when an info block stores something in a way that isn't supported yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum UnsupportedBlock {
+    /// A data block of a file is referred to by something other than its block id.
+    #[error("Expected the data blocks of files to be referred to by block id.")]
+    FileDataId,
+    /// A directory entry refers to its node by shard id.
+    #[error("Expected the nodes of directory entries to be referred to by block id or local id.")]
+    ShardedEntry,
+    /// A directory entry refers to its node by block id where only nodes of the same block can be read.
+    #[error("Expected the nodes of directory entries to be stored in the same block.")]
+    BlockIdEntry,
+}
+
+/// Error returned when a directory entry would refer to a local node whose index doesn't fit into a local id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("Local node {0} is out of reach, a block can hold at most {} local nodes.", u32::from(u16::MAX) + 1)]
+pub struct TooManyLocalNodes(pub u32);
+
+/// Error returned when the block sizes of a [`ContentDefinedChunker`](crate::ContentDefinedChunker)
+/// don't fit together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error(
+    "Expected the minimum and the average block size to be at most the maximum, got {}, {} and {} bytes.",
+    **.min, **.average, **.max
+)]
+pub struct InvalidChunkSizes {
+    /// The smallest block length.
+    pub min: BlockSize,
+    /// The average distance between boundaries past the minimum length.
+    pub average: BlockSize,
+    /// The largest block length.
+    pub max: BlockSize,
+}
+
+/// Error returned when a string isn't the hex representation of a [`BlockId`].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Expected a block id of 64 hex digits, got {0:?}.")]
+pub struct InvalidBlockIdHex(pub String);
+
+/// Error returned when a string isn't the name of a [`NodeKind`](crate::NodeKind).
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Expected a node kind of Vault, Directory, File or Symlink, got {0:?}.")]
+pub struct InvalidNodeKind(pub String);
+
+/// Error returned when a [`TieredStore`](crate::TieredStore) has no tier at the index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("There is no tier at index {0}.")]
+pub struct NoSuchTier(pub usize);
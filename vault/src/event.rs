@@ -0,0 +1,33 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use crate::{BlockId, VaultPath};
+
+/// A change to a [`Vault`](crate::Vault), delivered to subscribers at commit time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VaultEvent {
+    /// A node was created at the path.
+    Created(VaultPath),
+    /// The contents of the file at the path were changed.
+    Modified(VaultPath),
+    /// The node at the path was removed along with everything below it.
+    Removed(VaultPath),
+    /// A new vault state was committed, pointing to the root directory block with this id.
+    Committed(BlockId),
+}
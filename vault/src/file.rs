@@ -17,16 +17,23 @@
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+#[cfg(feature = "std-fs")]
 use std::fs;
-use std::io::{Error, ErrorKind};
+#[cfg(feature = "std-fs")]
+use std::io::ErrorKind;
+use std::io::{Error, Read};
+#[cfg(feature = "std-fs")]
 use std::path::Path;
 
+use crate::{Block, BlockSize, ChunkStrategy};
+
 pub struct File {
     pub name: String,
     pub data: Vec<u8>,
 }
 
 impl File {
+    #[cfg(feature = "std-fs")]
     pub fn from_os(path: &Path) -> Result<File, Error> {
         if !path.is_file() {
             return Err(Error::new(ErrorKind::InvalidInput, "Not a file."));
@@ -45,4 +52,173 @@ impl File {
             data,
         })
     }
+
+    /// Returns an iterator that reads the file at `path` incrementally, one [`Block`] per size in `sizes`.
+    ///
+    /// Only a single block worth of data is held in memory at a time, which makes this suitable
+    /// for files that are too large to be read fully. The last block contains whatever data remains
+    /// and can thus be smaller than its expected size. Iteration stops when the file is exhausted.
+    #[cfg(feature = "std-fs")]
+    pub fn block_stream(
+        path: &Path,
+        sizes: impl Iterator<Item = BlockSize>,
+    ) -> impl Iterator<Item = Result<Block, Error>> {
+        let (reader, open_error) = match fs::File::open(path) {
+            Ok(file) => (Some(file), None),
+            Err(e) => (None, Some(e)),
+        };
+        BlockStream {
+            reader,
+            open_error,
+            sizes,
+        }
+    }
+
+    /// Returns an iterator that reads `reader` incrementally, one [`Block`] per size in `sizes`.
+    ///
+    /// Behaves like [`File::block_stream`], but for data that doesn't come from the OS file system.
+    pub fn reader_block_stream<R: Read>(
+        reader: R,
+        sizes: impl Iterator<Item = BlockSize>,
+    ) -> impl Iterator<Item = Result<Block, Error>> {
+        BlockStream {
+            reader: Some(reader),
+            open_error: None,
+            sizes,
+        }
+    }
+}
+
+impl File {
+    /// Returns an iterator that reads `reader` incrementally, split into blocks by `strategy`.
+    ///
+    /// The blocks are numbered from `first_block_index` when looking up their sizes in the strategy.
+    /// Only the largest block size worth of data is held in memory at a time.
+    pub fn reader_chunk_stream<'s, R: Read + 's>(
+        reader: R,
+        strategy: &'s dyn ChunkStrategy,
+        first_block_index: usize,
+    ) -> impl Iterator<Item = Result<Block, Error>> + 's {
+        ChunkStream {
+            reader: Some(reader),
+            strategy,
+            sizes: Box::new(strategy.sizes().skip(first_block_index)),
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// Iterator over the blocks of a source of data, as split by a [`ChunkStrategy`].
+struct ChunkStream<'s, R: Read> {
+    /// The data source, or `None` once it has been exhausted.
+    reader: Option<R>,
+    /// Decides where the blocks end.
+    strategy: &'s dyn ChunkStrategy,
+    /// The largest sizes of the upcoming blocks.
+    sizes: Box<dyn Iterator<Item = BlockSize> + 's>,
+    /// The data that has been read, but isn't part of a block yet.
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> Iterator for ChunkStream<'_, R> {
+    type Item = Result<Block, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let size = *self.sizes.next()? as usize;
+        if let Some(reader) = self.reader.as_mut() {
+            let missing = size.saturating_sub(self.buffer.len());
+            // Growing the buffer step by step while reading would copy large blocks several times over
+            self.buffer.reserve(missing);
+            match reader.take(missing as u64).read_to_end(&mut self.buffer) {
+                // Reached the end of the data
+                Ok(read) if read < missing => self.reader = None,
+                Ok(_) => (),
+                Err(e) => {
+                    self.reader = None;
+                    self.buffer.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let len = self.strategy.block_len(&self.buffer).clamp(1, self.buffer.len());
+        let rest = self.buffer.split_off(len);
+        let data = std::mem::replace(&mut self.buffer, rest);
+        Some(Ok(Block::from_data(data.into())))
+    }
+}
+
+/// Iterator over the blocks of a file or any other source of data.
+struct BlockStream<R: Read, I: Iterator<Item = BlockSize>> {
+    /// The data source, or `None` once the stream has ended.
+    reader: Option<R>,
+    /// The error from opening the file, which is returned as the first item.
+    open_error: Option<Error>,
+    /// The expected sizes of the upcoming blocks.
+    sizes: I,
+}
+
+impl<R: Read, I: Iterator<Item = BlockSize>> Iterator for BlockStream<R, I> {
+    type Item = Result<Block, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.open_error.take() {
+            return Some(Err(e));
+        }
+        let reader = self.reader.as_mut()?;
+        let Some(size) = self.sizes.next() else {
+            self.reader = None;
+            return None;
+        };
+        let mut data = Vec::with_capacity(*size as usize);
+        match reader.take(*size as u64).read_to_end(&mut data) {
+            Ok(0) => {
+                self.reader = None;
+                None
+            }
+            Ok(read) => {
+                if read < *size as usize {
+                    // Reached the end of the data
+                    self.reader = None;
+                }
+                Some(Ok(Block::from_data(data.into())))
+            }
+            Err(e) => {
+                self.reader = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std-fs"))]
+mod tests {
+    use std::iter;
+
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+
+    /// Make sure that a file spanning multiple blocks is streamed back in full.
+    #[test]
+    fn block_stream() {
+        let mut data = vec![0; 3 * 4096 + 100];
+        thread_rng().fill(&mut data[..]);
+        let path = std::env::temp_dir().join(format!("exomem-block-stream-{}.bin", thread_rng().gen::<u64>()));
+        fs::write(&path, &data).unwrap();
+
+        let blocks = File::block_stream(&path, iter::repeat(BlockSize::from_marker(0)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks[0].size(), 4096);
+        assert_eq!(blocks[2].size(), 4096);
+        assert_eq!(blocks[3].size(), 100);
+        let streamed: Vec<u8> = blocks.iter().flat_map(|block| block.data()).collect();
+        assert_eq!(streamed, data);
+    }
 }
@@ -0,0 +1,56 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::sync::Arc;
+
+/// Hash function used to derive [`BlockId`](crate::BlockId)s from block contents.
+///
+/// Ids computed by different hash functions must never be mixed, so the [`algorithm`](Hasher::algorithm)
+/// of the [`Provider`](crate::Provider)'s hasher is recorded in the vault state file and checked on load.
+pub trait Hasher: Send + Sync {
+    /// Returns the number that identifies the hash algorithm.
+    fn algorithm(&self) -> u8;
+
+    /// Returns the 32 byte hash of `data`.
+    fn hash(&self, data: &[u8]) -> [u8; 32];
+}
+
+impl<H: Hasher + ?Sized> Hasher for Arc<H> {
+    fn algorithm(&self) -> u8 {
+        (**self).algorithm()
+    }
+
+    fn hash(&self, data: &[u8]) -> [u8; 32] {
+        (**self).hash(data)
+    }
+}
+
+/// The default [`Hasher`], which uses BLAKE3.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Blake3;
+
+impl Hasher for Blake3 {
+    fn algorithm(&self) -> u8 {
+        0
+    }
+
+    fn hash(&self, data: &[u8]) -> [u8; 32] {
+        blake3::hash(data).into()
+    }
+}
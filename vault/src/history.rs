@@ -0,0 +1,122 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{BlockId, ProviderError};
+
+/// The number of vault blocks that the history keeps, older ones are forgotten.
+pub(crate) const MAX_HISTORY_LEN: usize = 1024;
+
+/// The length of a line of the history file, a base64 block id followed by a newline.
+const LINE_LEN: u64 = 44;
+
+/// List of the vault blocks that the state file used to point to, oldest first.
+///
+/// The history lives next to the vault state file. Every commit appends the vault block it replaced.
+/// Once the file holds twice [`MAX_HISTORY_LEN`] entries, it's rewritten with only the newest ones,
+/// which keeps it bounded without rewriting it on every commit.
+pub(crate) struct History {
+    path: PathBuf,
+}
+
+impl History {
+    /// Create a new `History` for the vault with the state file at `state_path`.
+    pub(crate) fn for_state(state_path: &Path) -> History {
+        History {
+            path: state_path.with_extension("history"),
+        }
+    }
+
+    /// Records that the state file no longer points to the vault block with the specified `id`.
+    pub(crate) fn record(&self, id: BlockId) -> Result<(), ProviderError> {
+        let len = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                file.write_all(format!("{}\n", id.base64()).as_bytes())?;
+                Ok(file.metadata()?.len())
+            })
+            .map_err(|e| ProviderError::Io(self.path.clone(), e))?;
+        if len > 2 * MAX_HISTORY_LEN as u64 * LINE_LEN {
+            self.rewrite(&self.read()?)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the file with the vault block `ids`, going through a temporary file so that a crash
+    /// leaves either the old or the new list behind.
+    fn rewrite(&self, ids: &[BlockId]) -> Result<(), ProviderError> {
+        let contents: String = ids.iter().map(|id| format!("{}\n", id.base64())).collect();
+        let temp_path = self.path.with_extension("history.tmp");
+        fs::write(&temp_path, contents)
+            .and_then(|()| fs::rename(&temp_path, &self.path))
+            .map_err(|e| ProviderError::Io(self.path.clone(), e))
+    }
+
+    /// Returns the newest [`MAX_HISTORY_LEN`] recorded vault block ids, oldest first,
+    /// or none if there is no history yet.
+    ///
+    /// A partially written last line is ignored.
+    pub(crate) fn read(&self) -> Result<Vec<BlockId>, ProviderError> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(ProviderError::Io(self.path.clone(), e)),
+        };
+        let mut ids: Vec<BlockId> = contents
+            .split_inclusive('\n')
+            .filter(|line| line.ends_with('\n'))
+            .filter_map(|line| BlockId::from_base64(line.trim_end()))
+            .collect();
+        ids.drain(..ids.len().saturating_sub(MAX_HISTORY_LEN));
+        Ok(ids)
+    }
+}
+
+#[cfg(all(test, feature = "std-fs"))]
+mod tests {
+    use std::fs;
+
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    /// Make sure that the history file only ever keeps a bounded number of the newest entries.
+    #[test]
+    fn bounded() {
+        let dir = TempDir::new("history");
+        fs::create_dir_all(&dir).unwrap();
+        let history = History::for_state(&dir.join("vault.db"));
+        assert!(history.read().unwrap().is_empty());
+
+        let ids: Vec<BlockId> = (0..2 * MAX_HISTORY_LEN + 1)
+            .map(|_| BlockId::from_data(thread_rng().gen()))
+            .collect();
+        for id in &ids {
+            history.record(*id).unwrap();
+            assert!(fs::metadata(&history.path).unwrap().len() <= 2 * MAX_HISTORY_LEN as u64 * LINE_LEN);
+        }
+        assert_eq!(history.read().unwrap(), &ids[ids.len() - MAX_HISTORY_LEN..]);
+    }
+}
@@ -0,0 +1,112 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{BlockId, ProviderError};
+
+/// Append-only log of the block writes and the final state update of an in-progress vault mutation.
+///
+/// The journal lives next to the vault state file and is removed once the state file has been updated.
+/// A journal that is still around on open means that the previous mutation was interrupted.
+pub(crate) struct Journal {
+    path: PathBuf,
+}
+
+/// The contents of a journal left behind by an interrupted mutation.
+pub(crate) struct JournalEntries {
+    /// The blocks that were about to be written, in order.
+    pub(crate) writes: Vec<BlockId>,
+    /// The vault block that the state file was about to point to, if the mutation got that far.
+    pub(crate) commit: Option<BlockId>,
+}
+
+impl Journal {
+    /// Create a new `Journal` for the vault with the state file at `state_path`.
+    pub(crate) fn for_state(state_path: &Path) -> Journal {
+        Journal {
+            path: state_path.with_extension("journal"),
+        }
+    }
+
+    /// Records that the block with the specified `id` is about to be written.
+    pub(crate) fn record_write(&self, id: BlockId) -> Result<(), ProviderError> {
+        self.append(&format!("write {}\n", id.base64()))
+    }
+
+    /// Records that the blocks with the specified `ids` are about to be written, syncing only once for all of them.
+    pub(crate) fn record_writes(&self, ids: &[BlockId]) -> Result<(), ProviderError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let lines: String = ids.iter().map(|id| format!("write {}\n", id.base64())).collect();
+        self.append(&lines)
+    }
+
+    /// Records that the state file is about to point to the vault block with the specified `id`.
+    pub(crate) fn record_commit(&self, id: BlockId) -> Result<(), ProviderError> {
+        self.append(&format!("commit {}\n", id.base64()))
+    }
+
+    /// Removes the journal after the mutation has been completed or recovered.
+    pub(crate) fn finish(&self) -> Result<(), ProviderError> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(ProviderError::Io(self.path.clone(), e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the entries of a journal left behind by an interrupted mutation, or `None` if there is none.
+    ///
+    /// A partially written last line is ignored, as it can't have been followed by the write it announced.
+    pub(crate) fn read(&self) -> Result<Option<JournalEntries>, ProviderError> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(ProviderError::Io(self.path.clone(), e)),
+        };
+        let mut entries = JournalEntries {
+            writes: Vec::new(),
+            commit: None,
+        };
+        for line in contents.split_inclusive('\n').filter(|line| line.ends_with('\n')) {
+            match line.trim_end().split_once(' ') {
+                Some(("write", id)) => entries.writes.extend(BlockId::from_base64(id)),
+                Some(("commit", id)) => entries.commit = BlockId::from_base64(id),
+                _ => (),
+            }
+        }
+        Ok(Some(entries))
+    }
+
+    /// Appends the `line` and makes sure it has reached the disk before returning.
+    fn append(&self, line: &str) -> Result<(), ProviderError> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                file.write_all(line.as_bytes())?;
+                file.sync_data()
+            })
+            .map_err(|e| ProviderError::Io(self.path.clone(), e))
+    }
+}
@@ -0,0 +1,414 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::remote;
+use crate::{BlockId, BlockStore, EncryptedBlock, Provider, ProviderError};
+
+/// The multicast group that peers listen to for block queries by default.
+pub const DEFAULT_LAN_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 99), 47999);
+
+/// The bytes that every query datagram starts with, followed by the id of the wanted block, a nonce and a tag.
+const QUERY_MAGIC: &[u8; 4] = b"EXOQ";
+/// The bytes that every reply datagram starts with, followed by the block id and the nonce of the query,
+/// the IPv4 address and little endian TCP port that the block is served on, and a tag.
+const HAVE_MAGIC: &[u8; 4] = b"EXOH";
+/// The bytes that the answer to the challenge of a serving peer is computed from, followed by the challenge.
+const AUTH_MAGIC: &[u8; 4] = b"EXOA";
+
+/// The length of a query datagram.
+const QUERY_LEN: usize = 4 + 32 + NONCE_LEN + 32;
+/// The length of a reply datagram.
+const REPLY_LEN: usize = 4 + 32 + NONCE_LEN + 4 + 2 + 32;
+/// The length of the nonces that queries and challenges carry.
+const NONCE_LEN: usize = 16;
+
+/// How often the background threads of a [`LanPeer`] check whether they should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a [`LanPeer`] waits for a connected [`LanBlockSource`] before dropping the connection.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Source of encrypted blocks that asks the peers on the local network for them.
+///
+/// A query for the wanted block is sent to a UDP multicast group. Every [`LanPeer`] that has the block
+/// replies with the address it serves blocks on, which the block is then fetched from over TCP
+/// with the protocol of [`RemoteBlockStore`](crate::RemoteBlockStore). Fetched blocks aren't verified here, as
+/// [`Provider::get_block`] verifies them before caching.
+///
+/// Peers only talk to each other if they share the same secret. Queries and replies are tagged with it,
+/// and a serving peer challenges every connection to prove that it knows the secret before serving any block.
+pub struct LanBlockSource {
+    socket: Mutex<UdpSocket>,
+    group: SocketAddrV4,
+    secret: [u8; 32],
+    timeout: Duration,
+}
+
+impl LanBlockSource {
+    /// Create a new `LanBlockSource` that sends queries to the multicast `group` over the `interface`,
+    /// only trusting the peers that share the `secret`.
+    pub fn new(group: SocketAddrV4, interface: Ipv4Addr, secret: [u8; 32]) -> Result<LanBlockSource, ProviderError> {
+        // Binding to the address of the interface also makes the queries leave through it
+        let socket = UdpSocket::bind((interface, 0)).map_err(ProviderError::Lan)?;
+        socket.set_multicast_loop_v4(true).map_err(ProviderError::Lan)?;
+        Ok(LanBlockSource {
+            socket: Mutex::new(socket),
+            group,
+            secret,
+            timeout: Duration::from_millis(500),
+        })
+    }
+
+    /// Sets how long to wait for peers to reply to a query, and for a peer to accept and answer
+    /// the connection that the block is fetched over, which is half a second by default.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Fetches the block with the specified `id` from the peer serving blocks at `addr`.
+    fn fetch(&self, addr: SocketAddrV4, id: BlockId) -> io::Result<EncryptedBlock> {
+        let mut stream = TcpStream::connect_timeout(&addr.into(), self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut challenge = [0; NONCE_LEN];
+        stream.read_exact(&mut challenge)?;
+        stream.write_all(tag(&self.secret, &[AUTH_MAGIC, &challenge[..]]).as_bytes())?;
+        match remote::exchange(&mut stream, &remote::request(remote::OP_GET, id), true)? {
+            (_, Some(data)) => Ok(EncryptedBlock::from_data(data.into())),
+            (status, None) => Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("Peer answered with status {status}"),
+            )),
+        }
+    }
+}
+
+impl BlockStore for LanBlockSource {
+    /// Fetches the block from the first peer that replies and manages to send it,
+    /// failing with [`ProviderError::LanNotFound`] if none does before the timeout.
+    fn load_encrypted_block(&self, id: BlockId) -> Result<EncryptedBlock, ProviderError> {
+        let socket = self.socket.lock().unwrap();
+        let nonce = nonce();
+        let mut query = QUERY_MAGIC.to_vec();
+        query.extend_from_slice(id.data());
+        query.extend_from_slice(&nonce);
+        query.extend_from_slice(tag(&self.secret, &[&query]).as_bytes());
+        socket.send_to(&query, self.group).map_err(ProviderError::Lan)?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut reply = [0; REPLY_LEN];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(ProviderError::LanNotFound(id));
+            }
+            socket.set_read_timeout(Some(remaining)).map_err(ProviderError::Lan)?;
+            let len = match socket.recv_from(&mut reply) {
+                Ok((len, _)) => len,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+                Err(e) => return Err(ProviderError::Lan(e)),
+            };
+            // Ignore stray datagrams, late replies to earlier queries and peers that don't share the secret
+            let (tagged, reply_tag) = reply.split_at(REPLY_LEN - 32);
+            if len != REPLY_LEN
+                || &tagged[..4] != HAVE_MAGIC
+                || tagged[4..36] != id.data()[..]
+                || tagged[36..36 + NONCE_LEN] != nonce
+                || tag(&self.secret, &[tagged]) != blake3::Hash::from_bytes(reply_tag.try_into().unwrap())
+            {
+                continue;
+            }
+            let addr = &tagged[36 + NONCE_LEN..];
+            let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            let port = u16::from_le_bytes([addr[4], addr[5]]);
+            if let Ok(encrypted_block) = self.fetch(SocketAddrV4::new(ip, port), id) {
+                return Ok(encrypted_block);
+            }
+        }
+    }
+}
+
+/// Serves the blocks of a [`Provider`] to the [`LanBlockSource`]s on the local network that share its secret.
+///
+/// The peer answers queries for the blocks that the provider has cached in memory or saved on disk.
+/// It keeps serving in the background until it's shut down or dropped.
+pub struct LanPeer {
+    /// The multicast group that the peer listens to, with the actual port if it was chosen by the system.
+    group: SocketAddrV4,
+    /// The address that the peer serves blocks on.
+    serve_addr: SocketAddrV4,
+    stop: Arc<AtomicBool>,
+    /// The threads answering queries and accepting connections, which are gone once the peer has stopped.
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl LanPeer {
+    /// Create a new `LanPeer` that listens to the multicast `group` on the `interface`,
+    /// only serving the peers that share the `secret`.
+    ///
+    /// A port of 0 in `group` lets the system choose one, check out [`LanPeer::group`] for the actual port.
+    pub fn serve(
+        provider: Arc<Provider>,
+        group: SocketAddrV4,
+        interface: Ipv4Addr,
+        secret: [u8; 32],
+    ) -> Result<LanPeer, ProviderError> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, group.port())).map_err(ProviderError::Lan)?;
+        socket
+            .join_multicast_v4(group.ip(), &interface)
+            .map_err(ProviderError::Lan)?;
+        socket
+            .set_read_timeout(Some(POLL_INTERVAL))
+            .map_err(ProviderError::Lan)?;
+        let group = SocketAddrV4::new(*group.ip(), socket.local_addr().map_err(ProviderError::Lan)?.port());
+
+        let listener = TcpListener::bind((interface, 0)).map_err(ProviderError::Lan)?;
+        let serve_addr = SocketAddrV4::new(interface, listener.local_addr().map_err(ProviderError::Lan)?.port());
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let udp_stop = stop.clone();
+        let udp_provider = provider.clone();
+        let udp_thread = thread::spawn(move || answer_queries(socket, &udp_provider, serve_addr, &secret, &udp_stop));
+        let tcp_stop = stop.clone();
+        let tcp_thread = thread::spawn(move || {
+            let mut connections: Vec<(TcpStream, JoinHandle<()>)> = Vec::new();
+            for stream in listener.incoming() {
+                if tcp_stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let Ok(shutdown_handle) = stream.try_clone() else {
+                    continue;
+                };
+                connections.retain(|(_, thread)| !thread.is_finished());
+                let provider = provider.clone();
+                let thread = thread::spawn(move || {
+                    // The client gets nothing but a closed connection if anything goes wrong
+                    let _ = serve_connection(stream, &provider, &secret);
+                });
+                connections.push((shutdown_handle, thread));
+            }
+            for (stream, thread) in connections {
+                let _ = stream.shutdown(Shutdown::Both);
+                let _ = thread.join();
+            }
+        });
+
+        Ok(LanPeer {
+            group,
+            serve_addr,
+            stop,
+            threads: vec![udp_thread, tcp_thread],
+        })
+    }
+
+    /// Returns the multicast group that the peer listens to.
+    pub fn group(&self) -> SocketAddrV4 {
+        self.group
+    }
+
+    /// Stops serving, closes the open connections and waits for all the background threads to finish.
+    ///
+    /// Dropping the peer does the same.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        if self.threads.is_empty() {
+            return;
+        }
+        self.stop.store(true, Ordering::Relaxed);
+        // Wake up the listener, which is blocked waiting for the next connection
+        let _ = TcpStream::connect(self.serve_addr);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for LanPeer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Replies to the queries arriving at `socket` for the blocks that `provider` has, until `stop` is set.
+///
+/// Only queries tagged with the `secret` are answered, with replies that say the blocks are served at `serve_addr`.
+fn answer_queries(
+    socket: UdpSocket,
+    provider: &Provider,
+    serve_addr: SocketAddrV4,
+    secret: &[u8; 32],
+    stop: &AtomicBool,
+) {
+    let mut query = [0; QUERY_LEN];
+    while !stop.load(Ordering::Relaxed) {
+        let Ok((len, querier)) = socket.recv_from(&mut query) else {
+            continue;
+        };
+        let (tagged, query_tag) = query.split_at(QUERY_LEN - 32);
+        if len != QUERY_LEN
+            || &tagged[..4] != QUERY_MAGIC
+            || tag(secret, &[tagged]) != blake3::Hash::from_bytes(query_tag.try_into().unwrap())
+        {
+            continue;
+        }
+        let id = BlockId::from_slice(&tagged[4..36]).unwrap();
+        if !provider.contains_block(id) {
+            continue;
+        }
+        let mut reply = HAVE_MAGIC.to_vec();
+        reply.extend_from_slice(&tagged[4..]);
+        reply.extend_from_slice(&serve_addr.ip().octets());
+        reply.extend_from_slice(&serve_addr.port().to_le_bytes());
+        reply.extend_from_slice(tag(secret, &[&reply]).as_bytes());
+        // The querier will simply time out if the reply gets lost
+        let _ = socket.send_to(&reply, querier);
+    }
+}
+
+/// Challenges the client connected over `stream` to prove that it knows the `secret`,
+/// then serves it the blocks of `provider` until it disconnects or stays idle for too long.
+fn serve_connection(mut stream: TcpStream, provider: &Provider, secret: &[u8; 32]) -> io::Result<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+    let challenge = nonce();
+    stream.write_all(&challenge)?;
+    let mut answer = [0; 32];
+    stream.read_exact(&mut answer)?;
+    // Comparing hashes takes constant time
+    if blake3::Hash::from_bytes(answer) != tag(secret, &[AUTH_MAGIC, &challenge]) {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "Wrong answer to the challenge",
+        ));
+    }
+    remote::serve_read_only(stream, provider)
+}
+
+/// Returns the tag of the concatenated `parts`, which only those who know the `secret` can compute.
+fn tag(secret: &[u8; 32], parts: &[&[u8]]) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new_keyed(secret);
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
+
+/// Returns a nonce that is unique within the process and very unlikely to repeat across processes.
+fn nonce() -> [u8; NONCE_LEN] {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    hasher.update(&std::process::id().to_le_bytes());
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    hasher.update(&time.as_nanos().to_le_bytes());
+    // Randomly seeded by the standard library
+    hasher.update(&RandomState::new().build_hasher().finish().to_le_bytes());
+    let mut nonce = [0; NONCE_LEN];
+    nonce.copy_from_slice(&hasher.finalize().as_bytes()[..NONCE_LEN]);
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+    use crate::{Block, BlockKind};
+
+    const SECRET: [u8; 32] = [7; 32];
+
+    /// Returns a peer on the loopback interface serving a random block, along with the block and its id.
+    fn serving_peer() -> (LanPeer, BlockId, Block) {
+        let mut data = vec![0; 4096];
+        thread_rng().fill(&mut data[..]);
+        let block = Block::from_data(data.into());
+        let encrypted_block = EncryptedBlock::encrypt(&block, 0);
+        let id = encrypted_block.id(BlockKind::Data).unwrap();
+
+        let serving = Arc::new(Provider::in_memory());
+        serving.add_block(id, encrypted_block, block.clone()).unwrap();
+        let group = SocketAddrV4::new(*DEFAULT_LAN_GROUP.ip(), 0);
+        let peer = LanPeer::serve(serving, group, Ipv4Addr::LOCALHOST, SECRET).unwrap();
+        (peer, id, block)
+    }
+
+    /// Make sure that a provider fetches a block it lacks from a peer on the loopback interface.
+    #[test]
+    fn fetch_from_peer() {
+        let (peer, id, block) = serving_peer();
+
+        let mut lacking = Provider::in_memory();
+        assert!(lacking.get_block(id).unwrap().is_none());
+        lacking.set_lan(LanBlockSource::new(peer.group(), Ipv4Addr::LOCALHOST, SECRET).unwrap());
+        assert_eq!(lacking.get_block(id).unwrap().unwrap().data(), block.data());
+
+        // Nobody has this one
+        let mut source = LanBlockSource::new(peer.group(), Ipv4Addr::LOCALHOST, SECRET).unwrap();
+        source.set_timeout(Duration::from_millis(100));
+        let missing_id = BlockId::from_data([0; 32]);
+        assert!(matches!(
+            source.load_encrypted_block(missing_id),
+            Err(ProviderError::LanNotFound(missing)) if missing == missing_id
+        ));
+    }
+
+    /// Make sure that peers that don't know the secret are neither answered nor served.
+    #[test]
+    fn wrong_secret() {
+        let (peer, id, _) = serving_peer();
+
+        let mut source = LanBlockSource::new(peer.group(), Ipv4Addr::LOCALHOST, [8; 32]).unwrap();
+        source.set_timeout(Duration::from_millis(100));
+        assert!(matches!(
+            source.load_encrypted_block(id),
+            Err(ProviderError::LanNotFound(missing)) if missing == id
+        ));
+        assert!(source.fetch(peer.serve_addr, id).is_err());
+    }
+
+    /// Make sure that shutting a peer down closes its connections and stops it from accepting new ones.
+    #[test]
+    fn shutdown() {
+        let (peer, _, _) = serving_peer();
+        let serve_addr = peer.serve_addr;
+        let mut stream = TcpStream::connect(serve_addr).unwrap();
+        let mut challenge = [0; NONCE_LEN];
+        stream.read_exact(&mut challenge).unwrap();
+
+        peer.shutdown();
+        assert_eq!(stream.read(&mut challenge).unwrap(), 0);
+        assert!(TcpStream::connect(serve_addr).is_err());
+    }
+}
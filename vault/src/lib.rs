@@ -18,22 +18,72 @@
 */
 
 mod block;
+mod bloom;
+mod builder;
+mod chunk;
+mod error;
+mod event;
 mod file;
+mod hasher;
+// Vaults only read their history and journal when opened from a state file
+#[cfg_attr(not(feature = "std-fs"), allow(dead_code))]
+mod history;
+#[cfg_attr(not(feature = "std-fs"), allow(dead_code))]
+mod journal;
+mod lan;
 mod node;
 mod path;
 mod provider;
+mod remote;
 mod shard;
+mod store;
+#[cfg(all(test, feature = "std-fs"))]
+mod temp_dir;
 mod vault;
 
 #[allow(dead_code)]
 mod vault_capnp;
 
 pub use block::*;
+pub use builder::*;
+pub use chunk::*;
+pub use error::*;
+pub use event::*;
 pub use file::*;
+pub use hasher::*;
+pub use lan::*;
 pub use node::*;
 pub use path::*;
 pub use provider::*;
+pub use remote::*;
 pub use shard::*;
+pub use store::*;
 pub use vault::*;
 
 pub use vault_capnp::NodeKind;
+
+/// Uses only the parts of the crate that build without the `std-fs` feature, such as for WASM.
+///
+/// CI also runs these with `--no-default-features`, so that they show the crate builds without it.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that a vault can be written and read back without the OS file system.
+    #[test]
+    fn in_memory() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault.create_directory(VaultPath::new("/notes")).unwrap();
+        vault
+            .put_reader("data.bin", &[7; 5000][..], FileSize::new(5000))
+            .unwrap();
+        vault.touch(VaultPath::new("/notes/empty.txt")).unwrap();
+
+        assert_eq!(vault.get(VaultPath::new("/data.bin")).unwrap(), [7; 5000]);
+        assert_eq!(
+            vault.stat(VaultPath::new("/notes/empty.txt")).unwrap().kind,
+            NodeKind::File
+        );
+    }
+}
@@ -0,0 +1,123 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{FileSize, InvalidNodeKind, NodeKind};
+
+/// Displays the name of the `NodeKind`, which [`NodeKind::from_str`] parses back.
+///
+/// The width and alignment of the formatter are respected, so that kinds can be lined up in columns.
+impl fmt::Display for NodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            NodeKind::Vault => "Vault",
+            NodeKind::Directory => "Directory",
+            NodeKind::File => "File",
+            NodeKind::Symlink => "Symlink",
+        })
+    }
+}
+
+impl FromStr for NodeKind {
+    type Err = InvalidNodeKind;
+
+    /// Parses the names written by the [`Display`](fmt::Display) implementation, in any case.
+    fn from_str(s: &str) -> Result<NodeKind, InvalidNodeKind> {
+        [NodeKind::Vault, NodeKind::Directory, NodeKind::File, NodeKind::Symlink]
+            .into_iter()
+            .find(|kind| kind.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| InvalidNodeKind(String::from(s)))
+    }
+}
+
+/// A point in time as the number of milliseconds since the Unix epoch, which nodes are stamped with.
+///
+/// Counting from a fixed epoch instead of the start of the process keeps timestamps comparable across
+/// sessions and devices, and being unsigned they can't go negative. Times before the epoch are clamped to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    /// Create a new `Timestamp` that is `millis` milliseconds after the Unix epoch.
+    pub const fn from_millis(millis: u64) -> Timestamp {
+        Timestamp(millis)
+    }
+
+    /// Returns the number of milliseconds since the Unix epoch.
+    pub const fn as_millis(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns the current time of the system clock.
+    ///
+    /// Panics on platforms without a system clock, like `wasm32-unknown-unknown`.
+    pub fn now() -> Timestamp {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Timestamp(u64::try_from(since_epoch.as_millis()).unwrap_or(u64::MAX))
+    }
+}
+
+/// Metadata of a single node in the vault, as returned by [`Vault::stat`](crate::Vault::stat).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NodeStat {
+    /// The kind of the node.
+    pub kind: NodeKind,
+    /// The size of the file contents, `None` if the node is not a file.
+    pub size: Option<FileSize>,
+    /// When the node was created, `None` if it wasn't stamped.
+    pub created: Option<Timestamp>,
+    /// When the contents of the node, or the entries of a directory, last changed, `None` if it wasn't stamped.
+    pub modified: Option<Timestamp>,
+}
+
+/// Entry of a directory in the vault, as returned by [`Vault::ls`](crate::Vault::ls).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    /// The name of the entry within the directory.
+    pub name: String,
+    /// The kind of the node that the entry refers to.
+    pub kind: NodeKind,
+    /// The size of the file contents, `None` if the node is not a file.
+    pub size: Option<FileSize>,
+    /// Whether the node is stored in the block of the directory, instead of a block of its own.
+    pub inlined: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that every kind can be parsed back from its displayed name.
+    #[test]
+    fn node_kind_round_trip() {
+        for kind in [NodeKind::Vault, NodeKind::Directory, NodeKind::File, NodeKind::Symlink] {
+            assert_eq!(kind.to_string().parse::<NodeKind>().unwrap(), kind);
+            assert_eq!(kind.to_string().to_uppercase().parse::<NodeKind>().unwrap(), kind);
+        }
+        assert_eq!(format!("{:<9}|", NodeKind::File), "File     |");
+        assert_eq!(
+            "Socket".parse::<NodeKind>(),
+            Err(InvalidNodeKind(String::from("Socket")))
+        );
+        assert!("".parse::<NodeKind>().is_err());
+    }
+}
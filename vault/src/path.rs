@@ -17,7 +17,32 @@
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use std::path::{Components, PathBuf};
+use std::path::{Component, Components, PathBuf};
+
+use crate::InvalidVaultPath;
+
+/// The maximum number of components below the root that a [`VaultPath`] may have by default.
+pub const MAX_PATH_DEPTH: usize = 256;
+/// The maximum length in bytes of a single component of a [`VaultPath`] by default.
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// Bounds on the shape of a [`VaultPath`], so that walking a path stays cheap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PathLimits {
+    /// The maximum number of components below the root.
+    pub max_depth: usize,
+    /// The maximum length in bytes of a single component.
+    pub max_name_length: usize,
+}
+
+impl Default for PathLimits {
+    fn default() -> Self {
+        PathLimits {
+            max_depth: MAX_PATH_DEPTH,
+            max_name_length: MAX_NAME_LENGTH,
+        }
+    }
+}
 
 /// Immutable filesystem path to a node in the vault.
 ///
@@ -28,10 +53,49 @@ pub struct VaultPath {
 }
 
 impl VaultPath {
+    /// Create a new `VaultPath` from `path`, panicking if it isn't valid.
+    ///
+    /// Check out [`VaultPath::try_new`] for paths that come from outside of the program.
     pub fn new(path: impl Into<PathBuf>) -> VaultPath {
+        match VaultPath::try_new(path) {
+            Ok(path) => path,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Create a new `VaultPath` from `path`, checking it against the default [`PathLimits`].
+    pub fn try_new(path: impl Into<PathBuf>) -> Result<VaultPath, InvalidVaultPath> {
+        VaultPath::with_limits(path, PathLimits::default())
+    }
+
+    /// Create a new `VaultPath` from `path`, checking it against `limits`.
+    pub fn with_limits(path: impl Into<PathBuf>, limits: PathLimits) -> Result<VaultPath, InvalidVaultPath> {
         let path = VaultPath { path: path.into() };
-        assert!(path.valid());
-        path
+        if !path.valid() {
+            return Err(InvalidVaultPath::NotAbsolute);
+        }
+        let mut depth = 0;
+        for component in path.path.components() {
+            let name = match component {
+                Component::Normal(name) => name,
+                Component::ParentDir => "..".as_ref(),
+                Component::Prefix(_) | Component::RootDir | Component::CurDir => continue,
+            };
+            depth += 1;
+            if name.len() > limits.max_name_length {
+                return Err(InvalidVaultPath::NameTooLong {
+                    length: name.len(),
+                    max: limits.max_name_length,
+                });
+            }
+        }
+        if depth > limits.max_depth {
+            return Err(InvalidVaultPath::TooDeep {
+                depth,
+                max: limits.max_depth,
+            });
+        }
+        Ok(path)
     }
 
     fn new_unchecked(path: impl Into<PathBuf>) -> VaultPath {
@@ -57,7 +121,141 @@ impl VaultPath {
         self.path.components()
     }
 
+    /// Returns the names of the components below the root, starting from the root.
+    ///
+    /// Components of `.` are skipped. Only paths for which [`VaultPath::is_normal`] holds can refer to a node,
+    /// for other paths the names end right before the first `..` or name that isn't valid Unicode.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.path
+            .components()
+            .filter(|component| !matches!(component, Component::Prefix(_) | Component::RootDir | Component::CurDir))
+            .map_while(|component| match component {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            })
+    }
+
+    /// Returns `true` if every component below the root is a name that is valid Unicode, or `.`.
+    pub fn is_normal(&self) -> bool {
+        self.path.components().all(|component| match component {
+            Component::Normal(name) => name.to_str().is_some(),
+            Component::ParentDir => false,
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => true,
+        })
+    }
+
+    /// Returns the name of the last component, or `None` if there is none or it isn't valid Unicode.
     pub fn file_name(&self) -> Option<&str> {
-        self.path.file_name().map(|str| str.to_str().unwrap())
+        self.path.file_name().and_then(|str| str.to_str())
+    }
+
+    /// Returns the path of the entry called `name` in this directory.
+    pub fn child(&self, name: &str) -> VaultPath {
+        VaultPath::new(self.path.join(name))
+    }
+
+    /// Returns the path of the entry called `name` in this directory, checking it like [`VaultPath::try_new`].
+    pub fn try_child(&self, name: &str) -> Result<VaultPath, InvalidVaultPath> {
+        VaultPath::try_new(self.path.join(name))
+    }
+
+    /// Create a new path relative to some directory, or `None` if `path` is absolute.
+    ///
+    /// Check out [`VaultPath::join`] for turning it into a `VaultPath`.
+    pub fn relative(path: impl Into<PathBuf>) -> Option<RelativeVaultPath> {
+        let path = path.into();
+        (!path.has_root()).then_some(RelativeVaultPath { path })
+    }
+
+    /// Returns the path of `relative` when starting from this directory.
+    ///
+    /// Components of `.` are skipped and `..` moves to the parent directory.
+    /// Returns `None` if that would move past the root, or beyond the default [`PathLimits`].
+    pub fn join(&self, relative: &RelativeVaultPath) -> Option<VaultPath> {
+        let mut path = self.path.clone();
+        for component in relative.path.components() {
+            match component {
+                Component::Normal(name) => path.push(name),
+                Component::ParentDir => {
+                    if !path.pop() {
+                        return None;
+                    }
+                }
+                Component::CurDir => (),
+                // Only possible with Windows drive prefixes such as `C:`
+                Component::Prefix(_) | Component::RootDir => return None,
+            }
+        }
+        VaultPath::try_new(path).ok()
+    }
+}
+
+/// Filesystem path to a node in the vault, relative to some directory.
+#[derive(Eq, PartialEq, PartialOrd, Ord, Clone, Debug)]
+pub struct RelativeVaultPath {
+    path: PathBuf,
+}
+
+impl RelativeVaultPath {
+    pub fn to_str(&self) -> Option<&str> {
+        self.path.to_str()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make sure that the names of a path are its components below the root, and that `..` ends them.
+    #[test]
+    fn names() {
+        let path = VaultPath::new("/a/b/c");
+        assert!(path.is_normal());
+        assert_eq!(path.names().collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(VaultPath::new("/").names().count(), 0);
+        assert_eq!(VaultPath::new("/a/./b").names().collect::<Vec<_>>(), ["a", "b"]);
+
+        let path = VaultPath::new("/a/../b");
+        assert!(!path.is_normal());
+        assert_eq!(path.names().collect::<Vec<_>>(), ["a"]);
+    }
+
+    /// Make sure that paths are accepted up to the limits and refused beyond them.
+    #[test]
+    fn limits() {
+        let deepest = format!("/{}", vec!["a"; MAX_PATH_DEPTH].join("/"));
+        assert!(VaultPath::try_new(&deepest).is_ok());
+        assert_eq!(
+            VaultPath::try_new(format!("{deepest}/a")),
+            Err(InvalidVaultPath::TooDeep {
+                depth: MAX_PATH_DEPTH + 1,
+                max: MAX_PATH_DEPTH
+            })
+        );
+
+        let longest = "n".repeat(MAX_NAME_LENGTH);
+        assert!(VaultPath::try_new(format!("/a/{longest}")).is_ok());
+        assert_eq!(
+            VaultPath::try_new(format!("/a/{longest}n")),
+            Err(InvalidVaultPath::NameTooLong {
+                length: MAX_NAME_LENGTH + 1,
+                max: MAX_NAME_LENGTH
+            })
+        );
+        assert_eq!(VaultPath::try_new("a/b"), Err(InvalidVaultPath::NotAbsolute));
+
+        let limits = PathLimits {
+            max_depth: 2,
+            max_name_length: 3,
+        };
+        assert!(VaultPath::with_limits("/abc/def", limits).is_ok());
+        assert!(VaultPath::with_limits("/abc/def/g", limits).is_err());
+        assert!(VaultPath::with_limits("/abcd", limits).is_err());
+        assert!(VaultPath::new("/")
+            .join(&VaultPath::relative(&deepest[1..]).unwrap())
+            .is_some());
+        assert!(VaultPath::new("/a")
+            .join(&VaultPath::relative(&deepest[1..]).unwrap())
+            .is_none());
     }
 }
@@ -17,77 +17,2008 @@
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 
-use crate::{Block, BlockId, EncryptedBlock};
+use bytes::Bytes;
 
-// NOTE: Add `Rc` when needing `Clone`
+use crate::bloom::BloomFilter;
+use crate::{
+    Blake3, Block, BlockId, BlockSize, BlockStore, ChunkStrategy, ContentDefinedChunker, EncryptedBlock, Hasher,
+    IntegrityError, InvalidBlockIdLength, LanBlockSource, Layout, ProviderError, TieredStore,
+};
+
+/// The bytes that every vault state file starts with.
+const STATE_MAGIC: &[u8] = b"EXOMEM";
+/// The version of the state file format, stored right after [`STATE_MAGIC`].
+///
+/// Version 2 added the algorithm of the [`Hasher`] right after the version.
+/// Version 3 added the id of the [`ChunkStrategy`] right after the algorithm.
+/// Version 4 widened the id of the `ChunkStrategy` to a little endian `u16`, which fits its parameters.
+/// Older versions are still read, and get upgraded the next time the state file is saved.
+const STATE_VERSION: u8 = 4;
+/// The id that version 3 state files recorded for every [`ContentDefinedChunker`].
+const LEGACY_CONTENT_DEFINED_ID: u8 = 17;
+
+/// Syncs the contents and metadata of the file or directory at a path to disk, check out [`Provider::set_sync`].
+type SyncFn = dyn Fn(&Path) -> io::Result<()> + Send + Sync;
+
+/// How hard a [`Provider`] tries to make the files it writes survive a crash of the OS or a power loss.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DurabilityMode {
+    /// Files are never synced, leaving it up to the OS when they reach the disk.
+    None,
+    /// Files are synced by [`Provider::flush`], which the vault calls after every commit.
+    ///
+    /// State files are synced right away, before they replace the previous one.
+    #[default]
+    Normal,
+    /// Every file is synced right after it has been written, and [`Provider::flush`] syncs the directories.
+    Fsync,
+}
+
+/// Summary of the blocks stored by a [`Provider`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProviderStats {
+    /// The number of distinct blocks, whether cached in memory, saved on disk or both.
+    pub blocks: usize,
+    /// The number of bytes that saved blocks take up on disk.
+    pub disk_bytes: u64,
+    /// The number of blocks cached in memory.
+    pub cached_blocks: usize,
+    /// The number of blocks per [`BlockSize`], as encoded in their ids.
+    pub block_sizes: BTreeMap<BlockSize, usize>,
+}
+
+/// Summary of a [`Provider::import_blocks`] operation.
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    /// The number of blocks that were copied from the source.
+    pub imported: usize,
+    /// The number of blocks that the provider already had, and were thus not copied.
+    pub skipped: usize,
+    /// The blocks that could not be copied, along with the reason.
+    pub failed: Vec<(BlockId, ProviderError)>,
+}
+
+// NOTE: Add `Arc` when needing `Clone`
+/// Stores blocks in memory and on disk.
+///
+/// All the state is behind locks, so a single `Provider` can be shared by multiple threads.
 pub struct Provider {
-    blocks: RefCell<HashMap<BlockId, Block>>,
+    blocks: RwLock<HashMap<BlockId, Block>>,
+    /// The maximum number of blocks cached in memory, or `None` if all blocks stay cached.
+    cache_capacity: Option<usize>,
+    /// The ids of the cached blocks in the order they were cached, oldest first, if the cache is bounded.
+    cache_order: Mutex<VecDeque<BlockId>>,
+    /// The directory where blocks are saved to disk, or `None` if blocks are only kept in memory.
+    base_dir: Option<PathBuf>,
+    /// The maximum number of bytes that saved blocks may take up on disk.
+    disk_budget: Option<u64>,
+    /// The number of bytes that saved blocks take up on disk, or `None` if not yet known.
+    disk_usage: Mutex<Option<u64>>,
+    /// The bloom filter of the saved blocks, or `None` if not yet loaded.
+    bloom: Mutex<Option<BloomFilter>>,
+    /// The ids of the blocks that are in the bloom filter, but whose files are still being written.
+    saving: Mutex<HashSet<BlockId>>,
+    /// Determines whether a saved block is still referenced, used for collecting garbage.
+    reachable: Option<Box<dyn Fn(BlockId) -> bool + Send + Sync>>,
+    /// Whether blocks loaded from disk are checked against their id.
+    verify_on_load: bool,
+    /// Whether block files are mapped into memory instead of read when loading blocks.
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+    /// Computes the ids of blocks, which must match the hasher that the stored ids were computed by.
+    hasher: Arc<dyn Hasher>,
+    /// Determines the sizes of the data blocks that files are split into.
+    chunk_strategy: Box<dyn ChunkStrategy>,
+    /// The LAN peers and the remote stores as tiers, which blocks missing from memory and disk are fetched from.
+    sources: TieredStore,
+    /// The number of times each block has been added, including the times it was already present.
+    references: Mutex<HashMap<BlockId, usize>>,
+    /// How hard the provider tries to make the files it writes survive a crash.
+    durability: DurabilityMode,
+    /// The files written since the last [`Provider::flush`] that still have to be synced.
+    unsynced: Mutex<Vec<PathBuf>>,
+    /// Syncs the contents and metadata of a file or directory to disk.
+    sync: Box<SyncFn>,
 }
 
 impl Provider {
+    /// Create a new `Provider` that saves blocks to the relative `temp` directory.
+    ///
+    /// Every provider created this way shares the same directory, relative to the current one.
+    #[cfg(feature = "std-fs")]
+    #[deprecated(note = "use `Provider::with_base_dir` or `Provider::in_memory` instead")]
     pub fn new() -> Provider {
+        Provider::with_base_dir("temp")
+    }
+
+    /// Create a new `Provider` that saves blocks to the `base_dir` directory.
+    ///
+    /// The directory is created when the first block gets saved, if it doesn't exist yet.
+    #[cfg(feature = "std-fs")]
+    pub fn with_base_dir(base_dir: impl Into<PathBuf>) -> Provider {
         Provider {
-            blocks: RefCell::new(HashMap::new()),
+            base_dir: Some(base_dir.into()),
+            ..Provider::in_memory()
         }
     }
 
-    pub fn get_block(&self, id: BlockId) -> Block {
-        // TODO: Check if it already exists in-memory
-        // TODO: Check if the disk has a copy
-        // TODO: Check if any LAN devices have a copy
-        // TODO: Get it from the service
+    /// Create a new `Provider` that keeps blocks only in memory, never touching the disk.
+    pub fn in_memory() -> Provider {
+        Provider {
+            blocks: RwLock::new(HashMap::new()),
+            cache_capacity: None,
+            cache_order: Mutex::new(VecDeque::new()),
+            base_dir: None,
+            disk_budget: None,
+            disk_usage: Mutex::new(None),
+            bloom: Mutex::new(None),
+            saving: Mutex::new(HashSet::new()),
+            reachable: None,
+            verify_on_load: true,
+            #[cfg(feature = "mmap")]
+            mmap: false,
+            hasher: Arc::new(Blake3),
+            chunk_strategy: Box::new(Layout),
+            sources: TieredStore::new(),
+            references: Mutex::new(HashMap::new()),
+            durability: DurabilityMode::default(),
+            unsynced: Mutex::new(Vec::new()),
+            sync: Box::new(|path| fs::File::open(path).and_then(|file| file.sync_all())),
+        }
+    }
 
-        self.blocks.borrow().get(&id).unwrap().clone()
+    /// Sets the maximum number of blocks cached in memory, evicting the oldest ones once there are more.
+    ///
+    /// Evicted blocks are loaded from disk again when needed, so the capacity only applies to providers
+    /// with a base directory. Blocks are all kept in memory by default.
+    pub fn set_cache_capacity(&mut self, capacity: Option<usize>) {
+        self.cache_capacity = capacity;
     }
 
-    // TODO: Single-file on-disk cache support ... dynamically sized capnp header and then aligned blocks follow
+    /// Returns the maximum number of blocks cached in memory, or `None` if all blocks stay cached.
+    pub fn cache_capacity(&self) -> Option<usize> {
+        self.cache_capacity
+    }
+
+    /// Sets how hard the provider tries to make the files it writes survive a crash,
+    /// which is [`DurabilityMode::Normal`] by default.
+    pub fn set_durability(&mut self, durability: DurabilityMode) {
+        self.durability = durability;
+    }
+
+    /// Sets the function that syncs the contents and metadata of a file or directory to disk,
+    /// which opens it and calls [`File::sync_all`](fs::File::sync_all) by default.
+    ///
+    /// Useful for file systems that need to be synced differently, and for simulating a failing disk.
+    pub fn set_sync(&mut self, sync: impl Fn(&Path) -> io::Result<()> + Send + Sync + 'static) {
+        self.sync = Box::new(sync);
+    }
+
+    /// Syncs the file or directory at `path` to disk with the function set by [`Provider::set_sync`].
+    fn sync_path(&self, path: &Path) -> Result<(), ProviderError> {
+        (self.sync)(path).map_err(|e| ProviderError::Io(path.to_path_buf(), e))
+    }
+
+    /// Syncs the files written since the last flush, along with the directories they were written to,
+    /// so that they survive a crash of the OS or a power loss.
+    ///
+    /// Does nothing with [`DurabilityMode::None`]. Files that fail to sync are tried again by the next flush.
+    pub fn flush(&self) -> Result<(), ProviderError> {
+        let mut unsynced = self.unsynced.lock().unwrap();
+        // Newly created files can only be found after a crash once their directory has been synced too
+        let mut directories = BTreeSet::new();
+        while let Some(path) = unsynced.last() {
+            if self.durability != DurabilityMode::Fsync {
+                self.sync_path(path)?;
+            }
+            if let Some(directory) = path.parent() {
+                directories.insert(directory.to_path_buf());
+            }
+            unsynced.pop();
+        }
+        // Directories can't be opened for syncing on every platform
+        #[cfg(unix)]
+        for directory in directories {
+            let directory = if directory.as_os_str().is_empty() {
+                PathBuf::from(".")
+            } else {
+                directory
+            };
+            self.sync_path(&directory)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` to the file at `path`, leaving the rest of the syncing to [`Provider::flush`].
+    ///
+    /// The data is written to a temporary file next to `path` first, which is then renamed into place.
+    /// An existing file is thus never truncated or changed, which matters to blocks that are mapped into memory.
+    /// The temporary file is synced before the rename if `sync` is set.
+    fn write_file(&self, path: PathBuf, data: &[u8], sync: bool) -> Result<(), ProviderError> {
+        let temp_path = temp_path(&path);
+        let written = fs::write(&temp_path, data)
+            .map_err(|e| ProviderError::Io(temp_path.clone(), e))
+            .and_then(|_| if sync { self.sync_path(&temp_path) } else { Ok(()) })
+            .and_then(|_| fs::rename(&temp_path, &path).map_err(|e| ProviderError::Io(path.clone(), e)));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+        if self.durability == DurabilityMode::None {
+            return Ok(());
+        }
+        // The contents may already be synced, but the rename still has to reach the directory
+        self.unsynced.lock().unwrap().push(path);
+        Ok(())
+    }
+
+    /// Writes the block file at `path` within the disk budget, unless it's already there.
+    ///
+    /// An existing file has the same contents, as the file name is their id, and may already be counted
+    /// against the budget, so overwriting it would count it twice.
+    fn save_block_file(&self, path: PathBuf, data: &[u8]) -> Result<(), ProviderError> {
+        if path.is_file() {
+            return Ok(());
+        }
+        let size = data.len() as u64;
+        self.reserve_disk_space(size)?;
+        self.write_file(path, data, self.durability == DurabilityMode::Fsync)
+            .inspect_err(|_| self.release_disk_space(size))
+    }
+
+    /// Sets the maximum number of bytes that saved blocks may take up on disk.
+    ///
+    /// Saving a block that would exceed the budget first collects garbage, if a reachability
+    /// function has been set via [`Provider::set_reachability`]. If that doesn't free up enough space,
+    /// the block is refused with [`ProviderError::QuotaExceeded`].
+    pub fn set_disk_budget(&mut self, budget: Option<u64>) {
+        self.disk_budget = budget;
+    }
+
+    /// Sets the function that determines whether a saved block is still referenced.
+    ///
+    /// Unreferenced blocks are deleted from disk when the disk budget is exceeded.
+    /// Blocks added by this provider are always kept then, as they may not be reachable until a later commit.
+    pub fn set_reachability(&mut self, reachable: impl Fn(BlockId) -> bool + Send + Sync + 'static) {
+        self.reachable = Some(Box::new(reachable));
+    }
+
+    /// Sets whether blocks loaded from disk are checked against their id, which is the default.
+    ///
+    /// A block that doesn't match is refused with [`ProviderError::IntegrityMismatch`].
+    pub fn set_verify_on_load(&mut self, verify: bool) {
+        self.verify_on_load = verify;
+    }
+
+    /// Sets whether blocks are loaded from disk by mapping their files into memory, instead of reading them.
+    ///
+    /// Mapped blocks aren't copied on load, which saves time and memory for large read-heavy workloads.
+    /// Block files must not be changed while they're mapped, not even by other processes,
+    /// as the loaded blocks would change along with them.
+    #[cfg(feature = "mmap")]
+    pub fn set_mmap(&mut self, mmap: bool) {
+        self.mmap = mmap;
+    }
 
-    pub fn load_block_from_file(&self, id: BlockId, key: u128) -> Block {
-        let path = Self::id_to_path(id);
-        let block = if let Ok(data) = fs::read(&path) {
-            EncryptedBlock::from_data(data.into()).decrypt(key)
-        } else {
-            panic!("Failed to read from file {path:?}");
+    /// Sets the [`Hasher`] that block ids are computed by, which is [`Blake3`] by default.
+    ///
+    /// Set it before adding any blocks, as ids computed by different hashers must not be mixed.
+    pub fn set_hasher(&mut self, hasher: impl Hasher + 'static) {
+        self.hasher = Arc::new(hasher);
+        self.sources.set_hasher(self.hasher.clone());
+    }
+
+    /// Returns the [`Hasher`] that block ids are computed by.
+    pub fn hasher(&self) -> &dyn Hasher {
+        self.hasher.as_ref()
+    }
+
+    /// Sets the [`ChunkStrategy`] that files are split into data blocks by, which is [`Layout`] by default.
+    ///
+    /// Set it before initializing a vault, as the vault state file records it and is refused by other strategies.
+    pub fn set_chunk_strategy(&mut self, chunk_strategy: impl ChunkStrategy + 'static) {
+        self.chunk_strategy = Box::new(chunk_strategy);
+    }
+
+    /// Returns the [`ChunkStrategy`] that files are split into data blocks by.
+    pub fn chunk_strategy(&self) -> &dyn ChunkStrategy {
+        self.chunk_strategy.as_ref()
+    }
+
+    /// Adds the store that blocks are fetched from when they're neither cached in memory nor saved on disk,
+    /// such as a [`RemoteBlockStore`](crate::RemoteBlockStore), after the sources added so far.
+    pub fn set_remote(&mut self, remote: impl BlockStore + Send + Sync + 'static) {
+        self.sources.push(Arc::new(remote));
+    }
+
+    /// Adds the peers on the local network that blocks are asked for when they're neither cached in memory
+    /// nor saved on disk, before the sources added so far, so that they're tried before the remote stores.
+    pub fn set_lan(&mut self, lan: LanBlockSource) {
+        self.sources.insert(0, Arc::new(lan));
+    }
+
+    /// Returns the block with the specified `id`, or `None` if no source has it.
+    ///
+    /// Uncached blocks are loaded from disk, or else fetched from the LAN peers and the remote stores
+    /// added via [`Provider::set_lan`] and [`Provider::set_remote`], in that order, and cached in memory.
+    /// The peers and the remote stores are tried as the tiers of a [`TieredStore`], which refuses fetched
+    /// blocks that don't match their id.
+    ///
+    /// A failing source doesn't end the lookup, the next one is tried instead.
+    /// If no source has the block, the error of the first failing one is returned.
+    pub fn get_block(&self, id: BlockId) -> Result<Option<Block>, ProviderError> {
+        if let Some(block) = self.blocks.read().unwrap().get(&id).cloned() {
+            return Ok(Some(block));
+        }
+        match self.load_block(id) {
+            Ok(block) => Ok(Some(block)),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Loads the uncached block with the specified `id` from disk, or else fetches it from the sources
+    /// like [`Provider::get_block`], caching it in memory.
+    ///
+    /// Fails with the error of the first failing source like a [`TieredStore`] with the disk as its highest tier,
+    /// or else with the error of the last source that doesn't have the block.
+    fn load_block(&self, id: BlockId) -> Result<Block, ProviderError> {
+        let disk_error = match self.id_to_path(id) {
+            // TODO: Take the keys once blocks are actually encrypted
+            Some(path) if path.is_file() => match self.load_block_from_file(id, 0) {
+                Ok(block) => return Ok(block),
+                Err(e) => e,
+            },
+            Some(path) => ProviderError::Io(path, ErrorKind::NotFound.into()),
+            None => ProviderError::NotSaved(id),
         };
-        self.blocks.borrow_mut().insert(id, block);
-        self.blocks.borrow().get(&id).unwrap().clone()
+        match self.sources.load_encrypted_block(id) {
+            Ok(encrypted_block) => {
+                let block = encrypted_block.decrypt(0);
+                self.cache_block(id, block.clone());
+                Ok(block)
+            }
+            Err(e) if disk_error.is_not_found() && !self.sources.is_empty() => Err(e),
+            Err(_) => Err(disk_error),
+        }
+    }
+
+    /// Returns the blocks with the specified `ids` in the same order, loading the uncached ones from disk
+    /// or the other sources of [`Provider::get_block`].
+    ///
+    /// Repeated ids are only looked up once, and the in-memory cache is locked only once for all of them.
+    pub fn get_blocks(&self, ids: &[BlockId]) -> Result<Vec<Block>, ProviderError> {
+        let mut found = HashMap::with_capacity(ids.len());
+        {
+            let blocks = self.blocks.read().unwrap();
+            for id in ids {
+                found.entry(*id).or_insert_with(|| blocks.get(id).cloned());
+            }
+        }
+
+        // TODO: Read sequentially from a single pack file once the on-disk cache supports it
+        let mut missing: Vec<BlockId> = found
+            .iter()
+            .filter(|(_, block)| block.is_none())
+            .map(|(id, _)| *id)
+            .collect();
+        missing.sort();
+        for id in missing {
+            found.insert(id, Some(self.load_block(id)?));
+        }
+
+        Ok(ids
+            .iter()
+            .map(|id| found[id].clone().expect("block was just loaded"))
+            .collect())
+    }
+
+    /// Loads the uncached blocks with the specified `ids` from disk or the other sources of [`Provider::get_block`]
+    /// into the in-memory cache.
+    ///
+    /// This is only a hint for upcoming [`Provider::get_block`] calls, so blocks that fail to load are skipped.
+    /// Returns the number of blocks that were loaded.
+    pub fn prefetch(&self, ids: &[BlockId]) -> usize {
+        let mut missing: Vec<BlockId> = {
+            let blocks = self.blocks.read().unwrap();
+            ids.iter().filter(|id| !blocks.contains_key(id)).copied().collect()
+        };
+        missing.sort();
+        missing.dedup();
+        missing.into_iter().filter(|id| self.load_block(*id).is_ok()).count()
+    }
+
+    /// Adds the block to the in-memory cache only, without saving it to disk.
+    ///
+    /// Evicts the oldest cached blocks if that exceeds the capacity set by [`Provider::set_cache_capacity`].
+    pub(crate) fn cache_block(&self, id: BlockId, block: Block) {
+        let mut blocks = self.blocks.write().unwrap();
+        if blocks.insert(id, block).is_some() {
+            return;
+        }
+        let Some(capacity) = self.cache_capacity.filter(|_| self.base_dir.is_some()) else {
+            return;
+        };
+        let mut cache_order = self.cache_order.lock().unwrap();
+        cache_order.push_back(id);
+        while blocks.len() > capacity {
+            let Some(oldest) = cache_order.pop_front() else {
+                break;
+            };
+            blocks.remove(&oldest);
+        }
+    }
+
+    /// Removes the block from the in-memory cache.
+    pub(crate) fn evict_block(&self, id: BlockId) -> Option<Block> {
+        let mut blocks = self.blocks.write().unwrap();
+        let block = blocks.remove(&id)?;
+        // A stale entry would later evict the block early, if it gets cached again
+        self.cache_order.lock().unwrap().retain(|cached_id| *cached_id != id);
+        Some(block)
+    }
+
+    // TODO: Single-file on-disk cache support ... dynamically sized capnp header and then aligned blocks follow
+
+    pub fn load_block_from_file(&self, id: BlockId, key: u128) -> Result<Block, ProviderError> {
+        let path = self.id_to_path(id).ok_or(ProviderError::NotSaved(id))?;
+        let data = self.read_block_file(&path)?;
+        let encrypted_block = EncryptedBlock::from_data(data);
+        if self.verify_on_load {
+            let actual = encrypted_block
+                .id_with(Block::kind_from_id(id), self.hasher())
+                .map_err(|e| ProviderError::InvalidBlockSize(id, e))?;
+            if actual != id {
+                return Err(ProviderError::IntegrityMismatch {
+                    path,
+                    expected: id,
+                    actual,
+                });
+            }
+        }
+        let block = encrypted_block.decrypt(key);
+        self.cache_block(id, block.clone());
+        Ok(block)
+    }
+
+    /// Returns the contents of the block file at `path`, mapped into memory if enabled by `Provider::set_mmap`.
+    fn read_block_file(&self, path: &Path) -> Result<Bytes, ProviderError> {
+        #[cfg(feature = "mmap")]
+        if self.mmap {
+            return map_file(path).map_err(|e| ProviderError::Io(path.to_path_buf(), e));
+        }
+        fs::read(path)
+            .map(Bytes::from)
+            .map_err(|e| ProviderError::Io(path.to_path_buf(), e))
     }
 
-    pub fn add_block(&self, id: BlockId, encrypted_block: EncryptedBlock, block: Block) -> Block {
+    pub fn add_block(
+        &self,
+        id: BlockId,
+        encrypted_block: EncryptedBlock,
+        block: Block,
+    ) -> Result<Block, ProviderError> {
+        // Counted up front, so that collecting garbage leaves the block alone while it's being added
+        self.count_reference(id);
+        if let Err(e) = self.save_block(id, &encrypted_block) {
+            self.uncount_reference(id);
+            return Err(e);
+        }
+
+        self.cache_block(id, block.clone());
+
+        Ok(block)
+    }
+
+    /// Saves the block with the specified `id` to disk, unless it's already there or there is no disk.
+    fn save_block(&self, id: BlockId, encrypted_block: &EncryptedBlock) -> Result<(), ProviderError> {
         // If we already have it, then no need to add it again.
-        if self.blocks.borrow().contains_key(&id) {
-            return block;
+        if self.contains_block(id) {
+            return Ok(());
+        }
+
+        if let (Some(base_dir), Some(path)) = (&self.base_dir, self.id_to_path(id)) {
+            fs::create_dir_all(base_dir).map_err(|e| ProviderError::Io(base_dir.clone(), e))?;
+            // The bloom filter learns about the block first, as a crash can then only leave a false positive
+            if self.record_saved_block(id)? {
+                let saved = self.save_block_file(path, &encrypted_block.data());
+                self.saving.lock().unwrap().remove(&id);
+                saved?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the `entries` as a unit, so that either all of their blocks are saved to disk or none are.
+    ///
+    /// Every block is first written to a temporary file next to its final one, and only once all of them
+    /// have been written are they renamed into place. A failed write deletes the temporary files again,
+    /// and a failed rename also deletes the blocks that were already renamed into place.
+    /// Blocks that are already present are only counted, like with [`Provider::add_block`].
+    pub fn add_blocks(&self, entries: &[(BlockId, EncryptedBlock)]) -> Result<(), ProviderError> {
+        // Counted up front, so that collecting garbage leaves the blocks alone while they're being added
+        for (id, _) in entries {
+            self.count_reference(*id);
+        }
+        if let Err(e) = self.save_blocks(entries) {
+            for (id, _) in entries {
+                self.uncount_reference(*id);
+            }
+            return Err(e);
+        }
+
+        for (id, encrypted_block) in entries {
+            self.cache_block(*id, encrypted_block.decrypt(0));
+        }
+        Ok(())
+    }
+
+    /// Saves the blocks of the `entries` that aren't there yet to disk as a unit, as described for
+    /// [`Provider::add_blocks`].
+    fn save_blocks(&self, entries: &[(BlockId, EncryptedBlock)]) -> Result<(), ProviderError> {
+        let mut missing: Vec<&(BlockId, EncryptedBlock)> = Vec::with_capacity(entries.len());
+        for entry in entries {
+            if !self.contains_block(entry.0) && missing.iter().all(|(id, _)| *id != entry.0) {
+                missing.push(entry);
+            }
+        }
+
+        if let Some(base_dir) = &self.base_dir {
+            let size = missing
+                .iter()
+                .map(|(_, encrypted_block)| encrypted_block.data().len() as u64)
+                .sum();
+            self.reserve_disk_space(size)?;
+            let mut staged = Vec::with_capacity(missing.len());
+            let result = fs::create_dir_all(base_dir)
+                .map_err(|e| ProviderError::Io(base_dir.clone(), e))
+                .and_then(|_| {
+                    for (id, encrypted_block) in &missing {
+                        let path = self.id_to_path(*id).unwrap();
+                        let temp_path = temp_path(&path);
+                        fs::write(&temp_path, encrypted_block.data())
+                            .map_err(|e| ProviderError::Io(temp_path.clone(), e))?;
+                        staged.push((*id, temp_path, path, encrypted_block.data().len() as u64));
+                        if self.durability == DurabilityMode::Fsync {
+                            self.sync_path(&staged.last().unwrap().1)?;
+                        }
+                    }
+                    Ok(())
+                });
+            if let Err(e) = result {
+                for (_, temp_path, _, _) in staged {
+                    let _ = fs::remove_file(temp_path);
+                }
+                self.release_disk_space(size);
+                return Err(e);
+            }
+
+            // The bloom filter learns about the blocks first, as a crash can then only leave false positives
+            let mut renamed: Vec<(PathBuf, u64)> = Vec::with_capacity(staged.len());
+            let mut staged = staged.into_iter();
+            let mut failure = None;
+            for (id, temp_path, path, block_size) in staged.by_ref() {
+                let claimed = match self.record_saved_block(id) {
+                    Ok(claimed) => claimed,
+                    Err(e) => {
+                        self.saving.lock().unwrap().remove(&id);
+                        failure = Some((e, temp_path, block_size));
+                        break;
+                    }
+                };
+                if !claimed || path.is_file() {
+                    // The same contents are already saved or being saved, and counted against the budget
+                    let _ = fs::remove_file(&temp_path);
+                    self.release_disk_space(block_size);
+                    if claimed {
+                        self.saving.lock().unwrap().remove(&id);
+                    }
+                    continue;
+                }
+                let result = fs::rename(&temp_path, &path);
+                self.saving.lock().unwrap().remove(&id);
+                if let Err(e) = result {
+                    failure = Some((ProviderError::Io(path, e), temp_path, block_size));
+                    break;
+                }
+                renamed.push((path, block_size));
+            }
+            if let Some((e, temp_path, block_size)) = failure {
+                // Undo the blocks renamed so far and drop the ones that weren't, leaving false positives only
+                let _ = fs::remove_file(temp_path);
+                self.release_disk_space(block_size);
+                for (_, temp_path, _, block_size) in staged {
+                    let _ = fs::remove_file(temp_path);
+                    self.release_disk_space(block_size);
+                }
+                for (path, block_size) in renamed {
+                    let _ = fs::remove_file(path);
+                    self.release_disk_space(block_size);
+                }
+                return Err(e);
+            }
+            if self.durability != DurabilityMode::None {
+                // The contents may already be synced, but the renames still have to reach the directory
+                self.unsynced
+                    .lock()
+                    .unwrap()
+                    .extend(renamed.into_iter().map(|(path, _)| path));
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts one more addition of the block with the specified `id`.
+    fn count_reference(&self, id: BlockId) {
+        *self.references.lock().unwrap().entry(id).or_default() += 1;
+    }
+
+    /// Takes back the addition of the block with the specified `id` counted for an add that failed.
+    fn uncount_reference(&self, id: BlockId) {
+        let mut references = self.references.lock().unwrap();
+        if let Some(count) = references.get_mut(&id) {
+            *count -= 1;
+            if *count == 0 {
+                references.remove(&id);
+            }
+        }
+    }
+
+    /// Returns the number of times the block with the specified `id` has been added by this provider,
+    /// including the times it was already present. A count above one means the block is shared.
+    ///
+    /// The counts are only kept in memory, so blocks added by an earlier provider aren't counted.
+    pub fn reference_count(&self, id: BlockId) -> usize {
+        self.references.lock().unwrap().get(&id).copied().unwrap_or(0)
+    }
+
+    /// Returns `true` if the block with the specified `id` is cached in memory or saved on disk.
+    ///
+    /// Saved blocks are only checked for existence, their contents aren't read or verified.
+    /// The disk is only checked if the bloom filter of the saved blocks says that the block might be there,
+    /// so block files written behind the back of the provider while it has the filter loaded aren't seen.
+    pub fn contains_block(&self, id: BlockId) -> bool {
+        if self.blocks.read().unwrap().contains_key(&id) {
+            return true;
+        }
+        let Some(path) = self.id_to_path(id) else {
+            return false;
+        };
+        // Without a usable filter every id might be there
+        if let Ok(Some(false)) = self.with_bloom(|bloom| Ok(bloom.might_contain(id))) {
+            return false;
+        }
+        path.is_file()
+    }
+
+    /// Returns the result of `f` run with the bloom filter of the saved blocks.
+    ///
+    /// The filter is loaded from the block directory when first needed, or built from the saved blocks
+    /// if it isn't there yet. Returns `Ok(None)` if there is no block directory yet.
+    fn with_bloom<T>(
+        &self,
+        f: impl FnOnce(&mut BloomFilter) -> Result<T, ProviderError>,
+    ) -> Result<Option<T>, ProviderError> {
+        let Some(base_dir) = self.base_dir.as_ref().filter(|base_dir| base_dir.is_dir()) else {
+            return Ok(None);
+        };
+        let mut bloom = self.bloom.lock().unwrap();
+        let bloom = match &mut *bloom {
+            Some(bloom) => bloom,
+            None => {
+                let path = base_dir.join("blocks.bloom");
+                let filter = match BloomFilter::load(path.clone())? {
+                    Some(filter) => filter,
+                    None => {
+                        let ids: Vec<BlockId> = self.saved_block_ids()?.into_iter().map(|(id, _)| id).collect();
+                        BloomFilter::build(path, &ids, 0)?
+                    }
+                };
+                bloom.insert(filter)
+            }
+        };
+        f(bloom).map(Some)
+    }
+
+    /// Adds the block with the specified `id` to the bloom filter of the saved blocks,
+    /// rebuilding the filter with room for twice as many blocks once it's full.
+    ///
+    /// The id is also added to the blocks being saved, which the caller removes it from once the file is written.
+    /// Rebuilding the filter includes those, as other threads may have recorded blocks whose files aren't there yet.
+    ///
+    /// Returns `false` if another thread is already saving the block, which then writes its file instead.
+    fn record_saved_block(&self, id: BlockId) -> Result<bool, ProviderError> {
+        let claimed = self.with_bloom(|bloom| {
+            let mut saving = self.saving.lock().unwrap();
+            if !saving.insert(id) {
+                return Ok(false);
+            }
+            if !bloom.is_full() {
+                bloom.insert(id)?;
+                return Ok(true);
+            }
+            let capacity = 2 * bloom.len();
+            self.rebuild_bloom(bloom, &saving, capacity)?;
+            Ok(true)
+        })?;
+        Ok(claimed.unwrap_or(true))
+    }
+
+    /// Rebuilds the `bloom` filter from the saved blocks and the blocks being `saving`, with room for `capacity` ids.
+    ///
+    /// The blocks being saved are already in the filter, but their files may not be there yet.
+    fn rebuild_bloom(
+        &self,
+        bloom: &mut BloomFilter,
+        saving: &HashSet<BlockId>,
+        capacity: u64,
+    ) -> Result<(), ProviderError> {
+        let mut ids: Vec<BlockId> = self.saved_block_ids()?.into_iter().map(|(id, _)| id).collect();
+        ids.extend(saving.iter().copied());
+        *bloom = BloomFilter::build(bloom.path().to_path_buf(), &ids, capacity)?;
+        Ok(())
+    }
+
+    /// Copies the blocks with the specified `ids` from `source`, skipping the ones the provider already has.
+    ///
+    /// Every copied block is verified against its id first. Failures don't stop the import,
+    /// they are reported in the returned [`ImportSummary`] instead.
+    pub fn import_blocks(&self, source: &dyn BlockStore, ids: impl Iterator<Item = BlockId>) -> ImportSummary {
+        let mut summary = ImportSummary::default();
+        for id in ids {
+            if self.contains_block(id) {
+                summary.skipped += 1;
+                continue;
+            }
+            let imported = source.load_encrypted_block(id).and_then(|encrypted_block| {
+                self.verify_encrypted_block(id, &encrypted_block)?;
+                let block = encrypted_block.decrypt(0);
+                self.add_block(id, encrypted_block, block)
+            });
+            match imported {
+                Ok(_) => summary.imported += 1,
+                Err(e) => summary.failed.push((id, e)),
+            }
+        }
+        summary
+    }
+
+    /// Makes sure that the contents of `encrypted_block` from another store hash to `id`.
+    fn verify_encrypted_block(&self, id: BlockId, encrypted_block: &EncryptedBlock) -> Result<(), ProviderError> {
+        let actual = encrypted_block
+            .id_with(Block::kind_from_id(id), self.hasher())
+            .map_err(|e| ProviderError::InvalidBlockSize(id, e))?;
+        if actual != id {
+            return Err(ProviderError::BlockMismatch { expected: id, actual });
+        }
+        Ok(())
+    }
+
+    /// Makes sure that `size` more bytes fit into the disk budget, collecting garbage if needed.
+    ///
+    /// The bytes are counted as used right away, so that concurrent saves can't exceed the budget together.
+    fn reserve_disk_space(&self, size: u64) -> Result<(), ProviderError> {
+        let Some(budget) = self.disk_budget else {
+            return Ok(());
+        };
+        let mut disk_usage = self.disk_usage.lock().unwrap();
+        let mut usage = match *disk_usage {
+            Some(usage) => usage,
+            None => self.measure_disk_usage()?,
+        };
+        if usage + size > budget {
+            if let Some(reachable) = &self.reachable {
+                // The blocks added by this provider may belong to changes that aren't committed yet,
+                // which `reachable` can't know about, so only blocks of earlier sessions are collected
+                usage -= self.collect_garbage(&|id| {
+                    reachable(id)
+                        || self.saving.lock().unwrap().contains(&id)
+                        || self.references.lock().unwrap().contains_key(&id)
+                })?;
+            }
+            if usage + size > budget {
+                *disk_usage = Some(usage);
+                return Err(ProviderError::QuotaExceeded {
+                    budget,
+                    required: usage + size,
+                });
+            }
+        }
+        *disk_usage = Some(usage + size);
+        Ok(())
+    }
+
+    /// Gives back `size` bytes reserved by a save that failed.
+    fn release_disk_space(&self, size: u64) {
+        if let Some(usage) = self.disk_usage.lock().unwrap().as_mut() {
+            *usage -= size;
+        }
+    }
+
+    /// Returns the number of bytes that saved blocks take up on disk.
+    fn measure_disk_usage(&self) -> Result<u64, ProviderError> {
+        let mut usage = 0;
+        for (_, path) in self.saved_block_ids()? {
+            usage += fs::metadata(&path).map_err(|e| ProviderError::Io(path, e))?.len();
+        }
+        Ok(usage)
+    }
+
+    /// Deletes the saved blocks that aren't in the `live` set and rebuilds the bloom filter without them,
+    /// returning the number of bytes freed.
+    ///
+    /// Every block is saved to a file of its own, so deleting the dead ones reclaims their space right away.
+    /// The bloom filter can't forget ids though, so it's rebuilt from the remaining blocks and swapped in
+    /// by renaming a temporary file. An interrupted compaction can only leave the old filter in place,
+    /// which reports the deleted blocks as false positives, so compacting again completes it.
+    pub fn compact(&self, live: &BTreeSet<BlockId>) -> Result<u64, ProviderError> {
+        let freed = self.collect_garbage(&|id| live.contains(&id))?;
+        if let Some(usage) = self.disk_usage.lock().unwrap().as_mut() {
+            *usage = usage.saturating_sub(freed);
+        }
+        self.with_bloom(|bloom| {
+            let saving = self.saving.lock().unwrap();
+            self.rebuild_bloom(bloom, &saving, 0)
+        })?;
+        Ok(freed)
+    }
+
+    /// Deletes all saved blocks that are not `reachable`.
+    ///
+    /// Returns the number of bytes freed.
+    fn collect_garbage(&self, reachable: &(dyn Fn(BlockId) -> bool + Send + Sync)) -> Result<u64, ProviderError> {
+        let mut freed = 0;
+        for (id, path) in self.saved_block_ids()? {
+            if !reachable(id) {
+                let size = fs::metadata(&path)
+                    .map_err(|e| ProviderError::Io(path.clone(), e))?
+                    .len();
+                fs::remove_file(&path).map_err(|e| ProviderError::Io(path, e))?;
+                self.evict_block(id);
+                freed += size;
+            }
+        }
+        Ok(freed)
+    }
+
+    /// Returns the ids and paths of all the blocks saved in the base directory.
+    pub(crate) fn saved_block_ids(&self) -> Result<Vec<(BlockId, PathBuf)>, ProviderError> {
+        let Some(base_dir) = &self.base_dir else {
+            return Ok(Vec::new());
+        };
+        let entries = match fs::read_dir(base_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(ProviderError::Io(base_dir.clone(), e)),
+        };
+        let mut ids = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(|e| ProviderError::Io(base_dir.clone(), e))?.path();
+            if path.extension().is_some_and(|extension| extension == "bin") {
+                let id = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(BlockId::from_base64);
+                if let Some(id) = id {
+                    ids.push((id, path));
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Returns the full id of the cached or saved block whose Base64 representation starts with `prefix`.
+    ///
+    /// Check out [`BlockId::short`] for creating such prefixes.
+    pub fn resolve_short_id(&self, prefix: &str) -> Result<BlockId, ProviderError> {
+        let mut matches: BTreeSet<BlockId> = self
+            .blocks
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|id| id.base64().starts_with(prefix))
+            .copied()
+            .collect();
+        for (id, _) in self.saved_block_ids()? {
+            if id.base64().starts_with(prefix) {
+                matches.insert(id);
+            }
+        }
+
+        let mut matches = matches.into_iter();
+        match (matches.next(), matches.next()) {
+            (Some(id), None) => Ok(id),
+            (None, _) => Err(ProviderError::UnknownShortId(String::from(prefix))),
+            (Some(_), Some(_)) => Err(ProviderError::AmbiguousShortId(String::from(prefix))),
+        }
+    }
+
+    /// Checks every block saved on disk against its id, regardless of [`Provider::set_verify_on_load`].
+    ///
+    /// Returns the ids of the damaged blocks along with what's wrong with them, ordered by id.
+    pub fn verify_all(&self) -> Result<Vec<(BlockId, IntegrityError)>, ProviderError> {
+        let mut saved = self.saved_block_ids()?;
+        saved.sort();
+        Ok(saved
+            .into_iter()
+            .filter_map(|(id, path)| Some((id, self.verify_block_file(id, &path)?)))
+            .collect())
+    }
+
+    /// Checks the block with the specified `id` against its id like [`Provider::verify_all`],
+    /// reading the saved file even if the block is cached. Returns `None` if it's intact.
+    ///
+    /// Blocks of a provider that keeps them only in memory are checked as cached.
+    pub fn verify_block(&self, id: BlockId) -> Option<IntegrityError> {
+        if let Some(path) = self.id_to_path(id) {
+            return self.verify_block_file(id, &path);
+        }
+        let Some(block) = self.blocks.read().unwrap().get(&id).cloned() else {
+            return Some(IntegrityError::Unreadable(io::Error::from(ErrorKind::NotFound)));
+        };
+        self.verify_block_data(id, EncryptedBlock::encrypt(&block, 0))
+    }
+
+    /// Returns what's wrong with the block file at `path` saved for the block with the specified `id`, if anything.
+    fn verify_block_file(&self, id: BlockId, path: &Path) -> Option<IntegrityError> {
+        if !id.valid() {
+            return Some(IntegrityError::InvalidId);
+        }
+        match fs::read(path) {
+            Ok(data) => self.verify_block_data(id, EncryptedBlock::from_data(data.into())),
+            Err(e) => Some(IntegrityError::Unreadable(e)),
         }
-        self.blocks.borrow_mut().insert(id, block.clone());
+    }
+
+    /// Returns what's wrong with the `encrypted_block` stored for the block with the specified `id`, if anything.
+    fn verify_block_data(&self, id: BlockId, encrypted_block: EncryptedBlock) -> Option<IntegrityError> {
+        match encrypted_block.id_with(Block::kind_from_id(id), self.hasher()) {
+            Ok(actual) if actual != id => Some(IntegrityError::Mismatch(actual)),
+            Ok(_) => None,
+            Err(e) => Some(IntegrityError::InvalidSize(e)),
+        }
+    }
 
-        // Save it to disk
-        // TODO: Check if the disk already has it
-        fs::write(Self::id_to_path(id), encrypted_block.data()).unwrap();
+    /// Returns a summary of the cached and saved blocks.
+    pub fn stats(&self) -> Result<ProviderStats, ProviderError> {
+        let mut ids: BTreeSet<BlockId> = self.blocks.read().unwrap().keys().copied().collect();
+        let cached_blocks = ids.len();
+        let mut disk_bytes = 0;
+        for (id, path) in self.saved_block_ids()? {
+            disk_bytes += fs::metadata(&path).map_err(|e| ProviderError::Io(path, e))?.len();
+            ids.insert(id);
+        }
 
-        block
+        let mut block_sizes = BTreeMap::new();
+        for id in &ids {
+            *block_sizes.entry(id.block_size()).or_default() += 1;
+        }
+        Ok(ProviderStats {
+            blocks: ids.len(),
+            disk_bytes,
+            cached_blocks,
+            block_sizes,
+        })
     }
 
-    fn id_to_path(id: BlockId) -> PathBuf {
-        format!("temp/{}.bin", id.base64()).into()
+    /// Returns the path of the file that the block with the specified `id` is saved to, unless kept in memory only.
+    fn id_to_path(&self, id: BlockId) -> Option<PathBuf> {
+        let base_dir = self.base_dir.as_ref()?;
+        Some(base_dir.join(format!("{}.bin", id.base64())))
     }
 
-    pub fn load_block_id_from_file(path: impl Into<PathBuf>) -> BlockId {
+    /// Loads the block id from the state file at `path`, after checking the magic and the format version.
+    ///
+    /// Fails with [`ProviderError::HasherMismatch`] if the id was computed by a different [`Hasher`],
+    /// and with [`ProviderError::ChunkStrategyMismatch`] if the vault was split up by a different [`ChunkStrategy`].
+    pub fn load_block_id_from_file(&self, path: impl Into<PathBuf>) -> Result<BlockId, ProviderError> {
         let path = path.into();
-        let block_id = if let Ok(data) = fs::read(&path) {
-            BlockId::from_data(data.try_into().unwrap())
-        } else {
-            panic!("Failed to read from file {path:?}");
+        let data = fs::read(&path).map_err(|e| ProviderError::Io(path.clone(), e))?;
+        let Some(data) = data.strip_prefix(STATE_MAGIC) else {
+            return Err(ProviderError::NotAStateFile(path));
         };
-        block_id
+        let (algorithm, chunk_strategy, data) = match data {
+            [STATE_VERSION, algorithm, low, high, data @ ..] => (*algorithm, u16::from_le_bytes([*low, *high]), data),
+            // Version 3 had a single id for all the content defined chunkers, which were only used with the default
+            [3, algorithm, LEGACY_CONTENT_DEFINED_ID, data @ ..] => {
+                (*algorithm, ContentDefinedChunker::default().id(), data)
+            }
+            [3, algorithm, chunk_strategy, data @ ..] => (*algorithm, u16::from(*chunk_strategy), data),
+            // Version 2 predates chunk strategies, so it always follows the layout
+            [2, algorithm, data @ ..] => (*algorithm, Layout.id(), data),
+            // Version 1 predates hashers as well, so it was always hashed with BLAKE3
+            [1, data @ ..] => (Blake3.algorithm(), Layout.id(), data),
+            [STATE_VERSION, ..] | [3, ..] | [2, ..] | [] => {
+                return Err(ProviderError::InvalidBlockId(path, InvalidBlockIdLength(0)))
+            }
+            [version, ..] => return Err(ProviderError::UnsupportedStateVersion(path, *version)),
+        };
+        if algorithm != self.hasher.algorithm() {
+            return Err(ProviderError::HasherMismatch {
+                path,
+                expected: self.hasher.algorithm(),
+                actual: algorithm,
+            });
+        }
+        if chunk_strategy != self.chunk_strategy.id() {
+            return Err(ProviderError::ChunkStrategyMismatch {
+                path,
+                expected: self.chunk_strategy.id(),
+                actual: chunk_strategy,
+            });
+        }
+        BlockId::from_slice(data).map_err(|e| ProviderError::InvalidBlockId(path, e))
     }
 
-    pub fn save_block_id_to_file(id: BlockId, path: impl Into<PathBuf>) {
+    /// Saves the block id to the state file at `path`, preceded by the magic, the format version,
+    /// the algorithm of the [`Hasher`] and the id of the [`ChunkStrategy`].
+    ///
+    /// The new state file replaces the old one by renaming, after it has been synced unless the
+    /// [`DurabilityMode`] is `None`, so a crash leaves either the old id or the new one behind.
+    pub fn save_block_id_to_file(&self, id: BlockId, path: impl Into<PathBuf>) -> Result<(), ProviderError> {
         let path = path.into();
-        fs::write(path, id.data()).unwrap();
+        let mut data = Vec::with_capacity(STATE_MAGIC.len() + 4 + id.data().len());
+        data.extend_from_slice(STATE_MAGIC);
+        data.push(STATE_VERSION);
+        data.push(self.hasher.algorithm());
+        data.extend_from_slice(&self.chunk_strategy.id().to_le_bytes());
+        data.extend_from_slice(id.data());
+        // A state file renamed into place before its contents reach the disk could be empty after a crash
+        self.write_file(path, &data, self.durability != DurabilityMode::None)
+    }
+}
+
+/// Returns the path of the temporary file that the file at `path` is written to before being renamed into place.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = path.as_os_str().to_os_string();
+    temp_path.push(".tmp");
+    PathBuf::from(temp_path)
+}
+
+impl BlockStore for Provider {
+    /// Returns the block from memory if it's cached there, otherwise reads it from disk without caching it.
+    fn load_encrypted_block(&self, id: BlockId) -> Result<EncryptedBlock, ProviderError> {
+        if let Some(block) = self.blocks.read().unwrap().get(&id) {
+            return Ok(EncryptedBlock::encrypt(block, 0));
+        }
+        let path = self.id_to_path(id).ok_or(ProviderError::NotSaved(id))?;
+        let data = fs::read(&path).map_err(|e| ProviderError::Io(path, e))?;
+        Ok(EncryptedBlock::from_data(data.into()))
+    }
+
+    /// Adds the block like [`Provider::add_block`], which caches it in memory and saves it to disk if possible.
+    fn store_encrypted_block(&self, id: BlockId, encrypted_block: &EncryptedBlock) -> Result<(), ProviderError> {
+        // TODO: Take the keys once blocks are actually encrypted
+        let block = encrypted_block.decrypt(0);
+        self.add_block(id, encrypted_block.clone(), block).map(|_| ())
+    }
+}
+
+/// Maps the file at `path` into memory, which stays mapped for as long as any clone of the returned bytes.
+#[cfg(feature = "mmap")]
+fn map_file(path: &Path) -> std::io::Result<Bytes> {
+    let file = fs::File::open(path)?;
+    // Mapping an empty file fails on some platforms
+    if file.metadata()?.len() == 0 {
+        return Ok(Bytes::new());
+    }
+    // SAFETY: The provider only ever puts block files in place by renaming completely written temporary files,
+    // so a mapped file is never truncated or changed by it. Others mustn't either, as documented by `Provider::set_mmap`.
+    let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    Ok(Bytes::from_owner(mmap))
+}
+
+#[cfg(all(test, feature = "std-fs"))]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use crate::{BlockKind, Vault};
+
+    /// Make sure that a block saved into the base directory can be read back.
+    #[test]
+    fn base_dir() {
+        let dir = TempDir::new("provider");
+        let base_dir = dir.join("blocks");
+
+        let mut data = vec![0; 4096];
+        thread_rng().fill(&mut data[..]);
+        let block = Block::from_data(data.into());
+        let encrypted_block = EncryptedBlock::encrypt(&block, 0);
+        let id = encrypted_block.id(BlockKind::Data).unwrap();
+
+        let provider = Provider::with_base_dir(&base_dir);
+        provider.add_block(id, encrypted_block, block.clone()).unwrap();
+        assert!(base_dir.join(format!("{}.bin", id.base64())).is_file());
+
+        let provider = Provider::with_base_dir(&base_dir);
+        let loaded_block = provider.load_block_from_file(id, 0).unwrap();
+        assert_eq!(loaded_block.data(), block.data());
+    }
+
+    /// Make sure that rewriting a file replaces it with a new one, instead of changing the existing one in place.
+    #[cfg(unix)]
+    #[test]
+    fn write_file_replaces() {
+        use std::os::unix::fs::MetadataExt;
+
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+        let (id, encrypted_block, block) = random_block();
+        provider.add_block(id, encrypted_block.clone(), block).unwrap();
+        let path = provider.id_to_path(id).unwrap();
+        let file = fs::File::open(&path).unwrap();
+
+        provider
+            .write_file(path.clone(), &encrypted_block.data(), false)
+            .unwrap();
+        assert_ne!(fs::metadata(&path).unwrap().ino(), file.metadata().unwrap().ino());
+        assert_eq!(file.metadata().unwrap().len(), encrypted_block.data().len() as u64);
+        assert!(!temp_path(&path).exists());
+    }
+
+    /// Make sure that providers with different base directories don't see each other's blocks.
+    #[test]
+    fn independent_base_dirs() {
+        let root = TempDir::new("provider");
+        let first = Provider::with_base_dir(root.join("first"));
+        let second = Provider::with_base_dir(root.join("second"));
+
+        let mut ids = Vec::new();
+        for provider in [&first, &second] {
+            let (id, encrypted_block, block) = random_block();
+            provider.add_block(id, encrypted_block, block).unwrap();
+            ids.push(id);
+        }
+
+        assert!(first.load_block_from_file(ids[0], 0).is_ok());
+        assert!(first.load_block_from_file(ids[1], 0).is_err());
+        assert!(second.load_block_from_file(ids[0], 0).is_err());
+        assert!(second.load_block_from_file(ids[1], 0).is_ok());
+        assert!(!Provider::with_base_dir(root.join("first")).contains_block(ids[1]));
+    }
+
+    /// Make sure that disk failures are returned as errors instead of panicking.
+    #[test]
+    fn unwritable_path() {
+        // A regular file can't be used as a directory
+        let dir = TempDir::new("provider");
+        fs::create_dir_all(&dir).unwrap();
+        let not_a_dir = dir.join("file");
+        fs::write(&not_a_dir, b"").unwrap();
+
+        let block = Block::from_data(vec![0; 4096].into());
+        let encrypted_block = EncryptedBlock::encrypt(&block, 0);
+        let id = encrypted_block.id(BlockKind::Data).unwrap();
+
+        let provider = Provider::with_base_dir(not_a_dir.join("blocks"));
+        assert!(matches!(
+            provider.add_block(id, encrypted_block, block),
+            Err(ProviderError::Io(..))
+        ));
+        assert!(provider.get_block(id).unwrap().is_none());
+        assert!(matches!(
+            provider.load_block_from_file(id, 0),
+            Err(ProviderError::Io(..))
+        ));
+        assert!(matches!(
+            provider.save_block_id_to_file(id, not_a_dir.join("vault.db")),
+            Err(ProviderError::Io(..))
+        ));
+        assert!(matches!(
+            provider.load_block_id_from_file(not_a_dir.join("vault.db")),
+            Err(ProviderError::Io(..))
+        ));
+        // An empty file isn't a state file
+        assert!(matches!(
+            provider.load_block_id_from_file(&not_a_dir),
+            Err(ProviderError::NotAStateFile(_))
+        ));
+    }
+
+    /// Make sure that the state file round trips and that foreign or damaged files are refused.
+    #[test]
+    fn state_file() {
+        let dir = TempDir::new("provider");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("vault.db");
+        let provider = Provider::in_memory();
+        let (id, _, _) = random_block();
+
+        provider.save_block_id_to_file(id, &path).unwrap();
+        let data = fs::read(&path).unwrap();
+        assert!(data.starts_with(STATE_MAGIC));
+        assert_eq!(provider.load_block_id_from_file(&path).unwrap(), id);
+
+        // A bare block id, as well as any other unrelated file, lacks the magic
+        fs::write(&path, id.data()).unwrap();
+        assert!(matches!(
+            provider.load_block_id_from_file(&path),
+            Err(ProviderError::NotAStateFile(_))
+        ));
+
+        // Older versions are still read
+        let mut v1 = STATE_MAGIC.to_vec();
+        v1.push(1);
+        v1.extend_from_slice(id.data());
+        fs::write(&path, &v1).unwrap();
+        assert_eq!(provider.load_block_id_from_file(&path).unwrap(), id);
+        let mut v2 = STATE_MAGIC.to_vec();
+        v2.extend_from_slice(&[2, Blake3.algorithm()]);
+        v2.extend_from_slice(id.data());
+        fs::write(&path, &v2).unwrap();
+        assert_eq!(provider.load_block_id_from_file(&path).unwrap(), id);
+        let mut v3 = STATE_MAGIC.to_vec();
+        v3.extend_from_slice(&[3, Blake3.algorithm(), 0]);
+        v3.extend_from_slice(id.data());
+        fs::write(&path, &v3).unwrap();
+        assert_eq!(provider.load_block_id_from_file(&path).unwrap(), id);
+        let mut chunked = Provider::in_memory();
+        chunked.set_chunk_strategy(ContentDefinedChunker::default());
+        v3[STATE_MAGIC.len() + 2] = LEGACY_CONTENT_DEFINED_ID;
+        fs::write(&path, &v3).unwrap();
+        assert_eq!(chunked.load_block_id_from_file(&path).unwrap(), id);
+        provider.save_block_id_to_file(id, &path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        let mut future = data.clone();
+        future[STATE_MAGIC.len()] = STATE_VERSION + 1;
+        fs::write(&path, future).unwrap();
+        assert!(matches!(
+            provider.load_block_id_from_file(&path),
+            Err(ProviderError::UnsupportedStateVersion(_, version)) if version == STATE_VERSION + 1
+        ));
+
+        fs::write(&path, &data[..data.len() - 10]).unwrap();
+        assert!(matches!(
+            provider.load_block_id_from_file(&path),
+            Err(ProviderError::InvalidBlockId(_, InvalidBlockIdLength(22)))
+        ));
+        fs::write(&path, &data[..STATE_MAGIC.len()]).unwrap();
+        assert!(matches!(
+            provider.load_block_id_from_file(&path),
+            Err(ProviderError::InvalidBlockId(_, InvalidBlockIdLength(0)))
+        ));
+        fs::write(&path, &STATE_MAGIC[..3]).unwrap();
+        assert!(matches!(
+            provider.load_block_id_from_file(&path),
+            Err(ProviderError::NotAStateFile(_))
+        ));
+    }
+
+    /// Hasher that sums up the bytes, which makes for predictable ids.
+    struct SumHasher;
+
+    impl Hasher for SumHasher {
+        fn algorithm(&self) -> u8 {
+            255
+        }
+
+        fn hash(&self, data: &[u8]) -> [u8; 32] {
+            let sum = data.iter().map(|byte| *byte as u64).sum::<u64>();
+            let mut hash = [0; 32];
+            hash[24..].copy_from_slice(&sum.to_be_bytes());
+            hash
+        }
+    }
+
+    /// Make sure that a swapped in hasher computes the ids and that its state files aren't mixed with others.
+    #[test]
+    fn hasher() {
+        let mut provider = Provider::in_memory();
+        provider.set_hasher(SumHasher);
+        assert_eq!(provider.hasher().algorithm(), 255);
+
+        let block = Block::from_data(vec![1; 4096].into());
+        let encrypted_block = EncryptedBlock::encrypt(&block, 0);
+        let id = encrypted_block.id_with(BlockKind::Data, provider.hasher()).unwrap();
+        assert_eq!(id, encrypted_block.id_with(BlockKind::Data, &SumHasher).unwrap());
+        assert_ne!(id, encrypted_block.id(BlockKind::Data).unwrap());
+        assert_eq!(&id.data()[1..24], &[0; 23]);
+        assert_eq!(&id.data()[24..], &4096u64.to_be_bytes());
+
+        // Vaults created with the same hasher end up with the same ids
+        let first = Vault::initialize_in_memory(&provider)
+            .unwrap()
+            .reachable_block_ids()
+            .unwrap();
+        let other_provider = Provider::in_memory();
+        let other = Vault::initialize_in_memory(&other_provider)
+            .unwrap()
+            .reachable_block_ids()
+            .unwrap();
+        assert_eq!(
+            first,
+            Vault::initialize_in_memory(&provider)
+                .unwrap()
+                .reachable_block_ids()
+                .unwrap()
+        );
+        assert_ne!(first, other);
+        assert!(first.iter().all(|id| provider.get_block(*id).unwrap().is_some()));
+
+        let dir = TempDir::new("provider");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("vault.db");
+        provider.save_block_id_to_file(id, &path).unwrap();
+        assert_eq!(provider.load_block_id_from_file(&path).unwrap(), id);
+        assert!(matches!(
+            other_provider.load_block_id_from_file(&path),
+            Err(ProviderError::HasherMismatch {
+                expected: 0,
+                actual: 255,
+                ..
+            })
+        ));
+    }
+
+    /// Returns a new data block of 4 KiB filled with random data, and its id.
+    fn random_block() -> (BlockId, EncryptedBlock, Block) {
+        let mut data = vec![0; 4096];
+        thread_rng().fill(&mut data[..]);
+        let block = Block::from_data(data.into());
+        let encrypted_block = EncryptedBlock::encrypt(&block, 0);
+        (encrypted_block.id(BlockKind::Data).unwrap(), encrypted_block, block)
+    }
+
+    /// Make sure that blocks past the disk budget are refused when nothing can be collected.
+    #[test]
+    fn disk_budget_exceeded() {
+        let base_dir = TempDir::new("provider");
+        let mut provider = Provider::with_base_dir(&base_dir);
+        provider.set_disk_budget(Some(2 * 4096));
+
+        let (id, encrypted_block, block) = random_block();
+        provider.add_block(id, encrypted_block, block).unwrap();
+        let (id, encrypted_block, block) = random_block();
+        provider.add_block(id, encrypted_block, block).unwrap();
+
+        let (id, encrypted_block, block) = random_block();
+        match provider.add_block(id, encrypted_block, block) {
+            Err(ProviderError::QuotaExceeded { budget, required }) => {
+                assert_eq!(budget, 2 * 4096);
+                assert_eq!(required, 3 * 4096);
+            }
+            _ => panic!("Expected ProviderError::QuotaExceeded"),
+        }
+        assert!(provider.get_block(id).unwrap().is_none());
+        assert!(!provider.id_to_path(id).unwrap().exists());
+
+        // Existing blocks on disk count against the budget of a new provider too
+        let mut provider = Provider::with_base_dir(&base_dir);
+        provider.set_disk_budget(Some(3 * 4096 - 1));
+        let (id, encrypted_block, block) = random_block();
+        assert!(provider.add_block(id, encrypted_block, block).is_err());
+    }
+
+    /// Make sure that unreferenced blocks of earlier sessions are collected to stay within the disk budget,
+    /// while the ones added by the provider itself are kept.
+    #[test]
+    fn disk_budget_collect_garbage() {
+        let base_dir = TempDir::new("provider");
+        let earlier = Provider::with_base_dir(&base_dir);
+        let (kept_id, encrypted_block, block) = random_block();
+        earlier.add_block(kept_id, encrypted_block, block).unwrap();
+        let (garbage_id, encrypted_block, block) = random_block();
+        earlier.add_block(garbage_id, encrypted_block, block).unwrap();
+
+        let mut provider = Provider::with_base_dir(&base_dir);
+        provider.set_disk_budget(Some(3 * 4096));
+        let (added_id, encrypted_block, block) = random_block();
+        provider.add_block(added_id, encrypted_block, block).unwrap();
+
+        provider.set_reachability(move |id| id == kept_id);
+        let (id, encrypted_block, block) = random_block();
+        provider.add_block(id, encrypted_block, block).unwrap();
+
+        assert!(provider.id_to_path(kept_id).unwrap().exists());
+        assert!(!provider.id_to_path(garbage_id).unwrap().exists());
+        assert!(provider.get_block(garbage_id).unwrap().is_none());
+        assert!(provider.id_to_path(added_id).unwrap().exists());
+        assert!(provider.id_to_path(id).unwrap().exists());
+    }
+
+    /// Make sure that saving a block whose file is already there doesn't count it against the budget twice.
+    #[test]
+    fn disk_budget_existing_block() {
+        let base_dir = TempDir::new("provider");
+        fs::create_dir_all(&base_dir).unwrap();
+        let mut provider = Provider::with_base_dir(&base_dir);
+        provider.set_disk_budget(Some(2 * 4096));
+        // The bloom filter is loaded before another provider saves the block, so it doesn't know about it
+        let (id, encrypted_block, block) = random_block();
+        assert!(!provider.contains_block(id));
+        Provider::with_base_dir(&base_dir)
+            .add_block(id, encrypted_block.clone(), block.clone())
+            .unwrap();
+
+        provider.add_block(id, encrypted_block, block).unwrap();
+        let (id, encrypted_block, block) = random_block();
+        provider.add_block(id, encrypted_block, block).unwrap();
+
+        // Concurrent saves of the same block only count it once too
+        let (id, encrypted_block, block) = random_block();
+        let mut provider = Provider::with_base_dir(&base_dir);
+        provider.set_disk_budget(Some(3 * 4096));
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| provider.add_block(id, encrypted_block.clone(), block.clone()).unwrap());
+            }
+        });
+        assert_eq!(*provider.disk_usage.lock().unwrap(), Some(3 * 4096));
+    }
+
+    /// Make sure that a batch mixing cached and saved blocks is returned in order.
+    #[test]
+    fn get_blocks() {
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+        let mut saved = Vec::new();
+        for _ in 0..3 {
+            let (id, encrypted_block, block) = random_block();
+            provider.add_block(id, encrypted_block, block.clone()).unwrap();
+            saved.push((id, block));
+        }
+
+        let provider = Provider::with_base_dir(&base_dir);
+        let (cached_id, _, cached_block) = random_block();
+        provider.cache_block(cached_id, cached_block.clone());
+
+        let ids = [saved[0].0, cached_id, saved[1].0, saved[0].0, saved[2].0];
+        let blocks = provider.get_blocks(&ids).unwrap();
+        assert_eq!(blocks.len(), ids.len());
+        assert_eq!(blocks[0].data(), saved[0].1.data());
+        assert_eq!(blocks[1].data(), cached_block.data());
+        assert_eq!(blocks[2].data(), saved[1].1.data());
+        assert_eq!(blocks[3].data(), saved[0].1.data());
+        assert_eq!(blocks[4].data(), saved[2].1.data());
+        for (id, _) in &saved {
+            assert!(provider.get_block(*id).unwrap().is_some());
+        }
+
+        let (missing_id, _, _) = random_block();
+        assert!(matches!(
+            provider.get_blocks(&[cached_id, missing_id]),
+            Err(ProviderError::Io(..))
+        ));
+    }
+
+    /// Make sure that a corrupted block file is refused when loading it.
+    #[test]
+    fn verify_on_load() {
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+        let (id, encrypted_block, block) = random_block();
+        provider.add_block(id, encrypted_block, block).unwrap();
+
+        let path = provider.id_to_path(id).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        data[100] ^= 0b0000_0001;
+        fs::write(&path, &data).unwrap();
+
+        let mut provider = Provider::with_base_dir(&base_dir);
+        match provider.load_block_from_file(id, 0) {
+            Err(ProviderError::IntegrityMismatch { expected, actual, .. }) => {
+                assert_eq!(expected, id);
+                assert_ne!(actual, id);
+            }
+            _ => panic!("Expected ProviderError::IntegrityMismatch"),
+        }
+        assert!(matches!(
+            provider.get_block(id),
+            Err(ProviderError::IntegrityMismatch { .. })
+        ));
+
+        provider.set_verify_on_load(false);
+        assert_eq!(provider.load_block_from_file(id, 0).unwrap().data(), data);
+    }
+
+    /// Make sure that a block loaded through a memory mapping matches the saved block.
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap() {
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+        let (id, encrypted_block, block) = random_block();
+        provider.add_block(id, encrypted_block, block.clone()).unwrap();
+
+        let mut provider = Provider::with_base_dir(&base_dir);
+        provider.set_mmap(true);
+        let mapped = provider.load_block_from_file(id, 0).unwrap();
+        assert_eq!(mapped.data(), block.data());
+        assert_eq!(provider.get_block(id).unwrap().unwrap().data(), block.data());
+    }
+
+    /// Make sure that short ids resolve only when they're unique.
+    #[test]
+    fn resolve_short_id() {
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+
+        // Two ids that share their first 12 bytes, thus their first 16 Base64 characters
+        let mut id_bytes = [0; 32];
+        thread_rng().fill(&mut id_bytes[..]);
+        let cached_id = BlockId::from_data(id_bytes);
+        id_bytes[20] = !id_bytes[20];
+        let saved_id = BlockId::from_data(id_bytes);
+        provider.cache_block(cached_id, Block::empty());
+        fs::create_dir_all(&base_dir).unwrap();
+        fs::write(provider.id_to_path(saved_id).unwrap(), b"").unwrap();
+
+        assert_eq!(provider.resolve_short_id(&cached_id.short(43)).unwrap(), cached_id);
+        assert_eq!(provider.resolve_short_id(&saved_id.short(30)).unwrap(), saved_id);
+        assert!(matches!(
+            provider.resolve_short_id(&cached_id.short(16)),
+            Err(ProviderError::AmbiguousShortId(_))
+        ));
+        assert!(matches!(
+            provider.resolve_short_id(""),
+            Err(ProviderError::AmbiguousShortId(_))
+        ));
+
+        let mut other_bytes = [0; 32];
+        other_bytes[0] = !id_bytes[0];
+        let other_id = BlockId::from_data(other_bytes);
+        assert!(matches!(
+            provider.resolve_short_id(&other_id.short(8)),
+            Err(ProviderError::UnknownShortId(_))
+        ));
+    }
+
+    /// Make sure that a provider can be shared by threads that add and read blocks simultaneously.
+    #[test]
+    fn concurrent_access() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Provider>();
+
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+        let (shared_id, encrypted_block, shared_block) = random_block();
+        provider
+            .add_block(shared_id, encrypted_block, shared_block.clone())
+            .unwrap();
+
+        let added: Vec<Vec<BlockId>> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut ids = Vec::new();
+                        for _ in 0..16 {
+                            let (id, encrypted_block, block) = random_block();
+                            provider.add_block(id, encrypted_block, block).unwrap();
+                            assert_eq!(
+                                provider.get_block(shared_id).unwrap().unwrap().data(),
+                                shared_block.data()
+                            );
+                            ids.push(id);
+                        }
+                        ids
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+        });
+
+        for id in added.iter().flatten() {
+            assert!(provider.get_block(*id).unwrap().is_some());
+        }
+        assert_eq!(provider.saved_block_ids().unwrap().len(), 1 + 4 * 16);
+    }
+
+    /// Make sure that the stats count saved and cached blocks and bucket them by size.
+    #[test]
+    fn stats() {
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+        assert_eq!(provider.stats().unwrap(), ProviderStats::default());
+
+        let mut ids = Vec::new();
+        for size in [100, 4096, 4096, 16384, 20000] {
+            let mut data = vec![0; size];
+            thread_rng().fill(&mut data[..]);
+            let block = Block::from_data(data.into());
+            let encrypted_block = EncryptedBlock::encrypt(&block, 0);
+            let id = encrypted_block.id(BlockKind::Data).unwrap();
+            provider.add_block(id, encrypted_block, block).unwrap();
+            ids.push(id);
+        }
+        // Cached only, not saved to disk
+        let (cached_id, _, cached_block) = random_block();
+        provider.cache_block(cached_id, cached_block);
+        // Saved to disk only, not cached
+        provider.evict_block(ids[0]);
+
+        let stats = provider.stats().unwrap();
+        assert_eq!(stats.blocks, 6);
+        assert_eq!(stats.cached_blocks, 5);
+        assert_eq!(stats.disk_bytes, 100 + 4096 + 4096 + 16384 + 20000);
+        assert_eq!(
+            stats.block_sizes,
+            BTreeMap::from([
+                (BlockSize::from_marker(0), 4),
+                (BlockSize::from_marker(2), 1),
+                (BlockSize::from_marker(3), 1),
+            ])
+        );
+    }
+
+    /// Make sure that a whole-store audit reports exactly the corrupted blocks.
+    #[test]
+    fn verify_all() {
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+        let ids: Vec<BlockId> = (0..4)
+            .map(|_| {
+                let (id, encrypted_block, block) = random_block();
+                provider.add_block(id, encrypted_block, block).unwrap();
+                id
+            })
+            .collect();
+        assert!(provider.verify_all().unwrap().is_empty());
+
+        let corrupted = ids[2];
+        fs::write(provider.id_to_path(corrupted).unwrap(), vec![1; 4096]).unwrap();
+        let invalid = BlockId::from_slice(&[0xff; 32]).unwrap();
+        fs::write(provider.id_to_path(invalid).unwrap(), vec![0; 4096]).unwrap();
+
+        let damaged = provider.verify_all().unwrap();
+        assert_eq!(damaged.len(), 2);
+        for (id, error) in damaged {
+            match error {
+                IntegrityError::Mismatch(_) => assert_eq!(id, corrupted),
+                IntegrityError::InvalidId => assert_eq!(id, invalid),
+                other => panic!("unexpected error {other}"),
+            }
+        }
+    }
+
+    /// Make sure that prefetched blocks are resident in memory before they're requested.
+    #[test]
+    fn prefetch() {
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+        let ids: Vec<BlockId> = (0..4)
+            .map(|_| {
+                let (id, encrypted_block, block) = random_block();
+                provider.add_block(id, encrypted_block, block).unwrap();
+                id
+            })
+            .collect();
+
+        let provider = Provider::with_base_dir(&base_dir);
+        provider.load_block_from_file(ids[0], 0).unwrap();
+        let (unknown_id, _, _) = random_block();
+        let mut requested = ids.clone();
+        requested.push(ids[1]);
+        requested.push(unknown_id);
+        assert_eq!(provider.prefetch(&requested), 3);
+
+        for id in &ids {
+            assert!(provider.get_block(*id).unwrap().is_some());
+        }
+        assert!(provider.get_block(unknown_id).unwrap().is_none());
+    }
+
+    /// Make sure that blocks are imported from an in-memory store into one backed by files.
+    #[test]
+    fn import_blocks() {
+        let source = Provider::in_memory();
+        let ids: Vec<BlockId> = (0..4)
+            .map(|_| {
+                let (id, encrypted_block, block) = random_block();
+                source.add_block(id, encrypted_block, block).unwrap();
+                id
+            })
+            .collect();
+        // A block stored under the wrong id
+        let (forged_id, _, _) = random_block();
+        let (_, encrypted_block, block) = random_block();
+        source.add_block(forged_id, encrypted_block, block).unwrap();
+        let (unknown_id, _, _) = random_block();
+
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+        let encrypted_block = source.load_encrypted_block(ids[0]).unwrap();
+        provider
+            .add_block(ids[0], encrypted_block, source.get_block(ids[0]).unwrap().unwrap())
+            .unwrap();
+
+        let requested = ids.iter().copied().chain([ids[1], forged_id, unknown_id]);
+        let summary = provider.import_blocks(&source, requested);
+        assert_eq!(summary.imported, 3);
+        assert_eq!(summary.skipped, 2);
+        assert_eq!(summary.failed.len(), 2);
+        assert_eq!(summary.failed[0].0, forged_id);
+        assert!(matches!(summary.failed[0].1, ProviderError::BlockMismatch { expected, .. } if expected == forged_id));
+        assert!(matches!(summary.failed[1], (id, ProviderError::NotSaved(_)) if id == unknown_id));
+
+        let provider = Provider::with_base_dir(&base_dir);
+        for id in &ids {
+            let block = provider.load_block_from_file(*id, 0).unwrap();
+            assert_eq!(block.data(), source.get_block(*id).unwrap().unwrap().data());
+        }
+        assert!(provider.load_block_from_file(forged_id, 0).is_err());
+    }
+
+    /// Make sure that evicted blocks give up their place in the cache order, so that caching them again
+    /// makes them the newest blocks instead of evicting them first.
+    #[test]
+    fn evict_block() {
+        let base_dir = TempDir::new("provider");
+        let mut provider = Provider::with_base_dir(&base_dir);
+        provider.set_cache_capacity(Some(2));
+        let blocks: Vec<(BlockId, EncryptedBlock, Block)> = (0..3).map(|_| random_block()).collect();
+        for (id, encrypted_block, block) in &blocks[..2] {
+            provider.add_block(*id, encrypted_block.clone(), block.clone()).unwrap();
+        }
+
+        let (first_id, _, first_block) = &blocks[0];
+        assert!(provider.evict_block(*first_id).is_some());
+        assert!(provider.evict_block(*first_id).is_none());
+        provider.cache_block(*first_id, first_block.clone());
+        let (id, encrypted_block, block) = &blocks[2];
+        provider.add_block(*id, encrypted_block.clone(), block.clone()).unwrap();
+
+        let cached = provider.blocks.read().unwrap();
+        assert!(cached.contains_key(first_id));
+        assert!(!cached.contains_key(&blocks[1].0));
+        assert!(cached.contains_key(id));
+        assert_eq!(provider.cache_order.lock().unwrap().len(), 2);
+        drop(cached);
+    }
+
+    /// Make sure that blocks are found both in memory and on disk, without loading the saved ones.
+    #[test]
+    fn contains_block() {
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+        let (id, encrypted_block, block) = random_block();
+        provider.add_block(id, encrypted_block, block).unwrap();
+        assert!(provider.contains_block(id));
+
+        // A fresh provider only has the block on disk, which it loads once it's asked for the block
+        let provider = Provider::with_base_dir(&base_dir);
+        assert!(provider.contains_block(id));
+        assert!(!provider.blocks.read().unwrap().contains_key(&id));
+        assert!(provider.get_block(id).unwrap().is_some());
+        assert!(provider.blocks.read().unwrap().contains_key(&id));
+
+        let (absent_id, _, _) = random_block();
+        assert!(!provider.contains_block(absent_id));
+        assert!(!Provider::in_memory().contains_block(id));
+    }
+
+    /// Make sure that the bloom filter of the saved blocks is persisted, reloaded by a fresh provider,
+    /// and rebuilt from the saved blocks if it's gone.
+    #[test]
+    fn bloom_filter() {
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let (id, encrypted_block, block) = random_block();
+            provider.add_block(id, encrypted_block, block).unwrap();
+            ids.push(id);
+        }
+        let bloom_path = base_dir.join("blocks.bloom");
+        assert!(bloom_path.is_file());
+
+        let (absent_id, _, _) = random_block();
+        let reloaded = Provider::with_base_dir(&base_dir);
+        assert!(ids.iter().all(|id| reloaded.contains_block(*id)));
+        assert!(!reloaded.contains_block(absent_id));
+        assert_eq!(reloaded.bloom.lock().unwrap().as_ref().unwrap().len(), 3);
+
+        // Adding a saved block again only counts the reference
+        let (id, encrypted_block, block) = random_block();
+        provider.add_block(id, encrypted_block.clone(), block.clone()).unwrap();
+        let fresh = Provider::with_base_dir(&base_dir);
+        fresh.add_block(id, encrypted_block, block).unwrap();
+        assert_eq!(fresh.reference_count(id), 1);
+        assert_eq!(fresh.bloom.lock().unwrap().as_ref().unwrap().len(), 4);
+        ids.push(id);
+
+        fs::remove_file(&bloom_path).unwrap();
+        let rebuilt = Provider::with_base_dir(&base_dir);
+        assert!(ids.iter().all(|id| rebuilt.contains_block(*id)));
+        assert!(!rebuilt.contains_block(absent_id));
+        assert!(bloom_path.is_file());
+    }
+
+    /// Make sure that written files stay pending until a flush syncs them, except for state files
+    /// that are synced before replacing the old one, and that failing syncs are reported.
+    #[test]
+    fn flush() {
+        let base_dir = TempDir::new("provider");
+        let mut provider = Provider::with_base_dir(&base_dir);
+        let synced = Arc::new(Mutex::new(Vec::new()));
+        let fail = Arc::new(AtomicBool::new(false));
+        let (synced_paths, failing) = (synced.clone(), fail.clone());
+        provider.set_sync(move |path| {
+            if failing.load(Ordering::Relaxed) {
+                return Err(io::Error::other("Simulated sync failure"));
+            }
+            synced_paths.lock().unwrap().push(path.to_path_buf());
+            Ok(())
+        });
+        let (id, encrypted_block, block) = random_block();
+        provider.add_block(id, encrypted_block, block).unwrap();
+        assert!(synced.lock().unwrap().is_empty());
+        let state_path = base_dir.join("vault.db");
+        provider.save_block_id_to_file(id, &state_path).unwrap();
+        assert_eq!(*synced.lock().unwrap(), [temp_path(&state_path)]);
+        assert_eq!(provider.unsynced.lock().unwrap().len(), 2);
+        provider.flush().unwrap();
+        assert!(provider.unsynced.lock().unwrap().is_empty());
+        assert!(synced.lock().unwrap().contains(&provider.id_to_path(id).unwrap()));
+
+        // A state file that fails to sync doesn't replace the old one
+        let (other_id, _, _) = random_block();
+        fail.store(true, Ordering::Relaxed);
+        let result = provider.save_block_id_to_file(other_id, &state_path);
+        fail.store(false, Ordering::Relaxed);
+        assert!(matches!(result, Err(ProviderError::Io(path, _)) if path == temp_path(&state_path)));
+        assert_eq!(provider.load_block_id_from_file(&state_path).unwrap(), id);
+        assert!(!temp_path(&state_path).exists());
+
+        // A failed sync is tried again by the next flush
+        let (id, encrypted_block, block) = random_block();
+        provider.add_block(id, encrypted_block, block).unwrap();
+        fail.store(true, Ordering::Relaxed);
+        let result = provider.flush();
+        fail.store(false, Ordering::Relaxed);
+        assert!(matches!(result, Err(ProviderError::Io(path, _)) if path == provider.id_to_path(id).unwrap()));
+        assert_eq!(provider.unsynced.lock().unwrap().len(), 1);
+        provider.flush().unwrap();
+        assert!(provider.unsynced.lock().unwrap().is_empty());
+
+        // Syncing right away reports the failure when writing
+        provider.set_durability(DurabilityMode::Fsync);
+        let (id, encrypted_block, block) = random_block();
+        fail.store(true, Ordering::Relaxed);
+        let result = provider.add_block(id, encrypted_block, block);
+        fail.store(false, Ordering::Relaxed);
+        assert!(matches!(result, Err(ProviderError::Io(..))));
+
+        // Without durability nothing gets synced
+        provider.set_durability(DurabilityMode::None);
+        let (id, encrypted_block, block) = random_block();
+        provider.add_block(id, encrypted_block, block).unwrap();
+        assert!(provider.unsynced.lock().unwrap().is_empty());
+    }
+
+    /// Make sure that a block whose file is still being written stays in the bloom filter
+    /// when the filter gets rebuilt, either because it's full or by compacting.
+    #[test]
+    fn rebuild_bloom_while_saving() {
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+        let (kept_id, encrypted_block, block) = random_block();
+        provider.add_block(kept_id, encrypted_block, block).unwrap();
+        // Claimed like by another thread that hasn't written the file yet
+        let (saving_id, _, _) = random_block();
+        assert!(provider.record_saved_block(saving_id).unwrap());
+        let might_contain = |id| provider.with_bloom(|bloom| Ok(bloom.might_contain(id))).unwrap();
+
+        // The smallest filter is full after this many blocks, which makes the last ones rebuild it larger
+        let bloom_path = base_dir.join("blocks.bloom");
+        let bloom_size = fs::metadata(&bloom_path).unwrap().len();
+        for _ in 0..1024 {
+            let (id, encrypted_block, block) = random_block();
+            provider.add_block(id, encrypted_block, block).unwrap();
+        }
+        assert!(fs::metadata(&bloom_path).unwrap().len() > bloom_size);
+        assert_eq!(might_contain(saving_id), Some(true));
+
+        provider.compact(&BTreeSet::from([kept_id])).unwrap();
+        assert_eq!(provider.with_bloom(|bloom| Ok(bloom.len())).unwrap(), Some(2));
+        assert_eq!(might_contain(saving_id), Some(true));
+        assert_eq!(might_contain(kept_id), Some(true));
+    }
+
+    /// Make sure that blocks added as a unit are all saved, and that a failed write leaves none of them behind.
+    #[test]
+    fn add_blocks() {
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+        let entries: Vec<(BlockId, EncryptedBlock)> = (0..3)
+            .map(|_| {
+                let (id, encrypted_block, _) = random_block();
+                (id, encrypted_block)
+            })
+            .collect();
+        provider.add_blocks(&entries).unwrap();
+        for (id, encrypted_block) in &entries {
+            assert_eq!(provider.reference_count(*id), 1);
+            let loaded = Provider::with_base_dir(&base_dir).load_block_from_file(*id, 0).unwrap();
+            assert_eq!(loaded.data(), encrypted_block.decrypt(0).data());
+        }
+        assert_eq!(provider.saved_block_ids().unwrap().len(), 3);
+
+        // A directory in the way of the last temporary file makes writing it fail
+        let provider = Provider::with_base_dir(&base_dir);
+        let mut entries: Vec<(BlockId, EncryptedBlock)> = (0..3)
+            .map(|_| {
+                let (id, encrypted_block, _) = random_block();
+                (id, encrypted_block)
+            })
+            .collect();
+        let blocked_path = temp_path(&provider.id_to_path(entries[2].0).unwrap());
+        fs::create_dir(&blocked_path).unwrap();
+        entries.push(entries[0].clone());
+        assert!(matches!(provider.add_blocks(&entries), Err(ProviderError::Io(path, _)) if path == blocked_path));
+        for (id, _) in &entries {
+            assert!(!provider.contains_block(*id));
+            assert_eq!(provider.reference_count(*id), 0);
+        }
+        fs::remove_dir(&blocked_path).unwrap();
+        let files = fs::read_dir(&base_dir).unwrap().count();
+        // Three blocks and the bloom filter
+        assert_eq!(files, 4);
+
+        // A directory in the way of the last block file makes renaming it fail, after the others were renamed
+        let mut provider = Provider::with_base_dir(&base_dir);
+        provider.set_disk_budget(Some(6 * 4096));
+        let entries: Vec<(BlockId, EncryptedBlock)> = (0..3)
+            .map(|_| {
+                let (id, encrypted_block, _) = random_block();
+                (id, encrypted_block)
+            })
+            .collect();
+        // Makes the provider measure its disk usage while nothing is in the way
+        provider.add_blocks(&[]).unwrap();
+        let blocked_path = provider.id_to_path(entries[2].0).unwrap();
+        fs::create_dir_all(blocked_path.join("in-the-way")).unwrap();
+        assert!(matches!(provider.add_blocks(&entries), Err(ProviderError::Io(path, _)) if path == blocked_path));
+        for (id, _) in &entries {
+            assert!(!provider.contains_block(*id));
+        }
+        fs::remove_dir_all(&blocked_path).unwrap();
+        assert_eq!(fs::read_dir(&base_dir).unwrap().count(), 4);
+        // The space reserved for the blocks was released again
+        provider.add_blocks(&entries).unwrap();
+    }
+
+    /// Make sure that compacting deletes the dead blocks, keeps the live ones readable and forgets the dead ids.
+    #[test]
+    fn compact() {
+        let base_dir = TempDir::new("provider");
+        let provider = Provider::with_base_dir(&base_dir);
+        let mut ids = Vec::new();
+        for _ in 0..4 {
+            let (id, encrypted_block, block) = random_block();
+            provider.add_block(id, encrypted_block, block).unwrap();
+            ids.push(id);
+        }
+        let live: BTreeSet<BlockId> = ids[..2].iter().copied().collect();
+        let disk_bytes = provider.stats().unwrap().disk_bytes;
+
+        let freed = provider.compact(&live).unwrap();
+        assert!(freed >= 2 * 4096);
+        assert_eq!(provider.stats().unwrap().disk_bytes, disk_bytes - freed);
+        assert_eq!(provider.compact(&live).unwrap(), 0);
+
+        let reloaded = Provider::with_base_dir(&base_dir);
+        for id in &live {
+            assert!(reloaded.load_block_from_file(*id, 0).is_ok());
+        }
+        assert!(!reloaded.contains_block(ids[2]));
+        assert!(!reloaded.contains_block(ids[3]));
+        assert_eq!(reloaded.bloom.lock().unwrap().as_ref().unwrap().len(), 2);
+        assert_eq!(
+            fs::read_dir(&base_dir).unwrap().count(),
+            3,
+            "only the live blocks and the filter are left"
+        );
     }
 }
@@ -0,0 +1,387 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::{BlockId, BlockStore, EncryptedBlock, Provider, ProviderError, MAX_BLOCK_SIZE};
+
+/// Request for the block with the id that follows.
+pub(crate) const OP_GET: u8 = 1;
+/// Request to store the block with the id, length and contents that follow.
+const OP_PUT: u8 = 2;
+/// Request whether the service has the block with the id that follows.
+const OP_CONTAINS: u8 = 3;
+
+/// How long a [`RemoteBlockStore`] waits for the service to accept its connection or to answer, by default.
+pub const DEFAULT_REMOTE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Response status for success, followed by the length and contents of the block in response to [`OP_GET`].
+const STATUS_OK: u8 = 0;
+/// Response status for a block that the service doesn't have.
+const STATUS_NOT_FOUND: u8 = 1;
+/// Response status for a request that the service doesn't accept, such as storing blocks on a read-only peer.
+const STATUS_REFUSED: u8 = 2;
+
+/// Client of a remote exomem service that stores encrypted blocks.
+///
+/// Every request starts with an operation byte followed by the 32 byte block id.
+/// Stored blocks follow with their length as a little endian `u32` and then their contents.
+/// Every response is a single status byte, followed by the length and contents of the block for fetches.
+///
+/// Requests are sent one at a time over a single connection. After a network error the connection
+/// is in an unknown state, so it's dropped and the next request connects again.
+pub struct RemoteBlockStore {
+    addrs: Vec<SocketAddr>,
+    timeout: Duration,
+    /// The connection to the service, or `None` if the last request broke it.
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl RemoteBlockStore {
+    /// Create a new `RemoteBlockStore` connected to the service at `addr`, with the [`DEFAULT_REMOTE_TIMEOUT`].
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<RemoteBlockStore, ProviderError> {
+        RemoteBlockStore::connect_timeout(addr, DEFAULT_REMOTE_TIMEOUT)
+    }
+
+    /// Create a new `RemoteBlockStore` connected to the service at `addr`,
+    /// which gives up on connecting, sending and receiving after `timeout`.
+    pub fn connect_timeout(addr: impl ToSocketAddrs, timeout: Duration) -> Result<RemoteBlockStore, ProviderError> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs().map_err(ProviderError::Remote)?.collect();
+        let stream = open(&addrs, timeout)?;
+        Ok(RemoteBlockStore {
+            addrs,
+            timeout,
+            stream: Mutex::new(Some(stream)),
+        })
+    }
+
+    /// Returns `true` if the service has the block with the specified `id`.
+    pub fn contains_block(&self, id: BlockId) -> Result<bool, ProviderError> {
+        match self.exchange(&request(OP_CONTAINS, id), false)? {
+            (STATUS_OK, _) => Ok(true),
+            (STATUS_NOT_FOUND, _) => Ok(false),
+            (status, _) => Err(unexpected_status(status)),
+        }
+    }
+
+    /// Sends the `request` and returns the status of the response, along with the block if it's a fetch that succeeded.
+    ///
+    /// Connects again first if an earlier request broke the connection.
+    fn exchange(&self, request: &[u8], fetch: bool) -> Result<(u8, Option<Vec<u8>>), ProviderError> {
+        let mut stream = self.stream.lock().unwrap();
+        let connection = match stream.as_mut() {
+            Some(connection) => connection,
+            None => stream.insert(open(&self.addrs, self.timeout)?),
+        };
+        let response = exchange(connection, request, fetch);
+        if response.is_err() {
+            *stream = None;
+        }
+        response.map_err(ProviderError::Remote)
+    }
+}
+
+impl BlockStore for RemoteBlockStore {
+    /// Fetches the block from the service, failing with [`ProviderError::RemoteNotFound`] if it doesn't have it.
+    fn load_encrypted_block(&self, id: BlockId) -> Result<EncryptedBlock, ProviderError> {
+        match self.exchange(&request(OP_GET, id), true)? {
+            (_, Some(data)) => Ok(EncryptedBlock::from_data(data.into())),
+            (STATUS_NOT_FOUND, _) => Err(ProviderError::RemoteNotFound(id)),
+            (status, _) => Err(unexpected_status(status)),
+        }
+    }
+
+    /// Stores the `encrypted_block` with the specified `id` on the service.
+    fn store_encrypted_block(&self, id: BlockId, encrypted_block: &EncryptedBlock) -> Result<(), ProviderError> {
+        let data = encrypted_block.data();
+        let mut request = Vec::with_capacity(1 + 32 + 4 + data.len());
+        request.push(OP_PUT);
+        request.extend_from_slice(id.data());
+        request.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        request.extend_from_slice(&data);
+        match self.exchange(&request, false)? {
+            (STATUS_OK, _) => Ok(()),
+            (status, _) => Err(unexpected_status(status)),
+        }
+    }
+}
+
+/// Answers the requests arriving over `stream` with the blocks of `provider` until the client disconnects.
+///
+/// Blocks are only served, requests to store them are refused.
+pub(crate) fn serve_read_only(mut stream: TcpStream, provider: &Provider) -> io::Result<()> {
+    let mut header = [0; 1 + 32];
+    loop {
+        match stream.read_exact(&mut header) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let id = BlockId::from_slice(&header[1..]).unwrap();
+        match header[0] {
+            OP_GET => match provider.load_encrypted_block(id) {
+                Ok(encrypted_block) => {
+                    let data = encrypted_block.data();
+                    stream.write_all(&[STATUS_OK])?;
+                    stream.write_all(&(data.len() as u32).to_le_bytes())?;
+                    stream.write_all(&data)?;
+                }
+                Err(_) => stream.write_all(&[STATUS_NOT_FOUND])?,
+            },
+            OP_PUT => {
+                let len = read_len(&mut stream)?;
+                io::copy(&mut (&mut stream).take(len as u64), &mut io::sink())?;
+                stream.write_all(&[STATUS_REFUSED])?;
+            }
+            OP_CONTAINS => {
+                let status = if provider.contains_block(id) {
+                    STATUS_OK
+                } else {
+                    STATUS_NOT_FOUND
+                };
+                stream.write_all(&[status])?;
+            }
+            op => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unexpected operation {op}"),
+                ));
+            }
+        }
+    }
+}
+
+/// Returns a request of the operation `op` that only consists of the block `id`.
+pub(crate) fn request(op: u8, id: BlockId) -> Vec<u8> {
+    let mut request = Vec::with_capacity(1 + 32);
+    request.push(op);
+    request.extend_from_slice(id.data());
+    request
+}
+
+/// Connects to the first of the `addrs` that accepts within `timeout`, which then also limits every send and receive.
+fn open(addrs: &[SocketAddr], timeout: Duration) -> Result<TcpStream, ProviderError> {
+    let mut error = io::Error::new(ErrorKind::InvalidInput, "No address to connect to");
+    for addr in addrs {
+        let stream = TcpStream::connect_timeout(addr, timeout).and_then(|stream| {
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
+            stream.set_nodelay(true)?;
+            Ok(stream)
+        });
+        match stream {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = e,
+        }
+    }
+    Err(ProviderError::Remote(error))
+}
+
+/// Sends the `request` over `stream` and returns the status of the response,
+/// followed by the block if `fetch` is set and the status is [`STATUS_OK`].
+pub(crate) fn exchange(stream: &mut TcpStream, request: &[u8], fetch: bool) -> io::Result<(u8, Option<Vec<u8>>)> {
+    let mut status = [0];
+    stream.write_all(request)?;
+    stream.read_exact(&mut status)?;
+    match status[0] {
+        STATUS_OK if fetch => {
+            let mut data = vec![0; read_len(stream)?];
+            stream.read_exact(&mut data)?;
+            Ok((STATUS_OK, Some(data)))
+        }
+        STATUS_OK | STATUS_NOT_FOUND | STATUS_REFUSED => Ok((status[0], None)),
+        // Whatever follows an unknown status can't be skipped
+        status => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Unexpected response status {status}"),
+        )),
+    }
+}
+
+/// Reads the length of a block, refusing lengths larger than the largest [`BlockSize`](crate::BlockSize).
+fn read_len(reader: &mut impl Read) -> io::Result<usize> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    if len > MAX_BLOCK_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Block length {len} is too large"),
+        ));
+    }
+    Ok(len as usize)
+}
+
+/// Returns the error for a response status that doesn't fit the request.
+fn unexpected_status(status: u8) -> ProviderError {
+    ProviderError::Remote(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Unexpected response status {status}"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+    use crate::{Block, BlockKind, Provider};
+
+    /// Serves blocks from memory to one connection after another on a loopback port, returning the port.
+    fn mock_server(blocks: HashMap<BlockId, Vec<u8>>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut blocks = blocks;
+            for stream in listener.incoming() {
+                serve_mock(stream.unwrap(), &mut blocks);
+            }
+        });
+        port
+    }
+
+    /// Answers the requests arriving over `stream` with the `blocks` until the client disconnects.
+    fn serve_mock(mut stream: TcpStream, blocks: &mut HashMap<BlockId, Vec<u8>>) {
+        let mut header = [0; 33];
+        while stream.read_exact(&mut header).is_ok() {
+            let id = BlockId::from_slice(&header[1..]).unwrap();
+            match header[0] {
+                OP_GET => match blocks.get(&id) {
+                    Some(data) => {
+                        stream.write_all(&[STATUS_OK]).unwrap();
+                        stream.write_all(&(data.len() as u32).to_le_bytes()).unwrap();
+                        stream.write_all(data).unwrap();
+                    }
+                    None => stream.write_all(&[STATUS_NOT_FOUND]).unwrap(),
+                },
+                OP_PUT => {
+                    let mut data = vec![0; read_len(&mut stream).unwrap()];
+                    stream.read_exact(&mut data).unwrap();
+                    blocks.insert(id, data);
+                    stream.write_all(&[STATUS_OK]).unwrap();
+                }
+                OP_CONTAINS => {
+                    let status = if blocks.contains_key(&id) {
+                        STATUS_OK
+                    } else {
+                        STATUS_NOT_FOUND
+                    };
+                    stream.write_all(&[status]).unwrap();
+                }
+                op => panic!("Unexpected operation {op}"),
+            }
+        }
+    }
+
+    fn random_block() -> (BlockId, EncryptedBlock) {
+        let mut data = vec![0; 4096];
+        thread_rng().fill(&mut data[..]);
+        let encrypted_block = EncryptedBlock::encrypt(&Block::from_data(data.into()), 0);
+        (encrypted_block.id(BlockKind::Data).unwrap(), encrypted_block)
+    }
+
+    /// Make sure that blocks can be stored, checked for and fetched over a loopback socket.
+    #[test]
+    fn get_put_contains() {
+        let port = mock_server(HashMap::new());
+        let store = RemoteBlockStore::connect(("127.0.0.1", port)).unwrap();
+        let (id, encrypted_block) = random_block();
+
+        assert!(!store.contains_block(id).unwrap());
+        assert!(matches!(
+            store.load_encrypted_block(id),
+            Err(ProviderError::RemoteNotFound(missing)) if missing == id
+        ));
+
+        store.store_encrypted_block(id, &encrypted_block).unwrap();
+        assert!(store.contains_block(id).unwrap());
+        assert_eq!(store.load_encrypted_block(id).unwrap().data(), encrypted_block.data());
+    }
+
+    /// Make sure that a provider fetches uncached blocks from the remote store, but only if they match their id.
+    #[test]
+    fn provider_fallback() {
+        let (id, encrypted_block) = random_block();
+        let (corrupted_id, _) = random_block();
+        let blocks = HashMap::from([
+            (id, encrypted_block.data().to_vec()),
+            (corrupted_id, encrypted_block.data().to_vec()),
+        ]);
+        let mut provider = Provider::in_memory();
+        provider.set_remote(RemoteBlockStore::connect(("127.0.0.1", mock_server(blocks))).unwrap());
+
+        assert_eq!(provider.get_block(id).unwrap().unwrap().data(), encrypted_block.data());
+        assert!(provider.evict_block(id).is_some(), "fetched blocks are cached");
+        assert!(matches!(
+            provider.get_block(corrupted_id),
+            Err(ProviderError::BlockMismatch { expected, .. }) if expected == corrupted_id
+        ));
+        let (missing_id, _) = random_block();
+        assert!(provider.get_block(missing_id).unwrap().is_none());
+
+        // Batched lookups and prefetching fetch from the remote store too
+        assert_eq!(provider.get_blocks(&[id]).unwrap()[0].data(), encrypted_block.data());
+        assert!(provider.evict_block(id).is_some());
+        assert_eq!(provider.prefetch(&[id, missing_id]), 1);
+        assert!(provider.evict_block(id).is_some());
+        assert!(matches!(
+            provider.get_blocks(&[id, missing_id]),
+            Err(ProviderError::RemoteNotFound(missing)) if missing == missing_id
+        ));
+    }
+
+    /// Make sure that a service that doesn't answer makes requests time out instead of blocking forever.
+    #[test]
+    fn timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let store =
+            RemoteBlockStore::connect_timeout(listener.local_addr().unwrap(), Duration::from_millis(50)).unwrap();
+        let (_stream, _) = listener.accept().unwrap();
+        let (id, _) = random_block();
+        match store.contains_block(id) {
+            Err(ProviderError::Remote(e)) => assert!(matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)),
+            result => panic!("Expected ProviderError::Remote, got {result:?}"),
+        }
+    }
+
+    /// Make sure that a broken connection fails the request that broke it, and the next request connects again.
+    #[test]
+    fn reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (id, encrypted_block) = random_block();
+        let mut blocks = HashMap::from([(id, encrypted_block.data().to_vec())]);
+        thread::spawn(move || {
+            let mut incoming = listener.incoming();
+            // The first connection is dropped right away
+            drop(incoming.next().unwrap().unwrap());
+            serve_mock(incoming.next().unwrap().unwrap(), &mut blocks);
+        });
+        let store = RemoteBlockStore::connect(("127.0.0.1", port)).unwrap();
+
+        assert!(matches!(store.load_encrypted_block(id), Err(ProviderError::Remote(_))));
+        assert_eq!(store.load_encrypted_block(id).unwrap().data(), encrypted_block.data());
+    }
+}
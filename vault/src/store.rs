@@ -0,0 +1,237 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::sync::Arc;
+
+use crate::{Blake3, Block, BlockId, EncryptedBlock, Hasher, NoSuchTier, ProviderError};
+
+/// Source of encrypted blocks, such as another [`Provider`](crate::Provider).
+///
+/// Check out [`Provider::import_blocks`](crate::Provider::import_blocks) for copying blocks between stores.
+pub trait BlockStore {
+    /// Returns the encrypted block with the specified `id`, without verifying that its contents match the id.
+    fn load_encrypted_block(&self, id: BlockId) -> Result<EncryptedBlock, ProviderError>;
+
+    /// Stores the `encrypted_block` with the specified `id`.
+    ///
+    /// Stores are read-only unless they say otherwise, refusing with [`ProviderError::ReadOnlyStore`].
+    fn store_encrypted_block(&self, id: BlockId, _encrypted_block: &EncryptedBlock) -> Result<(), ProviderError> {
+        Err(ProviderError::ReadOnlyStore(id))
+    }
+}
+
+/// Ordered list of block stores that are tried in turn, such as memory, disk, LAN peers and a remote service.
+///
+/// A block found in a lower tier is promoted into all the tiers above it, so that the next load
+/// finds it sooner. Found blocks are checked against their id, and a tier with a block that doesn't match
+/// is passed over like one that doesn't have it. New blocks are stored in the primary tier only.
+pub struct TieredStore {
+    /// The stores from the highest tier to the lowest.
+    tiers: Vec<Arc<dyn BlockStore + Send + Sync>>,
+    /// The index of the tier that new blocks are stored in.
+    primary: usize,
+    /// Computes the ids of blocks, to check the found ones.
+    hasher: Box<dyn Hasher>,
+}
+
+impl TieredStore {
+    /// Create a new `TieredStore` without any tiers, which doesn't find any blocks.
+    pub fn new() -> TieredStore {
+        TieredStore {
+            tiers: Vec::new(),
+            primary: 0,
+            hasher: Box::new(Blake3),
+        }
+    }
+
+    /// Adds the `store` as the lowest tier, below all the tiers added so far.
+    pub fn push(&mut self, store: Arc<dyn BlockStore + Send + Sync>) {
+        self.tiers.push(store);
+    }
+
+    /// Adds the `store` as the tier at `index`, above the tiers from `index` on.
+    ///
+    /// The primary tier stays the same store. Panics if `index` is past the number of tiers, like [`Vec::insert`].
+    pub fn insert(&mut self, index: usize, store: Arc<dyn BlockStore + Send + Sync>) {
+        self.tiers.insert(index, store);
+        if index <= self.primary && self.tiers.len() > 1 {
+            self.primary += 1;
+        }
+    }
+
+    /// Sets the tier that new blocks are stored in by its index, which is the highest tier by default.
+    pub fn set_primary(&mut self, index: usize) -> Result<(), NoSuchTier> {
+        if index >= self.tiers.len() {
+            return Err(NoSuchTier(index));
+        }
+        self.primary = index;
+        Ok(())
+    }
+
+    /// Sets the [`Hasher`] that block ids are computed by, which is [`Blake3`] by default.
+    pub fn set_hasher(&mut self, hasher: impl Hasher + 'static) {
+        self.hasher = Box::new(hasher);
+    }
+
+    /// Returns the number of tiers.
+    pub fn len(&self) -> usize {
+        self.tiers.len()
+    }
+
+    /// Returns `true` if there are no tiers.
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+}
+
+impl Default for TieredStore {
+    fn default() -> TieredStore {
+        TieredStore::new()
+    }
+}
+
+impl BlockStore for TieredStore {
+    /// Returns the block from the highest tier that has it, promoting it into the tiers above.
+    ///
+    /// If no tier has it, fails with the error of the first tier that failed for another reason than not having
+    /// the block, or else with the error of the lowest tier. Fails with [`ProviderError::NotSaved`] without tiers.
+    fn load_encrypted_block(&self, id: BlockId) -> Result<EncryptedBlock, ProviderError> {
+        let mut error: Option<ProviderError> = None;
+        for (i, tier) in self.tiers.iter().enumerate() {
+            let found = tier.load_encrypted_block(id).and_then(|encrypted_block| {
+                let actual = encrypted_block
+                    .id_with(Block::kind_from_id(id), self.hasher.as_ref())
+                    .map_err(|e| ProviderError::InvalidBlockSize(id, e))?;
+                if actual != id {
+                    return Err(ProviderError::BlockMismatch { expected: id, actual });
+                }
+                Ok(encrypted_block)
+            });
+            match found {
+                Ok(encrypted_block) => {
+                    for higher in &self.tiers[..i] {
+                        // Promoting is only an optimization, the block was found either way
+                        let _ = higher.store_encrypted_block(id, &encrypted_block);
+                    }
+                    return Ok(encrypted_block);
+                }
+                Err(e) => match &error {
+                    Some(kept) if !kept.is_not_found() => (),
+                    _ => error = Some(e),
+                },
+            }
+        }
+        Err(error.unwrap_or(ProviderError::NotSaved(id)))
+    }
+
+    /// Stores the block in the primary tier, failing with [`ProviderError::NotSaved`] without tiers.
+    fn store_encrypted_block(&self, id: BlockId, encrypted_block: &EncryptedBlock) -> Result<(), ProviderError> {
+        match self.tiers.get(self.primary) {
+            Some(primary) => primary.store_encrypted_block(id, encrypted_block),
+            None => Err(ProviderError::NotSaved(id)),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std-fs"))]
+mod tests {
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use crate::{BlockKind, Provider};
+
+    /// Make sure that a block only saved on disk is promoted into the memory tier once it's loaded,
+    /// and that new blocks go to the primary tier.
+    #[test]
+    fn promote() {
+        let base_dir = TempDir::new("tiered");
+        let mut data = vec![0; 4096];
+        thread_rng().fill(&mut data[..]);
+        let block = Block::from_data(data.into());
+        let encrypted_block = EncryptedBlock::encrypt(&block, 0);
+        let id = encrypted_block.id(BlockKind::Data).unwrap();
+        Provider::with_base_dir(&base_dir)
+            .add_block(id, encrypted_block.clone(), block)
+            .unwrap();
+
+        let memory = Arc::new(Provider::in_memory());
+        let disk = Arc::new(Provider::with_base_dir(&base_dir));
+        let mut store = TieredStore::new();
+        store.push(memory.clone());
+        store.push(disk.clone());
+        assert!(!memory.contains_block(id));
+        assert_eq!(store.load_encrypted_block(id).unwrap().data(), encrypted_block.data());
+        assert!(memory.contains_block(id));
+        assert!(matches!(
+            store.load_encrypted_block(BlockId::from_data([0; 32])),
+            Err(ProviderError::Io(..))
+        ));
+
+        assert_eq!(store.set_primary(2), Err(NoSuchTier(2)));
+        store.set_primary(1).unwrap();
+        let mut data = vec![0; 4096];
+        thread_rng().fill(&mut data[..]);
+        let encrypted_block = EncryptedBlock::encrypt(&Block::from_data(data.into()), 0);
+        let id = encrypted_block.id(BlockKind::Data).unwrap();
+        store.store_encrypted_block(id, &encrypted_block).unwrap();
+        assert!(!memory.contains_block(id));
+        assert!(Provider::with_base_dir(&base_dir).contains_block(id));
+
+        // Inserting a tier above the primary one keeps storing new blocks in the same store
+        let top = Arc::new(Provider::in_memory());
+        store.insert(0, top.clone());
+        assert_eq!(store.len(), 3);
+        let mut data = vec![0; 4096];
+        thread_rng().fill(&mut data[..]);
+        let encrypted_block = EncryptedBlock::encrypt(&Block::from_data(data.into()), 0);
+        let id = encrypted_block.id(BlockKind::Data).unwrap();
+        store.store_encrypted_block(id, &encrypted_block).unwrap();
+        assert!(!top.contains_block(id) && !memory.contains_block(id));
+        assert!(Provider::with_base_dir(&base_dir).contains_block(id));
+    }
+
+    /// Make sure that a tier with a block that doesn't match its id is passed over for the next tier,
+    /// and that its mismatch is reported if no tier has the block.
+    #[test]
+    fn skip_mismatch() {
+        let mut data = vec![0; 4096];
+        thread_rng().fill(&mut data[..]);
+        let block = Block::from_data(data.into());
+        let encrypted_block = EncryptedBlock::encrypt(&block, 0);
+        let id = encrypted_block.id(BlockKind::Data).unwrap();
+        let forged = Block::from_data(vec![1; 4096].into());
+
+        let forging = Arc::new(Provider::in_memory());
+        forging
+            .add_block(id, EncryptedBlock::encrypt(&forged, 0), forged)
+            .unwrap();
+        let honest = Arc::new(Provider::in_memory());
+        let mut store = TieredStore::new();
+        store.push(forging);
+        store.push(honest.clone());
+        assert!(matches!(
+            store.load_encrypted_block(id),
+            Err(ProviderError::BlockMismatch { expected, .. }) if expected == id
+        ));
+
+        honest.add_block(id, encrypted_block.clone(), block).unwrap();
+        assert_eq!(store.load_encrypted_block(id).unwrap().data(), encrypted_block.data());
+    }
+}
@@ -0,0 +1,63 @@
+/*
+    Copyright 2023 OÜ Nevermore <strom@nevermore.ee>
+
+    This file is part of exomem.
+
+    Exomem is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as
+    published by the Free Software Foundation, either version 3 of the
+    License, or (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use rand::{thread_rng, Rng};
+
+/// Uniquely named directory in the OS temporary directory for tests, deleted along with its contents on drop.
+///
+/// The directory itself isn't created up front, as providers create their base directory on their own.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create a new `TempDir` whose name starts with `exomem-` followed by `name`.
+    pub(crate) fn new(name: &str) -> TempDir {
+        TempDir(std::env::temp_dir().join(format!("exomem-{name}-{}", thread_rng().gen::<u64>())))
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl From<&TempDir> for PathBuf {
+    fn from(dir: &TempDir) -> PathBuf {
+        dir.0.clone()
+    }
+}
+
+impl Drop for TempDir {
+    /// Deletes the directory, which may not exist if the test failed early.
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -17,252 +17,5020 @@
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+#[cfg(feature = "std-fs")]
+use std::fs;
+#[cfg(feature = "std-fs")]
 use std::io;
-use std::path::Component;
+use std::io::Read;
+#[cfg(feature = "std-fs")]
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
 
+use rayon::prelude::*;
+
+use crate::Block;
 use crate::BlockId;
 use crate::BlockKind;
+use crate::BlockSize;
+use crate::ChunkStrategy;
+use crate::DirEntry;
+use crate::DirectoryBuilder;
 use crate::EncryptedBlock;
 use crate::File;
+use crate::FileOffset;
+use crate::FileSize;
 use crate::InfoBlock;
+use crate::IntegrityError;
+use crate::Layout;
 use crate::NodeKind;
+use crate::NodeStat;
 use crate::Provider;
+use crate::ProviderError;
+use crate::RelativeVaultPath;
+use crate::Timestamp;
+use crate::UnsupportedBlock;
+use crate::VaultError;
+use crate::VaultEvent;
 use crate::VaultPath;
+use crate::DIRECTORY_CONTINUATION;
+use crate::MAX_BLOCK_SIZE;
+use crate::MAX_FILE_SIZE;
+
+use crate::history::{History, MAX_HISTORY_LEN};
+use crate::journal::Journal;
+
+/// The most bytes of data blocks that are hashed at once while storing a file, which bounds the memory used.
+const MAX_BATCH_LEN: usize = 256 * 1024 * 1024;
+
+/// The most symlinks that are followed while resolving a single path, before giving up on it as a loop.
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// Tells the current time, which nodes are stamped with as they are created and changed.
+pub type Clock = dyn Fn() -> Timestamp + Send + Sync;
+
+/// Returns the clock that vaults start out with, which is the system clock where there is one.
+fn default_clock() -> Option<Box<Clock>> {
+    // The system clock panics on the web, where the embedder has to set a clock of its own
+    if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+        return None;
+    }
+    Some(Box::new(Timestamp::now))
+}
+
+/// Determines what a [`Vault`] does when a block it needs is missing from the [`Provider`].
+#[derive(Clone, Copy, Default)]
+pub enum MissingBlockPolicy<'a> {
+    /// Fail the operation with [`VaultError::MissingBlock`].
+    #[default]
+    Error,
+    /// Skip the missing block and continue, treating its contents as empty.
+    ///
+    /// Useful for lossy listing of a partially available vault.
+    /// Mutating operations still fail because they can't be completed without the block.
+    Skip,
+    /// Attempt to recover the block from a backup [`Provider`], failing if it doesn't have it either.
+    ///
+    /// Blocks that the primary provider fails to load, such as damaged ones, are recovered as well.
+    /// A recovered block is checked against its id and added to the primary provider,
+    /// which saves it to disk if the provider has a base directory.
+    Recover(&'a Provider),
+}
+
+/// What [`Vault::recover`] did about an interrupted mutation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recovery {
+    /// There was no interrupted mutation.
+    Clean,
+    /// All the blocks of the mutation had been written, so the state file now points to this vault block.
+    RolledForward(BlockId),
+    /// The mutation didn't get to write all its blocks, so the state file was left as is.
+    ///
+    /// The blocks that did get written are unreferenced and can be collected as garbage.
+    RolledBack,
+}
+
+/// Summary of a [`Vault::put`] or [`Vault::put_reader`] operation.
+#[derive(Debug)]
+pub struct PutOutcome {
+    /// The path of the newly added file in the vault.
+    pub file: VaultPath,
+    /// The number of data blocks that were written to the provider.
+    pub blocks_written: usize,
+    /// The number of data blocks that the provider already had, and were thus reused.
+    pub blocks_deduped: usize,
+    /// The number of data blocks that were all zeros, and were thus stored as holes.
+    pub holes: usize,
+}
+
+/// Summary of a [`Vault::import`] operation.
+#[derive(Debug, Default)]
+pub struct ImportOutcome {
+    /// The number of files that were added to the vault.
+    pub files: usize,
+    /// The number of directories that were added to the vault or already existed.
+    pub directories: usize,
+    /// The OS paths that were skipped, such as symbolic links and unreadable files, along with the reason.
+    pub skipped: Vec<(PathBuf, VaultError)>,
+}
+
+/// Disk usage of a subtree, as computed by [`Vault::du`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// The sum of the sizes of all the files in bytes, counting every copy of a file separately.
+    pub logical: u64,
+    /// The number of bytes in the distinct data blocks of all the files, counting shared blocks only once.
+    /// Holes aren't stored, so they don't count.
+    pub physical: u64,
+}
+
+/// The saved blocks that [`Vault::gc`] deleted, or would delete on a dry run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcOutcome {
+    /// The number of blocks.
+    pub blocks: usize,
+    /// The number of bytes the blocks take up on disk.
+    pub bytes: u64,
+}
+
+/// A difference between two snapshots of a vault, as computed by [`Vault::diff`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// A node exists at the path only in the newer snapshot. Its descendants aren't listed separately.
+    Added(VaultPath),
+    /// A node exists at the path only in the older snapshot. Its descendants aren't listed separately.
+    Removed(VaultPath),
+    /// The file at the path exists in both snapshots, but with different contents.
+    Modified(VaultPath),
+}
+
+/// The changes that [`Vault::create_directory`] would make, as computed by [`Vault::plan_create_directory`].
+pub struct DirectoryPlan {
+    /// The directories that would be created, from the outermost to the innermost.
+    pub created: Vec<VaultPath>,
+    /// The number of blocks that would be written, including the new vault block.
+    pub blocks_to_write: usize,
+    /// The existing files whose contents would change.
+    modified: Vec<VaultPath>,
+    /// The existing nodes that would be removed along with everything below them.
+    removed: Vec<VaultPath>,
+    /// The pending changes to the blocks along the path, `None` means the node is inlined in the parent's block.
+    blocks: Vec<Option<DirectoryBuilder>>,
+    /// The entry names along the path, starting with the empty name of the root.
+    entry_names: Vec<String>,
+    /// The node indexes along the path, local to their block.
+    node_indexes: Vec<u32>,
+}
 
 pub struct Vault<'a> {
-    path: PathBuf,
+    /// The path of the state file, or `None` if the vault state is only kept in memory.
+    path: Option<PathBuf>,
     provider: &'a Provider,
     vault: InfoBlock,
     root: InfoBlock,
     root_id: BlockId,
     index: InfoBlock,
+    /// The id of the current vault block.
+    vault_id: BlockId,
+    /// The ids of the vault blocks that preceded the current one, oldest first.
+    history: Vec<BlockId>,
+    missing_block_policy: MissingBlockPolicy<'a>,
+    subscribers: Vec<Sender<VaultEvent>>,
+    /// The current directory that relative paths are resolved against.
+    cwd: VaultPath,
+    /// The size that directory blocks are split at.
+    max_directory_block_size: BlockSize,
+    /// The clock that new and changed nodes are stamped with, or `None` if they aren't stamped.
+    clock: Option<Box<Clock>>,
 }
 
 impl<'a> Vault<'a> {
-    pub fn open(provider: &'a Provider, path: impl Into<PathBuf>) -> Vault<'a> {
+    #[cfg(feature = "std-fs")]
+    pub fn open(provider: &'a Provider, path: impl Into<PathBuf>) -> Result<Vault<'a>, VaultError> {
         let path = path.into();
-        let vault_id = Provider::load_block_id_from_file(path.clone());
+        let recovery = Vault::recover(provider, path.clone())?;
+        if recovery != Recovery::Clean {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(?recovery, "Recovered an interrupted mutation");
+        }
+        let vault_id = match provider.load_block_id_from_file(path.clone()) {
+            Err(ProviderError::Io(_, e)) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(VaultError::MissingState(path));
+            }
+            result => result?,
+        };
 
-        println!("Opening vault starting at block {}", vault_id.base64());
+        #[cfg(feature = "tracing")]
+        tracing::info!(vault_id = %vault_id.base64(), "Opening vault");
 
-        let vault_block = provider.load_block_from_file(vault_id, 0).info();
+        let vault_block = provider
+            .load_block_from_file(vault_id, 0)
+            .map_err(|e| VaultError::UnreadableVaultBlock(vault_id, e))?
+            .info()?;
 
         let (root_id, index_id) = vault_block.get_root_id_and_index_id();
 
-        let root_block = provider.load_block_from_file(root_id, 0).info();
-        let index_block = provider.load_block_from_file(index_id, 0).info();
+        let root_block = provider
+            .load_block_from_file(root_id, 0)
+            .map_err(|e| VaultError::DanglingRoot(root_id, e))?
+            .info()?;
+        let index_block = provider
+            .load_block_from_file(index_id, 0)
+            .map_err(|e| VaultError::DanglingIndex(index_id, e))?
+            .info()?;
+        let history = History::for_state(&path).read()?;
 
-        Vault {
-            path,
+        Ok(Vault {
+            path: Some(path),
             provider,
             vault: vault_block,
             root: root_block,
             root_id,
             index: index_block,
-        }
+            vault_id,
+            history,
+            missing_block_policy: MissingBlockPolicy::default(),
+            subscribers: Vec::new(),
+            cwd: VaultPath::new("/"),
+            max_directory_block_size: BlockSize::new(MAX_BLOCK_SIZE),
+            clock: default_clock(),
+        })
     }
 
-    pub fn initialize(provider: &'a Provider, path: impl Into<PathBuf>) -> Vault<'a> {
+    /// Completes or undoes an interrupted mutation of the vault with the state file at `path`.
+    ///
+    /// Every mutation journals its block writes and the final state file update next to the state file.
+    /// If the journal shows that all the blocks made it to disk, the state file update is replayed.
+    /// This is done by [`Vault::open`], so only code that reads the state file on its own needs to call it.
+    #[cfg(feature = "std-fs")]
+    pub fn recover(provider: &Provider, path: impl Into<PathBuf>) -> Result<Recovery, VaultError> {
         let path = path.into();
+        let journal = Journal::for_state(&path);
+        let Some(entries) = journal.read()? else {
+            return Ok(Recovery::Clean);
+        };
+
+        let written = |id: BlockId| provider.load_block_from_file(id, 0).is_ok();
+        let recovery = match entries.commit {
+            Some(vault_id) if written(vault_id) && entries.writes.iter().all(|id| written(*id)) => {
+                // The vault block being replaced goes to the history, unless that happened before the crash
+                let previous = provider.load_block_id_from_file(&path)?;
+                let history = History::for_state(&path);
+                if previous != vault_id && history.read()?.last() != Some(&previous) {
+                    history.record(previous)?;
+                }
+                provider.save_block_id_to_file(vault_id, path)?;
+                provider.flush()?;
+                Recovery::RolledForward(vault_id)
+            }
+            _ => Recovery::RolledBack,
+        };
+        journal.finish()?;
+        Ok(recovery)
+    }
+
+    #[cfg(feature = "std-fs")]
+    pub fn initialize(provider: &'a Provider, path: impl Into<PathBuf>) -> Result<Vault<'a>, VaultError> {
+        Vault::initialize_with(provider, Some(path.into()))
+    }
+
+    /// Create a new vault whose state is only kept in memory, without a state file.
+    ///
+    /// Combined with [`Provider::in_memory`] nothing touches the disk, which suits tests and ephemeral sessions.
+    pub fn initialize_in_memory(provider: &'a Provider) -> Result<Vault<'a>, VaultError> {
+        Vault::initialize_with(provider, None)
+    }
 
-        // Initialize the root block
+    /// Create a new vault, saving its state to the state file at `path` if there is one.
+    fn initialize_with(provider: &'a Provider, path: Option<PathBuf>) -> Result<Vault<'a>, VaultError> {
+        // Initialize the root block, which isn't stamped so that every new vault starts out with the same blocks
         let root_block = InfoBlock::new_directory();
         let (root_block, _) = root_block
-            .info()
-            .directory_create_local_node(0, "welcome", NodeKind::Directory);
+            .info()?
+            .directory_create_local_node(0, "welcome", NodeKind::Directory, None);
         let encrypted_root_block = EncryptedBlock::encrypt(&root_block, 0);
-        let root_id = encrypted_root_block.id(BlockKind::Info);
-        let root_block = provider.add_block(root_id, encrypted_root_block, root_block).info();
+        let root_id = encrypted_root_block.id_with(BlockKind::Info, provider.hasher())?;
+        let root_block = provider.add_block(root_id, encrypted_root_block, root_block)?.info()?;
 
-        println!("Initialized root  block {}", root_id.base64());
+        #[cfg(feature = "tracing")]
+        tracing::debug!(root_id = %root_id.base64(), "Initialized the root block");
 
         // Initialize the index block
         let index_block = InfoBlock::new_index();
         let encrypted_index_block = EncryptedBlock::encrypt(&index_block, 0);
-        let index_id = encrypted_index_block.id(BlockKind::Info);
-        let index_block = provider.add_block(index_id, encrypted_index_block, index_block).info();
+        let index_id = encrypted_index_block.id_with(BlockKind::Info, provider.hasher())?;
+        let index_block = provider
+            .add_block(index_id, encrypted_index_block, index_block)?
+            .info()?;
 
-        println!("Initialized index block {}", index_id.base64());
+        #[cfg(feature = "tracing")]
+        tracing::debug!(index_id = %index_id.base64(), "Initialized the index block");
 
         // Initialize the vault block
         let vault_block = InfoBlock::new_vault(root_id, index_id);
         let encrypted_vault_block = EncryptedBlock::encrypt(&vault_block, 0);
-        let vault_id = encrypted_vault_block.id(BlockKind::Info);
-        let vault_block = provider.add_block(vault_id, encrypted_vault_block, vault_block).info();
+        let vault_id = encrypted_vault_block.id_with(BlockKind::Info, provider.hasher())?;
+        let vault_block = provider
+            .add_block(vault_id, encrypted_vault_block, vault_block)?
+            .info()?;
 
-        println!("Initialized vault block {}", vault_id.base64());
+        #[cfg(feature = "tracing")]
+        tracing::info!(vault_id = %vault_id.base64(), "Initialized the vault block");
 
-        Provider::save_block_id_to_file(vault_id, path.clone());
+        if let Some(path) = &path {
+            provider.save_block_id_to_file(vault_id, path.clone())?;
+        }
+        provider.flush()?;
+        let history = Vec::new();
 
-        Vault {
+        Ok(Vault {
             path,
             provider,
             vault: vault_block,
             root: root_block,
             root_id,
             index: index_block,
+            vault_id,
+            history,
+            missing_block_policy: MissingBlockPolicy::default(),
+            subscribers: Vec::new(),
+            cwd: VaultPath::new("/"),
+            max_directory_block_size: BlockSize::new(MAX_BLOCK_SIZE),
+            clock: default_clock(),
+        })
+    }
+
+    /// Returns a read-only view of the vault as of the vault block with the specified `vault_id`,
+    /// such as one of the snapshots listed by [`Vault::history`].
+    pub fn open_at(provider: &'a Provider, vault_id: BlockId) -> Result<VaultReader<'a>, VaultError> {
+        VaultReader::new(provider, vault_id)
+    }
+
+    /// Returns the changes that lead from the vault block `old` to the vault block `new`.
+    ///
+    /// Nodes that are stored at the same place in both snapshots are skipped without being read,
+    /// so only the subtrees that actually changed are traversed.
+    /// A node that changed its kind is reported as removed and added again.
+    pub fn diff(provider: &'a Provider, old: BlockId, new: BlockId) -> Result<Vec<Change>, VaultError> {
+        let old = VaultReader::new(provider, old)?;
+        let new = VaultReader::new(provider, new)?;
+        let mut changes = Vec::new();
+        old.diff_node(VaultPath::new("/"), (old.root_id, 0), (new.root_id, 0), &mut changes)?;
+        Ok(changes)
+    }
+
+    /// Returns the id of the vault block that the vault currently points to.
+    pub fn vault_id(&self) -> BlockId {
+        self.vault_id
+    }
+
+    /// Returns the id of the root directory block that the current vault block points to.
+    pub fn root_id(&self) -> BlockId {
+        self.root_id
+    }
+
+    /// Returns the id of the index block that the current vault block points to.
+    pub fn index_id(&self) -> BlockId {
+        let (_, index_id) = self.vault.get_root_id_and_index_id();
+        index_id
+    }
+
+    /// Returns the ids of the vault blocks that the vault pointed to before, oldest first.
+    ///
+    /// Every commit adds the vault block it replaced, and only the newest 1024 are kept. Vaults with a state file
+    /// keep the history next to it, while in-memory vaults only remember their own commits.
+    /// A snapshot can only be opened as long as its blocks haven't been collected as garbage.
+    pub fn history(&self) -> &[BlockId] {
+        &self.history
+    }
+
+    /// Sets the [`MissingBlockPolicy`] consulted whenever a block is missing during traversal.
+    pub fn set_missing_block_policy(&mut self, policy: MissingBlockPolicy<'a>) {
+        self.missing_block_policy = policy;
+    }
+
+    /// Sets the current directory that [`Vault::resolve_relative`] resolves paths against, the root by default.
+    ///
+    /// The directory doesn't need to exist, in which case resolved paths just won't be found.
+    pub fn set_cwd(&mut self, cwd: VaultPath) {
+        self.cwd = cwd;
+    }
+
+    /// Sets the size that directory blocks are split at, which is the largest [`BlockSize`] by default.
+    ///
+    /// A directory that would outgrow it moves its oldest entries out to another block,
+    /// which it continues in. Lookups and listings follow these continuation blocks transparently.
+    pub fn set_max_directory_block_size(&mut self, size: BlockSize) {
+        self.max_directory_block_size = size;
+    }
+
+    /// Sets the clock that nodes are stamped with as they are created and changed, see [`NodeStat::created`]
+    /// and [`NodeStat::modified`]. Vaults use the system clock by default, except on the web where there is none.
+    ///
+    /// Without a clock nothing is stamped, so the same tree results in the same blocks no matter when it's built.
+    pub fn set_clock(&mut self, clock: Option<Box<Clock>>) {
+        self.clock = clock;
+    }
+
+    /// Returns the current time of the clock, or `None` if there is no clock.
+    fn now(&self) -> Option<Timestamp> {
+        self.clock.as_ref().map(|clock| clock())
+    }
+
+    /// Returns the current directory that [`Vault::resolve_relative`] resolves paths against.
+    pub fn cwd(&self) -> &VaultPath {
+        &self.cwd
+    }
+
+    /// Returns the absolute path of `path` relative to the current directory.
+    ///
+    /// Fails with [`VaultError::EscapesRoot`] if `path` leads past the root with `..` components.
+    pub fn resolve_relative(&self, path: &RelativeVaultPath) -> Result<VaultPath, VaultError> {
+        self.cwd.join(path).ok_or_else(|| VaultError::EscapesRoot(path.clone()))
+    }
+
+    /// Returns a new [`Receiver`] of all the [`VaultEvent`]s published from now on.
+    pub fn subscribe(&mut self) -> Receiver<VaultEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Delivers the `events` to all subscribers, forgetting the ones that have hung up.
+    fn publish(&mut self, events: Vec<VaultEvent>) {
+        self.subscribers
+            .retain(|subscriber| events.iter().all(|event| subscriber.send(event.clone()).is_ok()));
+    }
+
+    /// Returns a read-only view of the current state of the vault.
+    ///
+    /// The view doesn't follow later changes to the vault, but keeps its [`MissingBlockPolicy`].
+    pub fn reader(&self) -> VaultReader<'a> {
+        VaultReader {
+            provider: self.provider,
+            root_id: self.root_id,
+            missing_block_policy: self.missing_block_policy,
         }
     }
 
-    pub fn put(&mut self, name: &str) -> Result<&File, io::Error> {
-        // TODO: Implement basic file importing.
-        /*
-        let p = Path::new(name);
-        let f = File::from_os(p)?;
-        // The eventual .last().unwrap() is critically depending on the .push()
-        self.files.push(f);
-        Ok(self.files.last().unwrap())
-        */
-        Err(io::Error::new(io::ErrorKind::Other, "foobar"))
+    /// Returns the block with the specified `id`, consulting the [`MissingBlockPolicy`] if it's missing.
+    ///
+    /// Returns `Ok(None)` if the block is missing and the policy is to skip it.
+    fn get_block(&self, id: BlockId) -> Result<Option<Block>, VaultError> {
+        self.reader().get_block(id)
     }
 
-    pub fn create_directory(&mut self, path: VaultPath) {
-        println!("Creating directory ..");
+    /// Returns the journal of the mutations of this vault, or `None` if there's no state file to recover.
+    fn journal(&self) -> Option<Journal> {
+        self.path.as_deref().map(Journal::for_state)
+    }
 
-        // Make sure that all the directories exist from left to right
+    /// Journals the write of the block with the specified `id` and then adds it to the provider.
+    fn write_block(&self, id: BlockId, encrypted_block: EncryptedBlock, block: Block) -> Result<Block, VaultError> {
+        if let Some(journal) = self.journal() {
+            journal.record_write(id)?;
+        }
+        Ok(self.provider.add_block(id, encrypted_block, block)?)
+    }
 
-        let mut blocks = vec![Some(self.root.block())]; // None means use parent
-        let mut entry_names = vec![""];
-        let mut node_indexes = vec![0];
-        let mut created_anything = false;
-        for component in path.components() {
-            match component {
-                Component::Prefix(_) => (),               // Ignore
-                Component::RootDir => (),                 // Ignore
-                Component::CurDir => (),                  // Ignore
-                Component::ParentDir => unimplemented!(), // Should probably just forbid for now in VaultPath
-                Component::Normal(name) => {
-                    // Does it exist?
-                    let entry_name = name.to_str().unwrap();
-                    let block = blocks
-                        .iter()
-                        .rev()
-                        .find(|block| block.is_some())
-                        .unwrap()
-                        .as_ref()
-                        .unwrap()
-                        .info();
-                    let node_index = *node_indexes.last().unwrap();
-                    if let Some((block_id, node_index)) =
-                        block.directory_get_entry_block_id_and_node_index(node_index, entry_name)
-                    {
-                        if let Some(block_id) = block_id {
-                            blocks.push(Some(self.provider.get_block(block_id)));
-                        } else {
-                            blocks.push(None);
-                        }
-                        node_indexes.push(node_index);
-                    } else {
-                        // It doesn't exist, so create the directory and continue the loop
-                        let (new_block, entry_node_index) =
-                            block.directory_create_local_node(node_index, entry_name, NodeKind::Directory);
-
-                        // Update the parent block
-                        *blocks.iter_mut().rev().find(|block| block.is_some()).unwrap() = Some(new_block);
-                        blocks.push(None); // We use the parent's block
-                        node_indexes.push(entry_node_index);
-                        created_anything = true;
-                    }
-                    entry_names.push(entry_name);
+    /// Journals the writes of the `blocks` and then adds them to the provider as a unit.
+    fn write_blocks(&self, blocks: Vec<(BlockId, EncryptedBlock)>) -> Result<(), VaultError> {
+        if let Some(journal) = self.journal() {
+            let ids: Vec<BlockId> = blocks.iter().map(|(block_id, _)| *block_id).collect();
+            journal.record_writes(&ids)?;
+        }
+        Ok(self.provider.add_blocks(&blocks)?)
+    }
+
+    /// Returns the block with the specified `id`, failing even if the policy is to skip missing blocks.
+    fn require_block(&self, id: BlockId) -> Result<Block, VaultError> {
+        self.reader().require_block(id)
+    }
+
+    /// Adds the OS file at `name` to the root directory of the vault.
+    ///
+    /// The file is read incrementally and split into data blocks following [`BlockSize::layout`].
+    /// Blocks that the provider already has are reused instead of being written again.
+    #[cfg(feature = "std-fs")]
+    pub fn put(&mut self, name: &str) -> Result<PutOutcome, VaultError> {
+        let os_path = Path::new(name);
+
+        // Reject oversized files before doing any block work
+        let size = check_file_size(fs::metadata(os_path)?.len())?;
+
+        let file_name = os_path
+            .file_name()
+            .ok_or(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't determine file name.",
+            ))?
+            .to_str()
+            .ok_or(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't determine file name because of invalid Unicode.",
+            ))?;
+        self.put_reader(file_name, fs::File::open(os_path)?, size)
+    }
+
+    /// Adds a file called `name` with the `size` bytes read from `reader` to the root directory of the vault.
+    ///
+    /// The data is split into data blocks following [`BlockSize::layout`] as it's being read.
+    /// Fails with [`VaultError::SizeMismatch`] if the reader doesn't provide exactly `size` bytes,
+    /// in which case the blocks written so far are left unreferenced.
+    pub fn put_reader(&mut self, name: &str, reader: impl Read, size: FileSize) -> Result<PutOutcome, VaultError> {
+        let file = VaultPath::try_new(format!("/{name}"))?;
+        // Only a single normal name refers to an entry of the root directory, not `.`, `..` or nested paths
+        if !file.is_normal() || file.file_name() != Some(name) {
+            return Err(VaultError::InvalidPath(file));
+        }
+        if self.reader().find_entry(&self.root, 0, name)?.1.is_some() {
+            return Err(VaultError::AlreadyExists(file));
+        }
+
+        let (block_ids, blocks_written) = self.write_data_blocks(reader, size)?;
+        let holes = block_ids.iter().filter(|block_id| block_id.is_hole()).count();
+        let blocks_deduped = block_ids.len() - blocks_written - holes;
+
+        let (root_block, _) = self
+            .root
+            .directory_create_local_file(0, name, size, &block_ids, self.now());
+        self.commit_root(root_block)?;
+        self.publish(vec![
+            VaultEvent::Created(file.clone()),
+            VaultEvent::Committed(self.root_id),
+        ]);
+
+        Ok(PutOutcome {
+            file,
+            blocks_written,
+            blocks_deduped,
+            holes,
+        })
+    }
+
+    /// Adds the OS files of `items` at their paths in the vault, creating the missing parent directories
+    /// like [`Vault::create_directory`].
+    ///
+    /// Files that share a parent directory are added to it together, so that the directories along the way
+    /// are only rewritten once per parent, and a single new vault block is committed at the end.
+    /// If any of the files can't be added, none are, though their data blocks may already have been written.
+    #[cfg(feature = "std-fs")]
+    pub fn put_many(&mut self, items: &[(VaultPath, &Path)]) -> Result<Vec<PutOutcome>, VaultError> {
+        let mut groups: BTreeMap<VaultPath, Vec<(usize, &str, &Path)>> = BTreeMap::new();
+        for (i, (path, os_path)) in items.iter().enumerate() {
+            let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                // The root can't be a file
+                return Err(VaultError::InvalidPath(path.clone()));
+            };
+            groups.entry(parent).or_default().push((i, name, os_path));
+        }
+
+        // The root is updated as every group gets written, but only committed once all of them are
+        let (root_block, root_id) = (self.root.block(), self.root_id);
+        let mut outcomes: Vec<Option<PutOutcome>> = items.iter().map(|_| None).collect();
+        let mut events = Vec::new();
+        for (parent, files) in groups {
+            let written = self.put_group(parent, items, &files, &mut outcomes);
+            match written {
+                Ok(Some((group_root_id, group_root, group_events))) => {
+                    self.root = group_root;
+                    self.root_id = group_root_id;
+                    events.extend(group_events);
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    self.root = root_block.info()?;
+                    self.root_id = root_id;
+                    return Err(e);
                 }
             }
         }
+        if !events.is_empty() {
+            self.commit_root_id(self.root_id, self.root.block().info()?)?;
+            events.push(VaultEvent::Committed(self.root_id));
+            self.publish(events);
+        }
+        Ok(outcomes.into_iter().flatten().collect())
+    }
 
-        // Tricky task of backtracking and updating all the blockid references
+    /// Adds the `files` to the directory at `parent` and writes the blocks along the way,
+    /// setting the outcome of each file at its index into `items`.
+    ///
+    /// Returns what [`Vault::write_directory_plan`] does, without committing a new vault block.
+    #[cfg(feature = "std-fs")]
+    fn put_group(
+        &self,
+        parent: VaultPath,
+        items: &[(VaultPath, &Path)],
+        files: &[(usize, &str, &Path)],
+        outcomes: &mut [Option<PutOutcome>],
+    ) -> Result<Option<(BlockId, InfoBlock, Vec<VaultEvent>)>, VaultError> {
+        let mut plan = self.plan_path(parent.clone(), true)?;
+        let builder = plan.blocks.iter_mut().rev().find_map(|block| block.as_mut()).unwrap();
+        let node_index = *plan.node_indexes.last().unwrap();
+        // A freshly created parent is known to be an empty directory
+        let parent_created = !plan.created.is_empty();
+        let mut names = BTreeSet::new();
+        for &(i, name, os_path) in files {
+            let path = &items[i].0;
+            if !parent_created {
+                let directory = builder.base();
+                if directory.node_stat(node_index).kind != NodeKind::Directory {
+                    return Err(VaultError::NotADirectory(parent));
+                }
+                if self.reader().find_entry(directory, node_index, name)?.1.is_some() {
+                    return Err(VaultError::AlreadyExists(path.clone()));
+                }
+            }
+            if !names.insert(name) {
+                return Err(VaultError::AlreadyExists(path.clone()));
+            }
 
-        if created_anything {
-            let mut entry_block = None;
-            let mut entry_block_id = None;
-            let mut entry_node_index = None;
-            let mut entry_name = None;
-
-            for i in (0..blocks.len()).rev() {
-                let block = &mut blocks[i];
-                let node_index = node_indexes[i];
-                let name = entry_names[i];
-
-                if let Some(block) = block {
-                    if let (Some(entry_node_index), Some(entry_name)) = (entry_node_index, entry_name) {
-                        // Make sure the entry is pointing to this
-                        if let Some(new_block) = block.info().directory_set_entry_block_id_and_node_index(
-                            node_index,
-                            entry_name,
-                            entry_block_id.as_ref(),
-                            entry_node_index,
-                        ) {
-                            *block = new_block;
-                        }
+            // Reject oversized files before doing any block work
+            let size = check_file_size(fs::metadata(os_path)?.len())?;
+            let (block_ids, blocks_written) = self.write_data_blocks(fs::File::open(os_path)?, size)?;
+            builder.create_local_file(node_index, name, size, &block_ids)?;
+            let holes = block_ids.iter().filter(|block_id| block_id.is_hole()).count();
+            outcomes[i] = Some(PutOutcome {
+                file: path.clone(),
+                blocks_written,
+                blocks_deduped: block_ids.len() - blocks_written - holes,
+                holes,
+            });
+            plan.created.push(path.clone());
+        }
+        // The files are inlined into the parent's block, which is the last one along the path
+        self.write_directory_plan(plan)
+    }
+
+    /// Stores the `size` bytes read from `reader` as data blocks following the provider's [`ChunkStrategy`].
+    ///
+    /// Blocks of zeros are turned into holes that aren't stored at all, check out [`BlockId::hole`].
+    /// Batches of blocks are encrypted, hashed and stored on the rayon thread pool.
+    /// Returns the ids of the blocks in file order, along with the number of blocks that had to be written.
+    /// Fails with [`VaultError::SizeMismatch`] if the reader doesn't provide exactly `size` bytes,
+    /// in which case the blocks written so far are left unreferenced.
+    fn write_data_blocks(&self, reader: impl Read, size: FileSize) -> Result<(Vec<BlockId>, usize), VaultError> {
+        let mut read = 0;
+        let mut block_ids = Vec::new();
+        let mut blocks_written = 0;
+        // Read one byte past the declared size to detect readers that provide too much
+        let chunk_strategy = self.provider.chunk_strategy();
+        let mut blocks = File::reader_chunk_stream(reader.take(*size + 1), chunk_strategy, 0);
+        let hasher = self.provider.hasher();
+        let mut batch = Vec::new();
+        loop {
+            // The blocks are independent of each other, so a batch of them is processed in parallel
+            let mut batch_len = 0;
+            for block in blocks.by_ref() {
+                let block = block?;
+                read += block.size() as u64;
+                // Only the extra byte can remain, so the stream ends right after this block
+                if read > *size {
+                    break;
+                }
+                batch_len += block.size();
+                batch.push(block);
+                if batch.len() >= rayon::current_num_threads() || batch_len >= MAX_BATCH_LEN {
+                    break;
+                }
+            }
+            if batch.is_empty() {
+                break;
+            }
+            let encrypted_blocks = batch
+                .par_drain(..)
+                .map(|block| {
+                    if let Some(hole_id) = hole_id(&block) {
+                        return Ok((hole_id, None));
                     }
+                    let encrypted_block = EncryptedBlock::encrypt(&block, 0);
+                    let block_id = encrypted_block.id_with(BlockKind::Data, hasher)?;
+                    Ok((block_id, Some((encrypted_block, block))))
+                })
+                .collect::<Result<Vec<_>, VaultError>>()?;
+            block_ids.extend(encrypted_blocks.iter().map(|(block_id, _)| *block_id));
+            let stored = encrypted_blocks
+                .into_iter()
+                .filter_map(|(block_id, stored)| {
+                    stored.map(|(encrypted_block, block)| (block_id, encrypted_block, block))
+                })
+                .collect();
+            blocks_written += self.store_data_blocks(stored)?;
+        }
+        if read != *size {
+            // Nothing refers to the written blocks, so there's nothing to recover
+            if let Some(journal) = self.journal() {
+                journal.finish()?;
+            }
+            return Err(VaultError::SizeMismatch {
+                expected: *size,
+                actual: read,
+            });
+        }
+        Ok((block_ids, blocks_written))
+    }
+
+    /// Stores the data `blocks`, given along with their ids and encrypted contents, skipping the ones
+    /// that the provider already has.
+    ///
+    /// The missing blocks are journaled together and then written in parallel. Blocks that occur several times
+    /// are only written once, the other occurrences are added afterwards like blocks the provider already had.
+    /// Returns the number of blocks that had to be written.
+    fn store_data_blocks(&self, blocks: Vec<(BlockId, EncryptedBlock, Block)>) -> Result<usize, VaultError> {
+        let provider = self.provider;
+        let present: Vec<bool> = blocks
+            .par_iter()
+            .map(|(block_id, _, _)| provider.contains_block(*block_id))
+            .collect();
+        let mut missing = Vec::new();
+        let mut others = Vec::new();
+        for (block, present) in blocks.into_iter().zip(present) {
+            if present || missing.iter().any(|(block_id, _, _)| *block_id == block.0) {
+                others.push(block);
+            } else {
+                missing.push(block);
+            }
+        }
+
+        if let Some(journal) = self.journal() {
+            let ids: Vec<BlockId> = missing.iter().map(|(block_id, _, _)| *block_id).collect();
+            journal.record_writes(&ids)?;
+        }
+        let written = missing.len();
+        missing
+            .into_par_iter()
+            .try_for_each(|(block_id, encrypted_block, block)| {
+                provider.add_block(block_id, encrypted_block, block).map(|_| ())
+            })?;
+        // Nothing gets written for these, but the provider still counts the references
+        for (block_id, encrypted_block, block) in others {
+            provider.add_block(block_id, encrypted_block, block)?;
+        }
+        Ok(written)
+    }
+
+    /// Stores the data `block`, unless the provider already has it or it's a hole.
+    ///
+    /// Returns the id of the block and whether it had to be written.
+    fn write_data_block(&self, block: Block) -> Result<(BlockId, bool), VaultError> {
+        if let Some(hole_id) = hole_id(&block) {
+            return Ok((hole_id, false));
+        }
+        let encrypted_block = EncryptedBlock::encrypt(&block, 0);
+        let block_id = encrypted_block.id_with(BlockKind::Data, self.provider.hasher())?;
+        if self.provider.contains_block(block_id) {
+            // Nothing gets written, but the provider still counts the reference
+            self.provider.add_block(block_id, encrypted_block, block)?;
+            Ok((block_id, false))
+        } else {
+            self.write_block(block_id, encrypted_block, block)?;
+            Ok((block_id, true))
+        }
+    }
+
+    /// Appends `data` to the end of the file at `path`.
+    ///
+    /// Only the last block of the file gets rewritten, as it may be shorter than its size in the provider's
+    /// [`ChunkStrategy`]. The rest of the data is split into new blocks following it, so that offsets still translate.
+    pub fn append(&mut self, path: VaultPath, data: &[u8]) -> Result<(), VaultError> {
+        let mut plan = self.plan_path(path.clone(), false)?;
+        let builder = plan.blocks.iter_mut().rev().find_map(|block| block.as_mut()).unwrap();
+        let node_index = *plan.node_indexes.last().unwrap();
+        let file = builder.base();
+        let Some(size) = file.node_stat(node_index).size else {
+            return Err(VaultError::NotAFile(path));
+        };
+        if data.is_empty() {
+            return Ok(());
+        }
+        let new_size = check_file_size(*size + data.len() as u64)?;
+
+        let mut block_ids = file.file_block_ids(node_index)?;
+        let mut tail = Vec::new();
+        if let Some(last_block_id) = block_ids.pop() {
+            tail.extend_from_slice(&self.require_block(last_block_id)?.data());
+        }
+        let chunk_strategy = self.provider.chunk_strategy();
+        for block in File::reader_chunk_stream(tail.as_slice().chain(data), chunk_strategy, block_ids.len()) {
+            let (block_id, _) = self.write_data_block(block?)?;
+            block_ids.push(block_id);
+        }
+
+        builder.set_local_file(node_index, new_size, &block_ids);
+        plan.modified.push(path);
+        self.commit_directory_plan(plan)
+    }
+
+    /// Shrinks the file at `path` to `new_size` bytes.
+    ///
+    /// The blocks past the new end are dropped from the file and the now last block is rewritten to fit,
+    /// which leaves the dropped blocks unreachable unless other files refer to them.
+    /// Fails if `new_size` is larger than the current size of the file.
+    pub fn truncate(&mut self, path: VaultPath, new_size: FileSize) -> Result<(), VaultError> {
+        let mut plan = self.plan_path(path.clone(), false)?;
+        let builder = plan.blocks.iter_mut().rev().find_map(|block| block.as_mut()).unwrap();
+        let node_index = *plan.node_indexes.last().unwrap();
+        let file = builder.base();
+        let Some(size) = file.node_stat(node_index).size else {
+            return Err(VaultError::NotAFile(path));
+        };
+        if new_size > size {
+            return Err(VaultError::Unsupported("growing a file with truncate"));
+        }
+        if new_size == size {
+            return Ok(());
+        }
+
+        let mut block_ids = file.file_block_ids(node_index)?;
+        if *new_size == 0 {
+            block_ids.clear();
+        } else {
+            // The cut point is right after the last byte that is kept
+            let (block_index, last_block_size) = self
+                .reader()
+                .locate_last_byte(&block_ids, FileOffset::new(*new_size - 1))?;
+            block_ids.truncate(block_index + 1);
+            let last_block = self.require_block(*block_ids.last().unwrap())?;
+            if last_block_size < last_block.size() {
+                let (block_id, _) =
+                    self.write_data_block(Block::from_data(last_block.data().slice(..last_block_size)))?;
+                *block_ids.last_mut().unwrap() = block_id;
+            }
+        }
+
+        builder.set_local_file(node_index, new_size, &block_ids);
+        plan.modified.push(path);
+        self.commit_directory_plan(plan)
+    }
+
+    /// Stores `root_block` as the new root directory and commits a new vault block pointing to it.
+    fn commit_root(&mut self, root_block: Block) -> Result<(), VaultError> {
+        let mut staged = Vec::new();
+        let root_block = self.split_directory_block(root_block, &mut staged)?;
+        let encrypted_block = EncryptedBlock::encrypt(&root_block, 0);
+        let root_id = encrypted_block.id_with(BlockKind::Info, self.provider.hasher())?;
+        staged.push((root_id, encrypted_block));
+        self.write_blocks(staged)?;
+        self.commit_root_id(root_id, root_block.info()?)
+    }
 
-                    let encrypted_block = EncryptedBlock::encrypt(block, 0);
-                    let block_id = encrypted_block.id(BlockKind::Info);
-                    let block = self.provider.add_block(block_id, encrypted_block, block.clone()).info();
-                    println!("Created a new dir   block {}", block_id.base64());
+    /// Commits a new vault block pointing to the already stored root directory `root_block`.
+    fn commit_root_id(&mut self, root_id: BlockId, root_block: InfoBlock) -> Result<(), VaultError> {
+        let vault_block = self.vault.update_root_id(root_id);
+        let encrypted_block = EncryptedBlock::encrypt(&vault_block, 0);
+        let vault_block_id = encrypted_block.id_with(BlockKind::Info, self.provider.hasher())?;
+        let vault_block = self.write_block(vault_block_id, encrypted_block, vault_block)?.info()?;
 
-                    entry_block = Some(block);
-                    entry_block_id = Some(block_id);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(vault_id = %vault_block_id.base64(), "Created a new vault block");
+
+        if let Some(path) = &self.path {
+            let journal = Journal::for_state(path);
+            journal.record_commit(vault_block_id)?;
+            // The state file must not refer to blocks that could still be lost
+            self.provider.flush()?;
+            // Recorded while the journal is still around, so that recovery can tell whether it got this far
+            History::for_state(path).record(self.vault_id)?;
+            self.provider.save_block_id_to_file(vault_block_id, path.clone())?;
+            journal.finish()?;
+        }
+
+        self.history.push(self.vault_id);
+        self.history.drain(..self.history.len().saturating_sub(MAX_HISTORY_LEN));
+        self.root = root_block;
+        self.root_id = root_id;
+        self.vault = vault_block;
+        self.vault_id = vault_block_id;
+
+        self.provider.flush()?;
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
+    pub fn create_directory(&mut self, path: VaultPath) -> Result<(), VaultError> {
+        let plan = self.plan_create_directory(path)?;
+        self.commit_directory_plan(plan)
+    }
+
+    /// Returns the changes that [`Vault::create_directory`] would make, without writing anything.
+    pub fn plan_create_directory(&self, path: VaultPath) -> Result<DirectoryPlan, VaultError> {
+        self.plan_path(path, true)
+    }
+
+    /// Walks the directories of `path` from left to right, creating the missing ones if `create_missing` is set.
+    ///
+    /// Otherwise the first missing directory is reported as [`VaultError::NotFound`].
+    fn plan_path(&self, path: VaultPath, create_missing: bool) -> Result<DirectoryPlan, VaultError> {
+        if !path.is_normal() {
+            return Err(VaultError::InvalidPath(path));
+        }
+
+        // Make sure that all the directories exist from left to right
+
+        let mut blocks = vec![Some(DirectoryBuilder::new(self.root.block().info()?))]; // None means use parent
+        let mut entry_names = vec![String::new()];
+        let mut node_indexes = vec![0];
+        let mut current_path = PathBuf::from("/");
+        let mut created = Vec::new();
+        for entry_name in path.names() {
+            // Does it exist?
+            let builder = blocks.iter_mut().rev().find_map(|block| block.as_mut()).unwrap();
+            let node_index = *node_indexes.last().unwrap();
+            // Once a directory has been created, none of its descendants can exist yet
+            let (continuations, entry) = if created.is_empty() {
+                let directory = builder.base();
+                if directory.node_stat(node_index).kind != NodeKind::Directory {
+                    return Err(VaultError::NotADirectory(VaultPath::new(current_path)));
+                }
+                self.reader().find_entry(directory, node_index, entry_name)?
+            } else {
+                (Vec::new(), None)
+            };
+            current_path.push(entry_name);
+            if let Some((block_id, node_index)) = entry {
+                // The entry is stored in the last continuation block, all of which get rewritten
+                for (_, continuation) in continuations {
+                    blocks.push(Some(DirectoryBuilder::new(continuation)));
+                    node_indexes.push(0);
+                    entry_names.push(String::from(DIRECTORY_CONTINUATION));
+                }
+                if let Some(block_id) = block_id {
+                    blocks.push(Some(DirectoryBuilder::new(self.require_block(block_id)?.info()?)));
                 } else {
-                    entry_block = None;
-                    entry_block_id = None;
+                    blocks.push(None);
                 }
-                entry_node_index = Some(node_index as u16);
-                entry_name = Some(name);
+                node_indexes.push(node_index);
+            } else if !create_missing {
+                return Err(VaultError::NotFound(VaultPath::new(current_path)));
+            } else {
+                // It doesn't exist, so create the directory in the parent's block and continue the loop
+                let entry_node_index = builder.create_local_node(node_index, entry_name, NodeKind::Directory)?;
+                blocks.push(None); // We use the parent's block
+                node_indexes.push(entry_node_index);
+                created.push(VaultPath::new(current_path.clone()));
             }
+            entry_names.push(String::from(entry_name));
+        }
 
-            let vault_block = self.vault.update_root_id(entry_block_id.unwrap());
-            let encrypted_block = EncryptedBlock::encrypt(&vault_block, 0);
-            let vault_block_id = encrypted_block.id(BlockKind::Info);
-            let vault_block = self
-                .provider
-                .add_block(vault_block_id, encrypted_block, vault_block)
-                .info();
+        // Every block along the path gets rewritten, followed by a new vault block
+        let blocks_to_write = if created.is_empty() {
+            0
+        } else {
+            blocks.iter().filter(|block| block.is_some()).count() + 1
+        };
 
-            println!("Created a new vault block {}", vault_block_id.base64());
+        Ok(DirectoryPlan {
+            created,
+            blocks_to_write,
+            modified: Vec::new(),
+            removed: Vec::new(),
+            blocks,
+            entry_names,
+            node_indexes,
+        })
+    }
 
-            Provider::save_block_id_to_file(vault_block_id, self.path.clone());
+    /// Creates an empty file at `path`, creating the missing parent directories like [`Vault::create_directory`].
+    pub fn touch(&mut self, path: VaultPath) -> Result<(), VaultError> {
+        self.create_file(path, FileSize::new(0), &[])
+    }
 
-            self.root = entry_block.unwrap();
-            self.vault = vault_block;
+    /// Creates a file of `size` bytes stored in the data blocks `block_ids` at `path`,
+    /// creating the missing parent directories like [`Vault::create_directory`].
+    fn create_file(&mut self, path: VaultPath, size: FileSize, block_ids: &[BlockId]) -> Result<(), VaultError> {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            // Only the root has no parent
+            return Err(VaultError::AlreadyExists(path));
+        };
+        let mut plan = self.plan_path(parent.clone(), true)?;
+        let builder = plan.blocks.iter_mut().rev().find_map(|block| block.as_mut()).unwrap();
+        let node_index = *plan.node_indexes.last().unwrap();
+        // A freshly created parent is known to be an empty directory
+        if plan.created.is_empty() {
+            let directory = builder.base();
+            if directory.node_stat(node_index).kind != NodeKind::Directory {
+                return Err(VaultError::NotADirectory(parent));
+            }
+            if self.reader().find_entry(directory, node_index, name)?.1.is_some() {
+                return Err(VaultError::AlreadyExists(path));
+            }
         }
+
+        let entry_node_index = builder.create_local_file(node_index, name, size, block_ids)?;
+        plan.blocks.push(None); // We use the parent's block
+        plan.node_indexes.push(entry_node_index);
+        plan.entry_names.push(String::from(name));
+        plan.created.push(path);
+        self.commit_directory_plan(plan)
     }
 
-    pub fn get(&self, name: &str) -> Option<&File> {
-        None
+    /// Copies the file at `from` to the new path `to`.
+    ///
+    /// The copy refers to the same data blocks as the original, so no file contents are read or written.
+    pub fn copy(&mut self, from: VaultPath, to: VaultPath) -> Result<(), VaultError> {
+        let (block_id, node_index) = self.resolve(from.clone())?;
+        let source = self.require_block(block_id)?.info()?;
+        let Some(size) = source.node_stat(node_index).size else {
+            return Err(VaultError::NotAFile(from));
+        };
+        let block_ids = source.file_block_ids(node_index)?;
+
+        let (Some(parent), Some(name)) = (to.parent(), to.file_name()) else {
+            // Only the root has no parent
+            return Err(VaultError::AlreadyExists(to));
+        };
+        let mut plan = self.plan_path(parent.clone(), false)?;
+        let builder = plan.blocks.iter_mut().rev().find_map(|block| block.as_mut()).unwrap();
+        let node_index = *plan.node_indexes.last().unwrap();
+        let directory = builder.base();
+        if directory.node_stat(node_index).kind != NodeKind::Directory {
+            return Err(VaultError::NotADirectory(parent));
+        }
+        if self.reader().find_entry(directory, node_index, name)?.1.is_some() {
+            return Err(VaultError::AlreadyExists(to));
+        }
+
+        let entry_node_index = builder.create_local_file(node_index, name, size, &block_ids)?;
+        plan.blocks.push(None); // We use the parent's block
+        plan.node_indexes.push(entry_node_index);
+        plan.entry_names.push(String::from(name));
+        plan.created.push(to.clone());
+        self.commit_directory_plan(plan)
     }
 
-    fn get_path_block_id_and_node_index(&self, path: VaultPath) -> (BlockId, u32) {
-        // TODO: Check in-memory cache
+    /// Adds the new path `new` that refers to the very same node as `existing`.
+    ///
+    /// Unlike with [`Vault::copy`], changes made through either path are visible through both,
+    /// as both entries point to the same node in the same block. Neither entry is the original one,
+    /// so removing one of them leaves the node reachable through the other.
+    ///
+    /// Fails with [`VaultError::LinkAcrossBlocks`] if the directory of `new` isn't stored in the same block
+    /// as the node, and with [`VaultError::LinkCycle`] if `new` would end up inside of the linked directory.
+    pub fn link(&mut self, existing: VaultPath, new: VaultPath) -> Result<(), VaultError> {
+        let (block_id, node_index) = self.resolve(existing)?;
+
+        let (Some(parent), Some(name)) = (new.parent(), new.file_name()) else {
+            // Only the root has no parent
+            return Err(VaultError::AlreadyExists(new));
+        };
+        let mut plan = self.plan_path(parent.clone(), false)?;
+        let builder = plan.blocks.iter_mut().rev().find_map(|block| block.as_mut()).unwrap();
+        let parent_node_index = *plan.node_indexes.last().unwrap();
+        let directory = builder.base();
+        if directory.node_stat(parent_node_index).kind != NodeKind::Directory {
+            return Err(VaultError::NotADirectory(parent));
+        }
+        if self
+            .reader()
+            .find_entry(directory, parent_node_index, name)?
+            .1
+            .is_some()
+        {
+            return Err(VaultError::AlreadyExists(new));
+        }
+        // Entries refer to nodes of other blocks by block id, which wouldn't stay shared after a change
+        if self.resolve(parent)?.0 != block_id {
+            return Err(VaultError::LinkAcrossBlocks(new));
+        }
+        if contains_local_node(directory, node_index, parent_node_index)? {
+            return Err(VaultError::LinkCycle(new));
+        }
 
-        // If we have a parent directory
-        if let Some(parent_path) = path.parent() {
-            // Get that directory's block id and node index
-            // TODO: Perhaps better performance to check here if parent is root, and then immediately use self.root
-            let (parent_block_id, parent_node_index) = self.get_path_block_id_and_node_index(parent_path);
+        builder.link_local_node(parent_node_index, name, node_index)?;
+        plan.blocks.push(None); // We use the parent's block
+        plan.node_indexes.push(node_index);
+        plan.entry_names.push(String::from(name));
+        plan.created.push(new);
+        self.commit_directory_plan(plan)
+    }
 
-            let parent_block = self.provider.get_block(parent_block_id).info();
+    /// Creates a symlink at `link` that points to the path `target`.
+    ///
+    /// Reading through the symlink, like [`Vault::get`] and [`Vault::list`], follows it to whatever is at `target`
+    /// at the time, which doesn't have to exist yet. Symlinks aren't followed when making changes,
+    /// so those have to be made through `target` itself.
+    pub fn symlink(&mut self, link: VaultPath, target: VaultPath) -> Result<(), VaultError> {
+        if !target.is_normal() {
+            return Err(VaultError::InvalidPath(target));
+        }
+        self.create_symlink(link, target, None)
+    }
 
-            let file_name = path.file_name().unwrap();
-            if let Some((block_id, node_index)) =
-                parent_block.directory_get_entry_block_id_and_node_index(parent_node_index, file_name)
-            {
-                let block_id = block_id.unwrap_or(parent_block_id);
-                return (block_id, node_index);
+    /// Creates a symlink at `link` that points to the node at `target` by its block, as well as by its path.
+    ///
+    /// The node is first moved to a block of its own like with [`Vault::move_to_block`], unless it already is.
+    /// Following the symlink leads to that block for as long as the provider has it, so the symlink survives
+    /// `target` being renamed. Only once the block is gone does it fall back to the path like [`Vault::symlink`].
+    /// As blocks are content addressed, the symlink keeps leading to the node as it was when it was created,
+    /// rather than to the changes made to it later.
+    pub fn symlink_to_block(&mut self, link: VaultPath, target: VaultPath) -> Result<(), VaultError> {
+        if !target.is_normal() {
+            return Err(VaultError::InvalidPath(target));
+        }
+        self.move_to_block(target.clone())?;
+        let (target_block, node_index) = self.resolve(target.clone())?;
+        if node_index != 0 {
+            // Reached through a symlink, which moving the path to a block doesn't follow
+            return Err(VaultError::InvalidPath(target));
+        }
+        self.create_symlink(link, target, Some(target_block))
+    }
+
+    /// Creates a symlink at `link` that points to the path `target`, and to the block `target_block` if present.
+    fn create_symlink(
+        &mut self,
+        link: VaultPath,
+        target: VaultPath,
+        target_block: Option<BlockId>,
+    ) -> Result<(), VaultError> {
+        let (Some(parent), Some(name)) = (link.parent(), link.file_name()) else {
+            // Only the root has no parent
+            return Err(VaultError::AlreadyExists(link));
+        };
+        let mut plan = self.plan_path(parent.clone(), false)?;
+        let builder = plan.blocks.iter_mut().rev().find_map(|block| block.as_mut()).unwrap();
+        let node_index = *plan.node_indexes.last().unwrap();
+        let directory = builder.base();
+        if directory.node_stat(node_index).kind != NodeKind::Directory {
+            return Err(VaultError::NotADirectory(parent));
+        }
+        if self.reader().find_entry(directory, node_index, name)?.1.is_some() {
+            return Err(VaultError::AlreadyExists(link));
+        }
+
+        // Normal paths are valid Unicode
+        let entry_node_index =
+            builder.create_local_symlink(node_index, name, target.to_str().unwrap(), target_block.as_ref())?;
+        plan.blocks.push(None); // We use the parent's block
+        plan.node_indexes.push(entry_node_index);
+        plan.entry_names.push(String::from(name));
+        plan.created.push(link);
+        self.commit_directory_plan(plan)
+    }
+
+    /// Renames the node at `from` to `to`, which has to be in the same directory.
+    ///
+    /// The entry keeps pointing to the same node, so the node and everything below it stays as it is,
+    /// including the block it's stored in if it has one of its own. Symlinks created with
+    /// [`Vault::symlink_to_block`] keep leading to it, unlike the ones pointing to the path `from`.
+    ///
+    /// Fails with [`VaultError::InvalidPath`] for the root and if `to` isn't in the same directory as `from`,
+    /// and with [`VaultError::AlreadyExists`] if there's something at `to` already.
+    pub fn rename(&mut self, from: VaultPath, to: VaultPath) -> Result<(), VaultError> {
+        let (Some(parent), Some(name)) = (from.parent(), from.file_name()) else {
+            // The root can't be renamed
+            return Err(VaultError::InvalidPath(from));
+        };
+        let new_name = match to.file_name() {
+            Some(new_name) if to.is_normal() && to.parent().as_ref() == Some(&parent) => new_name,
+            _ => return Err(VaultError::InvalidPath(to)),
+        };
+        let mut plan = self.plan_path(parent.clone(), false)?;
+        let builder = plan.blocks.iter_mut().rev().find_map(|block| block.as_mut()).unwrap();
+        let parent_node_index = *plan.node_indexes.last().unwrap();
+        let directory = builder.base();
+        if directory.node_stat(parent_node_index).kind != NodeKind::Directory {
+            return Err(VaultError::NotADirectory(parent));
+        }
+        let (continuations, entry) = self.reader().find_entry(directory, parent_node_index, name)?;
+        if entry.is_none() {
+            return Err(VaultError::NotFound(from));
+        }
+        if self
+            .reader()
+            .find_entry(directory, parent_node_index, new_name)?
+            .1
+            .is_some()
+        {
+            return Err(VaultError::AlreadyExists(to));
+        }
+
+        // The entry is stored in the last continuation block, all of which get rewritten
+        let mut entry_node_index = parent_node_index;
+        for (_, continuation) in continuations {
+            plan.blocks.push(Some(DirectoryBuilder::new(continuation)));
+            plan.node_indexes.push(0);
+            plan.entry_names.push(String::from(DIRECTORY_CONTINUATION));
+            entry_node_index = 0;
+        }
+        let builder = plan.blocks.iter_mut().rev().find_map(|block| block.as_mut()).unwrap();
+        builder.rename_entry(entry_node_index, name, new_name);
+        plan.removed.push(from);
+        plan.created.push(to);
+        self.commit_directory_plan(plan)
+    }
+
+    /// Moves the node at `path` out of the block of its parent directory into a block of its own,
+    /// along with all the nodes inlined below it.
+    ///
+    /// The parent's entry then refers to the new block by its id, which keeps the parent's block from filling up.
+    /// Nodes that already are in a block of their own, like the root, are left as they are.
+    ///
+    /// Fails with [`VaultError::LinkAcrossBlocks`] if any of the moved nodes is also linked from outside of `path`,
+    /// as the links would no longer share the node.
+    pub fn move_to_block(&mut self, path: VaultPath) -> Result<(), VaultError> {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            // Only the root has no parent
+            return Ok(());
+        };
+        let mut plan = self.plan_path(parent.clone(), false)?;
+        let builder = plan.blocks.iter_mut().rev().find_map(|block| block.as_mut()).unwrap();
+        let parent_node_index = *plan.node_indexes.last().unwrap();
+        let directory = builder.base();
+        if directory.node_stat(parent_node_index).kind != NodeKind::Directory {
+            return Err(VaultError::NotADirectory(parent));
+        }
+        let (continuations, entry) = self.reader().find_entry(directory, parent_node_index, name)?;
+        let Some((block_id, node_index)) = entry else {
+            return Err(VaultError::NotFound(path));
+        };
+        if block_id.is_some() {
+            return Ok(());
+        }
+        // The entry is stored in the last continuation block, all of which get rewritten
+        for (_, continuation) in continuations {
+            plan.blocks.push(Some(DirectoryBuilder::new(continuation)));
+            plan.node_indexes.push(0);
+            plan.entry_names.push(String::from(DIRECTORY_CONTINUATION));
+        }
+        let builder = plan.blocks.iter_mut().rev().find_map(|block| block.as_mut()).unwrap();
+        let directory = builder.base();
+        if directory.local_subtree_is_shared(node_index)? {
+            return Err(VaultError::LinkAcrossBlocks(path));
+        }
+
+        let subtree = directory.local_subtree(node_index)?;
+        let block = directory.extract_local_subtree(node_index)?.info()?;
+        builder.remove_local_nodes(&subtree);
+        // Committing the plan writes the new block and points the parent's entry to it
+        plan.blocks.push(Some(DirectoryBuilder::new(block)));
+        plan.node_indexes.push(0);
+        plan.entry_names.push(String::from(name));
+        plan.modified.push(path);
+        self.commit_directory_plan(plan)
+    }
+
+    /// Removes the directory at `path` along with everything below it, calling `progress` for every removed node.
+    ///
+    /// The subtree is walked depth-first, so `progress` sees the contents of a directory before the directory itself.
+    /// The removal is committed at once, rewriting the directories along the path and the vault block
+    /// only a single time. `progress` is only called after that commit succeeds, so it's never called
+    /// if the removal fails. It's journaled like any other mutation, so an interrupted removal is either
+    /// completed or undone by [`Vault::recover`]. The blocks of the removed nodes are left in the provider.
+    ///
+    /// Fails with [`VaultError::NotADirectory`] if `path` is a file, and with [`VaultError::InvalidPath`] for the root.
+    pub fn rmdir_recursive(&mut self, path: VaultPath, progress: impl FnMut(&VaultPath)) -> Result<(), VaultError> {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            // The root can't be removed
+            return Err(VaultError::InvalidPath(path));
+        };
+        let mut plan = self.plan_path(parent.clone(), false)?;
+        let builder = plan.blocks.iter_mut().rev().find_map(|block| block.as_mut()).unwrap();
+        let parent_node_index = *plan.node_indexes.last().unwrap();
+        let directory = builder.base();
+        if directory.node_stat(parent_node_index).kind != NodeKind::Directory {
+            return Err(VaultError::NotADirectory(parent));
+        }
+        let (continuations, entry) = self.reader().find_entry(directory, parent_node_index, name)?;
+        let Some((block_id, node_index)) = entry else {
+            return Err(VaultError::NotFound(path));
+        };
+        if self.stat(path.clone())?.kind != NodeKind::Directory {
+            return Err(VaultError::NotADirectory(path));
+        }
+        let (directory_id, directory_node_index) = self.resolve(path.clone())?;
+        let mut removed = Vec::new();
+        self.walk_removed(directory_id, directory_node_index, path.clone(), &mut removed)?;
+
+        // The entry is stored in the last continuation block, all of which get rewritten
+        let mut entry_node_index = parent_node_index;
+        for (_, continuation) in continuations {
+            plan.blocks.push(Some(DirectoryBuilder::new(continuation)));
+            plan.node_indexes.push(0);
+            plan.entry_names.push(String::from(DIRECTORY_CONTINUATION));
+            entry_node_index = 0;
+        }
+        let builder = plan.blocks.iter_mut().rev().find_map(|block| block.as_mut()).unwrap();
+        let directory = builder.base();
+        // Inlined nodes that are also linked from elsewhere have to stay
+        let subtree = match block_id {
+            None if !directory.local_subtree_is_shared(node_index)? => directory.local_subtree(node_index)?,
+            _ => Vec::new(),
+        };
+        builder.remove_entry(entry_node_index, name);
+        builder.remove_local_nodes(&subtree);
+        plan.removed.push(path);
+        self.commit_directory_plan(plan)?;
+        removed.iter().for_each(progress);
+        Ok(())
+    }
+
+    /// Adds the paths of the nodes below the directory at `node_index` of the block `block_id` to `removed`,
+    /// followed by its own `path`, in the order reported by [`Vault::rmdir_recursive`].
+    ///
+    /// Entries in blocks that are missing and skipped are left out.
+    fn walk_removed(
+        &self,
+        block_id: BlockId,
+        node_index: u32,
+        path: VaultPath,
+        removed: &mut Vec<VaultPath>,
+    ) -> Result<(), VaultError> {
+        // Most entries are stored in the same block as the previous one
+        let mut entry_block: Option<(BlockId, InfoBlock)> = None;
+        for (entry_block_id, entry_node_index, name, _) in self.reader().directory_entries(block_id, node_index)? {
+            if entry_block.as_ref().map(|(id, _)| *id) != Some(entry_block_id) {
+                let Some(block) = self.get_block(entry_block_id)? else {
+                    continue;
+                };
+                entry_block = Some((entry_block_id, block.info()?));
+            }
+            let (_, block) = entry_block.as_ref().unwrap();
+            let child = path.child(&name);
+            if block.node_stat(entry_node_index).kind == NodeKind::Directory {
+                self.walk_removed(entry_block_id, entry_node_index, child, removed)?;
             } else {
-                panic!("No such entry: {:?} in {:?}", file_name, path.parent().unwrap());
+                removed.push(child);
             }
         }
-        // Root node
-        (self.root_id, 0)
+        removed.push(path);
+        Ok(())
     }
 
-    pub fn list(&self, path: VaultPath) -> Vec<(NodeKind, String)> {
-        let (block_id, node_index) = self.get_path_block_id_and_node_index(path);
-        let list_block = self.provider.get_block(block_id).info();
-        list_block
-            .directory_list(node_index)
-            .iter()
-            .map(|(kind, name)| (*kind, String::from(*name)))
-            .collect()
+    /// Writes the blocks of the `plan` to the provider and commits the new vault state.
+    fn commit_directory_plan(&mut self, plan: DirectoryPlan) -> Result<(), VaultError> {
+        if let Some((root_id, root_block, mut events)) = self.write_directory_plan(plan)? {
+            self.commit_root_id(root_id, root_block)?;
+            events.push(VaultEvent::Committed(self.root_id));
+            self.publish(events);
+        }
+        Ok(())
+    }
+
+    /// Writes the blocks of the `plan` to the provider, without committing a new vault block.
+    ///
+    /// Returns the id of the new root directory block along with the block itself and the events
+    /// to publish once committed, or `None` if the plan doesn't change anything.
+    fn write_directory_plan(
+        &self,
+        plan: DirectoryPlan,
+    ) -> Result<Option<(BlockId, InfoBlock, Vec<VaultEvent>)>, VaultError> {
+        let DirectoryPlan {
+            created,
+            modified,
+            removed,
+            blocks,
+            entry_names,
+            node_indexes,
+            ..
+        } = plan;
+
+        // Tricky task of backtracking and updating all the blockid references
+
+        if created.is_empty() && modified.is_empty() && removed.is_empty() {
+            return Ok(None);
+        }
+        // All the blocks of the plan are stamped with the same time
+        let time = self.now();
+        let mut entry_block = None;
+        let mut entry_block_id = None;
+        let mut entry_node_index = None;
+        let mut entry_name = None;
+        // Entries of inlined directories are stored in the closest block up the path
+        let mut pending_entries = Vec::new();
+        // All the directory blocks are saved together, so that a failure doesn't leave some of them behind
+        let mut staged = Vec::new();
+
+        for (i, block) in blocks.into_iter().enumerate().rev() {
+            let node_index = node_indexes[i];
+            let name = entry_names[i].as_str();
+
+            if let (Some(entry_node_index), Some(entry_name)) = (entry_node_index, entry_name) {
+                pending_entries.push((node_index, entry_name, entry_block_id, entry_node_index));
+            }
+            if let Some(mut builder) = block {
+                builder.set_time(time);
+                // Make sure the entries are pointing to this
+                for (directory_node_index, entry_name, entry_block_id, entry_node_index) in pending_entries.drain(..) {
+                    builder.set_entry_block_id_and_node_index(
+                        directory_node_index,
+                        entry_name,
+                        entry_block_id.as_ref(),
+                        entry_node_index,
+                    );
+                }
+
+                let block = self.split_directory_block(builder.build()?, &mut staged)?;
+                let encrypted_block = EncryptedBlock::encrypt(&block, 0);
+                let block_id = encrypted_block.id_with(BlockKind::Info, self.provider.hasher())?;
+                staged.push((block_id, encrypted_block));
+                let block = block.info()?;
+                #[cfg(feature = "tracing")]
+                tracing::debug!(block_id = %block_id.base64(), "Created a new directory block");
+
+                entry_block = Some(block);
+                entry_block_id = Some(block_id);
+            } else {
+                entry_block = None;
+                entry_block_id = None;
+            }
+            entry_node_index = Some(node_index as u16);
+            entry_name = Some(name);
+        }
+
+        self.write_blocks(staged)?;
+
+        let mut events: Vec<VaultEvent> = created.into_iter().map(VaultEvent::Created).collect();
+        events.extend(modified.into_iter().map(VaultEvent::Modified));
+        events.extend(removed.into_iter().map(VaultEvent::Removed));
+        Ok(Some((entry_block_id.unwrap(), entry_block.unwrap(), events)))
+    }
+
+    /// Splits the directory at the first node of `block` into chained blocks if the block is larger than
+    /// the size set by [`Vault::set_max_directory_block_size`], adding all the blocks but the first one to `staged`.
+    ///
+    /// The oldest entries that fit are moved out to a new block, which the directory continues in.
+    /// An entry that doesn't fit next to any other is first moved into a block of its own,
+    /// like with [`Vault::move_to_block`]. Returns the first block, which is left for the caller to stage.
+    fn split_directory_block(
+        &self,
+        mut block: Block,
+        staged: &mut Vec<(BlockId, EncryptedBlock)>,
+    ) -> Result<Block, VaultError> {
+        let max_size = *self.max_directory_block_size as usize;
+        while block.size() > max_size {
+            let directory = block.info()?;
+            if directory.node_stat(0).kind != NodeKind::Directory {
+                return Err(VaultError::Unsupported(
+                    "nodes that don't fit into a block of their own",
+                ));
+            }
+            let count = directory.directory_fitting_entries(max_size)?;
+            let (entries, _) = directory.directory_entries(0, 0, usize::MAX)?;
+            // Moving out nothing but the continuation entry wouldn't make the block any smaller
+            if entries[..count]
+                .iter()
+                .any(|(_, _, name)| *name != DIRECTORY_CONTINUATION)
+            {
+                let moved = directory.directory_take_entries(count)?;
+                let encrypted_block = EncryptedBlock::encrypt(&moved, 0);
+                let moved_id = encrypted_block.id_with(BlockKind::Info, self.provider.hasher())?;
+                staged.push((moved_id, encrypted_block));
+                block = directory.directory_continue(count, &moved_id)?;
+                continue;
+            }
+
+            let Some((None, node_index, name)) = entries.get(count).copied() else {
+                return Err(VaultError::Unsupported(
+                    "nodes that don't fit into a block of their own",
+                ));
+            };
+            if directory.local_subtree_is_shared(node_index)? {
+                return Err(VaultError::Unsupported(
+                    "splitting directories with links that don't fit into a block",
+                ));
+            }
+            let own = self.split_directory_block(directory.extract_local_subtree(node_index)?, staged)?;
+            let encrypted_block = EncryptedBlock::encrypt(&own, 0);
+            let own_id = encrypted_block.id_with(BlockKind::Info, self.provider.hasher())?;
+            staged.push((own_id, encrypted_block));
+            let mut builder = DirectoryBuilder::new(directory.block().info()?);
+            builder.set_entry_block_id_and_node_index(0, name, Some(&own_id), 0);
+            builder.remove_local_nodes(&directory.local_subtree(node_index)?);
+            block = builder.build()?;
+        }
+        Ok(block)
+    }
+
+    /// Returns the contents of the file at `path`.
+    pub fn get(&self, path: VaultPath) -> Result<Vec<u8>, VaultError> {
+        self.reader().get(path)
+    }
+
+    /// Returns `len` bytes of the file at `path` starting from `offset`, or fewer if the file ends before that.
+    ///
+    /// Only the blocks covering the range are loaded, apart from content defined blocks before it,
+    /// which are read for their sizes. Fails with [`VaultError::OffsetPastEnd`] if `offset` lies past the end.
+    pub fn get_range(&self, path: VaultPath, offset: FileOffset, len: FileSize) -> Result<Vec<u8>, VaultError> {
+        self.reader().get_range(path, offset, len)
+    }
+
+    /// Returns the block id and node index of the node at `path`.
+    ///
+    /// Symlinks along the path are followed, but a symlink at `path` itself is returned as it is.
+    /// Fails with [`VaultError::NotFound`] if any component of the path doesn't exist,
+    /// including when it can't be looked up because a missing block was skipped.
+    pub fn resolve(&self, path: VaultPath) -> Result<(BlockId, u32), VaultError> {
+        self.reader().resolve(path)
+    }
+
+    /// Returns the ids of all the blocks reachable from the current vault block, including itself.
+    ///
+    /// Blocks that are missing and skipped by the [`MissingBlockPolicy`] are included, but not traversed.
+    pub fn reachable_block_ids(&self) -> Result<BTreeSet<BlockId>, VaultError> {
+        let mut reachable = BTreeSet::new();
+        reachable
+            .insert(EncryptedBlock::encrypt(&self.vault.block(), 0).id_with(BlockKind::Info, self.provider.hasher())?);
+        reachable
+            .insert(EncryptedBlock::encrypt(&self.index.block(), 0).id_with(BlockKind::Info, self.provider.hasher())?);
+
+        self.reader().add_reachable_block_ids(&mut reachable)?;
+        Ok(reachable)
+    }
+
+    /// Deletes the saved blocks that are neither reachable from the current vault block
+    /// nor from the last `keep_snapshots` entries of the [history](Vault::history).
+    ///
+    /// With `dry_run` nothing is deleted and the outcome tells what would be.
+    /// Snapshots whose blocks have already been collected are skipped, and the older ones can't be opened
+    /// anymore once their blocks are gone. Only blocks saved to disk are collected, cached ones stay.
+    #[cfg(feature = "std-fs")]
+    pub fn gc(&self, keep_snapshots: usize, dry_run: bool) -> Result<GcOutcome, VaultError> {
+        let mut live = self.reachable_block_ids()?;
+        for &vault_id in self.history.iter().rev().take(keep_snapshots) {
+            let Ok(snapshot) = VaultReader::new(self.provider, vault_id) else {
+                continue;
+            };
+            let Some(vault_block) = self.provider.get_block(vault_id)? else {
+                continue;
+            };
+            let (_, index_id) = vault_block.info()?.get_root_id_and_index_id();
+            live.extend([vault_id, index_id]);
+            VaultReader {
+                missing_block_policy: MissingBlockPolicy::Skip,
+                ..snapshot
+            }
+            .add_reachable_block_ids(&mut live)?;
+        }
+
+        let mut outcome = GcOutcome::default();
+        for (id, path) in self.provider.saved_block_ids()? {
+            if !live.contains(&id) {
+                outcome.blocks += 1;
+                outcome.bytes += fs::metadata(&path).map_err(|e| ProviderError::Io(path, e))?.len();
+            }
+        }
+        if !dry_run {
+            outcome.bytes = self.provider.compact(&live)?;
+        }
+        Ok(outcome)
+    }
+
+    /// Copies the whole vault into `target`, hashing every block again with the [`Hasher`](crate::Hasher)
+    /// of `target`, and returns the id of the new vault block there.
+    ///
+    /// This migrates a vault to another hash function, which changes the ids of all the blocks.
+    /// The tree is walked depth-first from the leaves up, so that every directory block is rebuilt
+    /// with the new ids of its children right after they have been stored. Only the blocks along
+    /// the current path are held at once, besides the ids already migrated, which keeps blocks shared
+    /// by several files from being migrated twice. Save the returned id with
+    /// [`Provider::save_block_id_to_file`] to open the migrated vault.
+    pub fn rehash_all(&self, target: &Provider) -> Result<BlockId, VaultError> {
+        let mut migrated = BTreeMap::new();
+        let root_id = self.rehash_block(self.root_id, target, &mut migrated)?;
+        let index_id = store_rehashed(target, BlockKind::Info, self.index.block())?;
+        store_rehashed(target, BlockKind::Info, InfoBlock::new_vault(root_id, index_id))
+    }
+
+    /// Stores the block `id` and all the blocks below it in `target`, returning its new id there.
+    ///
+    /// The new ids of the blocks that are already stored are kept in `migrated`.
+    fn rehash_block(
+        &self,
+        id: BlockId,
+        target: &Provider,
+        migrated: &mut BTreeMap<BlockId, BlockId>,
+    ) -> Result<BlockId, VaultError> {
+        if let Some(new_id) = migrated.get(&id) {
+            return Ok(*new_id);
+        }
+        // Holes don't depend on the hasher
+        if id.is_hole() {
+            return Ok(id);
+        }
+        let kind = Block::kind_from_id(id);
+        let mut block = self.require_block(id)?;
+        if kind == BlockKind::Info {
+            let info = block.info()?;
+            let mut new_ids = BTreeMap::new();
+            for child_id in info.referenced_block_ids() {
+                let new_id = self.rehash_block(child_id, target, migrated)?;
+                new_ids.insert(child_id, new_id);
+            }
+            block = info.replace_block_ids(|child_id| new_ids[&child_id]);
+        }
+        let new_id = store_rehashed(target, kind, block)?;
+        migrated.insert(id, new_id);
+        Ok(new_id)
+    }
+
+    /// Returns the number of reachable blocks per [`BlockSize`].
+    pub fn block_size_histogram(&self) -> Result<BTreeMap<BlockSize, usize>, VaultError> {
+        let mut histogram = BTreeMap::new();
+        for block_id in self.reachable_block_ids()? {
+            *histogram.entry(block_id.block_size()).or_default() += 1;
+        }
+        Ok(histogram)
+    }
+
+    /// Returns the disk usage of the file or the whole directory tree at `path`.
+    ///
+    /// The totals are plain byte counts instead of [`FileSize`], as a tree can hold more than the largest file.
+    pub fn du(&self, path: VaultPath) -> Result<DiskUsage, VaultError> {
+        let mut usage = DiskUsage::default();
+        let mut visited = BTreeSet::new();
+        self.du_node(path, &mut usage, &mut visited)?;
+        Ok(usage)
+    }
+
+    /// Adds the disk usage of the node at `path` to `usage`, skipping the data blocks already `visited`.
+    fn du_node(
+        &self,
+        path: VaultPath,
+        usage: &mut DiskUsage,
+        visited: &mut BTreeSet<BlockId>,
+    ) -> Result<(), VaultError> {
+        let (block_id, node_index) = self.resolve(path.clone())?;
+        let Some(block) = self.get_block(block_id)? else {
+            return Ok(());
+        };
+        let block = block.info()?;
+        let stat = block.node_stat(node_index);
+        if let Some(size) = stat.size {
+            usage.logical += *size;
+            // Every block is full according to the chunk strategy, except the last one, which holds what remains.
+            // Content defined blocks are read for their sizes, which can't be told from the strategy alone.
+            let chunk_strategy = self.provider.chunk_strategy();
+            let mut remaining = *size;
+            for (block_id, block_size) in block
+                .file_block_ids(node_index)?
+                .into_iter()
+                .zip(chunk_strategy.sizes())
+            {
+                let block_len = if chunk_strategy.is_content_defined() {
+                    self.require_block(block_id)?.size() as u64
+                } else {
+                    *FileSize::from(block_size)
+                };
+                let bytes = remaining.min(block_len);
+                remaining -= bytes;
+                if !block_id.is_hole() && visited.insert(block_id) {
+                    usage.physical += bytes;
+                }
+            }
+        } else if stat.kind == NodeKind::Directory {
+            let (entries, _) = self.list_paged(path.clone(), 0, usize::MAX)?;
+            for (_, name) in entries {
+                self.du_node(path.child(&name), usage, visited)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the paths of all the nodes that reference the block `id`, in sorted order.
+    ///
+    /// A node references the block that it's stored in, which is shared by the nodes inlined into it,
+    /// and files also reference their data blocks. Handy for tracking down the files affected by a bad block.
+    pub fn find_by_block_id(&self, id: BlockId) -> Result<Vec<VaultPath>, VaultError> {
+        let mut found = BTreeSet::new();
+        self.find_in_node(VaultPath::new("/"), id, &mut found)?;
+        Ok(found.into_iter().collect())
+    }
+
+    /// Adds the paths of the node at `path` and the nodes below it that reference the block `id` to `found`.
+    fn find_in_node(&self, path: VaultPath, id: BlockId, found: &mut BTreeSet<VaultPath>) -> Result<(), VaultError> {
+        let (block_id, node_index) = self.resolve(path.clone())?;
+        let Some(block) = self.get_block(block_id)? else {
+            return Ok(());
+        };
+        let block = block.info()?;
+        let stat = block.node_stat(node_index);
+        if block_id == id || (stat.kind == NodeKind::File && block.file_block_ids(node_index)?.contains(&id)) {
+            found.insert(path.clone());
+        }
+        if stat.kind == NodeKind::Directory {
+            let (entries, _) = self.list_paged(path.clone(), 0, usize::MAX)?;
+            for (_, name) in entries {
+                self.find_in_node(path.child(&name), id, found)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the ids of the data blocks of the file at `path`, in the order of the file contents.
+    pub fn file_block_ids(&self, path: VaultPath) -> Result<Vec<BlockId>, VaultError> {
+        self.reader().file_block_ids(path)
+    }
+
+    /// Returns a digest of the contents of the file at `path`, derived from the ids of its data blocks.
+    ///
+    /// Files with the same contents have the same digest, so they can be compared without reading any data.
+    /// The digest isn't the id of an actual block, so its header doesn't carry any meaning.
+    pub fn file_digest(&self, path: VaultPath) -> Result<BlockId, VaultError> {
+        let data: Vec<u8> = self
+            .file_block_ids(path)?
+            .iter()
+            .flat_map(|block_id| *block_id.data())
+            .collect();
+        Ok(BlockId::new(self.provider.hasher().hash(&data), 0, false)?)
+    }
+
+    /// Hashes the saved data blocks of the file at `path` again and returns the ids of the damaged ones
+    /// along with what's wrong with them, in file order and each only once.
+    ///
+    /// Only the blocks of the one file are read, which is much cheaper than [`Provider::verify_all`].
+    /// The block files are read even if the blocks are cached, check out [`Provider::verify_block`].
+    /// A block whose file can't be read is reported as [`IntegrityError::Unreadable`].
+    pub fn verify_file(&self, path: VaultPath) -> Result<Vec<(BlockId, IntegrityError)>, VaultError> {
+        let mut block_ids = self.file_block_ids(path)?;
+        block_ids.retain(|block_id| !block_id.is_hole());
+        let mut seen = BTreeSet::new();
+        block_ids.retain(|block_id| seen.insert(*block_id));
+        let provider = self.provider;
+        let damaged: Vec<Option<IntegrityError>> = block_ids
+            .par_iter()
+            .map(|block_id| provider.verify_block(*block_id))
+            .collect();
+        Ok(block_ids
+            .into_iter()
+            .zip(damaged)
+            .filter_map(|(block_id, damaged)| Some((block_id, damaged?)))
+            .collect())
+    }
+
+    /// Returns `true` if a node exists at `path`.
+    ///
+    /// Paths that can't be resolved because of missing blocks are reported as not existing.
+    pub fn exists(&self, path: VaultPath) -> bool {
+        self.reader().exists(path)
+    }
+
+    /// Returns the [`NodeStat`] of the node at `path`.
+    ///
+    /// A symlink at `path` is reported as a symlink, rather than as the node it points to.
+    /// Fails with [`VaultError::NotFound`] if it doesn't exist.
+    pub fn stat(&self, path: VaultPath) -> Result<NodeStat, VaultError> {
+        self.reader().stat(path)
+    }
+
+    pub fn list(&self, path: VaultPath) -> Result<Vec<(NodeKind, String)>, VaultError> {
+        self.reader().list(path)
+    }
+
+    /// Returns the entries of the directory at `path` with the size of files and where their nodes are stored.
+    ///
+    /// Check out [`Vault::list_paged`] for how entries stored in other blocks are handled.
+    pub fn ls(&self, path: VaultPath) -> Result<Vec<DirEntry>, VaultError> {
+        self.reader().ls(path)
+    }
+
+    /// Returns up to `limit` entries of the directory at `path` starting from `offset`,
+    /// along with whether more entries follow.
+    ///
+    /// Entries stored in other blocks are looked up for their kind.
+    /// If such a block is missing and the policy is to skip it, the entry is left out of the page.
+    pub fn list_paged(
+        &self,
+        path: VaultPath,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<(NodeKind, String)>, bool), VaultError> {
+        self.reader().list_paged(path, offset, limit)
+    }
+
+    /// Returns the paths of all the nodes that match `pattern`, in sorted order.
+    ///
+    /// The pattern is matched one path component at a time, starting from the root.
+    /// In a component `*` matches any number of characters and `?` matches a single one,
+    /// while a whole component of `**` matches any number of directories, including none.
+    /// Only directory blocks are loaded, never file data.
+    pub fn glob(&self, pattern: &str) -> Result<Vec<VaultPath>, VaultError> {
+        let segments: Vec<&str> = pattern.split('/').filter(|segment| !segment.is_empty()).collect();
+        let mut matches = BTreeSet::new();
+        self.glob_directory(VaultPath::new("/"), &segments, &mut matches)?;
+        Ok(matches.into_iter().collect())
+    }
+
+    /// Adds the paths below the directory at `path` that match the pattern `segments` to `matches`.
+    fn glob_directory(
+        &self,
+        path: VaultPath,
+        segments: &[&str],
+        matches: &mut BTreeSet<VaultPath>,
+    ) -> Result<(), VaultError> {
+        let Some((segment, rest)) = segments.split_first() else {
+            matches.insert(path);
+            return Ok(());
+        };
+        if *segment == "**" {
+            // Match no directories at all
+            self.glob_directory(path.clone(), rest, matches)?;
+        }
+        let (entries, _) = self.list_paged(path.clone(), 0, usize::MAX)?;
+        for (kind, name) in entries {
+            if *segment == "**" {
+                if kind == NodeKind::Directory {
+                    self.glob_directory(path.child(&name), segments, matches)?;
+                }
+            } else if wildcard_matches(segment, &name) {
+                if rest.is_empty() {
+                    matches.insert(path.child(&name));
+                } else if kind == NodeKind::Directory {
+                    self.glob_directory(path.child(&name), rest, matches)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the contents of the OS directory `src_dir` to the directory at `dest`, which is created if missing.
+    ///
+    /// Symbolic links, unreadable files and entries that can't be added are skipped and reported
+    /// in the returned [`ImportOutcome`] instead of stopping the import.
+    /// Fails only if `src_dir` itself can't be read or `dest` can't be created.
+    #[cfg(feature = "std-fs")]
+    pub fn import(&mut self, src_dir: &Path, dest: VaultPath) -> Result<ImportOutcome, VaultError> {
+        self.create_directory(dest.clone())?;
+        let mut outcome = ImportOutcome::default();
+        self.import_directory(src_dir, dest, &mut outcome)?;
+        Ok(outcome)
+    }
+
+    /// Adds the entries of the OS directory `os_dir` to the existing directory at `path`.
+    #[cfg(feature = "std-fs")]
+    fn import_directory(&mut self, os_dir: &Path, path: VaultPath, outcome: &mut ImportOutcome) -> io::Result<()> {
+        let mut entries = fs::read_dir(os_dir)?.collect::<io::Result<Vec<_>>>()?;
+        // Sorted for a deterministic order of commits
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let os_path = entry.path();
+            let Some(name) = entry.file_name().to_str().map(String::from) else {
+                let error = io::Error::new(io::ErrorKind::InvalidData, "Name isn't valid Unicode.");
+                outcome.skipped.push((os_path, error.into()));
+                continue;
+            };
+            let child = match path.try_child(&name) {
+                Ok(child) => child,
+                Err(e) => {
+                    outcome.skipped.push((os_path, e.into()));
+                    continue;
+                }
+            };
+            let imported = entry.file_type().map_err(VaultError::from).and_then(|file_type| {
+                if file_type.is_symlink() {
+                    Err(VaultError::Unsupported("importing symbolic links"))
+                } else if file_type.is_dir() {
+                    self.create_directory(child.clone())?;
+                    outcome.directories += 1;
+                    self.import_directory(&os_path, child, outcome)
+                        .map_err(VaultError::from)
+                } else {
+                    let size = check_file_size(entry.metadata()?.len())?;
+                    let (block_ids, _) = self.write_data_blocks(fs::File::open(&os_path)?, size)?;
+                    self.create_file(child, size, &block_ids)?;
+                    outcome.files += 1;
+                    Ok(())
+                }
+            });
+            if let Err(e) = imported {
+                outcome.skipped.push((os_path, e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the subtree at `src` to the OS directory `dest_dir`, which is created if it doesn't exist.
+    ///
+    /// The entries of a directory at `src` end up directly in `dest_dir`, while a file at `src`
+    /// is written into `dest_dir` under its own name. Existing OS files with the same names are overwritten.
+    #[cfg(feature = "std-fs")]
+    pub fn export(&self, src: VaultPath, dest_dir: &Path) -> Result<(), VaultError> {
+        let stat = self.stat(src.clone())?;
+        if stat.kind != NodeKind::File {
+            return self.export_node(src, stat.kind, dest_dir);
+        }
+        fs::create_dir_all(dest_dir)?;
+        // Only the root has no file name, and it's a directory
+        let os_path = dest_dir.join(src.file_name().unwrap());
+        self.export_node(src, NodeKind::File, &os_path)
+    }
+
+    /// Writes the node of `kind` at `path` to the OS path `os_path`, including all of its descendants.
+    ///
+    /// Symlinks are left out, as their targets are vault paths that mean nothing outside of the vault.
+    #[cfg(feature = "std-fs")]
+    fn export_node(&self, path: VaultPath, kind: NodeKind, os_path: &Path) -> Result<(), VaultError> {
+        match kind {
+            NodeKind::File => fs::write(os_path, self.get(path)?)?,
+            NodeKind::Symlink => (),
+            _ => {
+                fs::create_dir_all(os_path)?;
+                for (kind, name) in self.list(path.clone())? {
+                    self.export_node(path.child(&name), kind, &os_path.join(name))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Vault<'_> {
+    /// Summarizes the vault by its state file and the Base64 ids of its current blocks.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Vault")
+            .field("path", &self.path)
+            .field("vault_id", &self.vault_id.base64())
+            .field("root_id", &self.root_id.base64())
+            .field("index_id", &self.index_id().base64())
+            .field("history", &self.history.len())
+            .finish()
+    }
+}
+
+/// Read-only view of a vault, which only borrows the [`Provider`] immutably.
+///
+/// Any number of readers can be used at the same time, including from multiple threads.
+/// A reader sees the vault as of the vault block it was created from.
+#[derive(Clone, Copy)]
+pub struct VaultReader<'a> {
+    provider: &'a Provider,
+    root_id: BlockId,
+    missing_block_policy: MissingBlockPolicy<'a>,
+}
+
+impl<'a> VaultReader<'a> {
+    /// Create a new `VaultReader` for the vault block with the specified `vault_id`.
+    ///
+    /// The vault block and the root block are loaded from disk unless the provider already has them.
+    pub fn new(provider: &'a Provider, vault_id: BlockId) -> Result<VaultReader<'a>, VaultError> {
+        let load = |id| match provider.get_block(id)? {
+            Some(block) => Ok(block),
+            None => provider.load_block_from_file(id, 0),
+        };
+        let vault_block = load(vault_id)
+            .map_err(|e| VaultError::UnreadableVaultBlock(vault_id, e))?
+            .info()?;
+        let (root_id, _) = vault_block.get_root_id_and_index_id();
+        load(root_id).map_err(|e| VaultError::DanglingRoot(root_id, e))?;
+
+        Ok(VaultReader {
+            provider,
+            root_id,
+            missing_block_policy: MissingBlockPolicy::default(),
+        })
+    }
+
+    /// Returns the block with the specified `id`, consulting the [`MissingBlockPolicy`] if it's missing.
+    ///
+    /// Returns `Ok(None)` if the block is missing and the policy is to skip it.
+    fn get_block(&self, id: BlockId) -> Result<Option<Block>, VaultError> {
+        if id.is_hole() {
+            // Holes aren't stored, their zeros are made up on the spot
+            return Ok(Some(Block::from_data(vec![0; *id.block_size() as usize].into())));
+        }
+        let error = match self.provider.get_block(id) {
+            Ok(Some(block)) => return Ok(Some(block)),
+            Ok(None) => VaultError::MissingBlock(id),
+            Err(e) => e.into(),
+        };
+        match self.missing_block_policy {
+            MissingBlockPolicy::Error => Err(error),
+            MissingBlockPolicy::Skip if matches!(error, VaultError::MissingBlock(_)) => Ok(None),
+            MissingBlockPolicy::Skip => Err(error),
+            MissingBlockPolicy::Recover(backup) => {
+                let block = backup.get_block(id)?.ok_or(error)?;
+                // Repair the primary provider, but only with the very block that was asked for
+                let encrypted_block = EncryptedBlock::encrypt(&block, 0);
+                let actual = encrypted_block.id_with(Block::kind_from_id(id), self.provider.hasher())?;
+                if actual != id {
+                    return Err(ProviderError::BlockMismatch { expected: id, actual }.into());
+                }
+                Ok(Some(self.provider.add_block(id, encrypted_block, block)?))
+            }
+        }
+    }
+
+    /// Returns the block with the specified `id`, failing even if the policy is to skip missing blocks.
+    fn require_block(&self, id: BlockId) -> Result<Block, VaultError> {
+        self.get_block(id)?.ok_or(VaultError::MissingBlock(id))
+    }
+
+    /// Adds the ids of the root block and all the blocks below it to `reachable`.
+    ///
+    /// Blocks that are missing and skipped by the [`MissingBlockPolicy`] are included, but not traversed.
+    fn add_reachable_block_ids(&self, reachable: &mut BTreeSet<BlockId>) -> Result<(), VaultError> {
+        let mut pending = vec![self.root_id];
+        while let Some(block_id) = pending.pop() {
+            if block_id.is_hole() || !reachable.insert(block_id) || Block::kind_from_id(block_id) != BlockKind::Info {
+                continue;
+            }
+            if let Some(block) = self.get_block(block_id)? {
+                pending.extend(block.info()?.referenced_block_ids());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the contents of the file at `path`.
+    pub fn get(&self, path: VaultPath) -> Result<Vec<u8>, VaultError> {
+        let block_ids = self.file_block_ids(path)?;
+        self.provider.prefetch(&block_ids);
+        let mut data = Vec::new();
+        for block_id in block_ids {
+            data.extend_from_slice(&self.require_block(block_id)?.data());
+        }
+        Ok(data)
+    }
+
+    /// Returns `len` bytes of the file at `path` starting from `offset`, or fewer if the file ends before that.
+    ///
+    /// Check out [`Vault::get_range`] for which blocks get loaded.
+    pub fn get_range(&self, path: VaultPath, offset: FileOffset, len: FileSize) -> Result<Vec<u8>, VaultError> {
+        let (block_id, node_index) = self.resolve_target(path.clone())?;
+        let block = self.require_block(block_id)?.info()?;
+        let NodeStat {
+            kind: NodeKind::File,
+            size: Some(size),
+            ..
+        } = block.node_stat(node_index)
+        else {
+            return Err(VaultError::NotAFile(path));
+        };
+        if *offset > *size {
+            return Err(VaultError::OffsetPastEnd {
+                offset: *offset,
+                size: *size,
+            });
+        }
+        let end = offset.saturating_add(*len).min(*size);
+        if end == *offset {
+            return Ok(Vec::new());
+        }
+
+        let block_ids = block.file_block_ids(node_index)?;
+        let (first_index, first_block_len) = self.locate_last_byte(&block_ids, offset)?;
+        let (last_index, last_block_len) = self.locate_last_byte(&block_ids, FileOffset::new(end - 1))?;
+        let block_ids = &block_ids[first_index..=last_index];
+        self.provider.prefetch(block_ids);
+        let mut data = Vec::with_capacity((end - *offset) as usize);
+        for (index, block_id) in block_ids.iter().enumerate() {
+            let block_data = self.require_block(*block_id)?.data();
+            let start = if index == 0 { first_block_len - 1 } else { 0 };
+            let stop = if index == block_ids.len() - 1 {
+                last_block_len
+            } else {
+                block_data.len()
+            };
+            data.extend_from_slice(&block_data[start..stop]);
+        }
+        Ok(data)
+    }
+
+    /// Returns the index of the block that contains the byte at `offset` of the file stored in `block_ids`,
+    /// along with the number of bytes up to and including that byte in the block.
+    ///
+    /// Content defined blocks are read for their sizes, which can't be told from the strategy alone.
+    fn locate_last_byte(&self, block_ids: &[BlockId], offset: FileOffset) -> Result<(usize, usize), VaultError> {
+        let chunk_strategy = self.provider.chunk_strategy();
+        if chunk_strategy.id() == Layout.id() {
+            let (block_index, block_offset) =
+                InfoBlock::translate_file_offset(offset).ok_or(VaultError::FileTooLarge {
+                    size: *offset + 1,
+                    max: MAX_FILE_SIZE,
+                })?;
+            return Ok((*block_index as usize, *FileOffset::from(block_offset) as usize + 1));
+        }
+        let mut block_start_offset = 0;
+        for (block_index, size) in chunk_strategy.sizes().enumerate() {
+            let size = if chunk_strategy.is_content_defined() {
+                self.require_block(block_ids[block_index])?.size()
+            } else {
+                *size as usize
+            };
+            let block_end_offset = block_start_offset + size as u64;
+            if *offset < block_end_offset {
+                return Ok((block_index, (*offset - block_start_offset) as usize + 1));
+            }
+            block_start_offset = block_end_offset;
+        }
+        unreachable!("chunk strategies have endless sizes");
+    }
+
+    /// Returns the block id and node index of the node at `path`, without following a symlink at `path` itself.
+    ///
+    /// Fails with [`VaultError::NotFound`] if any component of the path doesn't exist,
+    /// including when it can't be looked up because a missing block was skipped.
+    fn resolve(&self, path: VaultPath) -> Result<(BlockId, u32), VaultError> {
+        self.get_path_block_id_and_node_index(path.clone(), false)?
+            .ok_or(VaultError::NotFound(path))
+    }
+
+    /// Returns the block id and node index of the node at `path`, following a symlink at `path` itself.
+    fn resolve_target(&self, path: VaultPath) -> Result<(BlockId, u32), VaultError> {
+        self.get_path_block_id_and_node_index(path.clone(), true)?
+            .ok_or(VaultError::NotFound(path))
+    }
+
+    /// Returns the block id and node index of the node at `path`.
+    ///
+    /// Symlinks along the path are followed, and so is the last component if `follow` is set.
+    /// Fails with [`VaultError::NotFound`] for the first component of the path that doesn't exist,
+    /// with [`VaultError::NotADirectory`] for the first one that is followed by more but isn't a directory,
+    /// and with [`VaultError::SymlinkLoop`] after following [`MAX_SYMLINK_DEPTH`] symlinks.
+    /// Returns `Ok(None)` if a block along the way is missing and the policy is to skip it.
+    fn get_path_block_id_and_node_index(
+        &self,
+        path: VaultPath,
+        follow: bool,
+    ) -> Result<Option<(BlockId, u32)>, VaultError> {
+        // TODO: Check in-memory cache
+        if !path.is_normal() {
+            return Err(VaultError::InvalidPath(path));
+        }
+
+        // Walk down from the root one directory at a time, with the names left to walk in reverse order
+        let mut names: Vec<String> = path.names().map(String::from).collect();
+        names.reverse();
+        let (mut block_id, mut node_index) = (self.root_id, 0);
+        let mut current_path = PathBuf::from("/");
+        let mut symlinks = 0;
+        while !names.is_empty() || follow {
+            let Some(block) = self.get_block(block_id)? else {
+                return Ok(None);
+            };
+            let block = block.info()?;
+            if let Some(target) = block.symlink_target(node_index) {
+                symlinks += 1;
+                if symlinks > MAX_SYMLINK_DEPTH {
+                    return Err(VaultError::SymlinkLoop(path));
+                }
+                // The block of the target takes precedence over its path, for as long as the block is around
+                if let Some(target_block) = block.symlink_block(node_index) {
+                    if self.provider.get_block(target_block)?.is_some() {
+                        (block_id, node_index) = (target_block, 0);
+                        current_path = PathBuf::from(target);
+                        continue;
+                    }
+                }
+                // Targets are absolute, so the walk starts over from the root
+                let target_names: Vec<String> = VaultPath::try_new(target)?.names().map(String::from).collect();
+                names.extend(target_names.into_iter().rev());
+                (block_id, node_index) = (self.root_id, 0);
+                current_path = PathBuf::from("/");
+                continue;
+            }
+            let Some(name) = names.pop() else {
+                break;
+            };
+            if block.node_stat(node_index).kind != NodeKind::Directory {
+                return Err(VaultError::NotADirectory(VaultPath::new(current_path)));
+            }
+            current_path.push(&name);
+            let (continuations, entry) = self.find_entry(&block, node_index, &name)?;
+            let Some((entry_block_id, entry_node_index)) = entry else {
+                return Err(VaultError::NotFound(VaultPath::new(current_path)));
+            };
+            // Local entries are stored in the last continuation block that was followed
+            let containing_block_id = continuations.last().map_or(block_id, |(id, _)| *id);
+            block_id = entry_block_id.unwrap_or(containing_block_id);
+            node_index = entry_node_index;
+        }
+        Ok(Some((block_id, node_index)))
+    }
+
+    /// Returns the ids of the data blocks of the file at `path`, in the order of the file contents.
+    fn file_block_ids(&self, path: VaultPath) -> Result<Vec<BlockId>, VaultError> {
+        let (block_id, node_index) = self.resolve_target(path.clone())?;
+        let block = self.require_block(block_id)?.info()?;
+        if block.node_stat(node_index).kind != NodeKind::File {
+            return Err(VaultError::NotAFile(path));
+        }
+        Ok(block.file_block_ids(node_index)?)
+    }
+
+    /// Returns `true` if a node exists at `path`.
+    ///
+    /// Paths that can't be resolved because of missing or unreadable blocks are reported as not existing.
+    pub fn exists(&self, path: VaultPath) -> bool {
+        self.stat(path).is_ok()
+    }
+
+    /// Returns the [`NodeStat`] of the node at `path`.
+    ///
+    /// Fails with [`VaultError::NotFound`] if it doesn't exist, including when its block is missing
+    /// and skipped by the [`MissingBlockPolicy`].
+    pub fn stat(&self, path: VaultPath) -> Result<NodeStat, VaultError> {
+        let (block_id, node_index) = self.resolve(path.clone())?;
+        let block = self.get_block(block_id)?.ok_or(VaultError::NotFound(path))?;
+        Ok(block.info()?.node_stat(node_index))
+    }
+
+    pub fn list(&self, path: VaultPath) -> Result<Vec<(NodeKind, String)>, VaultError> {
+        let (entries, _) = self.list_paged(path, 0, usize::MAX)?;
+        Ok(entries)
+    }
+
+    /// Returns the entries of the directory at `path` with the size of files and where their nodes are stored.
+    pub fn ls(&self, path: VaultPath) -> Result<Vec<DirEntry>, VaultError> {
+        let (entries, _) = self.dir_entries_paged(path, 0, usize::MAX)?;
+        Ok(entries)
+    }
+
+    /// Returns up to `limit` entries of the directory at `path` starting from `offset`,
+    /// along with whether more entries follow.
+    ///
+    /// Check out [`Vault::list_paged`] for how entries stored in other blocks are handled.
+    pub fn list_paged(
+        &self,
+        path: VaultPath,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<(NodeKind, String)>, bool), VaultError> {
+        let (entries, more) = self.dir_entries_paged(path, offset, limit)?;
+        Ok((
+            entries.into_iter().map(|entry| (entry.kind, entry.name)).collect(),
+            more,
+        ))
+    }
+
+    /// Returns up to `limit` [`DirEntry`]s of the directory at `path` starting from `offset`,
+    /// along with whether more entries follow.
+    ///
+    /// Fails with [`VaultError::NotFound`] if there is nothing at `path`, and with [`VaultError::NotADirectory`]
+    /// if it isn't a directory.
+    fn dir_entries_paged(
+        &self,
+        path: VaultPath,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<DirEntry>, bool), VaultError> {
+        let Some((block_id, node_index)) = self.get_path_block_id_and_node_index(path.clone(), true)? else {
+            return Ok((Vec::new(), false));
+        };
+        let Some(block) = self.get_block(block_id)? else {
+            return Ok((Vec::new(), false));
+        };
+        if block.info()?.node_stat(node_index).kind != NodeKind::Directory {
+            return Err(VaultError::NotADirectory(path));
+        }
+        let entries = self.directory_entries(block_id, node_index)?;
+        let more = entries.len().saturating_sub(offset) > limit;
+
+        let mut page = Vec::with_capacity(limit.min(entries.len()));
+        // Most entries are stored in the same block as the previous one
+        let mut entry_block: Option<(BlockId, InfoBlock)> = None;
+        for (entry_block_id, entry_node_index, name, inlined) in entries.into_iter().skip(offset).take(limit) {
+            if entry_block.as_ref().map(|(id, _)| *id) != Some(entry_block_id) {
+                let Some(block) = self.get_block(entry_block_id)? else {
+                    continue;
+                };
+                entry_block = Some((entry_block_id, block.info()?));
+            }
+            let (_, block) = entry_block.as_ref().unwrap();
+            let stat = block.node_stat(entry_node_index);
+            page.push(DirEntry {
+                name,
+                kind: stat.kind,
+                size: stat.size,
+                inlined,
+            });
+        }
+        Ok((page, more))
+    }
+
+    /// Looks up the entry `name` of the directory at `node_index` of `directory`, following its continuation blocks.
+    ///
+    /// Returns the continuation blocks that were followed, along with the entry as found in the last of them,
+    /// or in `directory` itself if none were followed. The entry is `None` if it doesn't exist,
+    /// including when a continuation block is missing and the policy is to skip it.
+    fn find_entry(&self, directory: &InfoBlock, node_index: u32, name: &str) -> Result<EntryLookup, VaultError> {
+        let mut continuations: Vec<(BlockId, InfoBlock)> = Vec::new();
+        loop {
+            let (block, block_node_index) = match continuations.last() {
+                Some((_, block)) => (block, 0),
+                None => (directory, node_index),
+            };
+            if let Some(entry) = block.directory_get_entry_block_id_and_node_index(block_node_index, name) {
+                return Ok((continuations, Some(entry)));
+            }
+            let Some((Some(next_id), _)) =
+                block.directory_get_entry_block_id_and_node_index(block_node_index, DIRECTORY_CONTINUATION)
+            else {
+                return Ok((continuations, None));
+            };
+            let Some(next_block) = self.get_block(next_id)? else {
+                return Ok((continuations, None));
+            };
+            continuations.push((next_id, next_block.info()?));
+        }
+    }
+
+    /// Returns the entries of the directory at `node_index` of the block `block_id`, oldest first,
+    /// including the ones stored in its continuation blocks.
+    ///
+    /// Every entry comes with the block id and node index of the node it refers to,
+    /// and whether that node is inlined into the block of the directory.
+    /// Continuation blocks that are missing and skipped are left out along with the blocks they continue in.
+    fn directory_entries(
+        &self,
+        block_id: BlockId,
+        node_index: u32,
+    ) -> Result<Vec<(BlockId, u32, String, bool)>, VaultError> {
+        let mut blocks = Vec::new();
+        let mut next = Some((block_id, node_index));
+        while let Some((block_id, node_index)) = next {
+            let Some(block) = self.get_block(block_id)? else {
+                break;
+            };
+            let block = block.info()?;
+            next = match block.directory_get_entry_block_id_and_node_index(node_index, DIRECTORY_CONTINUATION) {
+                Some((Some(next_id), next_node_index)) => Some((next_id, next_node_index)),
+                _ => None,
+            };
+            blocks.push((block_id, block, node_index));
+        }
+
+        // Continuation entries come first, so the blocks they refer to hold the older entries
+        let mut entries = Vec::new();
+        for (block_id, block, node_index) in blocks.iter().rev() {
+            let (block_entries, _) = block.directory_entries(*node_index, 0, usize::MAX)?;
+            for (entry_block_id, entry_node_index, name) in block_entries {
+                if name != DIRECTORY_CONTINUATION {
+                    entries.push((
+                        entry_block_id.unwrap_or(*block_id),
+                        entry_node_index,
+                        String::from(name),
+                        entry_block_id.is_none(),
+                    ));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Adds the changes between the node `old` and the node `new` at `path` to `changes`.
+    ///
+    /// Both nodes are given as the block id and the node index local to that block.
+    fn diff_node(
+        &self,
+        path: VaultPath,
+        old: (BlockId, u32),
+        new: (BlockId, u32),
+        changes: &mut Vec<Change>,
+    ) -> Result<(), VaultError> {
+        // Blocks are content addressed, so the same node of the same block is the same subtree
+        if old == new {
+            return Ok(());
+        }
+        let old_block = self.require_block(old.0)?.info()?;
+        let new_block = self.require_block(new.0)?.info()?;
+        let old_stat = old_block.node_stat(old.1);
+        let new_stat = new_block.node_stat(new.1);
+        if old_stat.kind != new_stat.kind {
+            changes.push(Change::Removed(path.clone()));
+            changes.push(Change::Added(path));
+            return Ok(());
+        }
+        if old_stat.kind == NodeKind::Symlink {
+            if old_block.symlink_target(old.1) != new_block.symlink_target(new.1)
+                || old_block.symlink_block(old.1) != new_block.symlink_block(new.1)
+            {
+                changes.push(Change::Modified(path));
+            }
+            return Ok(());
+        }
+        if old_stat.kind != NodeKind::Directory {
+            if old_stat.size != new_stat.size || old_block.file_block_ids(old.1)? != new_block.file_block_ids(new.1)? {
+                changes.push(Change::Modified(path));
+            }
+            return Ok(());
+        }
+
+        let old_entries = self.directory_entries(old.0, old.1)?;
+        let new_entries = self.directory_entries(new.0, new.1)?;
+        let new_by_name: BTreeMap<&str, (BlockId, u32)> = new_entries
+            .iter()
+            .map(|(block_id, node_index, name, _)| (name.as_str(), (*block_id, *node_index)))
+            .collect();
+        for (block_id, node_index, name, _) in &old_entries {
+            let old_child = (*block_id, *node_index);
+            match new_by_name.get(name.as_str()) {
+                Some(&new_child) => self.diff_node(path.child(name), old_child, new_child, changes)?,
+                None => changes.push(Change::Removed(path.child(name))),
+            }
+        }
+        let old_names: BTreeSet<&str> = old_entries.iter().map(|(_, _, name, _)| name.as_str()).collect();
+        for (_, _, name, _) in &new_entries {
+            if !old_names.contains(name.as_str()) {
+                changes.push(Change::Added(path.child(name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The continuation blocks followed by [`VaultReader::find_entry`], along with the entry it found, if any.
+///
+/// The entry consists of the block id and node index as returned by
+/// [`InfoBlock::directory_get_entry_block_id_and_node_index`].
+type EntryLookup = (Vec<(BlockId, InfoBlock)>, Option<(Option<BlockId>, u32)>);
+
+/// Returns `true` if the node at `node_idx` of `block` is, or contains, the local node at `target_idx`.
+///
+/// Only local entries are followed, as nodes of other blocks can't refer back to this block.
+fn contains_local_node(block: &InfoBlock, node_idx: u32, target_idx: u32) -> Result<bool, UnsupportedBlock> {
+    if node_idx == target_idx {
+        return Ok(true);
+    }
+    if block.node_stat(node_idx).kind != NodeKind::Directory {
+        return Ok(false);
+    }
+    let (entries, _) = block.directory_entries(node_idx, 0, usize::MAX)?;
+    for (block_id, entry_idx, _) in entries {
+        if block_id.is_none() && contains_local_node(block, entry_idx, target_idx)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Returns `true` if `name` matches `pattern`, where `*` matches any number of characters and `?` a single one.
+fn wildcard_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // The position of the last `*` in the pattern and the position in the name that it was tried at
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` match one more character
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Returns the id of the hole that the data `block` is stored as, if it's all zeros and fills a whole [`BlockSize`].
+///
+/// Shorter runs of zeros, like at the end of a file, are stored as usual, which keeps the length of every block
+/// known from its id alone.
+fn hole_id(block: &Block) -> Option<BlockId> {
+    let size = u32::try_from(block.size())
+        .ok()
+        .filter(|size| BlockSize::valid(*size))?;
+    let data = block.data();
+    data.iter()
+        .all(|byte| *byte == 0)
+        .then(|| BlockId::hole(BlockSize::new(size)))
+}
+
+/// Adds `block` of `kind` to `target`, returning its id as computed by the hasher of `target`.
+fn store_rehashed(target: &Provider, kind: BlockKind, block: Block) -> Result<BlockId, VaultError> {
+    let encrypted_block = EncryptedBlock::encrypt(&block, 0);
+    let id = encrypted_block.id_with(kind, target.hasher())?;
+    target.add_block(id, encrypted_block, block)?;
+    Ok(id)
+}
+
+/// Returns the [`FileSize`] if `size` is within the [`MAX_FILE_SIZE`] limit.
+fn check_file_size(size: u64) -> Result<FileSize, VaultError> {
+    FileSize::checked_new(size).ok_or(VaultError::FileTooLarge {
+        size,
+        max: MAX_FILE_SIZE,
+    })
+}
+
+#[cfg(all(test, feature = "std-fs"))]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use crate::{BlockIdIndex, ContentDefinedChunker, FixedSize, InvalidVaultPath, MAX_NAME_LENGTH, MAX_PATH_DEPTH};
+
+    /// Returns a provider that saves blocks to the `blocks` directory in `dir`.
+    fn disk_provider(dir: &TempDir) -> Provider {
+        Provider::with_base_dir(dir.join("blocks"))
+    }
+
+    /// Runs `mutate` on the vault with the state file at `path`, then leaves the state file, history and journal
+    /// behind as if the process had died right before updating the state file.
+    fn crash_before_state_update(provider: &Provider, path: &Path, mutate: impl FnOnce(&mut Vault)) {
+        let saved_ids = || -> HashSet<BlockId> {
+            provider
+                .saved_block_ids()
+                .unwrap()
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect()
+        };
+        let history_path = path.with_extension("history");
+        let state = fs::read(path).unwrap();
+        let history = fs::read(&history_path).ok();
+        let before = saved_ids();
+
+        let mut vault = Vault::open(provider, path).unwrap();
+        mutate(&mut vault);
+        let vault_id = vault.vault_id;
+
+        let writes: Vec<BlockId> = saved_ids().difference(&before).copied().collect();
+        fs::write(path, state).unwrap();
+        match history {
+            Some(history) => fs::write(&history_path, history).unwrap(),
+            None => fs::remove_file(&history_path).unwrap(),
+        }
+        let journal = Journal::for_state(path);
+        journal.record_writes(&writes).unwrap();
+        journal.record_commit(vault_id).unwrap();
+    }
+
+    /// Returns a freshly initialized vault that lives only in the provider's memory.
+    fn memory_vault(provider: &Provider) -> Vault<'_> {
+        let root_block = InfoBlock::new_directory();
+        let (root_block, _) =
+            root_block
+                .info()
+                .unwrap()
+                .directory_create_local_node(0, "welcome", NodeKind::Directory, None);
+        let root_id = EncryptedBlock::encrypt(&root_block, 0).id(BlockKind::Info).unwrap();
+        provider.cache_block(root_id, root_block.clone());
+
+        let index_block = InfoBlock::new_index();
+        let index_id = EncryptedBlock::encrypt(&index_block, 0).id(BlockKind::Info).unwrap();
+        provider.cache_block(index_id, index_block.clone());
+
+        let vault_block = InfoBlock::new_vault(root_id, index_id);
+        let vault_id = EncryptedBlock::encrypt(&vault_block, 0).id(BlockKind::Info).unwrap();
+        provider.cache_block(vault_id, vault_block.clone());
+
+        Vault {
+            path: None,
+            provider,
+            vault: vault_block.info().unwrap(),
+            root: root_block.info().unwrap(),
+            root_id,
+            index: index_block.info().unwrap(),
+            vault_id,
+            history: Vec::new(),
+            missing_block_policy: MissingBlockPolicy::default(),
+            subscribers: Vec::new(),
+            cwd: VaultPath::new("/"),
+            max_directory_block_size: BlockSize::new(MAX_BLOCK_SIZE),
+            clock: default_clock(),
+        }
+    }
+
+    /// Make sure that a reopened vault is verified, and each kind of failure is reported distinctly.
+    #[test]
+    fn open_verification() {
+        let dir = TempDir::new("open");
+        let base_dir = dir.join("blocks");
+        let state_path = dir.join("vault.db");
+        let block_path = |id: BlockId| base_dir.join(format!("{}.bin", id.base64()));
+
+        let reopen = || Vault::open(&Provider::with_base_dir(&base_dir), &state_path).map(|vault| vault.root_id);
+
+        assert!(matches!(reopen(), Err(VaultError::MissingState(_))));
+
+        fs::create_dir_all(&dir).unwrap();
+        let provider = Provider::with_base_dir(&base_dir);
+        let vault = Vault::initialize(&provider, &state_path).unwrap();
+        let vault_id = provider.load_block_id_from_file(&state_path).unwrap();
+        let (root_id, index_id) = vault.vault.get_root_id_and_index_id();
+        assert_eq!(root_id, vault.root_id);
+        assert_eq!(reopen().unwrap(), root_id);
+
+        fs::remove_file(block_path(index_id)).unwrap();
+        assert!(matches!(reopen(), Err(VaultError::DanglingIndex(id, _)) if id == index_id));
+
+        fs::remove_file(block_path(root_id)).unwrap();
+        assert!(matches!(reopen(), Err(VaultError::DanglingRoot(id, _)) if id == root_id));
+
+        fs::remove_file(block_path(vault_id)).unwrap();
+        assert!(matches!(reopen(), Err(VaultError::UnreadableVaultBlock(id, _)) if id == vault_id));
+    }
+
+    /// Make sure that a vault initialized in memory is fully functional without touching the disk.
+    #[test]
+    fn initialize_in_memory() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+
+        vault.create_directory(VaultPath::new("/welcome/a/b")).unwrap();
+        vault.create_directory(VaultPath::new("/c")).unwrap();
+        assert_eq!(
+            vault.list(VaultPath::new("/")).unwrap(),
+            vec![
+                (NodeKind::Directory, String::from("welcome")),
+                (NodeKind::Directory, String::from("c"))
+            ]
+        );
+        assert_eq!(
+            vault.list(VaultPath::new("/welcome/a")).unwrap(),
+            vec![(NodeKind::Directory, String::from("b"))]
+        );
+        assert!(provider.saved_block_ids().unwrap().is_empty());
+        assert!(matches!(
+            provider.load_block_from_file(vault.root_id, 0),
+            Err(ProviderError::NotSaved(_))
+        ));
+    }
+
+    /// Make sure that a missing block is an error by default.
+    #[test]
+    fn missing_block_policy_error() {
+        let provider = Provider::in_memory();
+        let vault = memory_vault(&provider);
+        provider.evict_block(vault.root_id);
+
+        match vault.list(VaultPath::new("/")) {
+            Err(VaultError::MissingBlock(id)) => assert!(id == vault.root_id),
+            _ => panic!("Expected VaultError::MissingBlock"),
+        }
+    }
+
+    /// Make sure that a missing block is treated as empty when skipping.
+    #[test]
+    fn missing_block_policy_skip() {
+        let provider = Provider::in_memory();
+        let mut vault = memory_vault(&provider);
+        provider.evict_block(vault.root_id);
+        vault.set_missing_block_policy(MissingBlockPolicy::Skip);
+
+        assert!(vault.list(VaultPath::new("/")).unwrap().is_empty());
+        assert!(vault.list(VaultPath::new("/welcome")).unwrap().is_empty());
+    }
+
+    /// Make sure that a missing block is recovered from the backup provider.
+    #[test]
+    fn missing_block_policy_recover() {
+        let dir = TempDir::new("vault");
+        let provider = disk_provider(&dir);
+        let backup = Provider::in_memory();
+        let mut vault = memory_vault(&provider);
+        let root_block = provider.evict_block(vault.root_id).unwrap();
+        vault.set_missing_block_policy(MissingBlockPolicy::Recover(&backup));
+
+        // Nothing to recover from when the backup doesn't have it either
+        assert!(vault.list(VaultPath::new("/")).is_err());
+
+        // A backup with a different block under the id doesn't get to repair anything
+        backup.cache_block(vault.root_id, InfoBlock::new_directory());
+        assert!(matches!(
+            vault.list(VaultPath::new("/")),
+            Err(VaultError::Provider(ProviderError::BlockMismatch { .. }))
+        ));
+
+        backup.cache_block(vault.root_id, root_block);
+        let entries = vault.list(VaultPath::new("/")).unwrap();
+        assert_eq!(entries, vec![(NodeKind::Directory, String::from("welcome"))]);
+        // The primary provider got repaired, down to the disk
+        provider.evict_block(vault.root_id);
+        assert!(provider.load_block_from_file(vault.root_id, 0).is_ok());
+
+        // A damaged block is recovered as well
+        provider.evict_block(vault.root_id);
+        fs::write(
+            dir.join("blocks").join(format!("{}.bin", vault.root_id.base64())),
+            b"damaged",
+        )
+        .unwrap();
+        assert_eq!(vault.list(VaultPath::new("/")).unwrap(), entries);
+    }
+
+    /// Make sure that mutations deliver the corresponding events in order.
+    #[test]
+    fn subscribe() {
+        let dir = TempDir::new("vault");
+        let provider = disk_provider(&dir);
+        let mut vault = memory_vault(&provider);
+        let events = vault.subscribe();
+
+        vault.create_directory(VaultPath::new("/welcome/a/b")).unwrap();
+        let root_id = vault.root_id;
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                VaultEvent::Created(VaultPath::new("/welcome/a")),
+                VaultEvent::Created(VaultPath::new("/welcome/a/b")),
+                VaultEvent::Committed(root_id),
+            ]
+        );
+
+        // Nothing is committed when nothing changes
+        vault.create_directory(VaultPath::new("/welcome/a")).unwrap();
+        assert_eq!(events.try_iter().count(), 0);
+
+        let path = dir.join("subscribe.bin");
+        fs::write(&path, b"hello").unwrap();
+        vault.put(path.to_str().unwrap()).unwrap();
+        let file = VaultPath::new(format!("/{}", path.file_name().unwrap().to_str().unwrap()));
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![VaultEvent::Created(file), VaultEvent::Committed(vault.root_id)]
+        );
+        assert!(vault.root_id != root_id);
+    }
+
+    /// Make sure that planning reports the changes without writing anything.
+    #[test]
+    fn plan_create_directory() {
+        let dir = TempDir::new("vault");
+        let provider = disk_provider(&dir);
+        let mut vault = memory_vault(&provider);
+        let root_id = vault.root_id;
+
+        let plan = vault.plan_create_directory(VaultPath::new("/a/b/c")).unwrap();
+        assert_eq!(
+            plan.created,
+            vec![VaultPath::new("/a"), VaultPath::new("/a/b"), VaultPath::new("/a/b/c")]
+        );
+        assert_eq!(plan.blocks_to_write, 2);
+        assert!(vault.root_id == root_id);
+        assert_eq!(provider.saved_block_ids().unwrap().len(), 0);
+
+        let plan = vault.plan_create_directory(VaultPath::new("/welcome")).unwrap();
+        assert!(plan.created.is_empty());
+        assert_eq!(plan.blocks_to_write, 0);
+
+        vault.create_directory(VaultPath::new("/a/b/c")).unwrap();
+        assert_eq!(provider.saved_block_ids().unwrap().len(), 2);
+        assert!(vault
+            .plan_create_directory(VaultPath::new("/a/b/c"))
+            .unwrap()
+            .created
+            .is_empty());
+    }
+
+    /// Make sure that putting identical content twice reuses all the data blocks.
+    #[test]
+    fn put_dedup() {
+        let dir = TempDir::new("put-dedup");
+        let provider = disk_provider(&dir);
+        let mut vault = memory_vault(&provider);
+
+        let mut data = vec![0; 5 * 4096 + 100];
+        thread_rng().fill(&mut data[..]);
+        fs::create_dir_all(&dir).unwrap();
+        let (path_a, path_b) = (dir.join("a.bin"), dir.join("b.bin"));
+        fs::write(&path_a, &data).unwrap();
+        fs::write(&path_b, &data).unwrap();
+
+        let outcome = vault.put(path_a.to_str().unwrap()).unwrap();
+        assert_eq!(outcome.file, VaultPath::new("/a.bin"));
+        assert_eq!(outcome.blocks_written, 6);
+        assert_eq!(outcome.blocks_deduped, 0);
+
+        let outcome = vault.put(path_b.to_str().unwrap()).unwrap();
+        assert_eq!(outcome.file, VaultPath::new("/b.bin"));
+        assert_eq!(outcome.blocks_written, 0);
+        assert_eq!(outcome.blocks_deduped, 6);
+
+        assert!(matches!(
+            vault.put(path_a.to_str().unwrap()),
+            Err(VaultError::AlreadyExists(_))
+        ));
+
+        let stat = vault.stat(VaultPath::new("/b.bin")).unwrap();
+        assert_eq!(stat.size, Some(FileSize::new(data.len() as u64)));
+
+        let entries = vault.list(VaultPath::new("/")).unwrap();
+        assert!(entries.contains(&(NodeKind::File, String::from("a.bin"))));
+        assert!(entries.contains(&(NodeKind::File, String::from("b.bin"))));
+    }
+
+    /// Make sure that the blocks shared by two files with overlapping contents are counted twice.
+    #[test]
+    fn put_reference_count() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+
+        let mut a = vec![0; 3 * 4096];
+        thread_rng().fill(&mut a[..]);
+        let mut b = a.clone();
+        thread_rng().fill(&mut b[2 * 4096..]);
+        for (name, data) in [("a.bin", &a), ("b.bin", &b)] {
+            vault
+                .put_reader(name, io::Cursor::new(data), FileSize::new(data.len() as u64))
+                .unwrap();
+        }
+
+        let a_ids = vault.file_block_ids(VaultPath::new("/a.bin")).unwrap();
+        let b_ids = vault.file_block_ids(VaultPath::new("/b.bin")).unwrap();
+        assert_eq!(a_ids[..2], b_ids[..2]);
+        for id in &a_ids[..2] {
+            assert_eq!(provider.reference_count(*id), 2);
+        }
+        assert_eq!(provider.reference_count(a_ids[2]), 1);
+        assert_eq!(provider.reference_count(b_ids[2]), 1);
+        assert_eq!(provider.reference_count(BlockId::from_data([0; 32])), 0);
+    }
+
+    /// Make sure that a put file node records the file's size and data blocks.
+    #[test]
+    fn put_file_size() {
+        let dir = TempDir::new("put-size");
+        let provider = disk_provider(&dir);
+        let mut vault = memory_vault(&provider);
+
+        let mut data = vec![0; 2 * 4096 + 1234];
+        thread_rng().fill(&mut data[..]);
+        fs::create_dir_all(&dir).unwrap();
+        let os_path = dir.join("data.bin");
+        fs::write(&os_path, &data).unwrap();
+        vault.put(os_path.to_str().unwrap()).unwrap();
+
+        let stat = vault.stat(VaultPath::new("/data.bin")).unwrap();
+        assert_eq!(stat.size, Some(FileSize::new(data.len() as u64)));
+
+        let (block_id, node_index) = vault.resolve(VaultPath::new("/data.bin")).unwrap();
+        let block_ids = provider
+            .get_block(block_id)
+            .unwrap()
+            .unwrap()
+            .info()
+            .unwrap()
+            .file_block_ids(node_index)
+            .unwrap();
+        assert_eq!(block_ids.len(), 3);
+        let stored: Vec<u8> = block_ids
+            .iter()
+            .flat_map(|id| provider.get_block(*id).unwrap().unwrap().data())
+            .collect();
+        assert_eq!(stored, data);
+    }
+
+    /// Make sure that ranges are read across block boundaries and clamped to the end of the file.
+    #[test]
+    fn get_range() {
+        let provider = Provider::in_memory();
+        let mut vault = memory_vault(&provider);
+        let mut data = vec![0; 3 * 4096 + 10];
+        thread_rng().fill(&mut data[..]);
+        vault
+            .put_reader("data.bin", io::Cursor::new(&data), FileSize::new(data.len() as u64))
+            .unwrap();
+        let path = VaultPath::new("/data.bin");
+        let range = |offset, len| vault.get_range(path.clone(), FileOffset::new(offset), FileSize::new(len));
+
+        // Spanning the first two blocks
+        assert_eq!(range(4000, 200).unwrap(), &data[4000..4200]);
+        assert_eq!(range(0, 4096).unwrap(), &data[..4096]);
+        // At the tail, clamped to the file size
+        assert_eq!(range(3 * 4096, 100).unwrap(), &data[3 * 4096..]);
+        assert_eq!(range(100, MAX_FILE_SIZE).unwrap(), &data[100..]);
+        assert!(range(data.len() as u64, 1).unwrap().is_empty());
+        assert!(matches!(
+            range(data.len() as u64 + 1, 1),
+            Err(VaultError::OffsetPastEnd { offset, size }) if offset == size + 1
+        ));
+        assert!(matches!(
+            vault.get_range(VaultPath::new("/welcome"), FileOffset::new(0), FileSize::new(1)),
+            Err(VaultError::NotAFile(_))
+        ));
+    }
+
+    /// Make sure that the zeros of a sparse file are stored as holes and read back intact.
+    #[test]
+    fn put_sparse() {
+        let dir = TempDir::new("put-sparse");
+        let provider = disk_provider(&dir);
+        let mut vault = memory_vault(&provider);
+
+        let mut data = vec![0; 4096 + 2 * 1024 * 1024 + 100];
+        thread_rng().fill(&mut data[..4096]);
+        let tail = data.len() - 100;
+        thread_rng().fill(&mut data[tail..]);
+        let size = FileSize::new(data.len() as u64);
+        let outcome = vault.put_reader("sparse.bin", io::Cursor::new(&data), size).unwrap();
+        let block_ids = vault.file_block_ids(VaultPath::new("/sparse.bin")).unwrap();
+        // Only the first block and the last one, which isn't all zeros, are stored
+        assert_eq!(outcome.blocks_written, 2);
+        assert_eq!(outcome.holes, block_ids.len() - 2);
+        let saved = provider.saved_block_ids().unwrap();
+        assert_eq!(
+            saved
+                .iter()
+                .filter(|(id, _)| id.block_kind() == BlockKind::Data)
+                .count(),
+            2
+        );
+
+        assert_eq!(vault.get(VaultPath::new("/sparse.bin")).unwrap(), data);
+        let range = vault
+            .get_range(
+                VaultPath::new("/sparse.bin"),
+                FileOffset::new(4000),
+                FileSize::new(200_000),
+            )
+            .unwrap();
+        assert_eq!(range, data[4000..204_000]);
+        let usage = vault.du(VaultPath::new("/sparse.bin")).unwrap();
+        assert_eq!(usage.logical, data.len() as u64);
+        assert!(usage.physical < 200_000);
+        assert!(vault.verify_file(VaultPath::new("/sparse.bin")).unwrap().is_empty());
+
+        // Appending zeros to a file turns the full blocks into holes too
+        vault.touch(VaultPath::new("/appended.bin")).unwrap();
+        vault.append(VaultPath::new("/appended.bin"), &[0; 3 * 4096]).unwrap();
+        let block_ids = vault.file_block_ids(VaultPath::new("/appended.bin")).unwrap();
+        assert!(block_ids.iter().all(|block_id| block_id.is_hole()));
+        assert_eq!(vault.get(VaultPath::new("/appended.bin")).unwrap(), [0; 3 * 4096]);
+    }
+
+    /// Make sure that collecting garbage to stay within the disk budget in the middle of a put
+    /// leaves the blocks of the put alone, even though the committed tree doesn't refer to them yet.
+    #[test]
+    fn put_over_disk_budget() {
+        let dir = TempDir::new("vault");
+        // An earlier session leaves behind the garbage that makes room for the put
+        let earlier = disk_provider(&dir);
+        let mut garbage = vec![0; 4 * 4096];
+        thread_rng().fill(&mut garbage[..]);
+        memory_vault(&earlier)
+            .put_reader(
+                "garbage.bin",
+                io::Cursor::new(&garbage),
+                FileSize::new(garbage.len() as u64),
+            )
+            .unwrap();
+        let garbage_ids = earlier.saved_block_ids().unwrap();
+        let garbage_usage: u64 = garbage_ids
+            .iter()
+            .map(|(_, path)| fs::metadata(path).unwrap().len())
+            .sum();
+
+        let mut provider = disk_provider(&dir);
+        provider.set_disk_budget(Some(garbage_usage + 2 * 4096));
+        // Knows only about the committed tree, which none of the blocks of the put are part of yet
+        provider.set_reachability(|_| false);
+        let mut vault = memory_vault(&provider);
+        let mut data = vec![0; 4 * 4096];
+        thread_rng().fill(&mut data[..]);
+        vault
+            .put_reader("data.bin", io::Cursor::new(&data), FileSize::new(data.len() as u64))
+            .unwrap();
+
+        assert!(garbage_ids.iter().all(|(_, path)| !path.exists()));
+        assert_eq!(vault.get(VaultPath::new("/data.bin")).unwrap(), data);
+    }
+
+    /// Make sure that data put from a reader is stored in full and that a size mismatch is rejected.
+    #[test]
+    fn put_reader() {
+        let dir = TempDir::new("vault");
+        let provider = disk_provider(&dir);
+        let mut vault = memory_vault(&provider);
+
+        let mut data = vec![0; 3 * 4096 + 10];
+        thread_rng().fill(&mut data[..]);
+        let size = FileSize::new(data.len() as u64);
+        let outcome = vault.put_reader("data.bin", io::Cursor::new(&data), size).unwrap();
+        assert_eq!(outcome.file, VaultPath::new("/data.bin"));
+        assert_eq!(vault.stat(VaultPath::new("/data.bin")).unwrap().size, Some(size));
+
+        let (block_id, node_index) = vault.resolve(VaultPath::new("/data.bin")).unwrap();
+        let stored: Vec<u8> = provider
+            .get_block(block_id)
+            .unwrap()
+            .unwrap()
+            .info()
+            .unwrap()
+            .file_block_ids(node_index)
+            .unwrap()
+            .iter()
+            .flat_map(|id| provider.get_block(*id).unwrap().unwrap().data())
+            .collect();
+        assert_eq!(stored, data);
+        assert_eq!(vault.get(VaultPath::new("/data.bin")).unwrap(), data);
+
+        for (name, declared) in [
+            ("short.bin", data.len() as u64 + 1),
+            ("long.bin", data.len() as u64 - 1),
+        ] {
+            match vault.put_reader(name, io::Cursor::new(&data), FileSize::new(declared)) {
+                Err(VaultError::SizeMismatch { expected, .. }) => assert_eq!(expected, declared),
+                other => panic!("expected a size mismatch, got {other:?}"),
+            }
+            assert!(!vault.exists(VaultPath::new(format!("/{name}"))));
+        }
+    }
+
+    /// Make sure that storing the blocks of a file in parallel saves every block to disk exactly once,
+    /// including blocks that occur several times within a batch, and keeps them in file order.
+    #[test]
+    fn put_parallel_disk() {
+        let dir = TempDir::new("vault");
+        // The first blocks are all the same
+        let mut data = vec![7; 2 * 1024 * 1024];
+        thread_rng().fill(&mut data[64 * 1024..]);
+        let serial: Vec<BlockId> = File::reader_chunk_stream(&data[..], &Layout, 0)
+            .map(|block| {
+                let encrypted_block = EncryptedBlock::encrypt(&block.unwrap(), 0);
+                encrypted_block.id(BlockKind::Data).unwrap()
+            })
+            .collect();
+        let distinct: BTreeSet<BlockId> = serial.iter().copied().collect();
+        assert!(distinct.len() < serial.len());
+
+        let base_dir = dir.join("blocks");
+        let provider = Provider::with_base_dir(&base_dir);
+        let path = dir.join("vault.db");
+        let mut vault = Vault::initialize(&provider, &path).unwrap();
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let outcome = pool
+            .install(|| vault.put_reader("data.bin", &data[..], FileSize::new(data.len() as u64)))
+            .unwrap();
+        assert_eq!(outcome.blocks_written, distinct.len());
+        assert_eq!(outcome.blocks_deduped, serial.len() - distinct.len());
+        assert_eq!(provider.reference_count(serial[0]), 16);
+        assert_eq!(vault.file_block_ids(VaultPath::new("/data.bin")).unwrap(), serial);
+        assert!(!path.with_extension("journal").exists());
+
+        // Everything made it to disk
+        let provider = Provider::with_base_dir(&base_dir);
+        let vault = Vault::open(&provider, &path).unwrap();
+        assert_eq!(vault.get(VaultPath::new("/data.bin")).unwrap(), data);
+    }
+
+    /// Make sure that hashing the blocks of a file in parallel results in the same blocks in the same order
+    /// as hashing them one by one.
+    #[test]
+    fn put_parallel() {
+        let mut data = vec![0; 3 * 1024 * 1024 + 123];
+        thread_rng().fill(&mut data[..]);
+        let serial: Vec<BlockId> = File::reader_chunk_stream(&data[..], &Layout, 0)
+            .map(|block| {
+                let encrypted_block = EncryptedBlock::encrypt(&block.unwrap(), 0);
+                encrypted_block.id(BlockKind::Data).unwrap()
+            })
+            .collect();
+        assert!(serial.len() > 64);
+
+        for threads in [1, 4] {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let provider = Provider::in_memory();
+            let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+            let outcome = pool
+                .install(|| vault.put_reader("data.bin", &data[..], FileSize::new(data.len() as u64)))
+                .unwrap();
+            assert_eq!(outcome.blocks_written, serial.len());
+            assert_eq!(vault.file_block_ids(VaultPath::new("/data.bin")).unwrap(), serial);
+            assert_eq!(vault.get(VaultPath::new("/data.bin")).unwrap(), data);
+        }
+    }
+
+    /// Make sure that many files are added with a single commit, and that a failure adds none of them.
+    #[test]
+    fn put_many() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let dir = TempDir::new("put-many");
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut files = Vec::new();
+        for i in 0..50 {
+            let mut data = vec![0; 100 + i * 97];
+            thread_rng().fill(&mut data[..]);
+            let os_path = dir.join(format!("{i}.bin"));
+            fs::write(&os_path, &data).unwrap();
+            files.push((VaultPath::new(format!("/data/nested/{i}.bin")), os_path, data));
+        }
+        let items: Vec<(VaultPath, &Path)> = files
+            .iter()
+            .map(|(path, os_path, _)| (path.clone(), os_path.as_path()))
+            .chain([(VaultPath::new("/welcome/first.bin"), files[0].1.as_path())])
+            .collect();
+
+        let history = vault.history().len();
+        let outcomes = vault.put_many(&items).unwrap();
+        assert_eq!(
+            vault.history().len(),
+            history + 1,
+            "only a single vault block is committed"
+        );
+        assert_eq!(outcomes.len(), items.len());
+        for ((path, _), outcome) in items.iter().zip(&outcomes) {
+            assert_eq!(outcome.file, *path);
+        }
+        assert_eq!(outcomes[50].blocks_deduped, 1);
+        for (path, _, data) in &files {
+            assert_eq!(vault.get(path.clone()).unwrap(), *data);
+        }
+        assert_eq!(vault.ls(VaultPath::new("/data/nested")).unwrap().len(), 50);
+
+        // An existing file fails the whole batch
+        let vault_id = vault.vault_id();
+        let items = [
+            (VaultPath::new("/other/new.bin"), files[1].1.as_path()),
+            (VaultPath::new("/data/nested/0.bin"), files[0].1.as_path()),
+        ];
+        assert!(matches!(
+            vault.put_many(&items),
+            Err(VaultError::AlreadyExists(path)) if path == items[1].0
+        ));
+        assert_eq!(vault.vault_id(), vault_id);
+        assert!(!vault.exists(VaultPath::new("/other")));
+        let duplicates = [items[0].clone(), items[0].clone()];
+        assert!(matches!(vault.put_many(&duplicates), Err(VaultError::AlreadyExists(_))));
+        assert!(!vault.exists(VaultPath::new("/other")));
+        let root = [(VaultPath::new("/"), files[0].1.as_path())];
+        assert!(matches!(
+            vault.put_many(&root),
+            Err(VaultError::InvalidPath(path)) if path == root[0].0
+        ));
+    }
+
+    /// Make sure that the data block ids of a file come back in the order that `put` wrote them.
+    #[test]
+    fn file_block_ids() {
+        let dir = TempDir::new("vault");
+        let provider = disk_provider(&dir);
+        let mut vault = memory_vault(&provider);
+
+        let mut data = vec![0; 16 * 4096 + 3 * 8192 + 10];
+        thread_rng().fill(&mut data[..]);
+        vault
+            .put_reader("data.bin", io::Cursor::new(&data), FileSize::new(data.len() as u64))
+            .unwrap();
+
+        let mut expected = Vec::new();
+        let mut remaining = &data[..];
+        for size in BlockSize::layout() {
+            if remaining.is_empty() {
+                break;
+            }
+            let (chunk, rest) = remaining.split_at(remaining.len().min(*size as usize));
+            let block = Block::from_data(chunk.to_vec().into());
+            expected.push(EncryptedBlock::encrypt(&block, 0).id(BlockKind::Data).unwrap());
+            remaining = rest;
+        }
+        let block_ids = vault.file_block_ids(VaultPath::new("/data.bin")).unwrap();
+        assert_eq!(block_ids.len(), 16 + 3 + 1);
+        assert_eq!(block_ids, expected);
+
+        assert!(matches!(
+            vault.file_block_ids(VaultPath::new("/welcome")),
+            Err(VaultError::NotAFile(_))
+        ));
+        assert!(matches!(
+            vault.file_block_ids(VaultPath::new("/missing.bin")),
+            Err(VaultError::NotFound(_))
+        ));
+    }
+
+    /// Make sure that the files referencing a data block and the nodes stored in a block are found.
+    #[test]
+    fn find_by_block_id() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        for name in ["a.bin", "b.bin"] {
+            let mut data = vec![0; 5000];
+            thread_rng().fill(&mut data[..]);
+            vault
+                .put_reader(name, data.as_slice(), FileSize::new(data.len() as u64))
+                .unwrap();
+        }
+        vault
+            .copy(VaultPath::new("/a.bin"), VaultPath::new("/welcome/c.bin"))
+            .unwrap();
+
+        let block_ids = vault.file_block_ids(VaultPath::new("/a.bin")).unwrap();
+        for block_id in block_ids {
+            assert_eq!(
+                vault.find_by_block_id(block_id).unwrap(),
+                [VaultPath::new("/a.bin"), VaultPath::new("/welcome/c.bin")]
+            );
+        }
+        let block_id = vault.file_block_ids(VaultPath::new("/b.bin")).unwrap()[1];
+        assert_eq!(vault.find_by_block_id(block_id).unwrap(), [VaultPath::new("/b.bin")]);
+
+        // Everything is inlined into the root block so far
+        assert_eq!(vault.find_by_block_id(vault.root_id()).unwrap().len(), 5);
+        vault.move_to_block(VaultPath::new("/welcome")).unwrap();
+        let (welcome_id, _) = vault.resolve(VaultPath::new("/welcome")).unwrap();
+        assert_eq!(
+            vault.find_by_block_id(welcome_id).unwrap(),
+            [VaultPath::new("/welcome"), VaultPath::new("/welcome/c.bin")]
+        );
+        assert!(vault.find_by_block_id(BlockId::from_data([0; 32])).unwrap().is_empty());
+    }
+
+    /// Make sure that the digest tells apart files by their contents only.
+    #[test]
+    fn file_digest() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+
+        let mut data = vec![0; 17 * 4096 + 100];
+        thread_rng().fill(&mut data[..]);
+        let mut changed = data.clone();
+        changed[5 * 4096] ^= 1;
+        let size = FileSize::new(data.len() as u64);
+        vault.put_reader("a.bin", io::Cursor::new(&data), size).unwrap();
+        vault.put_reader("b.bin", io::Cursor::new(&data), size).unwrap();
+        vault.put_reader("c.bin", io::Cursor::new(&changed), size).unwrap();
+
+        let digest = |path| vault.file_digest(VaultPath::new(path)).unwrap();
+        assert_eq!(digest("/a.bin"), digest("/b.bin"));
+        assert_ne!(digest("/a.bin"), digest("/c.bin"));
+        assert!(matches!(
+            vault.file_digest(VaultPath::new("/welcome")),
+            Err(VaultError::NotAFile(_))
+        ));
+    }
+
+    /// Make sure that a block of a file that got corrupted on disk is reported, and only that one,
+    /// and that a block whose file is gone is reported as unreadable.
+    #[test]
+    fn verify_file() {
+        let dir = TempDir::new("vault");
+        let base_dir = dir.join("blocks");
+        let path = dir.join("vault.db");
+        let provider = Provider::with_base_dir(&base_dir);
+        let mut vault = Vault::initialize(&provider, &path).unwrap();
+        let mut data = vec![0; 5 * 4096 + 100];
+        thread_rng().fill(&mut data[..]);
+        let size = FileSize::new(data.len() as u64);
+        vault.put_reader("data.bin", io::Cursor::new(&data), size).unwrap();
+        let block_ids = vault.file_block_ids(VaultPath::new("/data.bin")).unwrap();
+        assert!(vault.verify_file(VaultPath::new("/data.bin")).unwrap().is_empty());
+
+        let block_path = base_dir.join(format!("{}.bin", block_ids[2].base64()));
+        let mut contents = fs::read(&block_path).unwrap();
+        contents[10] ^= 1;
+        fs::write(&block_path, contents).unwrap();
+
+        // The cached copy doesn't hide the damage
+        let damaged = vault.verify_file(VaultPath::new("/data.bin")).unwrap();
+        assert_eq!(damaged.len(), 1);
+        assert!(matches!(damaged[0], (id, IntegrityError::Mismatch(_)) if id == block_ids[2]));
+
+        fs::remove_file(base_dir.join(format!("{}.bin", block_ids[4].base64()))).unwrap();
+        let damaged = vault.verify_file(VaultPath::new("/data.bin")).unwrap();
+        assert_eq!(damaged.len(), 2);
+        assert!(matches!(damaged[1], (id, IntegrityError::Unreadable(_)) if id == block_ids[4]));
+        assert!(matches!(
+            vault.verify_file(VaultPath::new("/")),
+            Err(VaultError::NotAFile(_))
+        ));
+    }
+
+    /// Make sure that copies count towards the logical size but their shared blocks only once physically.
+    #[test]
+    fn du() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+
+        let size = 5 * 4096 + 100;
+        let mut data = vec![0; size];
+        thread_rng().fill(&mut data[..]);
+        vault
+            .put_reader("data.bin", io::Cursor::new(&data), FileSize::new(size as u64))
+            .unwrap();
+        vault.create_directory(VaultPath::new("/docs")).unwrap();
+        for copy in ["/docs/a.bin", "/docs/b.bin"] {
+            vault.copy(VaultPath::new("/data.bin"), VaultPath::new(copy)).unwrap();
+        }
+        vault
+            .put_reader("other.bin", io::Cursor::new(&data[..4096]), FileSize::new(4096))
+            .unwrap();
+        let size = size as u64;
+
+        let du = |path| vault.du(VaultPath::new(path)).unwrap();
+        assert_eq!(
+            du("/docs"),
+            DiskUsage {
+                logical: 2 * size,
+                physical: size,
+            }
+        );
+        assert_eq!(
+            du("/docs/a.bin"),
+            DiskUsage {
+                logical: size,
+                physical: size,
+            }
+        );
+        // The first block of the other file is the same as the first block of the copies
+        assert_eq!(
+            du("/"),
+            DiskUsage {
+                logical: 3 * size + 4096,
+                physical: size,
+            }
+        );
+        assert_eq!(du("/welcome"), DiskUsage::default());
+        assert!(matches!(
+            vault.du(VaultPath::new("/missing")),
+            Err(VaultError::NotFound(_))
+        ));
+
+        // Content defined blocks hold as much as their contents say, not what the layout says
+        let mut data = vec![0; 200_000];
+        thread_rng().fill(&mut data[..]);
+        let size = data.len() as u64;
+        let mut provider = Provider::in_memory();
+        provider.set_chunk_strategy(ContentDefinedChunker::default());
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault
+            .put_reader("data.bin", data.as_slice(), FileSize::new(size))
+            .unwrap();
+        assert_eq!(
+            vault.du(VaultPath::new("/data.bin")).unwrap(),
+            DiskUsage {
+                logical: size,
+                physical: size,
+            }
+        );
+    }
+
+    /// Make sure that the histogram counts every reachable block by its size.
+    #[test]
+    fn block_size_histogram() {
+        let dir = TempDir::new("histogram");
+        let provider = disk_provider(&dir);
+        let mut vault = memory_vault(&provider);
+
+        fs::create_dir_all(&dir).unwrap();
+        for (name, size) in [("large.bin", 16 * 4096 + 5000), ("small.bin", 100)] {
+            let mut data = vec![0; size];
+            thread_rng().fill(&mut data[..]);
+            let os_path = dir.join(name);
+            fs::write(&os_path, &data).unwrap();
+            vault.put(os_path.to_str().unwrap()).unwrap();
+        }
+
+        // 16 + 1 data blocks of the large file, 1 of the small file, and the vault, index and root blocks
+        let histogram = vault.block_size_histogram().unwrap();
+        let expected = BTreeMap::from([(BlockSize::new(4096), 20), (BlockSize::new(8192), 1)]);
+        assert_eq!(histogram, expected);
+    }
+
+    /// Make sure that copying a file shares its data blocks instead of writing new ones.
+    #[test]
+    fn copy() {
+        let dir = TempDir::new("copy");
+        let provider = disk_provider(&dir);
+        let mut vault = memory_vault(&provider);
+
+        let mut data = vec![0; 5 * 4096 + 100];
+        thread_rng().fill(&mut data[..]);
+        fs::create_dir_all(&dir).unwrap();
+        let os_path = dir.join("data.bin");
+        fs::write(&os_path, &data).unwrap();
+        vault.put(os_path.to_str().unwrap()).unwrap();
+
+        let data_block_count = || {
+            provider
+                .saved_block_ids()
+                .unwrap()
+                .into_iter()
+                .filter(|(id, _)| Block::kind_from_id(*id) == BlockKind::Data)
+                .count()
+        };
+        assert_eq!(data_block_count(), 6);
+
+        let events = vault.subscribe();
+        vault
+            .copy(VaultPath::new("/data.bin"), VaultPath::new("/welcome/copy.bin"))
+            .unwrap();
+        assert_eq!(data_block_count(), 6);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            VaultEvent::Created(VaultPath::new("/welcome/copy.bin"))
+        );
+
+        let file_block_ids = |path: &str| {
+            let (block_id, node_index) = vault.resolve(VaultPath::new(path)).unwrap();
+            provider
+                .get_block(block_id)
+                .unwrap()
+                .unwrap()
+                .info()
+                .unwrap()
+                .file_block_ids(node_index)
+                .unwrap()
+        };
+        assert_eq!(file_block_ids("/welcome/copy.bin"), file_block_ids("/data.bin"));
+        // The copy is a node of its own, created at the time of copying
+        let (copy, original) = (
+            vault.stat(VaultPath::new("/welcome/copy.bin")).unwrap(),
+            vault.stat(VaultPath::new("/data.bin")).unwrap(),
+        );
+        assert_eq!((copy.kind, copy.size), (original.kind, original.size));
+        assert!(copy.created >= original.created);
+
+        assert!(matches!(
+            vault.copy(VaultPath::new("/data.bin"), VaultPath::new("/welcome/copy.bin")),
+            Err(VaultError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            vault.copy(VaultPath::new("/missing.bin"), VaultPath::new("/copy.bin")),
+            Err(VaultError::NotFound(_))
+        ));
+        assert!(matches!(
+            vault.copy(VaultPath::new("/welcome"), VaultPath::new("/copy.bin")),
+            Err(VaultError::NotAFile(_))
+        ));
+        assert!(matches!(
+            vault.copy(VaultPath::new("/data.bin"), VaultPath::new("/missing/copy.bin")),
+            Err(VaultError::NotFound(_))
+        ));
+        assert!(matches!(
+            vault.copy(VaultPath::new("/data.bin"), VaultPath::new("/data.bin/copy.bin")),
+            Err(VaultError::NotADirectory(_))
+        ));
+    }
+
+    /// Make sure that appending across block boundaries ends up with the same blocks as putting the whole file.
+    #[test]
+    fn append() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let mut rng = thread_rng();
+        let data: Vec<u8> = (0..3 * 4096 + 100).map(|_| rng.gen()).collect();
+
+        vault.touch(VaultPath::new("/welcome/appended.bin")).unwrap();
+        let events = vault.subscribe();
+        for chunk in [&data[..3000], &data[3000..5000], &data[5000..]] {
+            vault.append(VaultPath::new("/welcome/appended.bin"), chunk).unwrap();
+            assert_eq!(
+                events.try_recv().unwrap(),
+                VaultEvent::Modified(VaultPath::new("/welcome/appended.bin"))
+            );
+            assert!(matches!(events.try_recv().unwrap(), VaultEvent::Committed(_)));
+        }
+        assert_eq!(vault.get(VaultPath::new("/welcome/appended.bin")).unwrap(), data);
+        assert_eq!(
+            vault.stat(VaultPath::new("/welcome/appended.bin")).unwrap().size,
+            Some(FileSize::new(data.len() as u64))
+        );
+
+        vault
+            .put_reader("whole.bin", data.as_slice(), FileSize::new(data.len() as u64))
+            .unwrap();
+        assert_eq!(
+            vault.file_block_ids(VaultPath::new("/welcome/appended.bin")).unwrap(),
+            vault.file_block_ids(VaultPath::new("/whole.bin")).unwrap()
+        );
+
+        assert!(matches!(
+            vault.append(VaultPath::new("/welcome"), b"data"),
+            Err(VaultError::NotAFile(_))
+        ));
+        assert!(matches!(
+            vault.append(VaultPath::new("/missing.bin"), b"data"),
+            Err(VaultError::NotFound(_))
+        ));
+    }
+
+    /// Make sure that truncating keeps the leading bytes and ends up with the same blocks as putting them.
+    #[test]
+    fn truncate() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let mut data = vec![0; 20 * 4096 + 100];
+        thread_rng().fill(&mut data[..]);
+        vault
+            .put_reader("file.bin", data.as_slice(), FileSize::new(data.len() as u64))
+            .unwrap();
+
+        for new_size in [20 * 4096, 17 * 4096 + 1000, 2 * 4096 + 1, 4095] {
+            vault
+                .truncate(VaultPath::new("/file.bin"), FileSize::new(new_size))
+                .unwrap();
+            assert_eq!(
+                vault.get(VaultPath::new("/file.bin")).unwrap(),
+                &data[..new_size as usize]
+            );
+            assert_eq!(
+                vault.stat(VaultPath::new("/file.bin")).unwrap().size,
+                Some(FileSize::new(new_size))
+            );
+
+            let name = format!("put-{new_size}.bin");
+            let data = &data[..new_size as usize];
+            vault.put_reader(&name, data, FileSize::new(new_size)).unwrap();
+            assert_eq!(
+                vault.file_block_ids(VaultPath::new("/file.bin")).unwrap(),
+                vault.file_block_ids(VaultPath::new(format!("/{name}"))).unwrap()
+            );
+        }
+
+        assert!(vault
+            .truncate(VaultPath::new("/file.bin"), FileSize::new(4096))
+            .is_err());
+        vault.truncate(VaultPath::new("/file.bin"), FileSize::new(0)).unwrap();
+        assert!(vault.get(VaultPath::new("/file.bin")).unwrap().is_empty());
+        assert!(vault.file_block_ids(VaultPath::new("/file.bin")).unwrap().is_empty());
+    }
+
+    /// Make sure that the public methods report failures as the matching `VaultError` instead of panicking.
+    #[test]
+    fn error_variants() {
+        let provider = Provider::in_memory();
+        let missing = std::env::temp_dir().join(format!("exomem-missing-{}", thread_rng().gen::<u64>()));
+        assert!(matches!(
+            Vault::open(&provider, &missing),
+            Err(VaultError::MissingState(_))
+        ));
+
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        assert!(matches!(vault.put(missing.to_str().unwrap()), Err(VaultError::Io(_))));
+        for name in ["a/b", "", ".", ".."] {
+            assert!(matches!(
+                vault.put_reader(name, b"data".as_slice(), FileSize::new(4)),
+                Err(VaultError::InvalidPath(_))
+            ));
+        }
+        assert!(matches!(
+            vault.put_reader("short.bin", b"data".as_slice(), FileSize::new(5)),
+            Err(VaultError::SizeMismatch { .. })
+        ));
+        vault
+            .put_reader("file.bin", b"data".as_slice(), FileSize::new(4))
+            .unwrap();
+        assert!(matches!(
+            vault.put_reader("file.bin", b"data".as_slice(), FileSize::new(4)),
+            Err(VaultError::AlreadyExists(_))
+        ));
+
+        assert!(matches!(
+            vault.get(VaultPath::new("/missing")),
+            Err(VaultError::NotFound(_))
+        ));
+        assert!(matches!(
+            vault.get(VaultPath::new("/welcome")),
+            Err(VaultError::NotAFile(_))
+        ));
+        assert!(matches!(
+            vault.get(VaultPath::new("/welcome/..")),
+            Err(VaultError::InvalidPath(_))
+        ));
+        assert!(matches!(
+            vault.list(VaultPath::new("/missing")),
+            Err(VaultError::NotFound(_))
+        ));
+
+        assert!(matches!(
+            vault.create_directory(VaultPath::new("/file.bin/inner")),
+            Err(VaultError::NotADirectory(_))
+        ));
+        assert!(matches!(
+            vault.create_directory(VaultPath::new("/welcome/../inner")),
+            Err(VaultError::InvalidPath(_))
+        ));
+        assert!(matches!(
+            vault.truncate(VaultPath::new("/file.bin"), FileSize::new(5)),
+            Err(VaultError::Unsupported(_))
+        ));
+    }
+
+    /// Make sure that an exported subtree matches the vault contents on disk.
+    #[test]
+    fn export() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let mut data = vec![0; 5000];
+        thread_rng().fill(&mut data[..]);
+        vault
+            .put_reader("data.bin", data.as_slice(), FileSize::new(5000))
+            .unwrap();
+        vault.create_directory(VaultPath::new("/welcome/a/b")).unwrap();
+        vault.touch(VaultPath::new("/welcome/a/empty.txt")).unwrap();
+        vault
+            .copy(VaultPath::new("/data.bin"), VaultPath::new("/welcome/a/b/copy.bin"))
+            .unwrap();
+
+        let dest_dir = TempDir::new("export");
+        vault.export(VaultPath::new("/"), &dest_dir).unwrap();
+        assert_eq!(fs::read(dest_dir.join("data.bin")).unwrap(), data);
+        assert!(fs::read(dest_dir.join("welcome/a/empty.txt")).unwrap().is_empty());
+        assert_eq!(fs::read(dest_dir.join("welcome/a/b/copy.bin")).unwrap(), data);
+        let mut names: Vec<_> = fs::read_dir(dest_dir.join("welcome/a"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["b", "empty.txt"]);
+
+        let file_dir = dest_dir.join("file");
+        vault
+            .export(VaultPath::new("/welcome/a/b/copy.bin"), &file_dir)
+            .unwrap();
+        assert_eq!(fs::read(file_dir.join("copy.bin")).unwrap(), data);
+
+        assert!(matches!(
+            vault.export(VaultPath::new("/missing"), &dest_dir),
+            Err(VaultError::NotFound(_))
+        ));
+    }
+
+    /// Make sure that an imported OS directory tree can be listed and read back, skipping symbolic links.
+    #[test]
+    fn import() {
+        let src_dir = TempDir::new("import");
+        fs::create_dir_all(src_dir.join("a/b")).unwrap();
+        fs::create_dir_all(src_dir.join("empty")).unwrap();
+        let mut data = vec![0; 5000];
+        thread_rng().fill(&mut data[..]);
+        fs::write(src_dir.join("data.bin"), &data).unwrap();
+        fs::write(src_dir.join("a/note.txt"), b"note").unwrap();
+        fs::write(src_dir.join("a/b/deep.txt"), b"deep").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(src_dir.join("data.bin"), src_dir.join("link.bin")).unwrap();
+
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let outcome = vault.import(&src_dir, VaultPath::new("/welcome/imported")).unwrap();
+        assert_eq!(outcome.files, 3);
+        assert_eq!(outcome.directories, 3);
+        #[cfg(unix)]
+        {
+            assert_eq!(outcome.skipped.len(), 1);
+            assert_eq!(outcome.skipped[0].0, src_dir.join("link.bin"));
+            assert!(matches!(outcome.skipped[0].1, VaultError::Unsupported(_)));
+        }
+
+        assert_eq!(
+            vault.list(VaultPath::new("/welcome/imported")).unwrap(),
+            vec![
+                (NodeKind::Directory, String::from("a")),
+                (NodeKind::File, String::from("data.bin")),
+                (NodeKind::Directory, String::from("empty")),
+            ]
+        );
+        assert_eq!(
+            vault.list(VaultPath::new("/welcome/imported/a")).unwrap(),
+            vec![
+                (NodeKind::Directory, String::from("b")),
+                (NodeKind::File, String::from("note.txt")),
+            ]
+        );
+        assert!(vault
+            .list(VaultPath::new("/welcome/imported/empty"))
+            .unwrap()
+            .is_empty());
+        assert_eq!(vault.get(VaultPath::new("/welcome/imported/data.bin")).unwrap(), data);
+        assert_eq!(
+            vault.get(VaultPath::new("/welcome/imported/a/b/deep.txt")).unwrap(),
+            b"deep"
+        );
+
+        // Importing again skips the files that already exist
+        let outcome = vault.import(&src_dir, VaultPath::new("/welcome/imported")).unwrap();
+        assert_eq!(outcome.files, 0);
+        assert!(outcome
+            .skipped
+            .iter()
+            .any(|(path, e)| *path == src_dir.join("a/note.txt") && matches!(e, VaultError::AlreadyExists(_))));
+
+        // The directory past the maximum depth is skipped instead of aborting the import
+        let deep_dir = TempDir::new("import-deep");
+        let deepest = (0..MAX_PATH_DEPTH).fold(deep_dir.to_path_buf(), |path, _| path.join("d"));
+        fs::create_dir_all(&deepest).unwrap();
+        let outcome = vault.import(&deep_dir, VaultPath::new("/deep")).unwrap();
+        assert_eq!(outcome.directories, MAX_PATH_DEPTH - 1);
+        assert_eq!(outcome.skipped.len(), 1);
+        assert_eq!(outcome.skipped[0].0, deepest);
+        assert!(matches!(
+            outcome.skipped[0].1,
+            VaultError::InvalidVaultPath(InvalidVaultPath::TooDeep { .. })
+        ));
+    }
+
+    /// Make sure that files are split following the provider's chunk strategy, which the state file records.
+    #[test]
+    fn chunk_strategy() {
+        let mut data = vec![0; 20 * 4096 + 100];
+        thread_rng().fill(&mut data[..]);
+        let size = FileSize::new(data.len() as u64);
+
+        // The layout starts with 16 blocks of 4 KiB followed by blocks of 8 KiB
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault.put_reader("data.bin", data.as_slice(), size).unwrap();
+        assert_eq!(vault.file_block_ids(VaultPath::new("/data.bin")).unwrap().len(), 19);
+
+        let mut provider = Provider::in_memory();
+        provider.set_chunk_strategy(FixedSize(BlockSize::from_marker(0)));
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault.put_reader("data.bin", data.as_slice(), size).unwrap();
+        assert_eq!(vault.file_block_ids(VaultPath::new("/data.bin")).unwrap().len(), 21);
+        assert_eq!(vault.get(VaultPath::new("/data.bin")).unwrap(), data);
+        assert_eq!(
+            vault.du(VaultPath::new("/data.bin")).unwrap(),
+            DiskUsage {
+                logical: *size,
+                physical: *size,
+            }
+        );
+
+        vault
+            .truncate(VaultPath::new("/data.bin"), FileSize::new(17 * 4096 + 1))
+            .unwrap();
+        assert_eq!(vault.file_block_ids(VaultPath::new("/data.bin")).unwrap().len(), 18);
+        vault
+            .append(VaultPath::new("/data.bin"), &data[17 * 4096 + 1..])
+            .unwrap();
+        assert_eq!(vault.get(VaultPath::new("/data.bin")).unwrap(), data);
+
+        // Content defined blocks are read to find the cut point
+        let mut provider = Provider::in_memory();
+        provider.set_chunk_strategy(ContentDefinedChunker::default());
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault.put_reader("data.bin", data.as_slice(), size).unwrap();
+        vault
+            .truncate(VaultPath::new("/data.bin"), FileSize::new(50_000))
+            .unwrap();
+        assert_eq!(vault.get(VaultPath::new("/data.bin")).unwrap(), &data[..50_000]);
+        vault.append(VaultPath::new("/data.bin"), &data[50_000..]).unwrap();
+        assert_eq!(vault.get(VaultPath::new("/data.bin")).unwrap(), data);
+
+        let dir = TempDir::new("chunk");
+        let base_dir = dir.join("blocks");
+        let state_path = dir.join("vault.db");
+        fs::create_dir_all(&dir).unwrap();
+        let mut provider = Provider::with_base_dir(&base_dir);
+        provider.set_chunk_strategy(FixedSize(BlockSize::from_marker(0)));
+        Vault::initialize(&provider, &state_path).unwrap();
+        assert!(Vault::open(&provider, &state_path).is_ok());
+        assert!(matches!(
+            Vault::open(&Provider::with_base_dir(&base_dir), &state_path),
+            Err(VaultError::Provider(ProviderError::ChunkStrategyMismatch {
+                expected: 0,
+                ..
+            }))
+        ));
+    }
+
+    /// Make sure that every commit keeps the previous vault block around as a snapshot, also after reopening.
+    #[test]
+    fn history() {
+        let dir = TempDir::new("history");
+        let state_path = dir.join("vault.db");
+        fs::create_dir_all(&dir).unwrap();
+        let provider = Provider::with_base_dir(dir.join("blocks"));
+        let mut vault = Vault::initialize(&provider, &state_path).unwrap();
+        assert!(vault.history().is_empty());
+
+        vault.create_directory(VaultPath::new("/a")).unwrap();
+        vault.create_directory(VaultPath::new("/b")).unwrap();
+        let history = vault.history();
+        assert_eq!(history.len(), 2);
+
+        let names = |vault_id: BlockId| -> Vec<String> {
+            let reader = Vault::open_at(&provider, vault_id).unwrap();
+            reader
+                .list(VaultPath::new("/"))
+                .unwrap()
+                .into_iter()
+                .map(|(_, name)| name)
+                .collect()
+        };
+        assert_eq!(names(history[0]), ["welcome"]);
+        assert_eq!(names(history[1]), ["welcome", "a"]);
+
+        let reopened = Vault::open(&provider, &state_path).unwrap();
+        assert_eq!(reopened.history(), history);
+        assert_eq!(names(reopened.vault_id), ["welcome", "a", "b"]);
+    }
+
+    /// Make sure that garbage collection keeps the requested snapshots and only deletes when it's not a dry run.
+    #[test]
+    fn gc() {
+        let dir = TempDir::new("gc");
+        let state_path = dir.join("vault.db");
+        fs::create_dir_all(&dir).unwrap();
+        let provider = Provider::with_base_dir(dir.join("blocks"));
+        let mut vault = Vault::initialize(&provider, &state_path).unwrap();
+        vault.create_directory(VaultPath::new("/a")).unwrap();
+        vault.create_directory(VaultPath::new("/b")).unwrap();
+        let history = vault.history();
+        let saved = provider.stats().unwrap().blocks;
+
+        let dry_run = vault.gc(1, true).unwrap();
+        assert!(dry_run.blocks > 0);
+        assert_eq!(provider.stats().unwrap().blocks, saved);
+
+        assert_eq!(vault.gc(1, false).unwrap(), dry_run);
+        assert_eq!(provider.stats().unwrap().blocks, saved - dry_run.blocks);
+        assert!(Vault::open_at(&provider, history[0]).is_err());
+        let kept = Vault::open_at(&provider, history[1]).unwrap();
+        assert!(kept.exists(VaultPath::new("/a")));
+
+        assert_eq!(vault.gc(1, false).unwrap(), GcOutcome::default());
+        vault.gc(0, false).unwrap();
+        assert_eq!(
+            provider.stats().unwrap().blocks,
+            vault.reachable_block_ids().unwrap().len()
+        );
+        assert!(vault.exists(VaultPath::new("/b")));
+    }
+
+    /// Make sure that an older snapshot shows the tree and file contents as they were, not as they are now.
+    #[test]
+    fn open_at() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault.touch(VaultPath::new("/welcome/notes.txt")).unwrap();
+        vault.append(VaultPath::new("/welcome/notes.txt"), b"first").unwrap();
+        let snapshot = vault.vault_id();
+
+        vault.append(VaultPath::new("/welcome/notes.txt"), b" second").unwrap();
+        vault.create_directory(VaultPath::new("/later")).unwrap();
+        assert!(vault.history().contains(&snapshot));
+
+        let old = Vault::open_at(&provider, snapshot).unwrap();
+        assert_eq!(old.get(VaultPath::new("/welcome/notes.txt")).unwrap(), b"first");
+        assert!(!old.exists(VaultPath::new("/later")));
+        assert_eq!(
+            vault.get(VaultPath::new("/welcome/notes.txt")).unwrap(),
+            b"first second"
+        );
+        assert!(vault.exists(VaultPath::new("/later")));
+
+        let current = Vault::open_at(&provider, vault.vault_id()).unwrap();
+        assert_eq!(
+            current.get(VaultPath::new("/welcome/notes.txt")).unwrap(),
+            b"first second"
+        );
+    }
+
+    /// Make sure that paths as deep as allowed can be created and resolved without recursing per directory.
+    #[test]
+    fn deep_path() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let deepest = VaultPath::new(format!("/{}", vec!["d"; MAX_PATH_DEPTH].join("/")));
+        vault.create_directory(deepest.clone()).unwrap();
+        assert_eq!(vault.stat(deepest.clone()).unwrap().kind, NodeKind::Directory);
+        assert!(matches!(
+            vault.resolve(deepest.parent().unwrap().child("x")),
+            Err(VaultError::NotFound(path)) if path.file_name() == Some("x")
+        ));
+        assert!(matches!(
+            vault.put_reader(&"n".repeat(MAX_NAME_LENGTH + 1), &[][..], FileSize::new(0)),
+            Err(VaultError::InvalidVaultPath(InvalidVaultPath::NameTooLong { .. }))
+        ));
+    }
+
+    /// Make sure that resolving reports the first component that is missing or can't contain the rest.
+    #[test]
+    fn resolve_missing_component() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault.create_directory(VaultPath::new("/a/c")).unwrap();
+        vault.touch(VaultPath::new("/a/c/notes.txt")).unwrap();
+        vault.move_to_block(VaultPath::new("/a/c")).unwrap();
+        // The moved directory is referred to from the inlined one above it
+        vault.create_directory(VaultPath::new("/a/c/new")).unwrap();
+
+        assert!(vault.resolve(VaultPath::new("/a/c/notes.txt")).is_ok());
+        assert!(vault.exists(VaultPath::new("/a/c/new")));
+        assert!(matches!(
+            vault.resolve(VaultPath::new("/a/b/c")),
+            Err(VaultError::NotFound(path)) if path == VaultPath::new("/a/b")
+        ));
+        assert!(matches!(
+            vault.resolve(VaultPath::new("/a/c/missing/notes.txt")),
+            Err(VaultError::NotFound(path)) if path == VaultPath::new("/a/c/missing")
+        ));
+        assert!(matches!(
+            vault.resolve(VaultPath::new("/a/c/notes.txt/inner")),
+            Err(VaultError::NotADirectory(path)) if path == VaultPath::new("/a/c/notes.txt")
+        ));
+        assert!(!vault.exists(VaultPath::new("/a/c/notes.txt/inner")));
+    }
+
+    /// Make sure that the block ids of the vault follow its mutations and show up in its debug output.
+    #[test]
+    fn block_ids() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let (vault_id, root_id, index_id) = (vault.vault_id(), vault.root_id(), vault.index_id());
+        assert_eq!(vault.resolve(VaultPath::new("/")).unwrap(), (root_id, 0));
+
+        vault.create_directory(VaultPath::new("/docs")).unwrap();
+        assert_ne!(vault.vault_id(), vault_id);
+        assert_ne!(vault.root_id(), root_id);
+        assert_eq!(vault.index_id(), index_id);
+        assert_eq!(vault.history(), [vault_id]);
+
+        let debug = format!("{vault:?}");
+        assert!(debug.contains(&vault.vault_id().base64()));
+        assert!(debug.contains(&vault.root_id().base64()));
+        assert!(debug.contains(&index_id.base64()));
+    }
+
+    /// Hasher that uses keyed BLAKE3, which computes other ids than the default one.
+    struct KeyedBlake3;
+
+    impl crate::Hasher for KeyedBlake3 {
+        fn algorithm(&self) -> u8 {
+            1
+        }
+
+        fn hash(&self, data: &[u8]) -> [u8; 32] {
+            blake3::keyed_hash(&[7; 32], data).into()
+        }
+    }
+
+    /// Make sure that rehashing a vault gives every block a new id, while the migrated vault reads back the same.
+    #[test]
+    fn rehash_all() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let mut data = vec![0; 100_000];
+        thread_rng().fill(&mut data[..]);
+        vault
+            .put_reader("data.bin", data.as_slice(), FileSize::new(data.len() as u64))
+            .unwrap();
+        vault.create_directory(VaultPath::new("/docs/inner")).unwrap();
+        vault.touch(VaultPath::new("/docs/inner/empty.txt")).unwrap();
+        vault
+            .copy(VaultPath::new("/data.bin"), VaultPath::new("/docs/copy.bin"))
+            .unwrap();
+        vault.move_to_block(VaultPath::new("/docs")).unwrap();
+
+        let mut target = Provider::in_memory();
+        target.set_hasher(KeyedBlake3);
+        let vault_id = vault.rehash_all(&target).unwrap();
+
+        let reachable = vault.reachable_block_ids().unwrap();
+        assert!(reachable.iter().all(|id| !target.contains_block(*id)));
+        // Every block is stored once, even the ones shared by both files
+        assert_eq!(target.stats().unwrap().blocks, reachable.len());
+
+        let migrated = Vault::open_at(&target, vault_id).unwrap();
+        assert_eq!(migrated.get(VaultPath::new("/data.bin")).unwrap(), data);
+        assert_eq!(migrated.get(VaultPath::new("/docs/copy.bin")).unwrap(), data);
+        assert_eq!(
+            migrated.list(VaultPath::new("/docs")).unwrap(),
+            [
+                (NodeKind::Directory, String::from("inner")),
+                (NodeKind::File, String::from("copy.bin"))
+            ]
+        );
+        assert_eq!(
+            migrated.list(VaultPath::new("/docs/inner")).unwrap(),
+            [(NodeKind::File, String::from("empty.txt"))]
+        );
+    }
+
+    /// Make sure that a diff between two snapshots reports only what changed in between.
+    #[test]
+    fn diff() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault.create_directory(VaultPath::new("/docs")).unwrap();
+        let before = vault.vault_id();
+        assert_eq!(Vault::diff(&provider, before, before).unwrap(), []);
+
+        vault.touch(VaultPath::new("/docs/new.txt")).unwrap();
+        let after = vault.vault_id();
+        assert_eq!(
+            Vault::diff(&provider, before, after).unwrap(),
+            [Change::Added(VaultPath::new("/docs/new.txt"))]
+        );
+        assert_eq!(
+            Vault::diff(&provider, after, before).unwrap(),
+            [Change::Removed(VaultPath::new("/docs/new.txt"))]
+        );
+
+        vault.append(VaultPath::new("/docs/new.txt"), b"contents").unwrap();
+        assert_eq!(
+            Vault::diff(&provider, after, vault.vault_id()).unwrap(),
+            [Change::Modified(VaultPath::new("/docs/new.txt"))]
+        );
+    }
+
+    /// Make sure that files ending inside a layout block get a partial last block that reads back exactly.
+    #[test]
+    fn unaligned_file_sizes() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let mut rng = thread_rng();
+        for size in [1, 4095, 4097, 16 * 4096 + 1, 16 * 4096 + 8192 + 5000] {
+            let data: Vec<u8> = (0..size).map(|_| rng.gen()).collect();
+            let name = format!("{size}.bin");
+            let file_size = FileSize::new(size as u64);
+            vault.put_reader(&name, data.as_slice(), file_size).unwrap();
+
+            let path = VaultPath::new("/").child(&name);
+            assert_eq!(vault.get(path.clone()).unwrap(), data);
+            assert_eq!(vault.stat(path.clone()).unwrap().size, Some(file_size));
+
+            let block_ids = vault.file_block_ids(path).unwrap();
+            let (last_id, full_ids) = block_ids.split_last().unwrap();
+            for (id, layout_size) in full_ids.iter().zip(BlockSize::layout()) {
+                assert_eq!(id.block_size(), layout_size);
+                assert_eq!(provider.get_block(*id).unwrap().unwrap().size(), *layout_size as usize);
+            }
+            let full_size: usize = BlockSize::layout()
+                .take(full_ids.len())
+                .map(|size| *size as usize)
+                .sum();
+            let last_size = size - full_size;
+            assert_eq!(provider.get_block(*last_id).unwrap().unwrap().size(), last_size);
+            assert_eq!(
+                last_id.block_size(),
+                BlockSize::best_fit(
+                    BlockIdIndex::from(full_ids.len() as u32),
+                    FileSize::new(last_size as u64)
+                )
+            );
+        }
+    }
+
+    /// Make sure that creating a directory emits an event for every new block, within a span of its own.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_events() {
+        use std::fmt;
+        use std::sync::{Arc, Mutex};
+
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Subscriber that captures the names of new spans and the messages of events.
+        struct Capture(Arc<Mutex<Vec<String>>>);
+
+        /// Visitor that keeps only the message of an event.
+        struct Message(String);
+
+        impl Visit for Message {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{value:?}");
+                }
+            }
+        }
+
+        impl Subscriber for Capture {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                self.0.lock().unwrap().push(format!("span {}", span.metadata().name()));
+                Id::from_u64(1)
+            }
+
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                let mut message = Message(String::new());
+                event.record(&mut message);
+                self.0.lock().unwrap().push(message.0);
+            }
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Capture(captured.clone()), || {
+            vault.create_directory(VaultPath::new("/a")).unwrap();
+        });
+        assert_eq!(
+            *captured.lock().unwrap(),
+            [
+                "span create_directory",
+                "Created a new directory block",
+                "Created a new vault block"
+            ]
+        );
+    }
+
+    /// Make sure that nodes are stamped with the time of the clock as they are created and changed,
+    /// without the modification time ever going backwards, and that nothing is stamped without a clock.
+    #[test]
+    fn timestamps() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let now = Arc::new(AtomicU64::new(1000));
+        let clock = now.clone();
+        vault.set_clock(Some(Box::new(move || {
+            Timestamp::from_millis(clock.load(Ordering::Relaxed))
+        })));
+        let at = |millis| Some(Timestamp::from_millis(millis));
+
+        vault.touch(VaultPath::new("/docs/notes.txt")).unwrap();
+        let stat = vault.stat(VaultPath::new("/docs/notes.txt")).unwrap();
+        assert_eq!((stat.created, stat.modified), (at(1000), at(1000)));
+
+        now.store(2000, Ordering::Relaxed);
+        vault.append(VaultPath::new("/docs/notes.txt"), b"hello").unwrap();
+        let stat = vault.stat(VaultPath::new("/docs/notes.txt")).unwrap();
+        assert_eq!((stat.created, stat.modified), (at(1000), at(2000)));
+        // Only adding, removing and renaming entries changes a directory
+        let stat = vault.stat(VaultPath::new("/docs")).unwrap();
+        assert_eq!((stat.created, stat.modified), (at(1000), at(1000)));
+
+        now.store(1500, Ordering::Relaxed);
+        vault
+            .truncate(VaultPath::new("/docs/notes.txt"), FileSize::new(2))
+            .unwrap();
+        vault.touch(VaultPath::new("/docs/other.txt")).unwrap();
+        assert_eq!(
+            vault.stat(VaultPath::new("/docs/notes.txt")).unwrap().modified,
+            at(2000)
+        );
+        let stat = vault.stat(VaultPath::new("/docs")).unwrap();
+        assert_eq!((stat.created, stat.modified), (at(1000), at(1500)));
+
+        vault.set_clock(None);
+        vault.touch(VaultPath::new("/docs/unstamped.txt")).unwrap();
+        let stat = vault.stat(VaultPath::new("/docs/unstamped.txt")).unwrap();
+        assert_eq!((stat.created, stat.modified), (None, None));
+        assert_eq!(vault.stat(VaultPath::new("/docs")).unwrap().modified, at(1500));
+    }
+
+    /// Make sure that a touched file is an empty file, along with its newly created parent directories.
+    #[test]
+    fn touch() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+
+        vault.touch(VaultPath::new("/empty.txt")).unwrap();
+        let stat = vault.stat(VaultPath::new("/empty.txt")).unwrap();
+        assert_eq!(stat.kind, NodeKind::File);
+        assert_eq!(stat.size, Some(FileSize::new(0)));
+        assert!(vault.get(VaultPath::new("/empty.txt")).unwrap().is_empty());
+        assert!(vault
+            .list(VaultPath::new("/"))
+            .unwrap()
+            .contains(&(NodeKind::File, String::from("empty.txt"))));
+
+        let events = vault.subscribe();
+        vault.touch(VaultPath::new("/welcome/a/b/note.txt")).unwrap();
+        for path in ["/welcome/a", "/welcome/a/b", "/welcome/a/b/note.txt"] {
+            assert_eq!(events.try_recv().unwrap(), VaultEvent::Created(VaultPath::new(path)));
+        }
+        assert_eq!(
+            vault.list(VaultPath::new("/welcome/a/b")).unwrap(),
+            vec![(NodeKind::File, String::from("note.txt"))]
+        );
+        assert_eq!(
+            vault.stat(VaultPath::new("/welcome/a/b/note.txt")).unwrap().size,
+            Some(FileSize::new(0))
+        );
+
+        assert!(matches!(
+            vault.touch(VaultPath::new("/empty.txt")),
+            Err(VaultError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            vault.touch(VaultPath::new("/empty.txt/nested.txt")),
+            Err(VaultError::NotADirectory(_))
+        ));
+    }
+
+    /// Make sure that a change made through one path of a linked directory is visible through the other.
+    #[test]
+    fn link() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault.create_directory(VaultPath::new("/shared")).unwrap();
+
+        let events = vault.subscribe();
+        vault
+            .link(VaultPath::new("/shared"), VaultPath::new("/welcome/alias"))
+            .unwrap();
+        assert_eq!(
+            events.try_recv().unwrap(),
+            VaultEvent::Created(VaultPath::new("/welcome/alias"))
+        );
+        assert_eq!(
+            vault.resolve(VaultPath::new("/welcome/alias")).unwrap(),
+            vault.resolve(VaultPath::new("/shared")).unwrap()
+        );
+
+        vault.create_directory(VaultPath::new("/welcome/alias/inner")).unwrap();
+        assert_eq!(
+            vault.list(VaultPath::new("/shared")).unwrap(),
+            vec![(NodeKind::Directory, String::from("inner"))]
+        );
+        vault.create_directory(VaultPath::new("/shared/inner/deeper")).unwrap();
+        assert_eq!(
+            vault.list(VaultPath::new("/welcome/alias/inner")).unwrap(),
+            vec![(NodeKind::Directory, String::from("deeper"))]
+        );
+
+        // Files can be linked as well
+        vault
+            .put_reader("a.txt", io::Cursor::new("a"), FileSize::new(1))
+            .unwrap();
+        vault
+            .link(VaultPath::new("/a.txt"), VaultPath::new("/shared/b.txt"))
+            .unwrap();
+        assert_eq!(vault.get(VaultPath::new("/welcome/alias/b.txt")).unwrap(), b"a");
+
+        assert!(matches!(
+            vault.link(VaultPath::new("/shared"), VaultPath::new("/shared/inner/loop")),
+            Err(VaultError::LinkCycle(_))
+        ));
+        assert!(matches!(
+            vault.link(VaultPath::new("/shared"), VaultPath::new("/welcome/alias/loop")),
+            Err(VaultError::LinkCycle(_))
+        ));
+        assert!(matches!(
+            vault.link(VaultPath::new("/"), VaultPath::new("/root")),
+            Err(VaultError::LinkCycle(_))
+        ));
+        assert!(matches!(
+            vault.link(VaultPath::new("/shared"), VaultPath::new("/a.txt")),
+            Err(VaultError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            vault.link(VaultPath::new("/missing"), VaultPath::new("/alias")),
+            Err(VaultError::NotFound(_))
+        ));
+        assert!(matches!(
+            vault.link(VaultPath::new("/shared"), VaultPath::new("/a.txt/alias")),
+            Err(VaultError::NotADirectory(_))
+        ));
+        assert_eq!(
+            vault.list(VaultPath::new("/")).unwrap(),
+            vec![
+                (NodeKind::Directory, String::from("welcome")),
+                (NodeKind::Directory, String::from("shared")),
+                (NodeKind::File, String::from("a.txt")),
+            ]
+        );
+    }
+
+    /// Make sure that a symlink to a directory can be listed and read through, while being listed as a symlink.
+    #[test]
+    fn symlink() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault.create_directory(VaultPath::new("/docs/inner")).unwrap();
+        vault
+            .put_reader("notes.txt", io::Cursor::new("notes"), FileSize::new(5))
+            .unwrap();
+        vault
+            .copy(VaultPath::new("/notes.txt"), VaultPath::new("/docs/notes.txt"))
+            .unwrap();
+
+        let events = vault.subscribe();
+        vault.symlink(VaultPath::new("/link"), VaultPath::new("/docs")).unwrap();
+        assert_eq!(events.try_recv().unwrap(), VaultEvent::Created(VaultPath::new("/link")));
+        assert_eq!(
+            vault.list(VaultPath::new("/link")).unwrap(),
+            vault.list(VaultPath::new("/docs")).unwrap()
+        );
+        assert_eq!(vault.get(VaultPath::new("/link/notes.txt")).unwrap(), b"notes");
+        assert_eq!(vault.stat(VaultPath::new("/link")).unwrap().kind, NodeKind::Symlink);
+        assert_eq!(
+            vault.list(VaultPath::new("/")).unwrap(),
+            vec![
+                (NodeKind::Directory, String::from("welcome")),
+                (NodeKind::Directory, String::from("docs")),
+                (NodeKind::File, String::from("notes.txt")),
+                (NodeKind::Symlink, String::from("link")),
+            ]
+        );
+
+        // Symlinks to files, to other symlinks and to paths that don't exist yet
+        vault
+            .symlink(
+                VaultPath::new("/docs/inner/notes.txt"),
+                VaultPath::new("/link/notes.txt"),
+            )
+            .unwrap();
+        assert_eq!(vault.get(VaultPath::new("/docs/inner/notes.txt")).unwrap(), b"notes");
+        vault
+            .symlink(VaultPath::new("/later"), VaultPath::new("/docs/later.txt"))
+            .unwrap();
+        assert!(matches!(
+            vault.get(VaultPath::new("/later")),
+            Err(VaultError::NotFound(_))
+        ));
+        vault
+            .copy(VaultPath::new("/notes.txt"), VaultPath::new("/docs/later.txt"))
+            .unwrap();
+        assert_eq!(vault.get(VaultPath::new("/later")).unwrap(), b"notes");
+
+        // Walking the tree doesn't follow symlinks, so a symlink to an ancestor doesn't recurse
+        vault.symlink(VaultPath::new("/docs/up"), VaultPath::new("/")).unwrap();
+        assert_eq!(vault.du(VaultPath::new("/")).unwrap().logical, 15);
+        assert!(matches!(
+            vault.symlink(VaultPath::new("/link"), VaultPath::new("/welcome")),
+            Err(VaultError::AlreadyExists(_))
+        ));
+    }
+
+    /// Make sure that symlinks pointing to each other fail to resolve instead of looping forever.
+    #[test]
+    fn symlink_loop() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault.symlink(VaultPath::new("/a"), VaultPath::new("/b")).unwrap();
+        vault.symlink(VaultPath::new("/b"), VaultPath::new("/a/inner")).unwrap();
+
+        assert!(matches!(
+            vault.list(VaultPath::new("/a")),
+            Err(VaultError::SymlinkLoop(_))
+        ));
+        assert!(matches!(
+            vault.get(VaultPath::new("/b/file.txt")),
+            Err(VaultError::SymlinkLoop(_))
+        ));
+        // The symlinks themselves are still there
+        assert_eq!(vault.stat(VaultPath::new("/a")).unwrap().kind, NodeKind::Symlink);
+    }
+
+    /// Make sure that a renamed node keeps its contents under the new name, within the same directory only.
+    #[test]
+    fn rename() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault.create_directory(VaultPath::new("/docs/nested")).unwrap();
+        vault.touch(VaultPath::new("/docs/notes.txt")).unwrap();
+        vault.append(VaultPath::new("/docs/notes.txt"), b"hello").unwrap();
+
+        let events = vault.subscribe();
+        vault
+            .rename(VaultPath::new("/docs/notes.txt"), VaultPath::new("/docs/renamed.txt"))
+            .unwrap();
+        vault
+            .rename(VaultPath::new("/docs"), VaultPath::new("/papers"))
+            .unwrap();
+        assert_eq!(vault.get(VaultPath::new("/papers/renamed.txt")).unwrap(), b"hello");
+        assert!(vault.exists(VaultPath::new("/papers/nested")));
+        assert!(!vault.exists(VaultPath::new("/docs")));
+        let events: Vec<VaultEvent> = events.try_iter().collect();
+        assert!(events.contains(&VaultEvent::Removed(VaultPath::new("/docs/notes.txt"))));
+        assert!(events.contains(&VaultEvent::Created(VaultPath::new("/docs/renamed.txt"))));
+
+        vault.touch(VaultPath::new("/other.txt")).unwrap();
+        assert!(matches!(
+            vault.rename(VaultPath::new("/other.txt"), VaultPath::new("/papers")),
+            Err(VaultError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            vault.rename(VaultPath::new("/other.txt"), VaultPath::new("/papers/other.txt")),
+            Err(VaultError::InvalidPath(_))
+        ));
+        assert!(matches!(
+            vault.rename(VaultPath::new("/missing"), VaultPath::new("/found")),
+            Err(VaultError::NotFound(_))
+        ));
+        assert!(matches!(
+            vault.rename(VaultPath::new("/"), VaultPath::new("/root")),
+            Err(VaultError::InvalidPath(_))
+        ));
+    }
+
+    /// Make sure that a symlink to the block of its target still resolves after the target is renamed,
+    /// and keeps taking precedence over its path when something else shows up there.
+    #[test]
+    fn symlink_to_block() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault.create_directory(VaultPath::new("/docs")).unwrap();
+        vault.touch(VaultPath::new("/docs/notes.txt")).unwrap();
+        vault.append(VaultPath::new("/docs/notes.txt"), b"hello").unwrap();
+        vault
+            .symlink_to_block(VaultPath::new("/by-block"), VaultPath::new("/docs"))
+            .unwrap();
+        vault
+            .symlink(VaultPath::new("/by-path"), VaultPath::new("/docs"))
+            .unwrap();
+        assert_ne!(vault.resolve(VaultPath::new("/docs")).unwrap().0, vault.root_id);
+
+        vault
+            .rename(VaultPath::new("/docs"), VaultPath::new("/papers"))
+            .unwrap();
+        assert_eq!(vault.get(VaultPath::new("/by-block/notes.txt")).unwrap(), b"hello");
+        assert!(matches!(
+            vault.get(VaultPath::new("/by-path/notes.txt")),
+            Err(VaultError::NotFound(_))
+        ));
+        assert_eq!(vault.stat(VaultPath::new("/by-block")).unwrap().kind, NodeKind::Symlink);
+
+        vault.create_directory(VaultPath::new("/docs")).unwrap();
+        assert_eq!(
+            vault.list(VaultPath::new("/by-block")).unwrap(),
+            vec![(NodeKind::File, String::from("notes.txt"))]
+        );
+        assert!(vault.list(VaultPath::new("/by-path")).unwrap().is_empty());
+    }
+
+    /// Make sure that an inlined node moves into a block of its own and still resolves, along with its contents.
+    #[test]
+    fn move_to_block() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault.create_directory(VaultPath::new("/first/nested")).unwrap();
+        vault.touch(VaultPath::new("/first/notes.txt")).unwrap();
+        vault.append(VaultPath::new("/first/notes.txt"), b"hello").unwrap();
+        vault.create_directory(VaultPath::new("/second")).unwrap();
+        assert_eq!(vault.resolve(VaultPath::new("/first")).unwrap().0, vault.root_id);
+        let inlined_size = vault.root.block().size();
+
+        let events = vault.subscribe();
+        vault.move_to_block(VaultPath::new("/first")).unwrap();
+        assert_eq!(
+            events.try_recv().unwrap(),
+            VaultEvent::Modified(VaultPath::new("/first"))
+        );
+        let Some((Some(block_id), 0)) = vault.root.directory_get_entry_block_id_and_node_index(0, "first") else {
+            panic!("The entry should refer to a block");
+        };
+        assert_eq!(vault.resolve(VaultPath::new("/first")).unwrap(), (block_id, 0));
+        assert_eq!(vault.resolve(VaultPath::new("/first/nested")).unwrap().0, block_id);
+        assert_eq!(vault.get(VaultPath::new("/first/notes.txt")).unwrap(), b"hello");
+        // The moved nodes no longer take up room in the root block
+        assert!(vault.root.block().size() < inlined_size);
+        assert_eq!(
+            vault.list(VaultPath::new("/")).unwrap(),
+            vec![
+                (NodeKind::Directory, String::from("welcome")),
+                (NodeKind::Directory, String::from("first")),
+                (NodeKind::Directory, String::from("second")),
+            ]
+        );
+
+        // The moved nodes can still be changed
+        vault.create_directory(VaultPath::new("/first/nested/deeper")).unwrap();
+        vault.create_directory(VaultPath::new("/second/other")).unwrap();
+        assert_eq!(
+            vault.list(VaultPath::new("/first/nested")).unwrap(),
+            vec![(NodeKind::Directory, String::from("deeper"))]
+        );
+        assert_eq!(
+            vault.list(VaultPath::new("/second")).unwrap(),
+            vec![(NodeKind::Directory, String::from("other"))]
+        );
+
+        // A moved node below an inlined directory can still be changed
+        vault.create_directory(VaultPath::new("/second/inner")).unwrap();
+        vault.move_to_block(VaultPath::new("/second/inner")).unwrap();
+        vault.touch(VaultPath::new("/second/inner/notes.txt")).unwrap();
+        assert_eq!(
+            vault.list(VaultPath::new("/second/inner")).unwrap(),
+            vec![(NodeKind::File, String::from("notes.txt"))]
+        );
+
+        // Nodes in a block of their own stay where they are
+        let vault_id = vault.vault_id();
+        vault.move_to_block(VaultPath::new("/first")).unwrap();
+        vault.move_to_block(VaultPath::new("/")).unwrap();
+        assert_eq!(vault.vault_id(), vault_id);
+
+        vault
+            .link(VaultPath::new("/second/other"), VaultPath::new("/welcome/alias"))
+            .unwrap();
+        assert!(matches!(
+            vault.move_to_block(VaultPath::new("/second")),
+            Err(VaultError::LinkAcrossBlocks(_))
+        ));
+        assert!(matches!(
+            vault.move_to_block(VaultPath::new("/missing")),
+            Err(VaultError::NotFound(_))
+        ));
+    }
+
+    /// Make sure that a directory tree is removed with a single commit, reporting every node depth-first
+    /// only once committed, and that an interrupted removal is completed on open.
+    #[test]
+    fn rmdir_recursive() {
+        let dir = TempDir::new("vault");
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault.create_directory(VaultPath::new("/trash/a/b")).unwrap();
+        vault.create_directory(VaultPath::new("/trash/own/deeper")).unwrap();
+        vault.touch(VaultPath::new("/trash/a/b/c.txt")).unwrap();
+        vault.touch(VaultPath::new("/trash/a/d.txt")).unwrap();
+        vault.touch(VaultPath::new("/trash/own/e.txt")).unwrap();
+        vault.move_to_block(VaultPath::new("/trash/own")).unwrap();
+        vault.touch(VaultPath::new("/keep.txt")).unwrap();
+        let history_len = vault.history().len();
+
+        let events = vault.subscribe();
+        let mut removed = Vec::new();
+        vault
+            .rmdir_recursive(VaultPath::new("/trash"), |path| removed.push(path.clone()))
+            .unwrap();
+        let expected = [
+            "/trash/a/b/c.txt",
+            "/trash/a/b",
+            "/trash/a/d.txt",
+            "/trash/a",
+            "/trash/own/deeper",
+            "/trash/own/e.txt",
+            "/trash/own",
+            "/trash",
+        ];
+        assert_eq!(removed, expected.map(VaultPath::new));
+        assert_eq!(vault.history().len(), history_len + 1);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            VaultEvent::Removed(VaultPath::new("/trash"))
+        );
+        assert!(!vault.exists(VaultPath::new("/trash")));
+        assert!(!vault.exists(VaultPath::new("/trash/a/b/c.txt")));
+        assert!(vault.exists(VaultPath::new("/keep.txt")));
+        // The inlined nodes are gone from the root block, leaving only the ones of the remaining entries
+        assert_eq!(vault.root.local_subtree(0).unwrap().len(), 3);
+
+        assert!(matches!(
+            vault.rmdir_recursive(VaultPath::new("/"), |_| ()),
+            Err(VaultError::InvalidPath(_))
+        ));
+        assert!(matches!(
+            vault.rmdir_recursive(VaultPath::new("/keep.txt"), |_| ()),
+            Err(VaultError::NotADirectory(_))
+        ));
+        assert!(matches!(
+            vault.rmdir_recursive(VaultPath::new("/trash"), |_| ()),
+            Err(VaultError::NotFound(_))
+        ));
+
+        let provider = disk_provider(&dir);
+        let path = dir.join("vault.db");
+        let mut vault = Vault::initialize(&provider, &path).unwrap();
+        vault.create_directory(VaultPath::new("/welcome/nested")).unwrap();
+        crash_before_state_update(&provider, &path, |vault| {
+            vault.rmdir_recursive(VaultPath::new("/welcome"), |_| ()).unwrap();
+        });
+        assert!(!Vault::open(&provider, &path)
+            .unwrap()
+            .exists(VaultPath::new("/welcome")));
+
+        // Nothing is reported as removed when the removal can't be committed
+        Vault::open(&provider, &path)
+            .unwrap()
+            .create_directory(VaultPath::new("/full/nested"))
+            .unwrap();
+        let mut full_provider = disk_provider(&dir);
+        full_provider.set_disk_budget(Some(0));
+        let mut vault = Vault::open(&full_provider, &path).unwrap();
+        let mut removed = Vec::new();
+        assert!(matches!(
+            vault.rmdir_recursive(VaultPath::new("/full"), |path| removed.push(path.clone())),
+            Err(VaultError::Provider(ProviderError::QuotaExceeded { .. }))
+        ));
+        assert!(removed.is_empty());
+        assert!(vault.exists(VaultPath::new("/full/nested")));
+    }
+
+    /// Make sure that a directory too large for a single block is split into chained blocks,
+    /// which lookups, listings and changes follow transparently.
+    #[test]
+    fn split_directory() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let max_size = BlockSize::from_marker(0);
+        vault.set_max_directory_block_size(max_size);
+
+        let names: Vec<String> = (0..2000).map(|i| format!("file-{i}")).collect();
+        vault.create_directory(VaultPath::new("/many")).unwrap();
+        for name in &names {
+            vault.touch(VaultPath::new("/many").child(name)).unwrap();
+        }
+        let listed: Vec<String> = vault
+            .list(VaultPath::new("/many"))
+            .unwrap()
+            .into_iter()
+            .map(|(kind, name)| {
+                assert_eq!(kind, NodeKind::File);
+                name
+            })
+            .collect();
+        assert_eq!(listed, names);
+        let (page, more) = vault.list_paged(VaultPath::new("/many"), 1990, 20).unwrap();
+        assert_eq!(page.len(), 10);
+        assert_eq!(page[0], (NodeKind::File, String::from("file-1990")));
+        assert!(!more);
+        assert_eq!(
+            vault.list(VaultPath::new("/")).unwrap(),
+            vec![
+                (NodeKind::Directory, String::from("welcome")),
+                (NodeKind::Directory, String::from("many")),
+            ]
+        );
+
+        for block_id in vault.reachable_block_ids().unwrap() {
+            assert!(provider.get_block(block_id).unwrap().unwrap().size() <= *max_size as usize);
+        }
+        let (oldest_block_id, _) = vault.resolve(VaultPath::new("/many/file-0")).unwrap();
+        let (newest_block_id, _) = vault.resolve(VaultPath::new("/many/file-1999")).unwrap();
+        assert_ne!(oldest_block_id, newest_block_id);
+
+        // Entries in continuation blocks can still be changed
+        let before = vault.vault_id();
+        vault.append(VaultPath::new("/many/file-0"), b"first").unwrap();
+        assert_eq!(vault.get(VaultPath::new("/many/file-0")).unwrap(), b"first");
+        assert_eq!(
+            Vault::diff(&provider, before, vault.vault_id()).unwrap(),
+            vec![Change::Modified(VaultPath::new("/many/file-0"))]
+        );
+        assert!(matches!(
+            vault.touch(VaultPath::new("/many/file-0")),
+            Err(VaultError::AlreadyExists(_))
+        ));
+        assert_eq!(vault.list(VaultPath::new("/many")).unwrap().len(), names.len());
+    }
+
+    /// Make sure that nested paths resolve and that a missing middle component is reported.
+    #[test]
+    fn resolve() {
+        let dir = TempDir::new("vault");
+        let provider = disk_provider(&dir);
+        let mut vault = memory_vault(&provider);
+        vault.create_directory(VaultPath::new("/welcome/a/b")).unwrap();
+
+        let (block_id, node_index) = vault.resolve(VaultPath::new("/welcome/a/b")).unwrap();
+        let stat = provider
+            .get_block(block_id)
+            .unwrap()
+            .unwrap()
+            .info()
+            .unwrap()
+            .node_stat(node_index);
+        assert_eq!(stat.kind, NodeKind::Directory);
+        assert_eq!(vault.resolve(VaultPath::new("/")).unwrap(), (vault.root_id, 0));
+
+        match vault.resolve(VaultPath::new("/welcome/missing/b")) {
+            Err(VaultError::NotFound(path)) => assert_eq!(path, VaultPath::new("/welcome/missing")),
+            other => panic!("expected not found, got {other:?}"),
+        }
+        assert!(matches!(
+            vault.list(VaultPath::new("/welcome/missing")),
+            Err(VaultError::NotFound(_))
+        ));
+    }
+
+    /// Make sure that detailed listings report the kind, size and storage of files and directories.
+    #[test]
+    fn ls() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        vault
+            .put_reader("data.bin", &[7; 5000][..], FileSize::new(5000))
+            .unwrap();
+        vault.create_directory(VaultPath::new("/moved/nested")).unwrap();
+        vault.touch(VaultPath::new("/empty.txt")).unwrap();
+        vault.move_to_block(VaultPath::new("/moved")).unwrap();
+
+        let entry = |name: &str, kind, size: Option<u64>, inlined| DirEntry {
+            name: String::from(name),
+            kind,
+            size: size.map(FileSize::new),
+            inlined,
+        };
+        assert_eq!(
+            vault.ls(VaultPath::new("/")).unwrap(),
+            vec![
+                entry("welcome", NodeKind::Directory, None, true),
+                entry("data.bin", NodeKind::File, Some(5000), true),
+                entry("moved", NodeKind::Directory, None, false),
+                entry("empty.txt", NodeKind::File, Some(0), true),
+            ]
+        );
+        assert_eq!(
+            vault.ls(VaultPath::new("/moved")).unwrap(),
+            vec![entry("nested", NodeKind::Directory, None, true)]
+        );
+        assert!(matches!(
+            vault.ls(VaultPath::new("/missing")),
+            Err(VaultError::NotFound(_))
+        ));
+        assert!(matches!(
+            vault.ls(VaultPath::new("/data.bin")),
+            Err(VaultError::NotADirectory(path)) if path == VaultPath::new("/data.bin")
+        ));
+        assert!(matches!(
+            vault.list_paged(VaultPath::new("/empty.txt"), 0, 10),
+            Err(VaultError::NotADirectory(_))
+        ));
+        assert!(matches!(
+            vault.list_paged(VaultPath::new("/moved/missing"), 0, 10),
+            Err(VaultError::NotFound(_))
+        ));
+    }
+
+    /// Make sure that paging splits a directory at the right boundaries, including entries in other blocks.
+    #[test]
+    fn list_paged() {
+        let dir = TempDir::new("vault");
+        let provider = disk_provider(&dir);
+        let mut vault = memory_vault(&provider);
+
+        let linked_block = InfoBlock::new_directory();
+        let linked_id = EncryptedBlock::encrypt(&linked_block, 0).id(BlockKind::Info).unwrap();
+        provider.cache_block(linked_id, linked_block);
+
+        let mut builder = DirectoryBuilder::new(InfoBlock::new_directory().info().unwrap());
+        for i in 0..9 {
+            builder
+                .create_local_node(0, &format!("d{i}"), NodeKind::Directory)
+                .unwrap();
+        }
+        builder.create_local_node(0, "linked", NodeKind::Directory).unwrap();
+        builder.set_entry_block_id_and_node_index(0, "linked", Some(&linked_id), 0);
+        let welcome_block = builder.build().unwrap();
+        let welcome_id = EncryptedBlock::encrypt(&welcome_block, 0).id(BlockKind::Info).unwrap();
+        provider.cache_block(welcome_id, welcome_block);
+        let root_block = vault
+            .root
+            .directory_set_entry_block_id_and_node_index(0, "welcome", Some(&welcome_id), 0)
+            .unwrap();
+        vault.commit_root(root_block).unwrap();
+
+        let names = |entries: Vec<(NodeKind, String)>| -> Vec<String> {
+            assert!(entries.iter().all(|(kind, _)| *kind == NodeKind::Directory));
+            entries.into_iter().map(|(_, name)| name).collect()
+        };
+        let page = |offset| vault.list_paged(VaultPath::new("/welcome"), offset, 4).unwrap();
+
+        let (entries, more) = page(0);
+        assert_eq!(names(entries), ["d0", "d1", "d2", "d3"]);
+        assert!(more);
+        let (entries, more) = page(4);
+        assert_eq!(names(entries), ["d4", "d5", "d6", "d7"]);
+        assert!(more);
+        let (entries, more) = page(8);
+        assert_eq!(names(entries), ["d8", "linked"]);
+        assert!(!more);
+        let (entries, more) = page(10);
+        assert!(entries.is_empty());
+        assert!(!more);
+
+        // Exactly one full page
+        let (entries, more) = vault.list_paged(VaultPath::new("/welcome"), 6, 4).unwrap();
+        assert_eq!(names(entries), ["d6", "d7", "d8", "linked"]);
+        assert!(!more);
+    }
+
+    /// Make sure that mutations flush the written files to disk and fail if syncing them fails.
+    #[test]
+    fn flush_on_commit() {
+        let dir = TempDir::new("vault");
+        let mut provider = disk_provider(&dir);
+        let fail = Arc::new(AtomicBool::new(false));
+        let failing = fail.clone();
+        provider.set_sync(move |path| {
+            if failing.load(Ordering::Relaxed) {
+                return Err(std::io::Error::other("Simulated sync failure"));
+            }
+            fs::File::open(path).and_then(|file| file.sync_all())
+        });
+        let path = dir.join("vault.db");
+        let mut vault = Vault::initialize(&provider, &path).unwrap();
+        let initial_id = provider.load_block_id_from_file(&path).unwrap();
+
+        fail.store(true, Ordering::Relaxed);
+        let created = vault.create_directory(VaultPath::new("/welcome/new"));
+        let put = vault.put_reader("notes.txt", &b"notes"[..], FileSize::new(5));
+        fail.store(false, Ordering::Relaxed);
+        assert!(matches!(created, Err(VaultError::Provider(ProviderError::Io(..)))));
+        assert!(matches!(put, Err(VaultError::Provider(ProviderError::Io(..)))));
+        // The blocks weren't synced, so the state file wasn't updated
+        assert_eq!(provider.load_block_id_from_file(&path).unwrap(), initial_id);
+
+        vault.create_directory(VaultPath::new("/welcome/new")).unwrap();
+        assert_ne!(provider.load_block_id_from_file(&path).unwrap(), initial_id);
+    }
+
+    /// Make sure that a mutation interrupted before the state update is completed or undone on open.
+    #[test]
+    fn recover() {
+        let dir = TempDir::new("vault");
+        let provider = disk_provider(&dir);
+        let path = dir.join("vault.db");
+        let journal_path = path.with_extension("journal");
+        Vault::initialize(&provider, &path).unwrap();
+        let initial_id = provider.load_block_id_from_file(&path).unwrap();
+        assert_eq!(Vault::recover(&provider, &path).unwrap(), Recovery::Clean);
+
+        let crash = |directory: &str| {
+            crash_before_state_update(&provider, &path, |vault| {
+                vault.create_directory(VaultPath::new(directory)).unwrap();
+            });
+        };
+
+        // All the blocks were written, so the state update gets replayed along with the history entry
+        crash("/welcome/new");
+        assert_eq!(provider.load_block_id_from_file(&path).unwrap(), initial_id);
+        let vault = Vault::open(&provider, &path).unwrap();
+        assert!(vault.exists(VaultPath::new("/welcome/new")));
+        assert_eq!(vault.history(), [initial_id]);
+        assert!(!journal_path.exists());
+        let committed_id = provider.load_block_id_from_file(&path).unwrap();
+        assert_ne!(committed_id, initial_id);
+
+        // Without the commit record the mutation gets rolled back
+        crash("/welcome/lost");
+        let journal = fs::read_to_string(&journal_path).unwrap();
+        let writes: String = journal
+            .lines()
+            .filter(|line| line.starts_with("write "))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        fs::write(&journal_path, writes).unwrap();
+        assert_eq!(Vault::recover(&provider, &path).unwrap(), Recovery::RolledBack);
+        assert_eq!(provider.load_block_id_from_file(&path).unwrap(), committed_id);
+        assert!(!Vault::open(&provider, &path)
+            .unwrap()
+            .exists(VaultPath::new("/welcome/lost")));
+        assert!(!journal_path.exists());
+    }
+
+    /// Make sure that several readers can look into the same vault at once, each seeing its own vault block.
+    #[test]
+    fn vault_reader() {
+        let dir = TempDir::new("vault");
+        let provider = disk_provider(&dir);
+        let path = dir.join("vault.db");
+        let mut vault = Vault::initialize(&provider, &path).unwrap();
+        vault
+            .put_reader("a.txt", io::Cursor::new("abc"), FileSize::new(3))
+            .unwrap();
+        vault.create_directory(VaultPath::new("/welcome/docs")).unwrap();
+        let vault_id = provider.load_block_id_from_file(&path).unwrap();
+        let snapshot = vault.reader();
+        vault.create_directory(VaultPath::new("/later")).unwrap();
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let reader = VaultReader::new(&provider, vault_id).unwrap();
+                    assert_eq!(reader.get(VaultPath::new("/a.txt")).unwrap(), b"abc");
+                    assert!(reader.exists(VaultPath::new("/welcome/docs")));
+                    assert!(!reader.exists(VaultPath::new("/later")));
+                    assert_eq!(
+                        reader.stat(VaultPath::new("/a.txt")).unwrap().size,
+                        Some(FileSize::new(3))
+                    );
+                    assert_eq!(
+                        reader.list(VaultPath::new("/welcome")).unwrap(),
+                        vec![(NodeKind::Directory, String::from("docs"))]
+                    );
+                });
+            }
+        });
+
+        assert!(!snapshot.exists(VaultPath::new("/later")));
+        assert!(vault.reader().exists(VaultPath::new("/later")));
+        assert!(matches!(
+            VaultReader::new(&provider, BlockId::from_data([0; 32])),
+            Err(VaultError::UnreadableVaultBlock(..))
+        ));
+    }
+
+    /// Make sure that relative paths are resolved against the current directory without escaping the root.
+    #[test]
+    fn resolve_relative() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        let relative = |path: &str| VaultPath::relative(path).unwrap();
+
+        assert_eq!(vault.cwd(), &VaultPath::new("/"));
+        assert_eq!(
+            vault.resolve_relative(&relative("welcome")).unwrap(),
+            VaultPath::new("/welcome")
+        );
+
+        vault.set_cwd(VaultPath::new("/x"));
+        assert_eq!(
+            vault.resolve_relative(&relative("a/b")).unwrap(),
+            VaultPath::new("/x/a/b")
+        );
+        assert_eq!(
+            vault.resolve_relative(&relative("./a/../b")).unwrap(),
+            VaultPath::new("/x/b")
+        );
+        assert_eq!(vault.resolve_relative(&relative("..")).unwrap(), VaultPath::new("/"));
+        assert!(matches!(
+            vault.resolve_relative(&relative("../..")),
+            Err(VaultError::EscapesRoot(_))
+        ));
+        assert!(matches!(
+            vault.resolve_relative(&relative("a/../../../b")),
+            Err(VaultError::EscapesRoot(_))
+        ));
+        assert!(VaultPath::relative("/x").is_none());
+    }
+
+    /// Make sure that glob patterns match within a directory and across directory levels.
+    #[test]
+    fn glob() {
+        let provider = Provider::in_memory();
+        let mut vault = Vault::initialize_in_memory(&provider).unwrap();
+        for name in ["a.txt", "b.txt", "c.bin", "notes.txt"] {
+            vault
+                .put_reader(name, io::Cursor::new(name), FileSize::new(name.len() as u64))
+                .unwrap();
+        }
+        for path in ["/notes", "/welcome/notes", "/welcome/x/notes/deeper"] {
+            vault.create_directory(VaultPath::new(path)).unwrap();
+        }
+        vault
+            .copy(VaultPath::new("/a.txt"), VaultPath::new("/welcome/d.txt"))
+            .unwrap();
+
+        let glob = |pattern| -> Vec<VaultPath> { vault.glob(pattern).unwrap() };
+        let paths = |paths: &[&str]| -> Vec<VaultPath> { paths.iter().map(VaultPath::new).collect() };
+        assert_eq!(glob("*.txt"), paths(&["/a.txt", "/b.txt", "/notes.txt"]));
+        assert_eq!(glob("/welcome/*.txt"), paths(&["/welcome/d.txt"]));
+        assert_eq!(glob("?.bin"), paths(&["/c.bin"]));
+        assert_eq!(glob("*s*"), paths(&["/notes", "/notes.txt"]));
+        assert_eq!(
+            glob("**/notes"),
+            paths(&["/notes", "/welcome/notes", "/welcome/x/notes"])
+        );
+        assert_eq!(glob("**/deeper"), paths(&["/welcome/x/notes/deeper"]));
+        assert!(glob("*.md").is_empty());
+
+        assert!(wildcard_matches("*a*b?", "xxaxxbc"));
+        assert!(!wildcard_matches("*a*b?", "xxaxxb"));
+        assert!(wildcard_matches("**", ""));
+    }
+
+    /// Make sure that `exists` and `stat` report files, directories and missing paths.
+    #[test]
+    fn exists_and_stat() {
+        let dir = TempDir::new("stat");
+        let provider = disk_provider(&dir);
+        let mut vault = memory_vault(&provider);
+
+        fs::create_dir_all(&dir).unwrap();
+        let os_path = dir.join("file.txt");
+        fs::write(&os_path, b"Hello, world!").unwrap();
+        vault.put(os_path.to_str().unwrap()).unwrap();
+
+        let stat = vault.stat(VaultPath::new("/file.txt")).unwrap();
+        assert_eq!(stat.kind, NodeKind::File);
+        assert_eq!(stat.size, Some(FileSize::new(13)));
+        assert!(vault.exists(VaultPath::new("/file.txt")));
+
+        let stat = vault.stat(VaultPath::new("/welcome")).unwrap();
+        assert_eq!(
+            stat,
+            NodeStat {
+                kind: NodeKind::Directory,
+                size: None,
+                created: None,
+                modified: None,
+            }
+        );
+        assert!(vault.exists(VaultPath::new("/welcome")));
+        assert!(vault.exists(VaultPath::new("/")));
+
+        assert!(matches!(
+            vault.stat(VaultPath::new("/missing")),
+            Err(VaultError::NotFound(_))
+        ));
+        assert!(!vault.exists(VaultPath::new("/missing")));
+        assert!(!vault.exists(VaultPath::new("/missing/deeper")));
+        assert!(!vault.exists(VaultPath::new("/welcome/missing")));
+    }
+
+    /// Make sure that files past `MAX_FILE_SIZE` are rejected with a clean error.
+    #[test]
+    fn file_too_large() {
+        assert_eq!(*check_file_size(MAX_FILE_SIZE).unwrap(), MAX_FILE_SIZE);
+        match check_file_size(MAX_FILE_SIZE + 1) {
+            Err(VaultError::FileTooLarge { size, max }) => {
+                assert_eq!(size, MAX_FILE_SIZE + 1);
+                assert_eq!(max, MAX_FILE_SIZE);
+            }
+            _ => panic!("Expected VaultError::FileTooLarge"),
+        }
     }
 }